@@ -1,8 +1,12 @@
-use std::{fs, path::Path};
+use std::{fs, path::Path, sync::Arc};
 
 use anyhow::Result;
+use bytesize::ByteSize;
 use clap::Parser;
 use wasmer_registry::wasmer_env::WasmerEnv;
+use wasmer_wasix::runtime::{module_cache::FileSystemCache, task_manager::tokio::TokioTaskManager};
+
+use crate::commands::run::module_cache_dir;
 
 #[derive(Debug, Parser)]
 /// The options for the `wasmer cache` subcommand
@@ -26,6 +30,9 @@ impl Cache {
             Cmd::Dir => {
                 println!("{}", self.env.cache_dir().display());
             }
+            Cmd::Stats => {
+                stats(&self.env)?;
+            }
         }
 
         Ok(())
@@ -38,6 +45,9 @@ enum Cmd {
     Clean,
     /// Display the location of the cache
     Dir,
+    /// Show how much space the compiled module cache uses and how effective
+    /// it has been
+    Stats,
 }
 
 fn clean(cache_dir: &Path) -> Result<()> {
@@ -49,3 +59,27 @@ fn clean(cache_dir: &Path) -> Result<()> {
 
     Ok(())
 }
+
+fn stats(env: &WasmerEnv) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let task_manager = Arc::new(TokioTaskManager::new(runtime.handle().clone()));
+    let cache = FileSystemCache::new(module_cache_dir(env), task_manager);
+    let stats = cache.stats()?;
+
+    let lookups = stats.hits + stats.misses;
+    let hit_rate = if lookups == 0 {
+        0.0
+    } else {
+        stats.hits as f64 / lookups as f64 * 100.0
+    };
+
+    println!("Location:  {}", cache.cache_dir().display());
+    println!("Modules:   {}", stats.entries);
+    println!("Size:      {}", ByteSize(stats.size));
+    println!("Hits:      {}", stats.hits);
+    println!("Misses:    {}", stats.misses);
+    println!("Hit rate:  {hit_rate:.1}%");
+    println!("Evictions: {}", stats.evictions);
+
+    Ok(())
+}
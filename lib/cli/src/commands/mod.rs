@@ -23,6 +23,7 @@ mod inspect;
 mod journal;
 mod login;
 pub(crate) mod namespace;
+pub(crate) mod net_server;
 mod package;
 mod publish;
 mod run;
@@ -135,6 +136,7 @@ impl WasmerCmd {
             Some(Cmd::Package(cmd)) => match cmd {
                 Package::Download(cmd) => cmd.execute(),
                 Package::Build(cmd) => cmd.execute(),
+                Package::Mirror(cmd) => cmd.execute(),
             },
             Some(Cmd::Container(cmd)) => match cmd {
                 crate::commands::Container::Unpack(cmd) => cmd.execute(),
@@ -157,6 +159,7 @@ impl WasmerCmd {
             #[cfg(feature = "journal")]
             Some(Cmd::Journal(journal)) => journal.run(),
             Some(Cmd::Ssh(ssh)) => ssh.run(),
+            Some(Cmd::NetServer(server)) => server.run(),
             Some(Cmd::Namespace(namespace)) => namespace.run(),
             Some(Cmd::Domain(namespace)) => namespace.run(),
             None => {
@@ -333,6 +336,10 @@ enum Cmd {
     #[clap(alias = "run-unstable")]
     Run(Run),
 
+    /// Serve the networking of this host to programs run with `--net-remote`.
+    #[clap(name = "net-server")]
+    NetServer(crate::commands::net_server::CmdNetServer),
+
     /// Manage journals (compacting, inspecting, filtering, ...)
     #[cfg(feature = "journal")]
     #[clap(subcommand)]
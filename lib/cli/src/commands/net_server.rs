@@ -0,0 +1,161 @@
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use virtual_net::{host::LocalNetworking, meta::FrameSerializationFormat, RemoteNetworkingServer};
+
+use super::AsyncCliCommand;
+
+/// The format of the frames exchanged with `wasmer run --net-remote`
+pub(crate) const NET_REMOTE_FORMAT: FrameSerializationFormat = FrameSerializationFormat::Bincode;
+
+/// A local transport for remote networking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NetTransport {
+    /// A Unix socket at this path (`unix:<path>`)
+    Unix(PathBuf),
+    /// The standard input and output of the process (`stdio`)
+    Stdio,
+}
+
+impl FromStr for NetTransport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(NetTransport::Unix(path.into())),
+            _ if s == "stdio" => Ok(NetTransport::Stdio),
+            _ => anyhow::bail!("Expected \"unix:<path>\" or \"stdio\", got \"{s}\""),
+        }
+    }
+}
+
+/// Serve the networking of this host to programs started with
+/// `wasmer run --net-remote`, so a sandboxed program's networking can be
+/// brokered by a separate, privileged process.
+///
+/// Anyone that can connect to the Unix socket gets to use the network of
+/// the host, so restrict who can access it.
+#[derive(clap::Parser, Debug)]
+pub struct CmdNetServer {
+    /// Where to serve: `unix:<path>` for a Unix socket, or `stdio` to serve
+    /// a single client over the standard input and output
+    #[clap(index = 1, name = "TRANSPORT")]
+    transport: NetTransport,
+}
+
+#[async_trait::async_trait]
+impl AsyncCliCommand for CmdNetServer {
+    type Output = ();
+
+    async fn run_async(self) -> Result<(), anyhow::Error> {
+        match self.transport {
+            NetTransport::Stdio => {
+                let (_server, driver) = RemoteNetworkingServer::new_from_stdio(
+                    NET_REMOTE_FORMAT,
+                    Arc::new(LocalNetworking::new()),
+                );
+                driver.await;
+                Ok(())
+            }
+            NetTransport::Unix(path) => serve_unix(path).await,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn serve_unix(path: PathBuf) -> Result<(), anyhow::Error> {
+    remove_stale_socket(&path).await?;
+
+    let listener = tokio::net::UnixListener::bind(&path)
+        .with_context(|| format!("Unable to listen on \"{}\"", path.display()))?;
+    eprintln!("Serving networking on unix:{}", path.display());
+
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .context("Unable to accept a connection")?;
+        tracing::debug!("Client connected");
+
+        let (_server, driver) = RemoteNetworkingServer::new_from_unix_stream(
+            stream,
+            NET_REMOTE_FORMAT,
+            Arc::new(LocalNetworking::new()),
+        );
+        tokio::spawn(async move {
+            driver.await;
+            tracing::debug!("Client disconnected");
+        });
+    }
+}
+
+/// Removes a socket left behind by a previous server, as it would stop us
+/// from binding, but not one that a running server still accepts
+/// connections on.
+#[cfg(unix)]
+async fn remove_stale_socket(path: &std::path::Path) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match tokio::net::UnixStream::connect(path).await {
+        Ok(_) => anyhow::bail!(
+            "Another server is already listening on \"{}\"",
+            path.display()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)
+            .with_context(|| format!("Unable to remove \"{}\"", path.display())),
+        Err(e) => Err(e)
+            .with_context(|| format!("Unable to check whether \"{}\" is in use", path.display())),
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_path: PathBuf) -> Result<(), anyhow::Error> {
+    anyhow::bail!("Unix sockets are not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_transports() {
+        assert_eq!(
+            "unix:/run/net.sock".parse::<NetTransport>().unwrap(),
+            NetTransport::Unix("/run/net.sock".into())
+        );
+        assert_eq!(
+            "stdio".parse::<NetTransport>().unwrap(),
+            NetTransport::Stdio
+        );
+        assert!("unix:".parse::<NetTransport>().is_err());
+        assert!("tcp:127.0.0.1:1234".parse::<NetTransport>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stale_sockets_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("net.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        remove_stale_socket(&path).await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sockets_of_running_servers_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("net.sock");
+        let _listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        assert!(remove_stale_socket(&path).await.is_err());
+        assert!(path.exists());
+        assert!(serve_unix(path.clone()).await.is_err());
+    }
+}
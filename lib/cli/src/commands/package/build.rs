@@ -1,12 +1,19 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::Context;
 use dialoguer::console::{style, Emoji};
 use indicatif::ProgressBar;
+use wasmer_registry::wasmer_env::WasmerEnv;
+use wasmer_wasix::runtime::resolver::{Lockfile, PackageId, PackageInfo};
+
+use crate::commands::run::Wasi;
 
 /// Build a container from a package manifest.
 #[derive(clap::Parser, Debug)]
 pub struct PackageBuild {
+    #[clap(flatten)]
+    env: WasmerEnv,
+
     /// Output path for the package file.
     /// Defaults to current directory + [name]-[version].webc.
     #[clap(short = 'o', long)]
@@ -24,6 +31,10 @@ pub struct PackageBuild {
     /// Only checks whether the package could be built successfully
     #[clap(long)]
     check: bool,
+
+    /// Fail if the dependencies no longer match the package's `wasmer.lock`
+    #[clap(long)]
+    locked: bool,
 }
 
 static READING_MANIFEST_EMOJI: Emoji<'_, '_> = Emoji("📖 ", "");
@@ -34,10 +45,12 @@ static SPARKLE: Emoji<'_, '_> = Emoji("✨ ", ":-)");
 impl PackageBuild {
     pub(crate) fn check(package_path: PathBuf) -> Self {
         PackageBuild {
+            env: WasmerEnv::default(),
             out: None,
             quiet: true,
             package: Some(package_path),
             check: true,
+            locked: false,
         }
     }
 
@@ -70,6 +83,8 @@ impl PackageBuild {
             return Ok(());
         }
 
+        self.update_lockfile(&pkg)?;
+
         let pkgname = manifest.name.replace('/', "-");
         let name = format!("{}-{}.webc", pkgname, manifest.version,);
 
@@ -122,6 +137,52 @@ impl PackageBuild {
         Ok(())
     }
 
+    /// Resolve the package's dependencies again so its `wasmer.lock` stays in
+    /// sync with the manifest.
+    ///
+    /// Packages without a lockfile are left alone unless `--locked` was
+    /// passed, in which case the lockfile is required.
+    fn update_lockfile(&self, pkg: &webc::wasmer_package::Package) -> Result<(), anyhow::Error> {
+        let manifest_path = self.manifest_path()?;
+        let lockfile_path = manifest_path.with_file_name(Lockfile::FILE_NAME);
+
+        let existing = if lockfile_path.exists() {
+            Lockfile::from_file(&lockfile_path)?
+        } else if self.locked {
+            anyhow::bail!(
+                "Building with --locked requires \"{}\" to exist",
+                lockfile_path.display()
+            );
+        } else {
+            return Ok(());
+        };
+
+        let root = PackageInfo::from_manifest(pkg.manifest())?;
+        let root_id = PackageId {
+            package_name: root.name.clone(),
+            version: root.version.clone(),
+        };
+
+        let client =
+            wasmer_wasix::http::default_http_client().context("No HTTP client available")?;
+        let source = Wasi::default().prepare_source(&self.env, Arc::new(client))?;
+
+        let resolution = tokio::runtime::Runtime::new()?
+            .block_on(wasmer_wasix::runtime::resolver::resolve_with_lockfile(
+                &root_id, &root, &source, &existing,
+            ))
+            .context("Dependency resolution failed")?;
+        let resolved = Lockfile::from_resolution(&resolution);
+
+        if self.locked {
+            existing.check(&resolved)?;
+        } else if existing != resolved {
+            resolved.save(&lockfile_path)?;
+        }
+
+        Ok(())
+    }
+
     fn manifest_path(&self) -> Result<PathBuf, anyhow::Error> {
         let path = if let Some(p) = &self.package {
             if p.is_dir() {
@@ -185,10 +246,12 @@ description = "hello"
         std::fs::write(path.join("data").join("hello.txt"), "Hello, world!").unwrap();
 
         let cmd = PackageBuild {
+            env: WasmerEnv::default(),
             package: Some(path.to_owned()),
             out: Some(path.to_owned()),
             quiet: true,
             check: false,
+            locked: false,
         };
 
         cmd.execute().unwrap();
@@ -0,0 +1,175 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{bail, Context};
+use dialoguer::console::{style, Emoji};
+use indicatif::ProgressBar;
+use wasmer_registry::wasmer_env::WasmerEnv;
+use wasmer_wasix::runtime::resolver::{
+    DistributionInfo, MirrorSource, PackageId, PackageSpecifier, Source, WebcHash,
+};
+
+use crate::commands::run::Wasi;
+
+/// Download packages and everything they depend on into a local mirror.
+///
+/// The mirror can be used with `wasmer run --mirror` to run the packages
+/// without network access.
+#[derive(clap::Parser, Debug)]
+pub struct PackageMirror {
+    #[clap(flatten)]
+    env: WasmerEnv,
+
+    /// The directory to store the mirror in. Existing mirrors are updated.
+    #[clap(short = 'o', long)]
+    out: PathBuf,
+
+    /// Run the mirror command without any output
+    #[clap(long)]
+    pub quiet: bool,
+
+    /// The packages to mirror (`namespace/package[@version]`)
+    #[clap(required = true)]
+    packages: Vec<PackageSpecifier>,
+}
+
+static RESOLVING_DEPENDENCIES_EMOJI: Emoji<'_, '_> = Emoji("🔍 ", "");
+static DOWNLOADING_PACKAGE_EMOJI: Emoji<'_, '_> = Emoji("🌐 ", "");
+static WRITING_INDEX_EMOJI: Emoji<'_, '_> = Emoji("📦 ", "");
+
+impl PackageMirror {
+    pub(crate) fn execute(&self) -> Result<(), anyhow::Error> {
+        if self.env.offline() {
+            bail!("Packages can't be mirrored in offline mode");
+        }
+
+        let pb = if self.quiet {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new_spinner()
+        };
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let client =
+            wasmer_wasix::http::default_http_client().context("No HTTP client available")?;
+        let source = Wasi::default().prepare_source(&self.env, Arc::new(client))?;
+
+        pb.println(format!(
+            "{} {}Resolving dependencies...",
+            style("[1/3]").bold().dim(),
+            RESOLVING_DEPENDENCIES_EMOJI
+        ));
+
+        let mut to_download: Vec<(PackageId, DistributionInfo)> = Vec::new();
+
+        for specifier in &self.packages {
+            pb.set_message(format!("Resolving {specifier}"));
+
+            let root = runtime
+                .block_on(source.latest(specifier))
+                .with_context(|| format!("Unable to find \"{specifier}\" in the registry"))?;
+            let resolution = runtime
+                .block_on(wasmer_wasix::runtime::resolver::resolve(
+                    &root.package_id(),
+                    &root.pkg,
+                    &source,
+                ))
+                .with_context(|| format!("Unable to resolve the dependencies of {specifier}"))?;
+
+            let dependencies = resolution
+                .graph
+                .graph()
+                .node_weights()
+                .filter_map(|node| Some((node.id.clone(), node.dist.clone()?)));
+
+            for (id, dist) in std::iter::once((root.package_id(), root.dist)).chain(dependencies) {
+                if !to_download.iter().any(|(existing, _)| *existing == id) {
+                    to_download.push((id, dist));
+                }
+            }
+        }
+
+        pb.println(format!(
+            "{} {}Downloading packages...",
+            style("[2/3]").bold().dim(),
+            DOWNLOADING_PACKAGE_EMOJI
+        ));
+
+        let mut mirror = MirrorSource::open_or_create(&self.out)?;
+        let client = reqwest::blocking::Client::new();
+        let token = self.env.token();
+        let mut downloaded = 0;
+
+        for (id, dist) in &to_download {
+            if mirror.path_for(&dist.webc_sha256).is_some() {
+                tracing::debug!(pkg=%id, "Already mirrored");
+                continue;
+            }
+
+            pb.set_message(format!("Downloading {id}"));
+            let webc = download(&client, dist, token.as_deref())
+                .with_context(|| format!("Unable to download {id} from \"{}\"", dist.webc))?;
+            mirror.add_webc(webc)?;
+            downloaded += 1;
+        }
+
+        pb.println(format!(
+            "{} {}Writing the index...",
+            style("[3/3]").bold().dim(),
+            WRITING_INDEX_EMOJI
+        ));
+
+        mirror.save()?;
+
+        pb.finish_and_clear();
+
+        if !self.quiet {
+            println!(
+                "Mirrored {} packages to \"{}\" ({downloaded} downloaded)",
+                to_download.len(),
+                self.out.display(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn download(
+    client: &reqwest::blocking::Client,
+    dist: &DistributionInfo,
+    token: Option<&str>,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let webc = if dist.webc.scheme() == "file" {
+        let path = dist
+            .webc
+            .to_file_path()
+            .map_err(|_| anyhow::anyhow!("\"{}\" isn't a valid file path", dist.webc))?;
+        std::fs::read(&path).with_context(|| format!("Unable to read \"{}\"", path.display()))?
+    } else {
+        let mut request = client
+            .get(dist.webc.clone())
+            .header(http::header::ACCEPT, "application/webc");
+        if let Some(token) = token {
+            request = request.header(http::header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        request
+            .send()
+            .context("http request failed")?
+            .error_for_status()
+            .context("http request failed with non-success status code")?
+            .bytes()
+            .context("Unable to read the response body")?
+            .to_vec()
+    };
+
+    let hash = WebcHash::sha256(&webc);
+    if hash != dist.webc_sha256 {
+        bail!(
+            "The hash of the downloaded package was {hash}, but {} was expected",
+            dist.webc_sha256
+        );
+    }
+
+    Ok(webc)
+}
@@ -1,8 +1,10 @@
 mod build;
 mod download;
+mod mirror;
 
 pub use build::PackageBuild;
 pub use download::PackageDownload;
+pub use mirror::PackageMirror;
 
 /// Package related commands.
 #[derive(clap::Subcommand, Debug)]
@@ -12,4 +14,5 @@ pub use download::PackageDownload;
 pub enum Package {
     Download(PackageDownload),
     Build(build::PackageBuild),
+    Mirror(PackageMirror),
 }
@@ -21,6 +21,7 @@ use once_cell::sync::Lazy;
 use sha2::{Digest, Sha256};
 use tempfile::NamedTempFile;
 use url::Url;
+use virtual_fs::{RootFileSystemBuilder, TmpFileSystem};
 use wasmer::{
     DeserializeError, Engine, Function, Imports, Instance, Module, Store, Type, TypedFunction,
     Value,
@@ -37,6 +38,7 @@ use wasmer_wasix::{
         dcgi::{DcgiInstanceFactory, DcgiRunner},
         dproxy::DProxyRunner,
         emscripten::EmscriptenRunner,
+        observer::{serve_metrics, AccessLog, AccessLogFormat, Metrics, Observer},
         wasi::WasiRunner,
         wcgi::{self, AbortHandle, NoOpWcgiCallbacks, WcgiRunner},
         MappedCommand, MappedDirectory, Runner,
@@ -44,14 +46,15 @@ use wasmer_wasix::{
     runtime::{
         module_cache::{CacheError, ModuleHash},
         package_loader::PackageLoader,
-        resolver::{PackageSpecifier, QueryError},
+        resolver::{Lockfile, LockfileMode, PackageSpecifier, QueryError},
         task_manager::VirtualTaskManagerExt,
     },
     Runtime, WasiError,
 };
 use webc::{metadata::Manifest, Container};
 
-use crate::{commands::run::wasi::Wasi, error::PrettyError, logging::Output, store::StoreOptions};
+pub(crate) use self::wasi::{module_cache_dir, Wasi};
+use crate::{error::PrettyError, logging::Output, store::StoreOptions};
 
 const TICK: Duration = Duration::from_millis(250);
 
@@ -118,7 +121,9 @@ impl Run {
         let runtime: Arc<dyn Runtime + Send + Sync> = monitoring_runtime.runtime.clone();
         let monitoring_runtime: Arc<dyn Runtime + Send + Sync> = monitoring_runtime;
 
-        let target = self.input.resolve_target(&monitoring_runtime, &pb)?;
+        let target = self
+            .input
+            .resolve_target(&monitoring_runtime, &pb, self.wasi.locked)?;
 
         pb.finish_and_clear();
 
@@ -236,7 +241,10 @@ impl Run {
         runtime: Arc<dyn Runtime + Send + Sync>,
     ) -> Result<(), Error> {
         let mut runner = self.build_wasi_runner(&runtime)?;
-        runner.run_command(command_name, pkg, runtime)
+        let root_fs = self.attach_fs_snapshot(&mut runner)?;
+        let result = runner.run_command(command_name, pkg, runtime);
+        self.save_fs_snapshot(root_fs)?;
+        result
     }
 
     fn run_wcgi(
@@ -247,7 +255,7 @@ impl Run {
         runtime: Arc<dyn Runtime + Send + Sync>,
     ) -> Result<(), Error> {
         let mut runner = wasmer_wasix::runners::wcgi::WcgiRunner::new(NoOpWcgiCallbacks);
-        self.config_wcgi(runner.config(), uses)?;
+        self.config_wcgi(runner.config(), uses, &runtime)?;
         runner.run_command(command_name, pkg, runtime)
     }
 
@@ -255,18 +263,37 @@ impl Run {
         &self,
         config: &mut wcgi::Config,
         uses: Vec<BinaryPackage>,
+        runtime: &Arc<dyn Runtime + Send + Sync>,
     ) -> Result<(), Error> {
         config
             .args(self.args.clone())
             .addr(self.wcgi.addr)
             .envs(self.wasi.env_vars.clone())
-            .map_directories(self.wasi.mapped_dirs.clone())
-            .callbacks(Callbacks::new(self.wcgi.addr))
-            .inject_packages(uses);
+            .mount_directories(self.wasi.mount_directories(self.wasi.mapped_dirs.clone())?)
+            .inject_packages(uses)
+            .static_root(self.wcgi.static_root.clone());
+        for prefix in &self.wcgi.static_prefixes {
+            config.static_prefix(prefix.clone());
+        }
+        for extension in &self.wcgi.static_extensions {
+            config.static_extension(extension.clone());
+        }
+        let tls = self.wasi.tls_server()?;
+        config.callbacks(Callbacks::new(
+            self.wcgi.addr,
+            tls.is_some(),
+            self.wcgi.observer(runtime)?,
+        ));
+        if let Some(tls) = tls {
+            config.tls(tls);
+        }
         *config.capabilities() = self.wasi.capabilities();
         if self.wasi.forward_host_env {
             config.forward_host_env();
         }
+        if let Some((policy, audit_sink)) = self.wasi.fs_policy()? {
+            config.fs_policy(policy, audit_sink);
+        }
 
         #[cfg(feature = "journal")]
         {
@@ -301,7 +328,7 @@ impl Run {
     ) -> Result<(), Error> {
         let factory = DcgiInstanceFactory::new();
         let mut runner = wasmer_wasix::runners::dcgi::DcgiRunner::new(factory);
-        self.config_wcgi(runner.config().inner(), uses);
+        self.config_wcgi(runner.config().inner(), uses, &runtime)?;
         runner.run_command(command_name, pkg, runtime)
     }
 
@@ -313,6 +340,9 @@ impl Run {
     ) -> Result<(), Error> {
         let mut inner = self.build_wasi_runner(&runtime)?;
         let mut runner = wasmer_wasix::runners::dproxy::DProxyRunner::new(inner, pkg);
+        if let Some(observer) = self.wcgi.observer(&runtime)? {
+            runner.config().observer(observer);
+        }
         runner.run_command(command_name, pkg, runtime)
     }
 
@@ -373,10 +403,17 @@ impl Run {
             .with_injected_packages(packages)
             .with_envs(self.wasi.env_vars.clone())
             .with_mapped_host_commands(self.wasi.build_mapped_commands()?)
-            .with_mapped_directories(self.wasi.build_mapped_directories()?)
+            .with_mounted_directories(self.wasi.build_mounted_directories()?)
             .with_forward_host_env(self.wasi.forward_host_env)
             .with_capabilities(self.wasi.capabilities());
 
+        if let Some(upper) = self.wasi.persistent_upper_layer()? {
+            runner.with_persistent_upper_layer(upper);
+        }
+        if let Some((policy, audit_sink)) = self.wasi.fs_policy()? {
+            runner.with_fs_policy(policy, audit_sink);
+        }
+
         #[cfg(feature = "journal")]
         {
             for trigger in self.wasi.snapshot_on.iter().cloned() {
@@ -412,14 +449,64 @@ impl Run {
     ) -> Result<(), Error> {
         let program_name = wasm_path.display().to_string();
 
-        let runner = self.build_wasi_runner(&runtime)?;
-        runner.run_wasm(
+        let mut runner = self.build_wasi_runner(&runtime)?;
+        let root_fs = self.attach_fs_snapshot(&mut runner)?;
+        let result = runner.run_wasm(
             runtime,
             &program_name,
             module,
             module_hash,
             self.wasi.enable_async_threads,
-        )
+        );
+        self.save_fs_snapshot(root_fs)?;
+        result
+    }
+
+    /// If the user asked to seed or snapshot the sandbox's file system, give
+    /// the runner a root file system we can hold onto.
+    fn attach_fs_snapshot(&self, runner: &mut WasiRunner) -> Result<Option<TmpFileSystem>, Error> {
+        if self.wasi.fs_snapshot_in.is_none() && self.wasi.fs_snapshot_out.is_none() {
+            return Ok(None);
+        }
+
+        let root_fs = RootFileSystemBuilder::default().build();
+
+        if let Some(path) = &self.wasi.fs_snapshot_in {
+            let file = File::open(path)
+                .with_context(|| format!("Unable to open \"{}\"", path.display()))?;
+            root_fs
+                .import_archive(std::io::BufReader::new(file))
+                .with_context(|| {
+                    format!(
+                        "Unable to load the file system snapshot from \"{}\"",
+                        path.display()
+                    )
+                })?;
+        }
+
+        runner.with_root_fs(root_fs.clone());
+        Ok(Some(root_fs))
+    }
+
+    fn save_fs_snapshot(&self, root_fs: Option<TmpFileSystem>) -> Result<(), Error> {
+        let (root_fs, path) = match (root_fs, &self.wasi.fs_snapshot_out) {
+            (Some(root_fs), Some(path)) => (root_fs, path),
+            _ => return Ok(()),
+        };
+
+        let file = File::create(path)
+            .with_context(|| format!("Unable to create \"{}\"", path.display()))?;
+        root_fs
+            .export_archive(std::io::BufWriter::new(file))
+            .and_then(|mut writer| writer.flush().map_err(Into::into))
+            .with_context(|| {
+                format!(
+                    "Unable to save the file system snapshot to \"{}\"",
+                    path.display()
+                )
+            })?;
+
+        Ok(())
     }
 
     #[tracing::instrument(skip_all)]
@@ -574,21 +661,37 @@ impl PackageSource {
     /// This will try to automatically download and cache any resources from the
     /// internet.
     #[tracing::instrument(level = "debug", skip_all)]
+    ///
+    /// Dependencies are resolved using the `wasmer.lock` file in a package's
+    /// directory, or the one in the current directory when running anything
+    /// else.
     fn resolve_target(
         &self,
         rt: &Arc<dyn Runtime + Send + Sync>,
         pb: &ProgressBar,
+        locked: bool,
     ) -> Result<ExecutableTarget, Error> {
         match self {
-            PackageSource::File(path) => ExecutableTarget::from_file(path, rt, pb),
-            PackageSource::Dir(d) => ExecutableTarget::from_dir(d, rt, pb),
+            PackageSource::File(path) => ExecutableTarget::from_file(path, rt, pb, locked),
+            PackageSource::Dir(d) => {
+                let lockfile = LockfileHandle::open(d.join(Lockfile::FILE_NAME), true, locked)?;
+                ExecutableTarget::from_dir(d, rt, pb, &lockfile)
+            }
             PackageSource::Package(pkg) => {
+                let lockfile = LockfileHandle::open(Lockfile::FILE_NAME, false, locked)?;
                 pb.set_message("Loading from the registry");
                 let inner_pck = pkg.clone();
                 let inner_rt = rt.clone();
-                let pkg = rt.task_manager().spawn_and_block_on(async move {
-                    BinaryPackage::from_registry(&inner_pck, inner_rt.as_ref()).await
+                let inner_lockfile = lockfile.clone();
+                let (pkg, resolved) = rt.task_manager().spawn_and_block_on(async move {
+                    BinaryPackage::from_registry_with_lockfile(
+                        &inner_pck,
+                        inner_rt.as_ref(),
+                        inner_lockfile.mode(),
+                    )
+                    .await
                 })??;
+                lockfile.update(&resolved)?;
                 Ok(ExecutableTarget::Package(pkg))
             }
         }
@@ -604,6 +707,68 @@ impl Display for PackageSource {
     }
 }
 
+/// The `wasmer.lock` file used when resolving a package's dependencies.
+#[derive(Debug, Clone)]
+struct LockfileHandle {
+    path: PathBuf,
+    existing: Option<Lockfile>,
+    /// Fail instead of deviating from the existing lockfile.
+    locked: bool,
+    /// Write a lockfile even if there wasn't one already.
+    create: bool,
+}
+
+impl LockfileHandle {
+    fn open(path: impl Into<PathBuf>, create: bool, locked: bool) -> Result<Self, Error> {
+        let path = path.into();
+
+        let existing = if path.exists() {
+            Some(Lockfile::from_file(&path)?)
+        } else if locked {
+            bail!(
+                "Running with --locked requires \"{}\" to exist",
+                path.display()
+            );
+        } else {
+            None
+        };
+
+        Ok(LockfileHandle {
+            path,
+            existing,
+            locked,
+            create,
+        })
+    }
+
+    fn mode(&self) -> Option<LockfileMode<'_>> {
+        self.existing.as_ref().map(|lockfile| {
+            if self.locked {
+                LockfileMode::Locked(lockfile)
+            } else {
+                LockfileMode::Prefer(lockfile)
+            }
+        })
+    }
+
+    /// Save the lockfile for a fresh resolution, if it belongs on disk.
+    fn update(&self, resolved: &Lockfile) -> Result<(), Error> {
+        let should_write = match &self.existing {
+            _ if self.locked => false,
+            // Don't clobber a lockfile that belongs to some other package
+            Some(existing) => existing.is_for(&resolved.root.name) && existing != resolved,
+            None => self.create,
+        };
+
+        if should_write {
+            tracing::debug!(path=%self.path.display(), "Updating the lockfile");
+            resolved.save(&self.path)?;
+        }
+
+        Ok(())
+    }
+}
+
 /// We've been given the path for a file... What does it contain and how should
 /// that be run?
 #[derive(Debug, Clone)]
@@ -670,6 +835,7 @@ impl ExecutableTarget {
         dir: &Path,
         runtime: &Arc<dyn Runtime + Send + Sync>,
         pb: &ProgressBar,
+        lockfile: &LockfileHandle,
     ) -> Result<Self, Error> {
         pb.set_message(format!("Loading \"{}\" into memory", dir.display()));
 
@@ -678,10 +844,7 @@ impl ExecutableTarget {
         let container = Container::from(webc);
 
         pb.set_message("Resolving dependencies");
-        let inner_runtime = runtime.clone();
-        let pkg = runtime.task_manager().spawn_and_block_on(async move {
-            BinaryPackage::from_webc(&container, inner_runtime.as_ref()).await
-        })??;
+        let pkg = load_webc(container, runtime, lockfile)?;
 
         Ok(ExecutableTarget::Package(pkg))
     }
@@ -692,6 +855,7 @@ impl ExecutableTarget {
         path: &Path,
         runtime: &Arc<dyn Runtime + Send + Sync>,
         pb: &ProgressBar,
+        locked: bool,
     ) -> Result<Self, Error> {
         pb.set_message(format!("Loading from \"{}\"", path.display()));
 
@@ -729,16 +893,35 @@ impl ExecutableTarget {
                 let container = Container::from_disk(path)?;
                 pb.set_message("Resolving dependencies");
 
-                let inner_runtime = runtime.clone();
-                let pkg = runtime.task_manager().spawn_and_block_on(async move {
-                    BinaryPackage::from_webc(&container, inner_runtime.as_ref()).await
-                })??;
+                let lockfile = LockfileHandle::open(Lockfile::FILE_NAME, false, locked)?;
+                let pkg = load_webc(container, runtime, &lockfile)?;
                 Ok(ExecutableTarget::Package(pkg))
             }
         }
     }
 }
 
+/// Load a container and its dependencies, keeping the lockfile up to date.
+fn load_webc(
+    container: Container,
+    runtime: &Arc<dyn Runtime + Send + Sync>,
+    lockfile: &LockfileHandle,
+) -> Result<BinaryPackage, Error> {
+    let inner_runtime = runtime.clone();
+    let inner_lockfile = lockfile.clone();
+    let (pkg, resolved) = runtime.task_manager().spawn_and_block_on(async move {
+        BinaryPackage::from_webc_with_lockfile(
+            &container,
+            inner_runtime.as_ref(),
+            inner_lockfile.mode(),
+        )
+        .await
+    })??;
+    lockfile.update(&resolved)?;
+
+    Ok(pkg)
+}
+
 #[cfg(feature = "coredump")]
 fn generate_coredump(err: &Error, source_name: String, coredump_path: &Path) -> Result<(), Error> {
     let err: &wasmer::RuntimeError = match err.downcast_ref() {
@@ -784,34 +967,112 @@ pub(crate) struct WcgiOptions {
     /// The address to serve on.
     #[clap(long, short, env, default_value_t = ([127, 0, 0, 1], 8000).into())]
     pub(crate) addr: SocketAddr,
+    /// Serve Prometheus metrics on `/metrics` at this address.
+    #[clap(long, env = "WASMER_METRICS_ADDR")]
+    pub(crate) metrics_addr: Option<SocketAddr>,
+    /// Write an access log line to stdout for every request ("common" or
+    /// "json").
+    #[clap(long)]
+    pub(crate) access_log: Option<AccessLogFormat>,
+    /// Serve requests whose path starts with this prefix straight from the
+    /// package's files (e.g. "/assets/").
+    #[clap(long = "static-prefix")]
+    pub(crate) static_prefixes: Vec<String>,
+    /// Serve requests for files with this extension straight from the
+    /// package's files (e.g. "css").
+    #[clap(long = "static-ext")]
+    pub(crate) static_extensions: Vec<String>,
+    /// The directory in the package that static files are served from.
+    #[clap(long, default_value = "/")]
+    pub(crate) static_root: PathBuf,
 }
 
 impl Default for WcgiOptions {
     fn default() -> Self {
         Self {
             addr: ([127, 0, 0, 1], 8000).into(),
+            metrics_addr: None,
+            access_log: None,
+            static_prefixes: Vec::new(),
+            static_extensions: Vec::new(),
+            static_root: PathBuf::from("/"),
         }
     }
 }
 
-#[derive(Debug)]
+impl WcgiOptions {
+    /// Create the observer that will produce access logs and metrics, starting
+    /// the metrics server if necessary.
+    fn observer(
+        &self,
+        runtime: &Arc<dyn Runtime + Send + Sync>,
+    ) -> Result<Option<Arc<dyn Observer>>, Error> {
+        let mut observers: Vec<Arc<dyn Observer>> = Vec::new();
+
+        if let Some(format) = self.access_log {
+            observers.push(Arc::new(
+                AccessLog::new(format, std::io::stdout())
+                    .context("Unable to start the access log")?,
+            ));
+        }
+
+        if let Some(address) = self.metrics_addr {
+            let metrics = Arc::new(Metrics::new());
+            observers.push(metrics.clone());
+            runtime
+                .task_manager()
+                .task_shared(Box::new(move || {
+                    Box::pin(async move {
+                        if let Err(e) = serve_metrics(address, metrics).await {
+                            tracing::error!(
+                                error = &e as &dyn std::error::Error,
+                                "The metrics server failed",
+                            );
+                        }
+                    })
+                }))
+                .context("Unable to start the metrics server")?;
+        }
+
+        Ok(match observers.len() {
+            0 => None,
+            1 => observers.pop(),
+            _ => Some(Arc::new(observers)),
+        })
+    }
+}
+
 struct Callbacks {
     stderr: Mutex<LineWriter<std::io::Stderr>>,
     addr: SocketAddr,
+    https: bool,
+    observer: Option<Arc<dyn Observer>>,
 }
 
 impl Callbacks {
-    fn new(addr: SocketAddr) -> Self {
+    fn new(addr: SocketAddr, https: bool, observer: Option<Arc<dyn Observer>>) -> Self {
         Callbacks {
             stderr: Mutex::new(LineWriter::new(std::io::stderr())),
             addr,
+            https,
+            observer,
         }
     }
 }
 
+impl std::fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Callbacks")
+            .field("addr", &self.addr)
+            .field("https", &self.https)
+            .finish_non_exhaustive()
+    }
+}
+
 impl wasmer_wasix::runners::wcgi::Callbacks for Callbacks {
     fn started(&self, _abort: AbortHandle) {
-        println!("WCGI Server running at http://{}/", self.addr);
+        let scheme = if self.https { "https" } else { "http" };
+        println!("WCGI Server running at {scheme}://{}/", self.addr);
     }
 
     fn on_stderr(&self, raw_message: &[u8]) {
@@ -823,6 +1084,10 @@ impl wasmer_wasix::runners::wcgi::Callbacks for Callbacks {
             let _ = stderr.write_all(raw_message);
         }
     }
+
+    fn observer(&self) -> Option<Arc<dyn Observer>> {
+        self.observer.clone()
+    }
 }
 
 /// Exit the current process, using the WASI exit code if the error contains
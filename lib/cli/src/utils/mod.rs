@@ -10,9 +10,11 @@ use std::{
 };
 
 use anyhow::{bail, Context as _, Result};
+use bytesize::ByteSize;
 use edge_schema::schema::StringWebcIdent;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use virtual_fs::QuotaLimits;
 use wasmer_api::WasmerClient;
 use wasmer_wasix::runners::MappedDirectory;
 
@@ -48,6 +50,155 @@ pub fn parse_mapdir(entry: &str) -> Result<MappedDirectory> {
     }
 }
 
+/// Parses a disk quota for a mapped directory, in the form
+/// `GUEST_DIR:MAX_BYTES[:MAX_INODES]`.
+///
+/// Either limit may be left empty to leave it unbounded (e.g. `/data::1000`
+/// only limits the number of files and directories).
+pub fn parse_mapdir_quota(entry: &str) -> Result<(String, QuotaLimits)> {
+    let mut parts = entry.split(':');
+
+    let (guest, max_bytes, max_inodes) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(guest), Some(max_bytes), max_inodes) if !guest.is_empty() => {
+            (guest, max_bytes, max_inodes.unwrap_or_default())
+        }
+        _ => bail!(
+            "Directory quotas must be of the form `<guest_dir>:<max_bytes>[:<max_inodes>]`. Found {}",
+            entry
+        ),
+    };
+
+    if parts.next().is_some() {
+        bail!("Too many fields in directory quota \"{}\"", entry);
+    }
+
+    let max_bytes = match max_bytes.trim() {
+        "" => None,
+        size => Some(
+            size.parse::<ByteSize>()
+                .map_err(|e| anyhow::anyhow!(e))
+                .with_context(|| format!("Invalid size \"{size}\" in \"{entry}\""))?
+                .as_u64(),
+        ),
+    };
+    let max_inodes = match max_inodes.trim() {
+        "" => None,
+        count => Some(
+            count
+                .parse::<u64>()
+                .with_context(|| format!("Invalid inode count \"{count}\" in \"{entry}\""))?,
+        ),
+    };
+
+    Ok((
+        guest.to_string(),
+        QuotaLimits {
+            max_bytes,
+            max_inodes,
+        },
+    ))
+}
+
+/// Parses a number of bytes, optionally with a unit (e.g. `512MB` or `2GiB`).
+pub fn parse_byte_size(entry: &str) -> Result<u64> {
+    let size = entry
+        .trim()
+        .parse::<ByteSize>()
+        .map_err(|e| anyhow::anyhow!(e))
+        .with_context(|| format!("Invalid size `{entry}`"))?;
+
+    Ok(size.as_u64())
+}
+
+/// Parses a subnet in CIDR notation (e.g. `10.0.0.0/24`).
+pub fn parse_subnet(entry: &str) -> Result<virtual_net::IpCidr> {
+    let (ip, prefix) = entry
+        .trim()
+        .split_once('/')
+        .with_context(|| format!("Subnets must be of the form `<ip>/<prefix>`; found `{entry}`"))?;
+    let ip = ip
+        .parse()
+        .with_context(|| format!("Invalid IP address in subnet `{entry}`"))?;
+    let prefix = prefix
+        .parse()
+        .with_context(|| format!("Invalid prefix length in subnet `{entry}`"))?;
+
+    Ok(virtual_net::IpCidr { ip, prefix })
+}
+
+/// Parses a name server given as `<ip>` or `<ip>:<port>` (`[<ipv6>]:<port>`
+/// for IPv6), the port defaults to 53.
+pub fn parse_dns_server(entry: &str) -> Result<std::net::SocketAddr> {
+    let entry = entry.trim();
+    if let Ok(addr) = entry.parse() {
+        return Ok(addr);
+    }
+    let ip: std::net::IpAddr = entry.parse().with_context(|| {
+        format!("Name servers must be of the form `<ip>` or `<ip>:<port>`; found `{entry}`")
+    })?;
+    Ok(std::net::SocketAddr::new(ip, virtual_net::dns::DNS_PORT))
+}
+
+/// Parses a static host entry of the form `<name>:<ip>`.
+pub fn parse_host_entry(entry: &str) -> Result<(String, std::net::IpAddr)> {
+    let (name, ip) = entry
+        .trim()
+        .split_once(':')
+        .with_context(|| format!("Hosts must be of the form `<name>:<ip>`; found `{entry}`"))?;
+    if name.is_empty() {
+        bail!("The name is missing in `<name>:<ip>`; found `{entry}`");
+    }
+    let ip = ip
+        .parse()
+        .with_context(|| format!("Invalid IP address in host `{entry}`"))?;
+
+    Ok((name.to_string(), ip))
+}
+
+/// Parses a published port of the form
+/// `[<host-ip>:]<host-port>:<guest-port>[/tcp|/udp]`, the host IP defaults
+/// to 127.0.0.1 and the protocol to TCP.
+pub fn parse_published_port(
+    entry: &str,
+) -> Result<(virtual_net::ForwardProtocol, std::net::SocketAddr, u16)> {
+    let entry = entry.trim();
+    let (ports, protocol) = match entry.rsplit_once('/') {
+        Some((ports, "tcp")) => (ports, virtual_net::ForwardProtocol::Tcp),
+        Some((ports, "udp")) => (ports, virtual_net::ForwardProtocol::Udp),
+        Some((_, protocol)) => {
+            bail!("Unknown protocol `{protocol}` in `{entry}`, expected `tcp` or `udp`")
+        }
+        None => (entry, virtual_net::ForwardProtocol::Tcp),
+    };
+    let (host, guest_port) = ports.rsplit_once(':').with_context(|| {
+        format!(
+            "Published ports must be of the form `[<host-ip>:]<host-port>:<guest-port>[/<protocol>]`; found `{entry}`"
+        )
+    })?;
+    let guest_port = guest_port
+        .parse()
+        .with_context(|| format!("Invalid guest port in `{entry}`"))?;
+    let (host_ip, host_port) = match host.rsplit_once(':') {
+        Some((ip, port)) => {
+            let ip = ip.trim_start_matches('[').trim_end_matches(']');
+            let ip = ip
+                .parse()
+                .with_context(|| format!("Invalid host address in `{entry}`"))?;
+            (ip, port)
+        }
+        None => (std::net::Ipv4Addr::LOCALHOST.into(), host),
+    };
+    let host_port = host_port
+        .parse()
+        .with_context(|| format!("Invalid host port in `{entry}`"))?;
+
+    Ok((
+        protocol,
+        std::net::SocketAddr::new(host_ip, host_port),
+        guest_port,
+    ))
+}
+
 /// Parses an environment variable.
 pub fn parse_envvar(entry: &str) -> Result<(String, String)> {
     let entry = entry.trim();
@@ -387,6 +538,14 @@ pub(crate) fn merge_yaml_values(a: &serde_yaml::Value, b: &serde_yaml::Value) ->
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_byte_sizes() {
+        assert_eq!(parse_byte_size("1024").unwrap(), 1024);
+        assert_eq!(parse_byte_size("2KiB").unwrap(), 2048);
+        assert_eq!(parse_byte_size(" 1 GB ").unwrap(), 1_000_000_000);
+        assert!(parse_byte_size("lots").is_err());
+    }
+
     #[test]
     fn test_merge_yaml_values() {
         use serde_yaml::Value;
@@ -477,4 +636,140 @@ c: c
             ("A".into(), "B=C=D".into())
         );
     }
+
+    #[test]
+    fn test_parse_mapdir_quota() {
+        assert_eq!(
+            parse_mapdir_quota("/data:10MiB").unwrap(),
+            (
+                "/data".to_string(),
+                QuotaLimits {
+                    max_bytes: Some(10 * 1024 * 1024),
+                    max_inodes: None,
+                }
+            )
+        );
+        assert_eq!(
+            parse_mapdir_quota("/data:1000:50").unwrap(),
+            (
+                "/data".to_string(),
+                QuotaLimits {
+                    max_bytes: Some(1000),
+                    max_inodes: Some(50),
+                }
+            )
+        );
+        assert_eq!(
+            parse_mapdir_quota("/data::50").unwrap(),
+            (
+                "/data".to_string(),
+                QuotaLimits {
+                    max_bytes: None,
+                    max_inodes: Some(50),
+                }
+            )
+        );
+        assert!(parse_mapdir_quota("/data").is_err());
+        assert!(parse_mapdir_quota(":1000").is_err());
+        assert!(parse_mapdir_quota("/data:lots").is_err());
+        assert!(parse_mapdir_quota("/data:1:2:3").is_err());
+    }
+
+    #[test]
+    fn test_parse_subnet() {
+        assert_eq!(
+            parse_subnet("10.1.0.0/16").unwrap(),
+            virtual_net::IpCidr {
+                ip: "10.1.0.0".parse().unwrap(),
+                prefix: 16,
+            }
+        );
+        assert!(parse_subnet("10.1.0.0").is_err());
+        assert!(parse_subnet("localhost/8").is_err());
+        assert!(parse_subnet("10.1.0.0/big").is_err());
+    }
+
+    #[test]
+    fn test_parse_dns_server() {
+        assert_eq!(
+            parse_dns_server("1.1.1.1").unwrap(),
+            "1.1.1.1:53".parse::<std::net::SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_dns_server("127.0.0.1:5353").unwrap(),
+            "127.0.0.1:5353".parse::<std::net::SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_dns_server("[fd00::1]:5353").unwrap(),
+            "[fd00::1]:5353".parse::<std::net::SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_dns_server("fd00::1").unwrap(),
+            "[fd00::1]:53".parse::<std::net::SocketAddr>().unwrap()
+        );
+        assert!(parse_dns_server("dns.google").is_err());
+    }
+
+    #[test]
+    fn test_parse_published_port() {
+        use virtual_net::ForwardProtocol;
+
+        assert_eq!(
+            parse_published_port("8080:80").unwrap(),
+            (
+                ForwardProtocol::Tcp,
+                "127.0.0.1:8080".parse::<std::net::SocketAddr>().unwrap(),
+                80
+            )
+        );
+        assert_eq!(
+            parse_published_port("5353:53/udp").unwrap(),
+            (
+                ForwardProtocol::Udp,
+                "127.0.0.1:5353".parse::<std::net::SocketAddr>().unwrap(),
+                53
+            )
+        );
+        assert_eq!(
+            parse_published_port("0.0.0.0:8080:80/tcp").unwrap(),
+            (
+                ForwardProtocol::Tcp,
+                "0.0.0.0:8080".parse::<std::net::SocketAddr>().unwrap(),
+                80
+            )
+        );
+        assert_eq!(
+            parse_published_port("[::1]:8080:80").unwrap(),
+            (
+                ForwardProtocol::Tcp,
+                "[::1]:8080".parse::<std::net::SocketAddr>().unwrap(),
+                80
+            )
+        );
+        assert!(parse_published_port("8080").is_err());
+        assert!(parse_published_port("8080:80/sctp").is_err());
+        assert!(parse_published_port("8080:http").is_err());
+        assert!(parse_published_port("localhost:8080:80").is_err());
+    }
+
+    #[test]
+    fn test_parse_host_entry() {
+        assert_eq!(
+            parse_host_entry("db.internal:10.0.0.5").unwrap(),
+            (
+                "db.internal".to_string(),
+                "10.0.0.5".parse::<std::net::IpAddr>().unwrap()
+            )
+        );
+        assert_eq!(
+            parse_host_entry("db6:fd00::5").unwrap(),
+            (
+                "db6".to_string(),
+                "fd00::5".parse::<std::net::IpAddr>().unwrap()
+            )
+        );
+        assert!(parse_host_entry("db.internal").is_err());
+        assert!(parse_host_entry(":10.0.0.5").is_err());
+        assert!(parse_host_entry("db:nowhere").is_err());
+    }
 }
@@ -1724,6 +1724,8 @@ pub enum JournalSockoptionV1 {
     MulticastTtlV4,
     Type,
     Proto,
+    TlsClient,
+    TlsServer,
 }
 
 #[repr(C)]
@@ -430,6 +430,8 @@ impl From<wasi::Sockoption> for JournalSockoptionV1 {
             wasi::Sockoption::MulticastTtlV4 => JournalSockoptionV1::MulticastTtlV4,
             wasi::Sockoption::Type => JournalSockoptionV1::Type,
             wasi::Sockoption::Proto => JournalSockoptionV1::Proto,
+            wasi::Sockoption::TlsClient => JournalSockoptionV1::TlsClient,
+            wasi::Sockoption::TlsServer => JournalSockoptionV1::TlsServer,
         }
     }
 }
@@ -464,6 +466,8 @@ impl From<JournalSockoptionV1> for wasi::Sockoption {
             JournalSockoptionV1::MulticastTtlV4 => wasi::Sockoption::MulticastTtlV4,
             JournalSockoptionV1::Type => wasi::Sockoption::Type,
             JournalSockoptionV1::Proto => wasi::Sockoption::Proto,
+            JournalSockoptionV1::TlsClient => wasi::Sockoption::TlsClient,
+            JournalSockoptionV1::TlsServer => wasi::Sockoption::TlsServer,
         }
     }
 }
@@ -498,6 +502,8 @@ impl From<&'_ ArchivedJournalSockoptionV1> for wasi::Sockoption {
             ArchivedJournalSockoptionV1::MulticastTtlV4 => wasi::Sockoption::MulticastTtlV4,
             ArchivedJournalSockoptionV1::Type => wasi::Sockoption::Type,
             ArchivedJournalSockoptionV1::Proto => wasi::Sockoption::Proto,
+            ArchivedJournalSockoptionV1::TlsClient => wasi::Sockoption::TlsClient,
+            ArchivedJournalSockoptionV1::TlsServer => wasi::Sockoption::TlsServer,
         }
     }
 }
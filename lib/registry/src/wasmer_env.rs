@@ -25,6 +25,10 @@ pub struct WasmerEnv {
     /// the environment by default)
     #[cfg_attr(feature = "clap", clap(long, env = "WASMER_TOKEN"))]
     token: Option<String>,
+    /// Only use packages that are in the local cache or a mirror, without
+    /// contacting the registry
+    #[cfg_attr(feature = "clap", clap(long, env = "WASMER_OFFLINE"))]
+    offline: bool,
 }
 
 impl WasmerEnv {
@@ -39,6 +43,7 @@ impl WasmerEnv {
             registry,
             token,
             cache_dir,
+            offline: false,
         }
     }
 
@@ -95,6 +100,11 @@ impl WasmerEnv {
         }
     }
 
+    /// Should we avoid contacting the registry and only use local packages?
+    pub fn offline(&self) -> bool {
+        self.offline
+    }
+
     /// Retrieve the specified token.
     ///
     /// NOTE: In contrast to [`Self::token`], this will not fall back to loading
@@ -126,6 +136,7 @@ impl Default for WasmerEnv {
             registry: None,
             token: None,
             cache_dir: None,
+            offline: false,
         }
     }
 }
@@ -205,6 +216,7 @@ mod tests {
             registry: None,
             cache_dir: None,
             token: None,
+            offline: false,
         };
 
         assert_eq!(
@@ -225,6 +237,7 @@ mod tests {
             registry: None,
             cache_dir: None,
             token: Some("asdf".to_string()),
+            offline: false,
         };
 
         assert_eq!(
@@ -244,6 +257,7 @@ mod tests {
             registry: Some(Registry::from("wasmer.wtf")),
             cache_dir: None,
             token: None,
+            offline: false,
         };
 
         assert_eq!(
@@ -264,6 +278,7 @@ mod tests {
             registry: Some(Registry::from("wasmer.wtf")),
             cache_dir: None,
             token: Some("asdf".to_string()),
+            offline: false,
         };
 
         assert_eq!(
@@ -285,6 +300,7 @@ mod tests {
             registry: None,
             cache_dir: Some(expected_cache_dir.clone()),
             token: None,
+            offline: false,
         };
 
         assert_eq!(
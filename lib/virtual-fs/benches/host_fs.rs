@@ -0,0 +1,112 @@
+//! Compare the blocking host file system against the io_uring one.
+//!
+//! Run with `cargo bench -p virtual-fs --features host-fs-io-uring`.
+
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    runtime::Runtime,
+};
+use virtual_fs::FileSystem;
+
+const CHUNK_SIZE: usize = 4096;
+const CHUNKS: usize = 256;
+
+fn file_systems(rt: &Runtime) -> Vec<(&'static str, Box<dyn FileSystem + Send + Sync>)> {
+    let _guard = rt.enter();
+
+    #[allow(unused_mut)]
+    let mut file_systems: Vec<(&'static str, Box<dyn FileSystem + Send + Sync>)> = vec![(
+        "blocking",
+        Box::new(virtual_fs::host_fs::FileSystem::new(rt.handle().clone())),
+    )];
+
+    #[cfg(all(target_os = "linux", feature = "host-fs-io-uring"))]
+    {
+        let fs = virtual_fs::host_fs::io_uring::FileSystem::new(rt.handle().clone());
+        if fs.is_io_uring() {
+            file_systems.push(("io_uring", Box::new(fs)));
+        }
+    }
+
+    file_systems
+}
+
+async fn write_chunks(fs: &dyn FileSystem, path: &Path, sync: bool) {
+    let chunk = [0xAB; CHUNK_SIZE];
+    let mut f = fs
+        .new_open_options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .unwrap();
+
+    for _ in 0..CHUNKS {
+        f.write_all(&chunk).await.unwrap();
+    }
+
+    if sync {
+        f.flush().await.unwrap();
+    }
+}
+
+async fn read_chunks(fs: &dyn FileSystem, path: &Path) {
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut f = fs.new_open_options().read(true).open(path).unwrap();
+
+    for _ in 0..CHUNKS {
+        f.read_exact(&mut chunk).await.unwrap();
+    }
+}
+
+async fn random_reads(fs: &dyn FileSystem, path: &Path) {
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut f = fs.new_open_options().read(true).open(path).unwrap();
+
+    // Emulates fd_pread(), which seeks before every read
+    for i in 0..CHUNKS {
+        let offset = (i * 7919 % CHUNKS) * CHUNK_SIZE;
+        f.seek(std::io::SeekFrom::Start(offset as u64))
+            .await
+            .unwrap();
+        f.read_exact(&mut chunk).await.unwrap();
+    }
+}
+
+fn bench_host_fs(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let temp = TempDir::new().unwrap();
+
+    let mut group = c.benchmark_group("host_fs");
+    group.throughput(Throughput::Bytes((CHUNK_SIZE * CHUNKS) as u64));
+
+    for (name, fs) in file_systems(&rt) {
+        let path = temp.path().join(format!("{name}.bin"));
+        let fs = &*fs;
+
+        group.bench_function(BenchmarkId::new("sequential_write", name), |b| {
+            b.iter(|| rt.block_on(write_chunks(fs, &path, false)))
+        });
+        group.bench_function(BenchmarkId::new("sequential_write_fsync", name), |b| {
+            b.iter(|| rt.block_on(write_chunks(fs, &path, true)))
+        });
+        group.bench_function(BenchmarkId::new("sequential_read", name), |b| {
+            b.iter(|| rt.block_on(read_chunks(fs, &path)))
+        });
+        group.bench_function(BenchmarkId::new("random_read", name), |b| {
+            b.iter(|| rt.block_on(random_reads(fs, &path)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_host_fs);
+criterion_main!(benches);
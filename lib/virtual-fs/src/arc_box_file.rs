@@ -132,6 +132,12 @@ impl VirtualFile for ArcBoxFile {
         let inner = Pin::new(inner.as_mut());
         inner.poll_write_ready(cx)
     }
+
+    fn poll_sync(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = Pin::new(inner.as_mut());
+        inner.poll_sync(cx)
+    }
 }
 
 impl From<Box<dyn VirtualFile + Send + Sync + 'static>> for ArcBoxFile {
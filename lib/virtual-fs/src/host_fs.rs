@@ -18,6 +18,9 @@ use tokio::fs as tfs;
 use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
 use tokio::runtime::Handle;
 
+#[cfg(all(target_os = "linux", feature = "host-fs-io-uring"))]
+pub mod io_uring;
+
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "enable-serde", derive(Serialize, Deserialize))]
 pub struct FileSystem {
@@ -213,6 +216,27 @@ impl TryInto<Metadata> for std::fs::Metadata {
     }
 }
 
+/// Translate an [`OpenOptionsConfig`] to the equivalent [`fs::OpenOptions`],
+/// also returning whether the file will be opened in append mode.
+pub(crate) fn std_open_options(conf: &OpenOptionsConfig) -> (fs::OpenOptions, bool) {
+    // according to Rust's stdlib, specifying both truncate and append is nonsensical,
+    // and it will return an error if we try to open a file with both flags set.
+    // in order to prevent this, and stay compatible with native binaries, we just ignore
+    // the append flag if truncate is set. the rationale behind this decision is that
+    // truncate is going to be applied first and append is going to be ignored anyway.
+    let append = if conf.truncate { false } else { conf.append() };
+
+    let mut oo = fs::OpenOptions::new();
+    oo.read(conf.read())
+        .write(conf.write())
+        .create_new(conf.create_new())
+        .create(conf.create())
+        .append(append)
+        .truncate(conf.truncate());
+
+    (oo, append)
+}
+
 impl crate::FileOpener for FileSystem {
     fn open(
         &self,
@@ -223,32 +247,17 @@ impl crate::FileOpener for FileSystem {
         let read = conf.read();
         let write = conf.write();
 
-        // according to Rust's stdlib, specifying both truncate and append is nonsensical,
-        // and it will return an error if we try to open a file with both flags set.
-        // in order to prevent this, and stay compatible with native binaries, we just ignore
-        // the append flag if truncate is set. the rationale behind this decision is that
-        // truncate is going to be applied first and append is going to be ignored anyway.
-        let append = if conf.truncate { false } else { conf.append() };
-
-        let mut oo = fs::OpenOptions::new();
-        oo.read(conf.read())
-            .write(conf.write())
-            .create_new(conf.create_new())
-            .create(conf.create())
-            .append(append)
-            .truncate(conf.truncate())
-            .open(path)
-            .map_err(Into::into)
-            .map(|file| {
-                Box::new(File::new(
-                    self.handle.clone(),
-                    file,
-                    path.to_owned(),
-                    read,
-                    write,
-                    append,
-                )) as Box<dyn VirtualFile + Send + Sync + 'static>
-            })
+        let (oo, append) = std_open_options(conf);
+        oo.open(path).map_err(Into::into).map(|file| {
+            Box::new(File::new(
+                self.handle.clone(),
+                file,
+                path.to_owned(),
+                read,
+                write,
+                append,
+            )) as Box<dyn VirtualFile + Send + Sync + 'static>
+        })
     }
 }
 
@@ -0,0 +1,941 @@
+//! A version of the host file system which does its file I/O using
+//! [io_uring](https://kernel.dk/io_uring.pdf) instead of blocking syscalls.
+//!
+//! Directory operations and metadata lookups are passed through to the
+//! normal [`host_fs::FileSystem`](super::FileSystem), but reads, writes and
+//! `fsync`s on open files are handed to a dedicated driver thread that owns
+//! the ring. Everything queued up while the driver is busy goes to the kernel
+//! in a single submission, so concurrent guests (or threads within a guest)
+//! don't pay for a thread hop on every operation.
+//!
+//! If the kernel doesn't support io_uring (it's too old, or it has been
+//! disabled with `kernel.io_uring_disabled` or a seccomp filter), the file
+//! system transparently falls back to the normal host file system.
+
+use std::{
+    fmt, fs,
+    future::Future,
+    io::{self, SeekFrom},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use ::io_uring::{cqueue, opcode, squeue, types, IoUring, Probe};
+use futures::future::BoxFuture;
+use slab::Slab;
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf},
+    runtime::Handle,
+    sync::oneshot,
+};
+
+use crate::{FileOpener, Metadata, OpenOptions, OpenOptionsConfig, ReadDir, Result, VirtualFile};
+
+/// The number of submission queue entries in the ring.
+const RING_SIZE: u32 = 256;
+/// The most data a single read or write will transfer.
+const MAX_IO_SIZE: usize = 16 * 1024 * 1024;
+/// The `user_data` used for the driver's own wakeup notifications.
+const WAKEUP: u64 = u64::MAX;
+
+/// A host file system which uses io_uring for file I/O.
+///
+/// See the [module-level docs](self) for more.
+#[derive(Debug, Clone)]
+pub struct FileSystem {
+    inner: super::FileSystem,
+    ring: Option<Arc<Ring>>,
+}
+
+impl Default for FileSystem {
+    fn default() -> Self {
+        FileSystem::new(Handle::current())
+    }
+}
+
+impl FileSystem {
+    /// Create a new [`FileSystem`], falling back to blocking I/O if io_uring
+    /// isn't available.
+    pub fn new(handle: Handle) -> Self {
+        let ring = match Ring::shared() {
+            Ok(ring) => Some(ring),
+            Err(e) => {
+                tracing::warn!(
+                    error = &e as &dyn std::error::Error,
+                    "io_uring is unavailable, falling back to blocking file I/O",
+                );
+                None
+            }
+        };
+
+        FileSystem {
+            inner: super::FileSystem::new(handle),
+            ring,
+        }
+    }
+
+    /// Create a [`FileSystem`] which always uses the blocking fallback.
+    pub fn without_io_uring(handle: Handle) -> Self {
+        FileSystem {
+            inner: super::FileSystem::new(handle),
+            ring: None,
+        }
+    }
+
+    /// Is file I/O actually going through io_uring?
+    pub fn is_io_uring(&self) -> bool {
+        self.ring.is_some()
+    }
+}
+
+impl crate::FileSystem for FileSystem {
+    fn read_dir(&self, path: &Path) -> Result<ReadDir> {
+        self.inner.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, Result<()>> {
+        self.inner.rename(from, to)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn new_open_options(&self) -> OpenOptions {
+        OpenOptions::new(self)
+    }
+}
+
+impl FileOpener for FileSystem {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        let ring = match &self.ring {
+            Some(ring) => Arc::clone(ring),
+            None => return self.inner.open(path, conf),
+        };
+
+        let (options, append) = super::std_open_options(conf);
+        let file = options.open(path)?;
+
+        Ok(Box::new(File {
+            ring,
+            file: Arc::new(file),
+            host_path: path.to_path_buf(),
+            append,
+            cursor: 0,
+            pending: None,
+            seek: None,
+        }))
+    }
+}
+
+/// A file on the host whose reads and writes go through io_uring.
+#[derive(Debug)]
+pub struct File {
+    ring: Arc<Ring>,
+    /// The file is shared with the driver so it can't be closed while the
+    /// kernel is still using it.
+    file: Arc<fs::File>,
+    pub host_path: PathBuf,
+    append: bool,
+    /// All I/O is positional, so we keep track of the cursor ourselves.
+    cursor: u64,
+    pending: Option<Pending>,
+    /// A seek that will be applied once any pending operation has finished.
+    seek: Option<SeekFrom>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    kind: OpKind,
+    offset: u64,
+    result: oneshot::Receiver<io::Result<Completion>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OpKind {
+    Read,
+    Write,
+    Fsync,
+}
+
+/// The operation a caller would like to perform.
+///
+/// Whoever submitted the pending operation may have stopped polling before
+/// it finished, so this is used to check that a completion really belongs to
+/// the current caller.
+#[derive(Debug, Copy, Clone)]
+enum Intent<'a> {
+    Read { len: usize },
+    Write { data: &'a [u8] },
+    Fsync,
+}
+
+impl Intent<'_> {
+    fn to_kind(self) -> OpKind {
+        match self {
+            Intent::Read { .. } => OpKind::Read,
+            Intent::Write { .. } => OpKind::Write,
+            Intent::Fsync => OpKind::Fsync,
+        }
+    }
+
+    fn to_op(self) -> Op {
+        match self {
+            Intent::Read { len } => Op::Read { len },
+            Intent::Write { data } => Op::Write {
+                data: data.to_vec(),
+            },
+            Intent::Fsync => Op::Fsync,
+        }
+    }
+
+    /// Would this operation have been submitted for the current call?
+    fn is_satisfied_by(
+        self,
+        kind: OpKind,
+        offset: u64,
+        expected_offset: u64,
+        result: &io::Result<Completion>,
+    ) -> bool {
+        match (self, kind) {
+            (Intent::Read { .. }, OpKind::Read) => offset == expected_offset,
+            // Only report bytes as written if they are the ones the caller
+            // asked for. Errors can't be told apart, but at least they never
+            // pretend something was written.
+            (Intent::Write { data }, OpKind::Write) => {
+                offset == expected_offset
+                    && match result {
+                        Ok(c) => c.len <= data.len() && c.buf[..c.len] == data[..c.len],
+                        Err(_) => true,
+                    }
+            }
+            (Intent::Fsync, OpKind::Fsync) => true,
+            _ => false,
+        }
+    }
+}
+
+impl File {
+    fn metadata(&self) -> io::Result<fs::Metadata> {
+        self.file.metadata()
+    }
+
+    /// Drive an operation to completion, submitting it if the operation in
+    /// flight (if any) was started for something else.
+    ///
+    /// Operations whose caller stopped polling are still allowed to finish,
+    /// and their effect on the cursor is applied before anything new is
+    /// submitted.
+    fn poll_op(
+        &mut self,
+        cx: &mut Context<'_>,
+        intent: Intent<'_>,
+        offset: impl Fn(&Self) -> u64,
+    ) -> Poll<io::Result<(u64, Completion)>> {
+        loop {
+            match self.poll_pending(cx) {
+                Poll::Ready(Some((kind, op_offset, result))) => {
+                    if intent.is_satisfied_by(kind, op_offset, offset(self), &result) {
+                        return Poll::Ready(result.map(|c| (op_offset, c)));
+                    }
+                    self.abandon(kind, op_offset, &result);
+                }
+                Poll::Ready(None) => {
+                    let offset = offset(self);
+                    let result =
+                        self.ring
+                            .submit(Arc::clone(&self.file), intent.to_op(), offset)?;
+                    self.pending = Some(Pending {
+                        kind: intent.to_kind(),
+                        offset,
+                        result,
+                    });
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Wait for the operation in flight (if any) to finish.
+    fn poll_pending(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<(OpKind, u64, io::Result<Completion>)>> {
+        let pending = match &mut self.pending {
+            Some(pending) => pending,
+            None => return Poll::Ready(None),
+        };
+
+        let result = match Pin::new(&mut pending.result).poll(cx) {
+            Poll::Ready(result) => result.unwrap_or_else(|_| Err(driver_gone())),
+            Poll::Pending => return Poll::Pending,
+        };
+        let Pending { kind, offset, .. } = self.pending.take().unwrap();
+
+        Poll::Ready(Some((kind, offset, result)))
+    }
+
+    /// Wait for any operation that nobody is waiting on anymore to finish.
+    fn poll_settle(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.poll_pending(cx) {
+            Poll::Ready(Some((kind, offset, result))) => {
+                self.abandon(kind, offset, &result);
+                Poll::Ready(())
+            }
+            Poll::Ready(None) => Poll::Ready(()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Handle an operation whose caller stopped polling before it finished.
+    /// Reads can be thrown away, but the cursor still needs to move past
+    /// anything that was written.
+    fn abandon(&mut self, kind: OpKind, offset: u64, result: &io::Result<Completion>) {
+        if let (OpKind::Write, Ok(completion)) = (kind, result) {
+            self.cursor = self.cursor_after_write(offset, completion.len);
+        }
+    }
+
+    fn cursor_after_write(&self, offset: u64, written: usize) -> u64 {
+        if self.append {
+            self.metadata().map(|m| m.len()).unwrap_or(self.cursor)
+        } else {
+            offset + written as u64
+        }
+    }
+
+    fn write_offset(&self) -> u64 {
+        // Files opened with O_APPEND ignore the offset, but -1 makes that
+        // explicit
+        if self.append {
+            u64::MAX
+        } else {
+            self.cursor
+        }
+    }
+}
+
+fn system_time_nanos(time: io::Result<SystemTime>) -> u64 {
+    time.ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|t| t.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl VirtualFile for File {
+    fn last_accessed(&self) -> u64 {
+        system_time_nanos(self.metadata().and_then(|m| m.accessed()))
+    }
+
+    fn last_modified(&self) -> u64 {
+        system_time_nanos(self.metadata().and_then(|m| m.modified()))
+    }
+
+    fn created_time(&self) -> u64 {
+        system_time_nanos(self.metadata().and_then(|m| m.created()))
+    }
+
+    fn size(&self) -> u64 {
+        self.metadata().map(|m| m.len()).unwrap_or(0)
+    }
+
+    fn set_len(&mut self, new_size: u64) -> Result<()> {
+        self.file.set_len(new_size).map_err(Into::into)
+    }
+
+    fn unlink(&mut self) -> Result<()> {
+        fs::remove_file(&self.host_path).map_err(Into::into)
+    }
+
+    fn get_special_fd(&self) -> Option<u32> {
+        None
+    }
+
+    fn poll_read_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let size = self.metadata()?.len();
+        Poll::Ready(Ok(size.saturating_sub(self.cursor) as usize))
+    }
+
+    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(8192))
+    }
+
+    fn poll_sync(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_op(cx, Intent::Fsync, |f| f.cursor).map_ok(|_| ())
+    }
+}
+
+impl AsyncRead for File {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let len = buf.remaining().min(MAX_IO_SIZE);
+        if len == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let (offset, completion) = match self.poll_op(cx, Intent::Read { len }, |f| f.cursor) {
+            Poll::Ready(Ok(c)) => c,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+
+        // The buffer we were given might be smaller than the one we started
+        // the read with
+        let data = &completion.buf[..completion.len];
+        let data = &data[..data.len().min(buf.remaining())];
+        buf.put_slice(data);
+        self.cursor = offset + data.len() as u64;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for File {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let data = &buf[..buf.len().min(MAX_IO_SIZE)];
+        let result = self.poll_op(cx, Intent::Write { data }, File::write_offset);
+
+        match result {
+            Poll::Ready(Ok((offset, completion))) => {
+                self.cursor = self.cursor_after_write(offset, completion.len);
+                Poll::Ready(Ok(completion.len))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes go straight to the kernel, so there is nothing to flush
+        // besides writes that nobody is waiting on anymore. Durability is
+        // left to fsync(), like with the normal host file system.
+        self.poll_settle(cx).map(Ok)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for File {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        // The seek is applied in poll_complete(), after any pending
+        // operation has had a chance to move the cursor
+        self.seek = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        if self.poll_settle(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let position = match self.seek.take() {
+            Some(position) => position,
+            None => return Poll::Ready(Ok(self.cursor)),
+        };
+
+        let new_position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.metadata()?.len().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.cursor.checked_add_signed(delta),
+        };
+
+        match new_position {
+            Some(position) => {
+                self.cursor = position;
+                Poll::Ready(Ok(position))
+            }
+            None => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ))),
+        }
+    }
+}
+
+fn driver_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "the io_uring driver has shut down")
+}
+
+#[derive(Debug)]
+enum Op {
+    Read { len: usize },
+    Write { data: Vec<u8> },
+    Fsync,
+}
+
+#[derive(Debug)]
+struct Completion {
+    /// The number of bytes transferred.
+    len: usize,
+    /// The buffer that was used for the operation.
+    buf: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Request {
+    file: Arc<fs::File>,
+    op: Op,
+    offset: u64,
+    reply: oneshot::Sender<io::Result<Completion>>,
+}
+
+/// A handle to the io_uring driver thread.
+struct Ring {
+    requests: Mutex<Option<mpsc::Sender<Request>>>,
+    wakeup: Arc<OwnedFd>,
+}
+
+impl fmt::Debug for Ring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ring").finish_non_exhaustive()
+    }
+}
+
+impl Ring {
+    /// Get the ring shared by all file systems in this process, starting a
+    /// new driver if necessary.
+    fn shared() -> io::Result<Arc<Ring>> {
+        static SHARED: Mutex<Weak<Ring>> = Mutex::new(Weak::new());
+
+        let mut shared = SHARED.lock().unwrap();
+        if let Some(ring) = shared.upgrade() {
+            return Ok(ring);
+        }
+
+        let ring = Arc::new(Ring::new()?);
+        *shared = Arc::downgrade(&ring);
+        Ok(ring)
+    }
+
+    fn new() -> io::Result<Self> {
+        let ring = IoUring::new(RING_SIZE)?;
+
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        for code in [
+            opcode::Read::CODE,
+            opcode::Write::CODE,
+            opcode::Fsync::CODE,
+            opcode::PollAdd::CODE,
+        ] {
+            if !probe.is_supported(code) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the kernel's io_uring doesn't support all the required operations",
+                ));
+            }
+        }
+
+        let wakeup = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wakeup < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let wakeup = Arc::new(unsafe { OwnedFd::from_raw_fd(wakeup) });
+
+        let (sender, receiver) = mpsc::channel();
+        let driver = Driver {
+            ring,
+            wakeup: Arc::clone(&wakeup),
+            requests: receiver,
+            in_flight: Slab::new(),
+            accepting: true,
+        };
+
+        std::thread::Builder::new()
+            .name("io-uring".to_string())
+            .spawn(move || driver.run())?;
+
+        Ok(Ring {
+            requests: Mutex::new(Some(sender)),
+            wakeup,
+        })
+    }
+
+    fn submit(
+        &self,
+        file: Arc<fs::File>,
+        op: Op,
+        offset: u64,
+    ) -> io::Result<oneshot::Receiver<io::Result<Completion>>> {
+        let (reply, result) = oneshot::channel();
+        let request = Request {
+            file,
+            op,
+            offset,
+            reply,
+        };
+
+        self.requests
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(driver_gone)?
+            .send(request)
+            .map_err(|_| driver_gone())?;
+        wake(self.wakeup.as_raw_fd());
+
+        Ok(result)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // Hang up and let the driver know, so it can finish what it's doing
+        // and exit.
+        self.requests.lock().unwrap().take();
+        wake(self.wakeup.as_raw_fd());
+    }
+}
+
+fn wake(fd: RawFd) {
+    let value: u64 = 1;
+    unsafe {
+        libc::write(fd, &value as *const u64 as *const libc::c_void, 8);
+    }
+}
+
+/// An operation the kernel is currently working on.
+///
+/// The kernel holds pointers into `buf` and uses the file's descriptor, so
+/// both need to stay alive until the operation completes.
+struct InFlight {
+    _file: Arc<fs::File>,
+    buf: Vec<u8>,
+    reply: oneshot::Sender<io::Result<Completion>>,
+}
+
+/// The background thread that owns the io_uring.
+struct Driver {
+    // Note: the ring must be dropped before the eventfd it is polling
+    ring: IoUring,
+    wakeup: Arc<OwnedFd>,
+    requests: mpsc::Receiver<Request>,
+    in_flight: Slab<InFlight>,
+    accepting: bool,
+}
+
+impl Driver {
+    fn run(mut self) {
+        if let Err(e) = self.arm_wakeup() {
+            tracing::error!(
+                error = &e as &dyn std::error::Error,
+                "Unable to start the io_uring driver"
+            );
+            return;
+        }
+
+        loop {
+            // Queue up everything that came in since we last checked so it
+            // all goes to the kernel in a single submission
+            while self.accepting {
+                match self.requests.try_recv() {
+                    Ok(request) => self.start(request),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => self.accepting = false,
+                }
+            }
+
+            if !self.accepting && self.in_flight.is_empty() {
+                break;
+            }
+
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    tracing::error!(
+                        error = &e as &dyn std::error::Error,
+                        "The io_uring driver failed",
+                    );
+                    if self.in_flight.is_empty() {
+                        break;
+                    }
+                    // We can't release the buffers until the kernel is done
+                    // with them, so keep trying
+                    continue;
+                }
+            }
+
+            self.reap();
+        }
+
+        tracing::debug!("The io_uring driver has shut down");
+    }
+
+    fn start(&mut self, request: Request) {
+        let Request {
+            file,
+            op,
+            offset,
+            reply,
+        } = request;
+
+        let fd = types::Fd(file.as_raw_fd());
+        let (mut buf, kind) = match op {
+            Op::Read { len } => (vec![0; len], OpKind::Read),
+            Op::Write { data } => (data, OpKind::Write),
+            Op::Fsync => (Vec::new(), OpKind::Fsync),
+        };
+
+        let entry = match kind {
+            OpKind::Read => opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+                .offset(offset)
+                .build(),
+            OpKind::Write => opcode::Write::new(fd, buf.as_ptr(), buf.len() as u32)
+                .offset(offset)
+                .build(),
+            OpKind::Fsync => opcode::Fsync::new(fd).build(),
+        };
+
+        // Note: moving the Vec into the slab doesn't move its heap allocation
+        let key = self.in_flight.insert(InFlight {
+            _file: file,
+            buf,
+            reply,
+        });
+        self.push(entry.user_data(key as u64));
+    }
+
+    fn arm_wakeup(&mut self) -> io::Result<()> {
+        let fd = types::Fd(self.wakeup.as_raw_fd());
+        let entry = opcode::PollAdd::new(fd, libc::POLLIN as u32)
+            .build()
+            .user_data(WAKEUP);
+        self.push(entry);
+        self.ring.submit().map(|_| ())
+    }
+
+    fn push(&mut self, entry: squeue::Entry) {
+        loop {
+            // Safety: the buffers and file descriptors referenced by the
+            // entry are kept alive in `self.in_flight` (or by `self.wakeup`)
+            // until the operation completes.
+            if unsafe { self.ring.submission().push(&entry) }.is_ok() {
+                return;
+            }
+
+            // The submission queue is full, so flush it to make room
+            if let Err(e) = self.ring.submit() {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    tracing::warn!(
+                        error = &e as &dyn std::error::Error,
+                        "Unable to flush the io_uring submission queue",
+                    );
+                }
+            }
+        }
+    }
+
+    fn reap(&mut self) {
+        let completions: Vec<cqueue::Entry> = self.ring.completion().collect();
+
+        for completion in completions {
+            if completion.user_data() == WAKEUP {
+                self.drain_wakeup();
+                if self.accepting {
+                    let fd = types::Fd(self.wakeup.as_raw_fd());
+                    let entry = opcode::PollAdd::new(fd, libc::POLLIN as u32)
+                        .build()
+                        .user_data(WAKEUP);
+                    self.push(entry);
+                }
+                continue;
+            }
+
+            let InFlight { buf, reply, .. } =
+                self.in_flight.remove(completion.user_data() as usize);
+            let result = match completion.result() {
+                errno if errno < 0 => Err(io::Error::from_raw_os_error(-errno)),
+                len => Ok(Completion {
+                    len: len as usize,
+                    buf,
+                }),
+            };
+            let _ = reply.send(result);
+        }
+    }
+
+    fn drain_wakeup(&self) {
+        let mut value: u64 = 0;
+        unsafe {
+            libc::read(
+                self.wakeup.as_raw_fd(),
+                &mut value as *mut u64 as *mut libc::c_void,
+                8,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::FileSystem as _;
+
+    async fn round_trip(fs: FileSystem) {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("file.txt");
+
+        let mut f = fs
+            .new_open_options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(b"Hello, World!").await.unwrap();
+        f.flush().await.unwrap();
+        assert_eq!(f.size(), 13);
+
+        f.seek(SeekFrom::Start(7)).await.unwrap();
+        f.write_all(b"io_uring").await.unwrap();
+        f.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut contents = String::new();
+        f.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "Hello, io_uring");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Hello, io_uring");
+
+        let mut f = fs.new_open_options().append(true).open(&path).unwrap();
+        f.write_all(b"!").await.unwrap();
+        assert_eq!(f.seek(SeekFrom::Current(0)).await.unwrap(), 16);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Hello, io_uring!");
+    }
+
+    #[tokio::test]
+    async fn read_and_write() {
+        round_trip(FileSystem::default()).await;
+    }
+
+    #[tokio::test]
+    async fn read_and_write_with_the_fallback() {
+        let fs = FileSystem::without_io_uring(Handle::current());
+        assert!(!fs.is_io_uring());
+
+        round_trip(fs).await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_are_batched() {
+        let fs = FileSystem::default();
+        let temp = TempDir::new().unwrap();
+
+        let writes = (0..32).map(|i| {
+            let path = temp.path().join(format!("{i}.txt"));
+            let mut f = fs
+                .new_open_options()
+                .write(true)
+                .create(true)
+                .open(&path)
+                .unwrap();
+            async move {
+                f.write_all(i.to_string().as_bytes()).await.unwrap();
+                f.flush().await.unwrap();
+                path
+            }
+        });
+
+        for (i, path) in futures::future::join_all(writes)
+            .await
+            .into_iter()
+            .enumerate()
+        {
+            assert_eq!(std::fs::read_to_string(path).unwrap(), i.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn abandoned_writes_are_not_reported_for_other_data() {
+        let fs = FileSystem::default();
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("file.txt");
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+
+        {
+            // Start a write and give up on it before it finishes
+            let write = std::pin::pin!(f.write(b"first"));
+            let _ = futures::poll!(write);
+        }
+        f.write_all(b"second").await.unwrap();
+        f.flush().await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "firstsecond");
+    }
+
+    #[tokio::test]
+    async fn seeking_after_an_abandoned_read() {
+        let fs = FileSystem::default();
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("file.txt");
+        std::fs::write(&path, "0123456789").unwrap();
+        let mut f = fs.new_open_options().read(true).open(&path).unwrap();
+
+        let mut buf = [0; 4];
+        {
+            let read = std::pin::pin!(f.read(&mut buf));
+            let _ = futures::poll!(read);
+        }
+        f.seek(SeekFrom::Start(6)).await.unwrap();
+        let mut contents = String::new();
+        f.read_to_string(&mut contents).await.unwrap();
+
+        assert_eq!(contents, "6789");
+    }
+
+    #[tokio::test]
+    async fn seeking_past_the_start_is_an_error() {
+        let fs = FileSystem::default();
+        let temp = TempDir::new().unwrap();
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open(temp.path().join("file.txt"))
+            .unwrap();
+
+        assert_eq!(
+            f.seek(SeekFrom::Current(-1)).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
@@ -40,12 +40,17 @@ mod filesystems;
 pub(crate) mod ops;
 mod overlay_fs;
 pub mod pipe;
+#[cfg(feature = "policy")]
+mod policy_fs;
+mod quota_fs;
 #[cfg(feature = "host-fs")]
 mod scoped_directory_fs;
 mod static_file;
 #[cfg(feature = "static-fs")]
 pub mod static_fs;
 mod trace_fs;
+#[cfg(feature = "host-fs")]
+mod upper_dir_fs;
 #[cfg(feature = "webc-fs")]
 pub mod webc_fs;
 #[cfg(feature = "webc-fs")]
@@ -67,6 +72,12 @@ pub use null_file::*;
 pub use overlay_fs::OverlayFileSystem;
 pub use passthru_fs::*;
 pub use pipe::*;
+#[cfg(feature = "policy")]
+pub use policy_fs::{
+    AuditRecord, AuditSink, FsAccess, FsDecision, FsOperation, FsPolicy, FsRule, JsonAuditSink,
+    PolicyFileSystem,
+};
+pub use quota_fs::{QuotaFileSystem, QuotaLimits};
 #[cfg(feature = "host-fs")]
 pub use scoped_directory_fs::ScopedDirectoryFileSystem;
 pub use special_file::*;
@@ -74,6 +85,8 @@ pub use static_file::StaticFile;
 pub use tmp_fs::*;
 pub use trace_fs::TraceFileSystem;
 pub use union_fs::*;
+#[cfg(feature = "host-fs")]
+pub use upper_dir_fs::UpperDirFileSystem;
 #[cfg(feature = "webc-fs")]
 pub use webc_volume_fs::WebcVolumeFileSystem;
 pub use zero_file::*;
@@ -105,6 +118,15 @@ pub trait FileSystem: fmt::Debug + Send + Sync + 'static + Upcastable {
     fn remove_file(&self, path: &Path) -> Result<()>;
 
     fn new_open_options(&self) -> OpenOptions;
+
+    /// Is this a persistent upper layer for an [`OverlayFileSystem`], where
+    /// whiteouts and opaque directories also hide everything underneath them?
+    ///
+    /// Checking every parent directory for markers is expensive, so overlays
+    /// only do it when their primary layer opts in.
+    fn is_upper_layer(&self) -> bool {
+        false
+    }
 }
 
 impl dyn FileSystem + 'static {
@@ -151,6 +173,10 @@ where
     fn new_open_options(&self) -> OpenOptions {
         (**self).new_open_options()
     }
+
+    fn is_upper_layer(&self) -> bool {
+        (**self).is_upper_layer()
+    }
 }
 
 pub trait FileOpener {
@@ -384,6 +410,12 @@ pub trait VirtualFile:
 
     /// Polls the file for when it is available for writing
     fn poll_write_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>>;
+
+    /// Polls the file until everything written to it has reached durable
+    /// storage (i.e. `fd_sync`), which defaults to a normal flush
+    fn poll_sync(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
 }
 
 // Implementation of `Upcastable` taken from https://users.rust-lang.org/t/why-does-downcasting-not-work-for-subtraits/33286/7 .
@@ -500,6 +532,9 @@ pub enum FsError {
     DirectoryNotEmpty,
     #[error("storage full")]
     StorageFull,
+    /// A disk quota has been exceeded
+    #[error("disk quota exceeded")]
+    QuotaExceeded,
     /// Some other unhandled error. If you see this, it's probably a bug.
     #[error("unknown error found")]
     UnknownError,
@@ -507,6 +542,11 @@ pub enum FsError {
 
 impl From<io::Error> for FsError {
     fn from(io_error: io::Error) -> Self {
+        // Errors that were converted from an `FsError` carry the original
+        if let Some(fs_error) = io_error.get_ref().and_then(|e| e.downcast_ref::<FsError>()) {
+            return *fs_error;
+        }
+
         match io_error.kind() {
             io::ErrorKind::AddrInUse => FsError::AddressInUse,
             io::ErrorKind::AddrNotAvailable => FsError::AddressNotAvailable,
@@ -563,6 +603,9 @@ impl From<FsError> for io::Error {
             FsError::DirectoryNotEmpty => io::ErrorKind::Other,
             FsError::UnknownError => io::ErrorKind::Other,
             FsError::StorageFull => io::ErrorKind::Other,
+            // There is no matching `io::ErrorKind`, so keep the original error
+            // around to make sure it can be recovered later on
+            FsError::QuotaExceeded => return io::Error::new(io::ErrorKind::Other, val),
             // NOTE: Add this once the "io_error_more" Rust feature is stabilized
             // FsError::StorageFull => io::ErrorKind::StorageFull,
         };
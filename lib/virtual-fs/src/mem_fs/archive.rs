@@ -0,0 +1,468 @@
+//! Exporting and importing a [`FileSystem`] as a tar archive.
+
+use super::filesystem::{FileSystemInner, InodeResolution};
+use super::*;
+use crate::{FileSystem as _, FsError, Metadata, Result};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// PAX keys used to preserve nanosecond timestamps, which don't fit in the
+/// classic tar header.
+const PAX_ATIME: &str = "atime";
+const PAX_CTIME: &str = "ctime";
+const PAX_MTIME: &str = "mtime";
+
+impl FileSystem {
+    /// Serialize the entire file system into a tar stream.
+    ///
+    /// Directories, file contents and timestamps are preserved. Nodes that
+    /// belong to another file system (i.e. things added with
+    /// [`FileSystem::mount()`], [`FileSystem::union()`] or the
+    /// `insert_arc_*()` methods) and device files are skipped, because their
+    /// contents don't live inside this file system.
+    ///
+    /// This file system has no notion of symbolic or hard links, so the
+    /// archive never contains any.
+    ///
+    /// The writer is handed back once the archive has been finished.
+    pub fn export_archive<W: Write>(&self, writer: W) -> Result<W> {
+        let guard = self.inner.read().map_err(|_| FsError::Lock)?;
+
+        let mut builder = tar::Builder::new(writer);
+
+        append_children(&guard, &mut builder, ROOT_INODE, Path::new(""))?;
+
+        builder.into_inner().map_err(FsError::from)
+    }
+
+    /// Populate the file system from a tar stream, like the one created by
+    /// [`FileSystem::export_archive()`].
+    ///
+    /// Entries are created relative to the root of the file system, replacing
+    /// any files that already exist. Symbolic links and hard links can't be
+    /// represented by this file system, so they are skipped with a warning.
+    pub fn import_archive<R: Read>(&self, reader: R) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?;
+            let path = match archive_path_to_absolute(&path) {
+                Some(p) => p,
+                None => {
+                    tracing::debug!(path=%path.display(), "Skipping an archive entry with an invalid path");
+                    continue;
+                }
+            };
+            let timestamps = Timestamps::from_entry(&mut entry)?;
+
+            let entry_type = entry.header().entry_type();
+            match entry_type {
+                tar::EntryType::Directory => {
+                    if path != Path::new("/") {
+                        self.create_dir_all(&path)?;
+                    }
+                }
+                tar::EntryType::Regular
+                | tar::EntryType::Continuous
+                | tar::EntryType::GNUSparse => {
+                    let mut data = Vec::with_capacity(entry.size() as usize);
+                    entry.read_to_end(&mut data)?;
+                    self.import_file(&path, &data)?;
+                }
+                tar::EntryType::Symlink | tar::EntryType::Link => {
+                    tracing::warn!(
+                        path=%path.display(),
+                        target=?entry.link_name().ok().flatten(),
+                        "Skipping a link because the in-memory file system can't represent links",
+                    );
+                    continue;
+                }
+                other => {
+                    tracing::debug!(path=%path.display(), entry_type=?other, "Skipping an unsupported archive entry");
+                    continue;
+                }
+            }
+
+            self.set_timestamps(&path, timestamps)?;
+        }
+
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        if self.metadata(path).is_ok() {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        self.create_dir(path)
+    }
+
+    /// Create (or truncate) a file and fill it with `data`.
+    fn import_file(&self, path: &Path, data: &[u8]) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        // Opening the file takes care of creating its node for us.
+        self.new_open_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut guard = self.inner.write().map_err(|_| FsError::Lock)?;
+        let inode = match guard.inode_of(path)? {
+            InodeResolution::Found(inode) => inode,
+            InodeResolution::Redirect(..) => return Err(FsError::InvalidInput),
+        };
+
+        let mut cursor = 0;
+        match guard.storage.get_mut(inode) {
+            Some(Node::File(node)) => {
+                node.file.truncate();
+                node.file.write(data, &mut cursor)?;
+                node.metadata.len = node.file.len() as u64;
+            }
+            Some(Node::OffloadedFile(node)) => {
+                node.file.truncate();
+                node.file
+                    .write(offloaded_file::OffloadWrite::Buffer(data), &mut cursor)?;
+                node.metadata.len = node.file.len();
+            }
+            _ => return Err(FsError::NotAFile),
+        }
+
+        Ok(())
+    }
+
+    fn set_timestamps(&self, path: &Path, timestamps: Timestamps) -> Result<()> {
+        let mut guard = self.inner.write().map_err(|_| FsError::Lock)?;
+        let inode = match guard.inode_of(path)? {
+            InodeResolution::Found(inode) => inode,
+            InodeResolution::Redirect(..) => return Ok(()),
+        };
+
+        if let Some(node) = guard.storage.get_mut(inode) {
+            let metadata = node.metadata_mut();
+            metadata.accessed = timestamps.accessed.unwrap_or(metadata.accessed);
+            metadata.created = timestamps.created.unwrap_or(metadata.created);
+            metadata.modified = timestamps.modified.unwrap_or(metadata.modified);
+        }
+
+        Ok(())
+    }
+}
+
+fn append_children<W: Write>(
+    fs: &FileSystemInner,
+    builder: &mut tar::Builder<W>,
+    inode: Inode,
+    path: &Path,
+) -> Result<()> {
+    let children = match fs.storage.get(inode) {
+        Some(Node::Directory(DirectoryNode { children, .. })) => children,
+        _ => return Ok(()),
+    };
+
+    for child in children {
+        let node = match fs.storage.get(*child) {
+            Some(node) => node,
+            None => continue,
+        };
+        let child_path = path.join(node.name());
+
+        match node {
+            Node::Directory(_) => {
+                append_entry(builder, &child_path, node.metadata(), None)?;
+                append_children(fs, builder, *child, &child_path)?;
+            }
+            Node::File(_) | Node::ReadOnlyFile(_) | Node::OffloadedFile(_) => {
+                let data = read_node(fs, *child)?.unwrap_or_default();
+                append_entry(builder, &child_path, node.metadata(), Some(&data))?;
+            }
+            Node::ArcFile(_) | Node::ArcDirectory(_) | Node::CustomFile(_) => {
+                tracing::trace!(
+                    path=%child_path.display(),
+                    "Skipping a node that isn't backed by the in-memory file system",
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the full contents of a file node, returning `None` if the node isn't
+/// a file stored in memory.
+fn read_node(fs: &FileSystemInner, inode: Inode) -> Result<Option<Vec<u8>>> {
+    let mut cursor = 0;
+    let data = match fs.storage.get(inode) {
+        Some(Node::File(FileNode { file, .. })) => {
+            let mut data = vec![0; file.len()];
+            file.read(&mut data, &mut cursor)?;
+            data
+        }
+        Some(Node::ReadOnlyFile(ReadOnlyFileNode { file, .. })) => {
+            let mut data = vec![0; file.len()];
+            file.read(&mut data, &mut cursor)?;
+            data
+        }
+        Some(Node::OffloadedFile(OffloadedFileNode { file, .. })) => {
+            let mut data = vec![0; file.len() as usize];
+            file.read(&mut data, &mut cursor)?;
+            data
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(data))
+}
+
+fn append_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    metadata: &Metadata,
+    data: Option<&[u8]>,
+) -> io::Result<()> {
+    let pax = [
+        (PAX_ATIME, metadata.accessed),
+        (PAX_CTIME, metadata.created),
+        (PAX_MTIME, metadata.modified),
+    ]
+    .iter()
+    .flat_map(|(key, nanos)| pax_record(key, &format_pax_time(*nanos)))
+    .collect::<Vec<u8>>();
+
+    let mut pax_header = tar::Header::new_ustar();
+    pax_header.set_entry_type(tar::EntryType::XHeader);
+    pax_header.set_size(pax.len() as u64);
+    pax_header.set_mode(0o644);
+    builder.append_data(&mut pax_header, "PaxHeaders", pax.as_slice())?;
+
+    let mut header = tar::Header::new_gnu();
+    header.set_mtime(metadata.modified / 1_000_000_000);
+    if let Some(gnu) = header.as_gnu_mut() {
+        gnu.set_atime(metadata.accessed / 1_000_000_000);
+        gnu.set_ctime(metadata.created / 1_000_000_000);
+    }
+
+    match data {
+        Some(data) => {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, data)
+        }
+        None => {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder.append_data(&mut header, path, io::empty())
+        }
+    }
+}
+
+/// Encode a single PAX record (`"<length> <key>=<value>\n"`), where the
+/// length includes itself.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = format!(" {key}={value}\n");
+    let mut len = rest.len() + 1;
+    while len.to_string().len() + rest.len() > len {
+        len += 1;
+    }
+    format!("{len}{rest}").into_bytes()
+}
+
+fn format_pax_time(nanos: u64) -> String {
+    format!("{}.{:09}", nanos / 1_000_000_000, nanos % 1_000_000_000)
+}
+
+fn parse_pax_time(value: &str) -> Option<u64> {
+    let (secs, fraction) = match value.split_once('.') {
+        Some((secs, fraction)) => (secs, fraction),
+        None => (value, ""),
+    };
+    let secs: u64 = secs.parse().ok()?;
+
+    // Normalize the fractional part to exactly 9 digits
+    let mut nanos = String::with_capacity(9);
+    nanos.extend(fraction.chars().take(9));
+    while nanos.len() < 9 {
+        nanos.push('0');
+    }
+    let nanos: u64 = nanos.parse().ok()?;
+
+    secs.checked_mul(1_000_000_000)?.checked_add(nanos)
+}
+
+/// Turn a path from an archive into an absolute path within the file system,
+/// rejecting anything that would escape the root.
+fn archive_path_to_absolute(path: &Path) -> Option<PathBuf> {
+    let mut absolute = PathBuf::from("/");
+
+    for component in path.components() {
+        match component {
+            std::path::Component::RootDir | std::path::Component::CurDir => {}
+            std::path::Component::Normal(name) => absolute.push(name),
+            std::path::Component::ParentDir | std::path::Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(absolute)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Timestamps {
+    accessed: Option<u64>,
+    created: Option<u64>,
+    modified: Option<u64>,
+}
+
+impl Timestamps {
+    fn from_entry<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Self> {
+        let header = entry.header();
+        let seconds_to_nanos = |secs: u64| secs.checked_mul(1_000_000_000);
+        let mut timestamps = Timestamps {
+            modified: header.mtime().ok().and_then(seconds_to_nanos),
+            ..Default::default()
+        };
+        if let Some(gnu) = header.as_gnu() {
+            timestamps.accessed = gnu
+                .atime()
+                .ok()
+                .filter(|t| *t != 0)
+                .and_then(seconds_to_nanos);
+            timestamps.created = gnu
+                .ctime()
+                .ok()
+                .filter(|t| *t != 0)
+                .and_then(seconds_to_nanos);
+        }
+
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                let (key, value) = match (extension.key(), extension.value()) {
+                    (Ok(key), Ok(value)) => (key, value),
+                    _ => continue,
+                };
+                let slot = match key {
+                    PAX_ATIME => &mut timestamps.accessed,
+                    PAX_CTIME => &mut timestamps.created,
+                    PAX_MTIME => &mut timestamps.modified,
+                    _ => continue,
+                };
+                if let Some(nanos) = parse_pax_time(value) {
+                    *slot = Some(nanos);
+                }
+            }
+        }
+
+        Ok(timestamps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{mem_fs::FileSystem, FileSystem as _};
+
+    async fn read_to_string(fs: &FileSystem, path: &str) -> String {
+        let mut f = fs.new_open_options().read(true).open(path).unwrap();
+        let mut buf = String::new();
+        f.read_to_string(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn round_trip_through_an_archive() {
+        let fs = FileSystem::default();
+        fs.create_dir(Path::new("/etc")).unwrap();
+        fs.create_dir(Path::new("/empty")).unwrap();
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open("/etc/hosts")
+            .unwrap();
+        f.write_all(b"127.0.0.1 localhost").await.unwrap();
+        drop(f);
+        fs.insert_ro_file(Path::new("/readonly.txt"), b"static".as_slice().into())
+            .unwrap();
+        let original_meta = fs.metadata(Path::new("/etc/hosts")).unwrap();
+
+        let archive = fs.export_archive(Vec::new()).unwrap();
+        let restored = FileSystem::default();
+        restored.import_archive(archive.as_slice()).unwrap();
+
+        // Note: reading a file updates its access time, so check the metadata first
+        let restored_meta = restored.metadata(Path::new("/etc/hosts")).unwrap();
+        assert_eq!(restored_meta, original_meta);
+        assert!(restored.metadata(Path::new("/empty")).unwrap().is_dir());
+        assert_eq!(
+            read_to_string(&restored, "/etc/hosts").await,
+            "127.0.0.1 localhost"
+        );
+        assert_eq!(read_to_string(&restored, "/readonly.txt").await, "static");
+    }
+
+    #[tokio::test]
+    async fn links_are_skipped() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "dir/file.txt", b"hello".as_slice())
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "dir/link.txt", "file.txt")
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "dangling", "missing")
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let fs = FileSystem::default();
+        fs.import_archive(archive.as_slice()).unwrap();
+
+        assert_eq!(read_to_string(&fs, "/dir/file.txt").await, "hello");
+        assert!(fs.metadata(Path::new("/dir/link.txt")).is_err());
+        assert!(fs.metadata(Path::new("/dangling")).is_err());
+    }
+
+    #[test]
+    fn entries_cannot_escape_the_root() {
+        let mut data = Vec::new();
+        {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(1);
+            header.set_mode(0o644);
+            // `append_data()` refuses ".." so we need to write the name by hand
+            header.as_gnu_mut().unwrap().name[..9].copy_from_slice(b"../escape");
+            header.set_cksum();
+            data.extend_from_slice(header.as_bytes());
+            data.extend_from_slice(&[b'x'; 512]);
+            data.extend_from_slice(&[0; 1024]);
+        }
+
+        let fs = FileSystem::default();
+        fs.import_archive(data.as_slice()).unwrap();
+
+        assert!(fs.read_dir(Path::new("/")).unwrap().is_empty());
+    }
+}
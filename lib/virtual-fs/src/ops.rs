@@ -129,6 +129,47 @@ pub fn is_white_out(path: impl AsRef<Path>) -> Option<PathBuf> {
     None
 }
 
+static OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Marks a directory as opaque, which hides anything in the same directory
+/// on secondary file systems
+pub fn create_opaque_marker<F>(fs: &F, dir: impl AsRef<Path>) -> Result<(), FsError>
+where
+    F: FileSystem + ?Sized,
+{
+    fs.new_open_options()
+        .create(true)
+        .write(true)
+        .open(dir.as_ref().join(OPAQUE_MARKER))?;
+    Ok(())
+}
+
+/// Removes the opaque marker from a directory
+pub fn remove_opaque_marker<F>(fs: &F, dir: impl AsRef<Path>)
+where
+    F: FileSystem + ?Sized,
+{
+    fs.remove_file(&dir.as_ref().join(OPAQUE_MARKER)).ok();
+}
+
+/// Returns true if the directory has been marked as opaque
+pub fn is_opaque<F>(fs: &F, dir: impl AsRef<Path>) -> bool
+where
+    F: FileSystem + ?Sized,
+{
+    fs.metadata(&dir.as_ref().join(OPAQUE_MARKER)).is_ok()
+}
+
+/// Returns the directory if the path is an opaque marker
+pub fn is_opaque_marker(path: impl AsRef<Path>) -> Option<PathBuf> {
+    let path = path.as_ref();
+    if path.file_name()? == OPAQUE_MARKER {
+        path.parent().map(Path::to_path_buf)
+    } else {
+        None
+    }
+}
+
 /// Copies the reference of a file from one file system to another
 pub fn copy_reference<'a>(
     source: &'a (impl FileSystem + ?Sized),
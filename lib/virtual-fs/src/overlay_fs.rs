@@ -2,7 +2,7 @@ use std::{
     collections::HashSet,
     fmt::Debug,
     io::{self, SeekFrom},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
@@ -105,6 +105,58 @@ where
 
         Err(FsError::EntryNotFound)
     }
+
+    /// Has the item at this path been hidden from the secondaries, either by
+    /// a whiteout for it (or one of its parents) or by one of its parents
+    /// being an opaque directory?
+    ///
+    /// Parent directories are only checked when the primary is a persistent
+    /// upper layer (see [`FileSystem::is_upper_layer()`]). Markers for an
+    /// item can only live inside its parent directory on the primary, so the
+    /// walk stops at the first directory the primary doesn't have.
+    fn is_hidden(&self, path: &Path) -> bool {
+        let primary = self.primary.as_ref();
+
+        if !primary.is_upper_layer() {
+            return ops::has_white_out(primary, path);
+        }
+
+        if ops::is_opaque(primary, Path::new("/")) {
+            return true;
+        }
+
+        let names: Vec<_> = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        let mut item = if path.has_root() {
+            PathBuf::from("/")
+        } else {
+            PathBuf::new()
+        };
+
+        for (i, name) in names.iter().enumerate() {
+            item.push(name);
+
+            if ops::has_white_out(primary, &item) {
+                return true;
+            }
+            if i + 1 == names.len() {
+                break;
+            }
+            if !ops::is_dir(primary, &item) {
+                return false;
+            }
+            if ops::is_opaque(primary, &item) {
+                return true;
+            }
+        }
+
+        false
+    }
 }
 
 impl<P, S> FileSystem for OverlayFileSystem<P, S>
@@ -118,15 +170,30 @@ where
         let mut had_at_least_one_success = false;
         let mut white_outs = HashSet::new();
 
+        let hidden = self.is_hidden(path);
+        let mut opaque = false;
+
         let filesystems = std::iter::once(&self.primary as &(dyn FileSystem + Send))
             .chain(self.secondaries().filesystems());
 
-        for fs in filesystems {
+        for (i, fs) in filesystems.enumerate() {
+            // An opaque directory on the primary hides everything beneath it
+            if i > 0 && (hidden || opaque) {
+                break;
+            }
+
             match fs.read_dir(path) {
                 Ok(r) => {
                     for entry in r {
                         let entry = entry?;
 
+                        if ops::is_opaque_marker(&entry.path).is_some() {
+                            if i == 0 {
+                                opaque = true;
+                            }
+                            continue;
+                        }
+
                         // White out entries block any later entries in the secondaries
                         // unless the entry has comes before the white out, thus the order
                         // that the file systems are parsed is important to this logic.
@@ -175,7 +242,14 @@ where
         }
 
         // It could be the case that the directory was earlier hidden in the secondaries
-        // by a whiteout file, hence we need to make sure those are cleared out.
+        // by a whiteout file, hence we need to make sure those are cleared out. The
+        // new directory then needs to be opaque so the old contents stay hidden.
+        let was_hidden = self.is_hidden(path)
+            && self
+                .secondaries
+                .filesystems()
+                .into_iter()
+                .any(|fs| ops::is_dir(fs, path));
         ops::remove_white_out(self.primary.as_ref(), path);
 
         // Make sure the parent tree is in place on the primary, this is to cover the
@@ -189,6 +263,7 @@ where
 
         // Create the directory in the primary
         match self.primary.create_dir(path) {
+            Ok(()) if was_hidden => return ops::create_opaque_marker(self.primary.as_ref(), path),
             Err(e) if should_continue(e) => {}
             other => return other,
         }
@@ -210,9 +285,23 @@ where
         // If the directory is contained in a secondary file system then we need to create a
         // whiteout file so that it is suppressed and is no longer returned in `readdir` calls.
 
-        let had_at_least_one_success = self.secondaries.filesystems().into_iter().any(|fs| {
-            fs.read_dir(path).is_ok() && ops::create_white_out(&self.primary, path).is_ok()
-        });
+        let hidden = self.is_hidden(path);
+        let had_at_least_one_success = !hidden
+            && self.secondaries.filesystems().into_iter().any(|fs| {
+                fs.read_dir(path).is_ok() && ops::create_white_out(&self.primary, path).is_ok()
+            });
+
+        // Whiteouts and opaque markers aren't visible, so they shouldn't stop
+        // an otherwise empty directory from being removed.
+        if self.read_dir(path).map(|r| r.is_empty()).unwrap_or(false) {
+            if let Ok(entries) = self.primary.read_dir(path) {
+                for entry in entries.flatten() {
+                    if ops::is_white_out(&entry.path).is_some() {
+                        self.primary.remove_file(&entry.path).ok();
+                    }
+                }
+            }
+        }
 
         // Attempt to remove it from the primary, if this succeeds then we may have also
         // added the whiteout file in the earlier step, but are required in this case to
@@ -267,7 +356,7 @@ where
             // If we have not yet renamed the file it may still reside in
             // the secondaries, in which case we need to copy it to the
             // primary rather than rename it
-            if !had_at_least_one_success {
+            if !had_at_least_one_success && !self.is_hidden(&from) {
                 for fs in self.secondaries.filesystems() {
                     if fs.metadata(&from).is_ok() {
                         ops::copy_reference_ext(fs, &self.primary, &from, &to).await?;
@@ -313,7 +402,7 @@ where
         }
 
         // There might be a whiteout, search for this
-        if ops::has_white_out(&self.primary, path) {
+        if self.is_hidden(path) {
             return Err(FsError::EntryNotFound);
         }
 
@@ -337,9 +426,10 @@ where
 
         // If the file is contained in a secondary then then we need to create a
         // whiteout file so that it is suppressed.
-        let had_at_least_one_success = self.secondaries.filesystems().into_iter().any(|fs| {
-            fs.metadata(path).is_ok() && ops::create_white_out(&self.primary, path).is_ok()
-        });
+        let had_at_least_one_success = !self.is_hidden(path)
+            && self.secondaries.filesystems().into_iter().any(|fs| {
+                fs.metadata(path).is_ok() && ops::create_white_out(&self.primary, path).is_ok()
+            });
 
         // Attempt to remove it from the primary
         match self.primary.remove_file(path) {
@@ -423,7 +513,8 @@ where
         // we are done as the secondary file or directory has been earlier
         // deleted via a white out (when the create flag is set then
         // the white out marker is ignored)
-        if !conf.create && ops::has_white_out(&self.primary, path) {
+        let hidden = self.is_hidden(path);
+        if !conf.create && hidden {
             tracing::trace!(
                 path=%path.display(),
                 "The file has been whited out",
@@ -435,7 +526,7 @@ where
         let require_mutations = conf.append || conf.write || conf.create_new | conf.truncate;
 
         // If the file is on a secondary then we should open it
-        if !ops::has_white_out(&self.primary, path) {
+        if !hidden {
             for fs in self.secondaries.filesystems() {
                 let mut sub_conf = conf.clone();
                 sub_conf.create = false;
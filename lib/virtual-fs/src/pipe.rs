@@ -6,9 +6,10 @@ use std::io::{self, Read, Seek, SeekFrom};
 use std::ops::DerefMut;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex};
 use std::task::Context;
 use std::task::Poll;
+use std::task::Waker;
 use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
 use tokio::sync::{mpsc, mpsc::error::TryRecvError};
 
@@ -26,6 +27,8 @@ pub struct Pipe {
 pub struct PipeTx {
     /// Sends bytes down the pipe
     tx: Arc<Mutex<mpsc::UnboundedSender<Vec<u8>>>>,
+    /// Limits how many bytes can be waiting in the pipe, if set
+    capacity: Option<Arc<PipeCapacity>>,
 }
 
 #[derive(Debug, Clone)]
@@ -67,7 +70,7 @@ impl PipeRx {
                     }
                 }
             };
-            rx.buffer.replace(Bytes::from(data));
+            rx.receive(data);
         }
     }
 }
@@ -76,28 +79,141 @@ impl PipeRx {
 struct PipeReceiver {
     chan: mpsc::UnboundedReceiver<Vec<u8>>,
     buffer: Option<Bytes>,
+    capacity: Option<Arc<PipeCapacity>>,
+}
+
+impl PipeReceiver {
+    /// Start consuming a message that was taken off the channel, making room
+    /// for the writer.
+    fn receive(&mut self, data: Vec<u8>) {
+        if let Some(capacity) = &self.capacity {
+            capacity.release(data.len());
+        }
+        self.buffer.replace(Bytes::from(data));
+    }
+}
+
+impl Drop for PipeReceiver {
+    fn drop(&mut self) {
+        // Nobody is going to make room any more, so writers shouldn't wait
+        if let Some(capacity) = &self.capacity {
+            capacity.close();
+        }
+    }
+}
+
+/// Keeps track of how many bytes are waiting in a bounded pipe.
+#[derive(Debug)]
+struct PipeCapacity {
+    limit: usize,
+    state: Mutex<PipeCapacityState>,
+    room: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeCapacityState {
+    buffered: usize,
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+impl PipeCapacity {
+    fn new(limit: usize) -> Self {
+        PipeCapacity {
+            limit,
+            state: Mutex::new(PipeCapacityState::default()),
+            room: Condvar::new(),
+        }
+    }
+
+    /// Reserve room for `len` bytes, or register the waker and return
+    /// [`Poll::Pending`] if the pipe is full.
+    ///
+    /// A single write may go over the limit so large writes still make
+    /// progress.
+    fn poll_reserve(&self, cx: &mut Context<'_>, len: usize) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.buffered >= self.limit && !state.closed {
+            if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                state.wakers.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+        state.buffered += len;
+        Poll::Ready(())
+    }
+
+    /// Like [`PipeCapacity::poll_reserve()`], except it blocks the current
+    /// thread until there is room.
+    fn reserve_blocking(&self, len: usize) {
+        let mut state = self.state.lock().unwrap();
+        while state.buffered >= self.limit && !state.closed {
+            state = self.room.wait(state).unwrap();
+        }
+        state.buffered += len;
+    }
+
+    fn is_full(&self, cx: &mut Context<'_>) -> bool {
+        self.poll_reserve(cx, 0).is_pending()
+    }
+
+    fn release(&self, len: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.buffered = state.buffered.saturating_sub(len);
+        Self::wake(&mut state);
+        self.room.notify_all();
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        Self::wake(&mut state);
+        self.room.notify_all();
+    }
+
+    fn wake(state: &mut PipeCapacityState) {
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+    }
 }
 
 impl Pipe {
-    fn new() -> Self {
+    fn new(capacity: Option<usize>) -> Self {
         let (tx, rx) = mpsc::unbounded_channel();
+        let capacity = capacity.map(|limit| Arc::new(PipeCapacity::new(limit)));
 
         Pipe {
             send: PipeTx {
                 tx: Arc::new(Mutex::new(tx)),
+                capacity: capacity.clone(),
             },
             recv: PipeRx {
                 rx: Arc::new(Mutex::new(PipeReceiver {
                     chan: rx,
                     buffer: None,
+                    capacity,
                 })),
             },
         }
     }
 
     pub fn channel() -> (Pipe, Pipe) {
-        let (tx1, rx1) = Pipe::new().split();
-        let (tx2, rx2) = Pipe::new().split();
+        Pipe::pair(None)
+    }
+
+    /// Create a pair of connected pipes where at most `capacity` bytes can be
+    /// waiting to be read in each direction.
+    ///
+    /// Writes to a full pipe wait until the other end reads from it, so a slow
+    /// reader applies backpressure to the writer.
+    pub fn bounded_channel(capacity: usize) -> (Pipe, Pipe) {
+        Pipe::pair(Some(capacity))
+    }
+
+    fn pair(capacity: Option<usize>) -> (Pipe, Pipe) {
+        let (tx1, rx1) = Pipe::new(capacity).split();
+        let (tx2, rx2) = Pipe::new(capacity).split();
 
         let end1 = Pipe::combine(tx1, rx2);
         let end2 = Pipe::combine(tx2, rx1);
@@ -196,7 +312,7 @@ impl Read for PipeRx {
                     }
                 }
             };
-            rx.buffer.replace(Bytes::from(data));
+            rx.receive(data);
         }
     }
 }
@@ -225,6 +341,9 @@ impl std::io::Write for Pipe {
 
 impl std::io::Write for PipeTx {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(capacity) = &self.capacity {
+            capacity.reserve_blocking(buf.len());
+        }
         let tx = self.tx.lock().unwrap();
         tx.send(buf.to_vec())
             .map_err(|_| Into::<std::io::Error>::into(std::io::ErrorKind::BrokenPipe))?;
@@ -302,10 +421,18 @@ impl AsyncWrite for Pipe {
 impl AsyncWrite for PipeTx {
     fn poll_write(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         let guard = self.tx.lock().unwrap();
+        if guard.is_closed() {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        if let Some(capacity) = &self.capacity {
+            if capacity.poll_reserve(cx, buf.len()).is_pending() {
+                return Poll::Pending;
+            }
+        }
         match guard.send(buf.to_vec()) {
             Ok(()) => Poll::Ready(Ok(buf.len())),
             Err(_) => Poll::Ready(Err(Into::<std::io::Error>::into(
@@ -361,7 +488,7 @@ impl AsyncRead for PipeRx {
                 Poll::Pending => return Poll::Pending,
             };
 
-            rx.buffer.replace(Bytes::from(data));
+            rx.receive(data);
         }
     }
 }
@@ -428,15 +555,17 @@ impl VirtualFile for Pipe {
                 Poll::Pending => return Poll::Pending,
             };
 
-            rx.buffer.replace(Bytes::from(data));
+            rx.receive(data);
         }
     }
 
     /// Polls the file for when it is available for writing
-    fn poll_write_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+    fn poll_write_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
         let tx = self.send.tx.lock().unwrap();
         if tx.is_closed() {
             Poll::Ready(Ok(0))
+        } else if self.send.capacity.as_ref().map_or(false, |c| c.is_full(cx)) {
+            Poll::Pending
         } else {
             Poll::Ready(Ok(8192))
         }
@@ -506,3 +635,41 @@ impl DuplexPipe {
 /// Shared version of BidiPipe for situations where you need
 /// to emulate the old behaviour of `Pipe` (both send and recv on one channel).
 pub type WasiBidirectionalSharedPipePair = ArcFile<DuplexPipe>;
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bounded_pipes_wait_for_the_reader() {
+        let (mut tx, mut rx) = Pipe::bounded_channel(4);
+
+        tx.write_all(b"abcd").await.unwrap();
+        let mut write = Box::pin(tx.write_all(b"efgh"));
+        assert!(futures::poll!(write.as_mut()).is_pending());
+
+        let mut buf = [0; 2];
+        AsyncReadExt::read_exact(&mut rx, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"ab");
+
+        write.await.unwrap();
+        let mut buf = [0; 6];
+        AsyncReadExt::read_exact(&mut rx, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"cdefgh");
+    }
+
+    #[tokio::test]
+    async fn writing_to_a_full_pipe_fails_once_the_reader_is_gone() {
+        let (mut tx, rx) = Pipe::bounded_channel(4);
+        tx.write_all(b"abcd").await.unwrap();
+        let mut write = Box::pin(tx.write_all(b"efgh"));
+        assert!(futures::poll!(write.as_mut()).is_pending());
+
+        drop(rx);
+
+        let err = write.await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
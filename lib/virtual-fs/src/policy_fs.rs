@@ -0,0 +1,681 @@
+//! A [`FileSystem`] wrapper which checks every operation against a set of
+//! access rules and keeps an audit trail of what was allowed or denied.
+//!
+//! Policies are normally loaded from a file, for example:
+//!
+//! ```toml
+//! default = "deny"
+//!
+//! [[rules]]
+//! path = "/app/**"
+//! access = ["read"]
+//! decision = "allow"
+//!
+//! [[rules]]
+//! path = "/tmp/**"
+//! decision = "allow"
+//!
+//! [[rules]]
+//! path = "/tmp/*.lock"
+//! operations = ["remove", "rename"]
+//! decision = "deny"
+//! ```
+//!
+//! Rules are checked in order and the first one that matches decides what
+//! happens. Paths are matched using [glob patterns][glob::Pattern] where `*`
+//! doesn't match `/` and `**` matches any number of directories.
+
+use std::{
+    fmt::Debug,
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use futures::future::BoxFuture;
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    FileOpener, FileSystem, FsError, Metadata, OpenOptions, OpenOptionsConfig, ReadDir, VirtualFile,
+};
+
+/// The kind of file system operation being checked.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsOperation {
+    /// Opening an existing file.
+    Open,
+    /// Creating a new file or directory.
+    Create,
+    /// Removing a file or directory.
+    Remove,
+    /// Renaming a file or directory. Both the source and destination paths
+    /// are checked.
+    Rename,
+}
+
+/// Whether an operation reads or modifies the file system.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsAccess {
+    Read,
+    Write,
+}
+
+/// What to do with an operation.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsDecision {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// A single access rule.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FsRule {
+    /// The paths this rule applies to.
+    #[serde(deserialize_with = "deserialize_pattern")]
+    pub path: Pattern,
+    /// The operations this rule applies to, or all operations if empty.
+    #[serde(default)]
+    pub operations: Vec<FsOperation>,
+    /// The kinds of access this rule applies to, or any access if empty.
+    #[serde(default)]
+    pub access: Vec<FsAccess>,
+    pub decision: FsDecision,
+}
+
+impl FsRule {
+    pub fn new(path: &str, decision: FsDecision) -> Result<Self, glob::PatternError> {
+        Ok(FsRule {
+            path: Pattern::new(path)?,
+            operations: Vec::new(),
+            access: Vec::new(),
+            decision,
+        })
+    }
+
+    pub fn with_operations(mut self, operations: impl IntoIterator<Item = FsOperation>) -> Self {
+        self.operations.extend(operations);
+        self
+    }
+
+    pub fn with_access(mut self, access: impl IntoIterator<Item = FsAccess>) -> Self {
+        self.access.extend(access);
+        self
+    }
+
+    fn matches(&self, request: &Request<'_>) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        (self.operations.is_empty() || self.operations.contains(&request.operation))
+            && (self.access.is_empty() || self.access.iter().any(|a| request.access.contains(a)))
+            && self.path.matches_path_with(request.path, options)
+    }
+}
+
+fn deserialize_pattern<'de, D>(deserializer: D) -> Result<Pattern, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+    Pattern::new(&raw).map_err(serde::de::Error::custom)
+}
+
+/// A set of [`FsRule`]s.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct FsPolicy {
+    /// The decision used when no rules match.
+    #[serde(default)]
+    pub default: FsDecision,
+    #[serde(default)]
+    pub rules: Vec<FsRule>,
+}
+
+impl FsPolicy {
+    pub fn new(default: FsDecision) -> Self {
+        FsPolicy {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn with_rule(mut self, rule: FsRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Check a request against the rules, returning the decision and the
+    /// index of the rule that made it (if any).
+    fn evaluate(&self, request: &Request<'_>) -> (FsDecision, Option<usize>) {
+        self.rules
+            .iter()
+            .position(|rule| rule.matches(request))
+            .map(|index| (self.rules[index].decision, Some(index)))
+            .unwrap_or((self.default, None))
+    }
+}
+
+struct Request<'a> {
+    operation: FsOperation,
+    path: &'a Path,
+    access: &'a [FsAccess],
+}
+
+/// A structured record of a single file system operation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    /// When the operation happened, in nanoseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub operation: FsOperation,
+    pub path: PathBuf,
+    /// The destination of a [`FsOperation::Rename`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    pub access: Vec<FsAccess>,
+    pub decision: FsDecision,
+    /// The index of the rule which made the decision, or `None` if the
+    /// policy's default was used.
+    pub rule: Option<usize>,
+    /// The error returned by the underlying file system, if an allowed
+    /// operation failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Somewhere [`AuditRecord`]s can be sent.
+pub trait AuditSink: Debug + Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// An [`AuditSink`] which writes each record to a [`Write`]r as a line of
+/// JSON.
+#[derive(Debug)]
+pub struct JsonAuditSink<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> JsonAuditSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonAuditSink {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write + Debug + Send> AuditSink for JsonAuditSink<W> {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!(
+                    error = &e as &dyn std::error::Error,
+                    "Unable to serialize an audit record"
+                );
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writer.write_all(&line).and_then(|_| writer.flush()) {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                "Unable to write an audit record"
+            );
+        }
+    }
+}
+
+/// A [`FileSystem`] wrapper that enforces a [`FsPolicy`], rejecting denied
+/// operations with [`FsError::PermissionDenied`] and reporting every checked
+/// operation to an optional [`AuditSink`].
+///
+/// Reading directories and metadata is always allowed and isn't audited.
+#[derive(Debug, Clone)]
+pub struct PolicyFileSystem<F> {
+    inner: F,
+    policy: Arc<FsPolicy>,
+    sink: Option<Arc<dyn AuditSink>>,
+}
+
+impl<F> PolicyFileSystem<F> {
+    pub fn new(inner: F, policy: FsPolicy) -> Self {
+        PolicyFileSystem {
+            inner,
+            policy: Arc::new(policy),
+            sink: None,
+        }
+    }
+
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    pub fn policy(&self) -> &FsPolicy {
+        &self.policy
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Check an operation against the policy, run it if it is allowed, and
+    /// record the outcome.
+    fn check<T>(
+        &self,
+        operation: FsOperation,
+        path: &Path,
+        target: Option<&Path>,
+        access: &[FsAccess],
+        op: impl FnOnce() -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let (decision, rule) = self.evaluate(operation, path, target, access);
+
+        let result = match decision {
+            FsDecision::Allow => op(),
+            FsDecision::Deny => Err(FsError::PermissionDenied),
+        };
+
+        self.audit(operation, path, target, access, decision, rule, &result);
+
+        result
+    }
+
+    fn evaluate(
+        &self,
+        operation: FsOperation,
+        path: &Path,
+        target: Option<&Path>,
+        access: &[FsAccess],
+    ) -> (FsDecision, Option<usize>) {
+        let path = normalize(path);
+        let first = self.policy.evaluate(&Request {
+            operation,
+            path: &path,
+            access,
+        });
+
+        match target {
+            Some(target) if first.0 == FsDecision::Allow => {
+                let target = normalize(target);
+                self.policy.evaluate(&Request {
+                    operation,
+                    path: &target,
+                    access,
+                })
+            }
+            _ => first,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn audit<T>(
+        &self,
+        operation: FsOperation,
+        path: &Path,
+        target: Option<&Path>,
+        access: &[FsAccess],
+        decision: FsDecision,
+        rule: Option<usize>,
+        result: &Result<T, FsError>,
+    ) {
+        if decision == FsDecision::Deny {
+            tracing::debug!(
+                ?operation,
+                path=%path.display(),
+                ?rule,
+                "File system operation denied by policy",
+            );
+        }
+
+        let Some(sink) = &self.sink else {
+            return;
+        };
+
+        let error = match (decision, result) {
+            (FsDecision::Allow, Err(e)) => Some(e.to_string()),
+            _ => None,
+        };
+
+        sink.record(&AuditRecord {
+            timestamp: timestamp(),
+            operation,
+            path: normalize(path),
+            target: target.map(normalize),
+            access: access.to_vec(),
+            decision,
+            rule,
+            error,
+        });
+    }
+}
+
+impl<F> FileSystem for PolicyFileSystem<F>
+where
+    F: FileSystem,
+{
+    fn read_dir(&self, path: &Path) -> Result<ReadDir, FsError> {
+        self.inner.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), FsError> {
+        self.check(FsOperation::Create, path, None, &[FsAccess::Write], || {
+            self.inner.create_dir(path)
+        })
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), FsError> {
+        self.check(FsOperation::Remove, path, None, &[FsAccess::Write], || {
+            self.inner.remove_dir(path)
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, Result<(), FsError>> {
+        Box::pin(async move {
+            let operation = FsOperation::Rename;
+            let access = [FsAccess::Write];
+            let (decision, rule) = self.evaluate(operation, from, Some(to), &access);
+
+            let result = match decision {
+                FsDecision::Allow => self.inner.rename(from, to).await,
+                FsDecision::Deny => Err(FsError::PermissionDenied),
+            };
+
+            self.audit(operation, from, Some(to), &access, decision, rule, &result);
+
+            result
+        })
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, FsError> {
+        self.inner.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata, FsError> {
+        self.inner.symlink_metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), FsError> {
+        self.check(FsOperation::Remove, path, None, &[FsAccess::Write], || {
+            self.inner.remove_file(path)
+        })
+    }
+
+    fn new_open_options(&self) -> OpenOptions {
+        OpenOptions::new(self)
+    }
+}
+
+impl<F> FileOpener for PolicyFileSystem<F>
+where
+    F: FileSystem,
+{
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> Result<Box<dyn VirtualFile + Send + Sync + 'static>, FsError> {
+        let creating = conf.create_new() || (conf.create() && self.inner.metadata(path).is_err());
+        let operation = if creating {
+            FsOperation::Create
+        } else {
+            FsOperation::Open
+        };
+
+        let mut access = Vec::with_capacity(2);
+        if conf.read() {
+            access.push(FsAccess::Read);
+        }
+        if conf.would_mutate() {
+            access.push(FsAccess::Write);
+        }
+
+        self.check(operation, path, None, &access, || {
+            self.inner
+                .new_open_options()
+                .options(conf.clone())
+                .open(path)
+        })
+    }
+}
+
+/// Lexically resolve `.` and `..` so they can't be used to sneak past a rule.
+/// Relative paths are treated as relative to `/`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+
+    normalized
+}
+
+fn timestamp() -> u64 {
+    #[cfg(not(feature = "no-time"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    }
+
+    #[cfg(feature = "no-time")]
+    {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::mem_fs;
+
+    #[derive(Debug, Default)]
+    struct Records(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for Records {
+        fn record(&self, record: &AuditRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    fn policy() -> FsPolicy {
+        FsPolicy::new(FsDecision::Deny)
+            .with_rule(
+                FsRule::new("/tmp/*.lock", FsDecision::Deny)
+                    .unwrap()
+                    .with_operations([FsOperation::Remove, FsOperation::Rename]),
+            )
+            .with_rule(FsRule::new("/tmp/**", FsDecision::Allow).unwrap())
+            .with_rule(
+                FsRule::new("/app/**", FsDecision::Allow)
+                    .unwrap()
+                    .with_access([FsAccess::Read]),
+            )
+    }
+
+    fn fs() -> mem_fs::FileSystem {
+        let fs = mem_fs::FileSystem::default();
+        fs.create_dir("/tmp".as_ref()).unwrap();
+        fs.create_dir("/app".as_ref()).unwrap();
+        fs.insert_ro_file("/app/main.py".as_ref(), b"print('hi')".as_slice().into())
+            .unwrap();
+        fs
+    }
+
+    #[tokio::test]
+    async fn rules_are_enforced() {
+        let fs = PolicyFileSystem::new(fs(), policy());
+
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open("/tmp/app.lock")
+            .unwrap();
+        f.write_all(b"1234").await.unwrap();
+
+        assert_eq!(
+            fs.remove_file("/tmp/app.lock".as_ref()).unwrap_err(),
+            FsError::PermissionDenied
+        );
+        assert_eq!(
+            fs.rename("/tmp/app.lock".as_ref(), "/tmp/other".as_ref())
+                .await
+                .unwrap_err(),
+            FsError::PermissionDenied
+        );
+        fs.new_open_options()
+            .read(true)
+            .open("/app/main.py")
+            .unwrap();
+        assert_eq!(
+            fs.new_open_options()
+                .write(true)
+                .open("/app/main.py")
+                .unwrap_err(),
+            FsError::PermissionDenied
+        );
+        assert_eq!(
+            fs.create_dir("/app/lib".as_ref()).unwrap_err(),
+            FsError::PermissionDenied
+        );
+        // `*` doesn't match across directories
+        fs.create_dir("/tmp/dir".as_ref()).unwrap();
+        fs.new_open_options()
+            .write(true)
+            .create(true)
+            .open("/tmp/dir/x.lock")
+            .unwrap();
+        fs.remove_file("/tmp/dir/x.lock".as_ref()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn renames_check_the_destination() {
+        let fs = PolicyFileSystem::new(fs(), policy());
+        fs.new_open_options()
+            .write(true)
+            .create(true)
+            .open("/tmp/file.txt")
+            .unwrap();
+
+        assert_eq!(
+            fs.rename("/tmp/file.txt".as_ref(), "/app/file.txt".as_ref())
+                .await
+                .unwrap_err(),
+            FsError::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn parent_directories_cant_escape_a_rule() {
+        let fs = PolicyFileSystem::new(fs(), policy());
+
+        assert_eq!(
+            fs.new_open_options()
+                .write(true)
+                .create(true)
+                .open("/tmp/../app/evil.py")
+                .unwrap_err(),
+            FsError::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn operations_are_audited() {
+        let records = Arc::new(Records::default());
+        let fs = PolicyFileSystem::new(fs(), policy()).with_audit_sink(records.clone());
+
+        fs.new_open_options()
+            .read(true)
+            .open("/app/main.py")
+            .unwrap();
+        fs.remove_file("/app/main.py".as_ref()).unwrap_err();
+        fs.remove_file("/tmp/missing.txt".as_ref()).unwrap_err();
+
+        let records = records.0.lock().unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| {
+                (
+                    r.operation,
+                    r.path.clone(),
+                    r.decision,
+                    r.rule,
+                    r.error.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    FsOperation::Open,
+                    "/app/main.py".into(),
+                    FsDecision::Allow,
+                    Some(2),
+                    false
+                ),
+                (
+                    FsOperation::Remove,
+                    "/app/main.py".into(),
+                    FsDecision::Deny,
+                    None,
+                    false
+                ),
+                (
+                    FsOperation::Remove,
+                    "/tmp/missing.txt".into(),
+                    FsDecision::Allow,
+                    Some(1),
+                    true
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_records() {
+        let sink = JsonAuditSink::new(Vec::new());
+        sink.record(&AuditRecord {
+            timestamp: 42,
+            operation: FsOperation::Rename,
+            path: "/tmp/a".into(),
+            target: Some("/tmp/b".into()),
+            access: vec![FsAccess::Write],
+            decision: FsDecision::Deny,
+            rule: Some(0),
+            error: None,
+        });
+
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+        assert_eq!(
+            output,
+            "{\"timestamp\":42,\"operation\":\"rename\",\"path\":\"/tmp/a\",\"target\":\"/tmp/b\",\"access\":[\"write\"],\"decision\":\"deny\",\"rule\":0}\n"
+        );
+    }
+}
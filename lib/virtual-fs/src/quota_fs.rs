@@ -0,0 +1,758 @@
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Once, Weak,
+    },
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+use crate::{
+    FileOpener, FileSystem, FsError, Metadata, OpenOptions, OpenOptionsConfig, ReadDir, Result,
+    VirtualFile,
+};
+
+/// The limits enforced by a [`QuotaFileSystem`].
+///
+/// A limit of `None` means that dimension is unlimited.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "enable-serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QuotaLimits {
+    /// The maximum number of bytes all files may use.
+    pub max_bytes: Option<u64>,
+    /// The maximum number of files and directories.
+    pub max_inodes: Option<u64>,
+}
+
+/// A [`FileSystem`] wrapper that limits how much data and how many inodes can
+/// be stored in the file system it wraps.
+///
+/// This is mainly intended for host-backed directories, where a misbehaving
+/// guest could otherwise fill up the host's disk. Existing contents count
+/// towards the quota, so the inner file system is scanned the first time
+/// anything needs to know how much space is in use.
+///
+/// Writes, [`VirtualFile::set_len()`] and file/directory creation that would
+/// exceed a limit fail with [`FsError::QuotaExceeded`], while removing files
+/// and directories gives the space back. Like on a real disk, the data of a
+/// file that is removed while it is still open keeps counting towards the
+/// quota until the last handle to it is closed.
+///
+/// Changes made to the inner file system without going through this wrapper
+/// are not tracked.
+#[derive(Debug, Clone)]
+pub struct QuotaFileSystem<F> {
+    inner: F,
+    usage: Arc<Usage>,
+}
+
+impl<F: FileSystem> QuotaFileSystem<F> {
+    pub fn new(inner: F, limits: QuotaLimits) -> Self {
+        QuotaFileSystem {
+            inner,
+            usage: Arc::new(Usage {
+                limits,
+                bytes: AtomicU64::new(0),
+                inodes: AtomicU64::new(0),
+                scanned: Once::new(),
+                open_files: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Tally up the existing contents of the inner file system, if that
+    /// hasn't been done already.
+    ///
+    /// This is deferred until it is actually needed because walking a large
+    /// host directory can take a while.
+    fn ensure_scanned(&self) {
+        self.usage.scanned.call_once(|| {
+            let (bytes, inodes) = scan(&self.inner, Path::new("/"));
+            tracing::debug!(bytes, inodes, limits=?self.usage.limits, "Initialized the quota usage");
+            self.usage.bytes.fetch_add(bytes, Ordering::SeqCst);
+            self.usage.inodes.fetch_add(inodes, Ordering::SeqCst);
+        });
+    }
+
+    pub fn limits(&self) -> QuotaLimits {
+        self.usage.limits
+    }
+
+    /// The number of bytes currently used by files.
+    pub fn used_bytes(&self) -> u64 {
+        self.ensure_scanned();
+        self.usage.bytes.load(Ordering::SeqCst)
+    }
+
+    /// The number of files and directories currently in use.
+    pub fn used_inodes(&self) -> u64 {
+        self.ensure_scanned();
+        self.usage.inodes.load(Ordering::SeqCst)
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// The usage that gets freed when the item at `path` is removed.
+    fn usage_of(&self, path: &Path) -> Option<u64> {
+        let meta = self.inner.symlink_metadata(path).ok()?;
+        Some(if meta.is_file() { meta.len() } else { 0 })
+    }
+
+    /// Forget about the item at `path` because it was removed or replaced,
+    /// releasing the space it used.
+    ///
+    /// If the file is still open, only the inode is released. Its data is
+    /// released once the last handle is closed.
+    fn forget(&self, path: &Path, bytes: u64) {
+        let open_file = self
+            .usage
+            .open_files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .and_then(|f| f.upgrade());
+
+        match open_file {
+            Some(f) => f.remove(),
+            None => {
+                self.usage.release_inode();
+                self.usage.release_bytes(bytes);
+            }
+        }
+    }
+}
+
+/// Recursively tally up the bytes and inodes used by a directory's contents.
+fn scan(fs: &dyn FileSystem, dir: &Path) -> (u64, u64) {
+    let entries = match fs.read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return (0, 0),
+    };
+
+    let mut bytes = 0;
+    let mut inodes = 0;
+
+    for entry in entries.flatten() {
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        inodes += 1;
+
+        if meta.is_dir() {
+            let (b, i) = scan(fs, &entry.path());
+            bytes += b;
+            inodes += i;
+        } else if meta.is_file() {
+            bytes += meta.len();
+        }
+    }
+
+    (bytes, inodes)
+}
+
+#[derive(Debug)]
+struct Usage {
+    limits: QuotaLimits,
+    bytes: AtomicU64,
+    inodes: AtomicU64,
+    /// Has the inner file system's existing content been counted yet?
+    scanned: Once,
+    /// The files that currently have open handles.
+    open_files: Mutex<HashMap<PathBuf, Weak<OpenFile>>>,
+}
+
+impl Usage {
+    fn reserve_bytes(&self, amount: u64) -> Result<()> {
+        reserve(&self.bytes, self.limits.max_bytes, amount)
+    }
+
+    fn release_bytes(&self, amount: u64) {
+        release(&self.bytes, amount);
+    }
+
+    fn reserve_inode(&self) -> Result<()> {
+        reserve(&self.inodes, self.limits.max_inodes, 1)
+    }
+
+    fn release_inode(&self) {
+        release(&self.inodes, 1);
+    }
+
+    /// The number of bytes that can still be written before hitting the
+    /// limit.
+    fn remaining_bytes(&self) -> u64 {
+        match self.limits.max_bytes {
+            Some(max) => max.saturating_sub(self.bytes.load(Ordering::SeqCst)),
+            None => u64::MAX,
+        }
+    }
+}
+
+/// Usage shared by every handle to the same file.
+#[derive(Debug)]
+struct OpenFile {
+    usage: Arc<Usage>,
+    /// The file's size, as tracked by its handles.
+    size: AtomicU64,
+    /// Has the file been deleted while it is still open?
+    removed: AtomicBool,
+}
+
+impl OpenFile {
+    fn remove(&self) {
+        // The inode goes away immediately, but the data sticks around until
+        // the last handle is closed
+        if !self.removed.swap(true, Ordering::SeqCst) {
+            self.usage.release_inode();
+        }
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        if *self.removed.get_mut() {
+            self.usage.release_bytes(*self.size.get_mut());
+        }
+
+        if let Ok(mut open_files) = self.usage.open_files.lock() {
+            open_files.retain(|_, f| f.strong_count() > 0);
+        }
+    }
+}
+
+fn reserve(counter: &AtomicU64, limit: Option<u64>, amount: u64) -> Result<()> {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+            let new = current.checked_add(amount)?;
+            match limit {
+                Some(limit) if new > limit => None,
+                _ => Some(new),
+            }
+        })
+        .map(|_| ())
+        .map_err(|_| FsError::QuotaExceeded)
+}
+
+fn release(counter: &AtomicU64, amount: u64) {
+    let _ = counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+        Some(current.saturating_sub(amount))
+    });
+}
+
+impl<F: FileSystem> FileSystem for QuotaFileSystem<F> {
+    fn read_dir(&self, path: &Path) -> Result<ReadDir> {
+        self.inner.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<()> {
+        self.ensure_scanned();
+        self.usage.reserve_inode()?;
+
+        self.inner.create_dir(path).map_err(|e| {
+            self.usage.release_inode();
+            e
+        })
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<()> {
+        self.ensure_scanned();
+        self.inner.remove_dir(path)?;
+        self.usage.release_inode();
+        Ok(())
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.ensure_scanned();
+
+            // Renaming on top of an existing file replaces it
+            let replaced = self.usage_of(to);
+
+            self.inner.rename(from, to).await?;
+
+            if let Some(bytes) = replaced {
+                self.forget(to, bytes);
+            }
+
+            // Handles that are still open follow the file to its new name
+            let mut open_files = self.usage.open_files.lock().unwrap();
+            if let Some(f) = open_files.remove(from) {
+                open_files.insert(to.to_path_buf(), f);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata> {
+        self.inner.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> Result<Metadata> {
+        self.inner.symlink_metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.ensure_scanned();
+        let bytes = self.usage_of(path).unwrap_or(0);
+
+        self.inner.remove_file(path)?;
+
+        self.forget(path, bytes);
+        Ok(())
+    }
+
+    fn new_open_options(&self) -> OpenOptions {
+        OpenOptions::new(self)
+    }
+}
+
+impl<F: FileSystem> FileOpener for QuotaFileSystem<F> {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> Result<Box<dyn VirtualFile + Send + Sync + 'static>> {
+        self.ensure_scanned();
+        let existing = self.inner.metadata(path).ok();
+
+        let creating = existing.is_none() && (conf.create() || conf.create_new());
+        if creating {
+            self.usage.reserve_inode()?;
+        }
+
+        let file = match self
+            .inner
+            .new_open_options()
+            .options(conf.clone())
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                if creating {
+                    self.usage.release_inode();
+                }
+                return Err(e);
+            }
+        };
+
+        if conf.truncate() && conf.write() {
+            if let Some(meta) = existing.filter(|m| m.is_file()) {
+                self.usage.release_bytes(meta.len());
+            }
+        }
+
+        let open_file = {
+            let mut open_files = self.usage.open_files.lock().unwrap();
+            match open_files
+                .get(path)
+                .and_then(|f| f.upgrade())
+                .filter(|f| !f.removed.load(Ordering::SeqCst))
+            {
+                Some(f) => f,
+                None => {
+                    let f = Arc::new(OpenFile {
+                        usage: Arc::clone(&self.usage),
+                        size: AtomicU64::new(0),
+                        removed: AtomicBool::new(false),
+                    });
+                    open_files.insert(path.to_path_buf(), Arc::downgrade(&f));
+                    f
+                }
+            }
+        };
+        open_file.size.store(file.size(), Ordering::SeqCst);
+
+        Ok(Box::new(QuotaFile {
+            path: path.to_path_buf(),
+            inner: file,
+            usage: Arc::clone(&self.usage),
+            open_file,
+            append: conf.append(),
+            position: 0,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct QuotaFile {
+    path: PathBuf,
+    inner: Box<dyn VirtualFile + Send + Sync + 'static>,
+    usage: Arc<Usage>,
+    open_file: Arc<OpenFile>,
+    append: bool,
+    /// Our best guess at the file's cursor, used to figure out how much a
+    /// write will grow the file by.
+    position: u64,
+}
+
+impl QuotaFile {
+    /// The file's size, including writes the inner file may not have
+    /// finished yet (e.g. because a host file writes in the background).
+    fn size(&self) -> u64 {
+        self.open_file.size.load(Ordering::SeqCst)
+    }
+}
+
+impl VirtualFile for QuotaFile {
+    fn last_accessed(&self) -> u64 {
+        self.inner.last_accessed()
+    }
+
+    fn last_modified(&self) -> u64 {
+        self.inner.last_modified()
+    }
+
+    fn created_time(&self) -> u64 {
+        self.inner.created_time()
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn set_len(&mut self, new_size: u64) -> Result<()> {
+        let old_size = self.size();
+
+        if new_size > old_size {
+            let grown = new_size - old_size;
+            self.usage.reserve_bytes(grown)?;
+            self.inner.set_len(new_size).map_err(|e| {
+                self.usage.release_bytes(grown);
+                e
+            })?;
+        } else {
+            self.inner.set_len(new_size)?;
+            self.usage.release_bytes(old_size - new_size);
+        }
+
+        self.open_file.size.store(new_size, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn unlink(&mut self) -> Result<()> {
+        self.inner.unlink()?;
+
+        // The file may have been renamed since we opened it
+        self.usage
+            .open_files
+            .lock()
+            .unwrap()
+            .retain(|_, f| f.as_ptr() != Arc::as_ptr(&self.open_file));
+
+        self.open_file.remove();
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.inner.is_open()
+    }
+
+    fn get_special_fd(&self) -> Option<u32> {
+        self.inner.get_special_fd()
+    }
+
+    fn poll_read_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write_ready(cx)
+    }
+
+    fn poll_sync(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_sync(cx)
+    }
+}
+
+impl AsyncRead for QuotaFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = &result {
+            self.position += (buf.filled().len() - before) as u64;
+        }
+
+        result
+    }
+}
+
+impl AsyncWrite for QuotaFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let size_before = self.size();
+        let position = if self.append {
+            size_before
+        } else {
+            self.position
+        };
+
+        // Only write as much as will fit, like a real disk would
+        let growth = (position + buf.len() as u64).saturating_sub(size_before);
+        let allowed_growth = growth.min(self.usage.remaining_bytes());
+        let len = buf.len() - (growth - allowed_growth) as usize;
+
+        if len == 0 && !buf.is_empty() {
+            tracing::debug!(path=%self.path.display(), "Disk quota exceeded");
+            return Poll::Ready(Err(FsError::QuotaExceeded.into()));
+        }
+
+        if let Err(e) = self.usage.reserve_bytes(allowed_growth) {
+            return Poll::Ready(Err(e.into()));
+        }
+
+        let result = Pin::new(&mut *self.inner).poll_write(cx, &buf[..len]);
+
+        // Reconcile our reservation with how much was actually written
+        let end = match &result {
+            Poll::Ready(Ok(written)) => position + *written as u64,
+            _ => position,
+        };
+        let actual_growth = end.saturating_sub(size_before);
+        self.usage
+            .release_bytes(allowed_growth.saturating_sub(actual_growth));
+
+        if let Poll::Ready(Ok(_)) = &result {
+            self.position = end;
+            self.open_file.size.fetch_max(end, Ordering::SeqCst);
+        }
+
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for QuotaFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut *self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let result = Pin::new(&mut *self.inner).poll_complete(cx);
+
+        if let Poll::Ready(Ok(position)) = &result {
+            self.position = *position;
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::mem_fs;
+
+    fn quota_fs(
+        max_bytes: Option<u64>,
+        max_inodes: Option<u64>,
+    ) -> QuotaFileSystem<mem_fs::FileSystem> {
+        QuotaFileSystem::new(
+            mem_fs::FileSystem::default(),
+            QuotaLimits {
+                max_bytes,
+                max_inodes,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn writes_are_limited_by_the_byte_quota() {
+        let fs = quota_fs(Some(10), None);
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open("/file.txt")
+            .unwrap();
+
+        // Partial writes fill up the remaining space
+        assert_eq!(f.write(b"Hello, World!").await.unwrap(), 10);
+        assert_eq!(fs.used_bytes(), 10);
+
+        let err = f.write(b"!").await.unwrap_err();
+        assert_eq!(FsError::from(err), FsError::QuotaExceeded);
+
+        // Overwriting existing data doesn't use any more space
+        f.seek(SeekFrom::Start(0)).await.unwrap();
+        f.write_all(b"0123456789").await.unwrap();
+        assert_eq!(fs.used_bytes(), 10);
+    }
+
+    #[tokio::test]
+    async fn set_len_is_accounted_for() {
+        let fs = quota_fs(Some(100), None);
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open("/file.txt")
+            .unwrap();
+
+        f.set_len(100).unwrap();
+        assert_eq!(fs.used_bytes(), 100);
+        assert_eq!(f.set_len(101).unwrap_err(), FsError::QuotaExceeded);
+        assert_eq!(f.size(), 100);
+
+        f.set_len(40).unwrap();
+        assert_eq!(fs.used_bytes(), 40);
+    }
+
+    #[tokio::test]
+    async fn removing_files_frees_space() {
+        let fs = quota_fs(Some(10), Some(2));
+        fs.create_dir(Path::new("/dir")).unwrap();
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open("/dir/file.txt")
+            .unwrap();
+        f.write_all(b"0123456789").await.unwrap();
+        drop(f);
+
+        assert_eq!(
+            fs.create_dir(Path::new("/another")).unwrap_err(),
+            FsError::QuotaExceeded
+        );
+        assert_eq!(
+            fs.new_open_options()
+                .write(true)
+                .create(true)
+                .open("/other.txt")
+                .unwrap_err(),
+            FsError::QuotaExceeded
+        );
+
+        fs.remove_file(Path::new("/dir/file.txt")).unwrap();
+        assert_eq!(fs.used_bytes(), 0);
+        assert_eq!(fs.used_inodes(), 1);
+
+        fs.remove_dir(Path::new("/dir")).unwrap();
+        assert_eq!(fs.used_inodes(), 0);
+    }
+
+    #[tokio::test]
+    async fn existing_contents_count_towards_the_quota() {
+        let inner = mem_fs::FileSystem::default();
+        inner.create_dir(Path::new("/dir")).unwrap();
+        inner
+            .insert_ro_file(Path::new("/dir/file.txt"), b"hello".as_slice().into())
+            .unwrap();
+
+        let fs = QuotaFileSystem::new(inner, QuotaLimits::default());
+
+        assert_eq!(fs.used_bytes(), 5);
+        assert_eq!(fs.used_inodes(), 2);
+    }
+
+    #[cfg(feature = "host-fs")]
+    #[tokio::test]
+    async fn files_removed_while_open_are_released_on_close() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let fs = QuotaFileSystem::new(
+            crate::ScopedDirectoryFileSystem::new_with_default_runtime(temp.path()),
+            QuotaLimits {
+                max_bytes: Some(100),
+                max_inodes: None,
+            },
+        );
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open("/file.txt")
+            .unwrap();
+        let other = fs.new_open_options().read(true).open("/file.txt").unwrap();
+        f.write_all(b"0123456789").await.unwrap();
+
+        fs.remove_file(Path::new("/file.txt")).unwrap();
+        assert_eq!(fs.used_inodes(), 0);
+        assert_eq!(fs.used_bytes(), 10);
+
+        // Writing through a handle that is still open keeps using space...
+        f.write_all(b"abc").await.unwrap();
+        f.flush().await.unwrap();
+        assert_eq!(fs.used_bytes(), 13);
+        drop(f);
+        assert_eq!(fs.used_bytes(), 13);
+
+        // ... until the last handle is closed
+        drop(other);
+        assert_eq!(fs.used_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn existing_contents_are_scanned_lazily() {
+        let inner = mem_fs::FileSystem::default();
+        let fs = QuotaFileSystem::new(inner.clone(), QuotaLimits::default());
+
+        inner
+            .insert_ro_file(Path::new("/file.txt"), b"hello".as_slice().into())
+            .unwrap();
+
+        assert_eq!(fs.used_bytes(), 5);
+        assert_eq!(fs.used_inodes(), 1);
+    }
+
+    #[tokio::test]
+    async fn truncating_on_open_frees_space() {
+        let fs = quota_fs(Some(10), None);
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .create(true)
+            .open("/file.txt")
+            .unwrap();
+        f.write_all(b"0123456789").await.unwrap();
+        drop(f);
+
+        let mut f = fs
+            .new_open_options()
+            .write(true)
+            .truncate(true)
+            .open("/file.txt")
+            .unwrap();
+        assert_eq!(fs.used_bytes(), 0);
+        f.write_all(b"abc").await.unwrap();
+        assert_eq!(fs.used_bytes(), 3);
+    }
+}
@@ -35,7 +35,7 @@ impl ScopedDirectoryFileSystem {
         ScopedDirectoryFileSystem::new(root, fs)
     }
 
-    fn prepare_path(&self, path: &Path) -> PathBuf {
+    pub(crate) fn prepare_path(&self, path: &Path) -> PathBuf {
         let path = normalize_path(path);
         let path = path.strip_prefix("/").unwrap_or(&path);
 
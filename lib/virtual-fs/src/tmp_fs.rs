@@ -54,6 +54,18 @@ impl TmpFileSystem {
         self.fs.mount(src_path, other, dst_path)
     }
 
+    /// See [`mem_fs::FileSystem::export_archive`].
+    #[cfg(feature = "archive")]
+    pub fn export_archive<W: std::io::Write>(&self, writer: W) -> Result<W> {
+        self.fs.export_archive(writer)
+    }
+
+    /// See [`mem_fs::FileSystem::import_archive`].
+    #[cfg(feature = "archive")]
+    pub fn import_archive<R: std::io::Read>(&self, reader: R) -> Result<()> {
+        self.fs.import_archive(reader)
+    }
+
     /// Canonicalize a path without validating that it actually exists.
     pub fn canonicalize_unchecked(&self, path: &Path) -> Result<PathBuf> {
         self.fs.canonicalize_unchecked(path)
@@ -160,6 +160,20 @@ impl VirtualFile for TraceFile {
 
         result
     }
+
+    #[tracing::instrument(level = "trace", skip_all, fields(path=%self.path.display()))]
+    fn poll_sync(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let result = Pin::new(&mut *self.file).poll_sync(cx);
+
+        if let Poll::Ready(Err(e)) = &result {
+            tracing::trace!(error = e as &dyn std::error::Error);
+        }
+
+        result
+    }
 }
 
 impl AsyncRead for TraceFile {
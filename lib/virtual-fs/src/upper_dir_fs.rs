@@ -0,0 +1,500 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    ops, DirEntry, FileOpener, FileSystem, FileType, FsError, Metadata, NullFile, OpenOptions,
+    OpenOptionsConfig, ReadDir, ScopedDirectoryFileSystem, VirtualFile,
+};
+
+/// A host directory that can be used as the writable primary layer of an
+/// [`OverlayFileSystem`][crate::OverlayFileSystem], letting changes made on
+/// top of read-only layers (e.g. a webc package) outlive the process.
+///
+/// The [`OverlayFileSystem`][crate::OverlayFileSystem] records deletions as
+/// `.wh.<name>` whiteout files and opaque directories with a `.wh..wh..opq`
+/// marker. This file system stores them on disk the same way as a Linux
+/// overlayfs upper directory does, so the directory can be inspected with
+/// normal tools or even used with `mount -t overlay`:
+///
+/// - a whiteout is a character device with device number 0/0 which has the
+///   same name as the item it hides
+/// - an opaque directory has its `trusted.overlay.opaque` (or
+///   `user.overlay.opaque`) extended attribute set to `y`
+///
+/// Creating device nodes and `trusted.*` attributes normally requires extra
+/// privileges, so when that isn't possible it falls back to the OCI image
+/// layer conventions and stores the `.wh.` files as they are. Both formats are
+/// understood when reading.
+#[derive(Debug, Clone)]
+pub struct UpperDirFileSystem {
+    inner: ScopedDirectoryFileSystem,
+}
+
+impl UpperDirFileSystem {
+    pub fn new(root: impl Into<PathBuf>, inner: crate::host_fs::FileSystem) -> Self {
+        UpperDirFileSystem {
+            inner: ScopedDirectoryFileSystem::new(root, inner),
+        }
+    }
+
+    /// Create a new [`UpperDirFileSystem`] using the current
+    /// [`tokio::runtime::Handle`].
+    ///
+    /// # Panics
+    ///
+    /// This will panic if called outside of a `tokio` context.
+    pub fn new_with_default_runtime(root: impl Into<PathBuf>) -> Self {
+        UpperDirFileSystem {
+            inner: ScopedDirectoryFileSystem::new_with_default_runtime(root),
+        }
+    }
+
+    fn host_path(&self, path: &Path) -> PathBuf {
+        self.inner.prepare_path(path)
+    }
+
+    /// Is there an overlayfs-style whiteout at this path?
+    fn is_whiteout(&self, path: &Path) -> bool {
+        native::is_whiteout(&self.host_path(path))
+    }
+
+    /// Has this directory been marked as opaque using an extended attribute?
+    fn is_opaque(&self, dir: &Path) -> bool {
+        native::is_opaque(&self.host_path(dir))
+    }
+
+    /// Create a whiteout for `target`, replacing whatever is currently there.
+    fn create_whiteout(&self, target: &Path, conf: &OpenOptionsConfig) -> Result<(), FsError> {
+        let host_path = self.host_path(target);
+
+        match std::fs::symlink_metadata(&host_path) {
+            Ok(_) if native::is_whiteout(&host_path) => {
+                return if conf.create_new() {
+                    Err(FsError::AlreadyExists)
+                } else {
+                    Ok(())
+                };
+            }
+            Ok(meta) if meta.is_dir() => std::fs::remove_dir(&host_path)?,
+            Ok(_) => std::fs::remove_file(&host_path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        native::create_whiteout(&host_path).map_err(Into::into)
+    }
+}
+
+fn marker_metadata() -> Metadata {
+    Metadata {
+        ft: FileType::new_file(),
+        ..Default::default()
+    }
+}
+
+impl FileSystem for UpperDirFileSystem {
+    fn is_upper_layer(&self) -> bool {
+        true
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<ReadDir, FsError> {
+        let mut entries = Vec::new();
+
+        for entry in self.inner.read_dir(path)? {
+            let entry = entry?;
+
+            let is_device = entry
+                .metadata
+                .as_ref()
+                .map(|m| m.ft.is_char_device())
+                .unwrap_or(false);
+
+            if is_device && self.is_whiteout(&entry.path) {
+                // Present the whiteout the way the overlay expects
+                let mut whiteout = entry.path.clone();
+                whiteout.set_file_name(format!(".wh.{}", entry.file_name().to_string_lossy()));
+                entries.push(DirEntry {
+                    path: whiteout,
+                    metadata: Ok(marker_metadata()),
+                });
+            } else {
+                entries.push(entry);
+            }
+        }
+
+        if self.is_opaque(path) {
+            entries.push(DirEntry {
+                path: path.join(".wh..wh..opq"),
+                metadata: Ok(marker_metadata()),
+            });
+        }
+
+        Ok(ReadDir::new(entries))
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), FsError> {
+        if self.is_whiteout(path) {
+            std::fs::remove_file(self.host_path(path))?;
+        }
+
+        self.inner.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), FsError> {
+        if self.is_whiteout(path) {
+            return Err(FsError::EntryNotFound);
+        }
+
+        self.inner.remove_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, Result<(), FsError>> {
+        Box::pin(async move {
+            if self.is_whiteout(from) {
+                return Err(FsError::EntryNotFound);
+            }
+
+            self.inner.rename(from, to).await
+        })
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, FsError> {
+        if let Some(dir) = ops::is_opaque_marker(path) {
+            if self.is_opaque(&dir) {
+                return Ok(marker_metadata());
+            }
+        } else if let Some(target) = ops::is_white_out(path) {
+            if self.is_whiteout(&target) {
+                return Ok(marker_metadata());
+            }
+        } else if self.is_whiteout(path) {
+            return Err(FsError::EntryNotFound);
+        }
+
+        self.inner.metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), FsError> {
+        if let Some(dir) = ops::is_opaque_marker(path) {
+            if self.is_opaque(&dir) {
+                return native::clear_opaque(&self.host_path(&dir)).map_err(Into::into);
+            }
+        } else if let Some(target) = ops::is_white_out(path) {
+            if self.is_whiteout(&target) {
+                return std::fs::remove_file(self.host_path(&target)).map_err(Into::into);
+            }
+        } else if self.is_whiteout(path) {
+            return Err(FsError::EntryNotFound);
+        }
+
+        self.inner.remove_file(path)
+    }
+
+    fn new_open_options(&self) -> OpenOptions {
+        OpenOptions::new(self)
+    }
+}
+
+impl FileOpener for UpperDirFileSystem {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &OpenOptionsConfig,
+    ) -> Result<Box<dyn VirtualFile + Send + Sync + 'static>, FsError> {
+        let creating = conf.create() || conf.create_new();
+
+        if let Some(dir) = ops::is_opaque_marker(path) {
+            if self.is_opaque(&dir) {
+                return Ok(Box::<NullFile>::default());
+            }
+            if creating && native::set_opaque(&self.host_path(&dir)).is_ok() {
+                return Ok(Box::<NullFile>::default());
+            }
+        } else if let Some(target) = ops::is_white_out(path) {
+            if self.is_whiteout(&target) && !conf.create_new() {
+                return Ok(Box::<NullFile>::default());
+            }
+            if creating {
+                match self.create_whiteout(&target, conf) {
+                    Ok(()) => return Ok(Box::<NullFile>::default()),
+                    Err(FsError::PermissionDenied) | Err(FsError::UnknownError) => {
+                        tracing::debug!(
+                            path=%path.display(),
+                            "Unable to create an overlayfs whiteout, falling back to a whiteout file",
+                        );
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        } else if self.is_whiteout(path) {
+            if !creating {
+                return Err(FsError::EntryNotFound);
+            }
+            std::fs::remove_file(self.host_path(path))?;
+        }
+
+        self.inner
+            .new_open_options()
+            .options(conf.clone())
+            .open(path)
+    }
+}
+
+#[cfg(unix)]
+mod native {
+    use std::{
+        ffi::CString,
+        io,
+        os::unix::{
+            ffi::OsStrExt,
+            fs::{FileTypeExt, MetadataExt},
+        },
+        path::Path,
+    };
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    pub(super) fn is_whiteout(path: &Path) -> bool {
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) => meta.file_type().is_char_device() && meta.rdev() == 0,
+            Err(_) => false,
+        }
+    }
+
+    pub(super) fn create_whiteout(path: &Path) -> io::Result<()> {
+        let path = c_path(path)?;
+        let ret = unsafe { libc::mknod(path.as_ptr(), libc::S_IFCHR, 0) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    const OPAQUE_XATTRS: [&str; 2] = ["trusted.overlay.opaque", "user.overlay.opaque"];
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn is_opaque(dir: &Path) -> bool {
+        let Ok(dir) = c_path(dir) else {
+            return false;
+        };
+
+        OPAQUE_XATTRS.iter().any(|name| {
+            let name = CString::new(*name).unwrap();
+            let mut value = [0_u8; 1];
+            let len = unsafe {
+                libc::lgetxattr(
+                    dir.as_ptr(),
+                    name.as_ptr(),
+                    value.as_mut_ptr().cast(),
+                    value.len(),
+                )
+            };
+            len == 1 && value[0] == b'y'
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn set_opaque(dir: &Path) -> io::Result<()> {
+        let dir = c_path(dir)?;
+        let mut error = io::Error::from(io::ErrorKind::Unsupported);
+
+        for name in OPAQUE_XATTRS {
+            let name = CString::new(name).unwrap();
+            let ret =
+                unsafe { libc::lsetxattr(dir.as_ptr(), name.as_ptr(), b"y".as_ptr().cast(), 1, 0) };
+            if ret == 0 {
+                return Ok(());
+            }
+            error = io::Error::last_os_error();
+        }
+
+        Err(error)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn clear_opaque(dir: &Path) -> io::Result<()> {
+        let dir = c_path(dir)?;
+
+        for name in OPAQUE_XATTRS {
+            let name = CString::new(name).unwrap();
+            unsafe {
+                libc::lremovexattr(dir.as_ptr(), name.as_ptr());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn is_opaque(_dir: &Path) -> bool {
+        false
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn set_opaque(_dir: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn clear_opaque(_dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod native {
+    use std::{io, path::Path};
+
+    pub(super) fn is_whiteout(_path: &Path) -> bool {
+        false
+    }
+
+    pub(super) fn create_whiteout(_path: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    pub(super) fn is_opaque(_dir: &Path) -> bool {
+        false
+    }
+
+    pub(super) fn set_opaque(_dir: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub(super) fn clear_opaque(_dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{mem_fs, OverlayFileSystem};
+
+    fn lower() -> mem_fs::FileSystem {
+        let fs = mem_fs::FileSystem::default();
+        ops::create_dir_all(&fs, "/lib/python").unwrap();
+        fs.insert_ro_file(
+            Path::new("/lib/python/os.py"),
+            b"import sys".as_slice().into(),
+        )
+        .unwrap();
+        fs.insert_ro_file(
+            Path::new("/lib/python/io.py"),
+            b"import os".as_slice().into(),
+        )
+        .unwrap();
+        fs
+    }
+
+    #[tokio::test]
+    async fn changes_survive_across_instances() {
+        let temp = TempDir::new().unwrap();
+        let lower = Arc::new(lower());
+
+        {
+            let upper = UpperDirFileSystem::new_with_default_runtime(temp.path());
+            let fs = OverlayFileSystem::new(upper, [Arc::clone(&lower)]);
+
+            let mut f = fs
+                .new_open_options()
+                .write(true)
+                .create(true)
+                .open("/lib/python/new.py")
+                .unwrap();
+            f.write_all(b"print('hi')").await.unwrap();
+            f.flush().await.unwrap();
+            fs.remove_file(Path::new("/lib/python/io.py")).unwrap();
+        }
+
+        // The new file is a normal file on the host
+        assert_eq!(
+            std::fs::read_to_string(temp.path().join("lib/python/new.py")).unwrap(),
+            "print('hi')"
+        );
+        // And the whiteout uses one of the two supported formats
+        let whiteout = temp.path().join("lib/python/io.py");
+        assert!(
+            native::is_whiteout(&whiteout) || temp.path().join("lib/python/.wh.io.py").exists()
+        );
+
+        let upper = UpperDirFileSystem::new_with_default_runtime(temp.path());
+        let fs = OverlayFileSystem::new(upper, [lower]);
+
+        let mut contents = String::new();
+        fs.new_open_options()
+            .read(true)
+            .open("/lib/python/new.py")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .await
+            .unwrap();
+        assert_eq!(contents, "print('hi')");
+        assert_eq!(
+            fs.metadata(Path::new("/lib/python/io.py")).unwrap_err(),
+            FsError::EntryNotFound
+        );
+
+        let mut names: Vec<_> = fs
+            .read_dir(Path::new("/lib/python"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["new.py", "os.py"]);
+    }
+
+    #[tokio::test]
+    async fn recreated_directories_are_opaque() {
+        let temp = TempDir::new().unwrap();
+        let upper = UpperDirFileSystem::new_with_default_runtime(temp.path());
+        let fs = OverlayFileSystem::new(upper, [lower()]);
+
+        fs.remove_file(Path::new("/lib/python/os.py")).unwrap();
+        fs.remove_file(Path::new("/lib/python/io.py")).unwrap();
+        fs.remove_dir(Path::new("/lib/python")).unwrap();
+        assert!(fs.metadata(Path::new("/lib/python")).is_err());
+
+        fs.create_dir(Path::new("/lib/python")).unwrap();
+
+        assert!(fs.read_dir(Path::new("/lib/python")).unwrap().is_empty());
+        assert_eq!(
+            fs.metadata(Path::new("/lib/python/os.py")).unwrap_err(),
+            FsError::EntryNotFound
+        );
+        assert!(temp.path().join("lib/python").is_dir());
+    }
+
+    #[tokio::test]
+    async fn overlayfs_whiteouts_are_understood() {
+        let temp = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp.path().join("lib/python")).unwrap();
+        if native::create_whiteout(&temp.path().join("lib/python/os.py")).is_err() {
+            // We don't have permission to create device nodes
+            return;
+        }
+
+        let upper = UpperDirFileSystem::new_with_default_runtime(temp.path());
+        let fs = OverlayFileSystem::new(upper, [lower()]);
+
+        assert_eq!(
+            fs.metadata(Path::new("/lib/python/os.py")).unwrap_err(),
+            FsError::EntryNotFound
+        );
+        assert!(fs.metadata(Path::new("/lib/python/io.py")).is_ok());
+    }
+}
@@ -195,6 +195,38 @@ impl RemoteNetworkingClient {
         Self::new(tx, rx, rx_work)
     }
 
+    /// Connects to a [`RemoteNetworkingServer`](crate::RemoteNetworkingServer)
+    /// that is listening on a Unix socket
+    #[cfg(all(unix, feature = "remote-ipc"))]
+    pub async fn connect_unix(
+        path: impl AsRef<std::path::Path>,
+        format: FrameSerializationFormat,
+    ) -> std::io::Result<(Self, RemoteNetworkingClientDriver)> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Ok(Self::new_from_unix_stream(stream, format))
+    }
+
+    /// Creates a new interface that talks to the remote location over
+    /// a connected Unix socket
+    #[cfg(all(unix, feature = "remote-ipc"))]
+    pub fn new_from_unix_stream(
+        stream: tokio::net::UnixStream,
+        format: FrameSerializationFormat,
+    ) -> (Self, RemoteNetworkingClientDriver) {
+        let (rx, tx) = stream.into_split();
+        Self::new_from_async_io(tx, rx, format)
+    }
+
+    /// Creates a new interface that talks to the remote location over the
+    /// standard input and output of this process, which is useful when it
+    /// was spawned by the process that provides its networking
+    #[cfg(feature = "remote-ipc")]
+    pub fn new_from_stdio(
+        format: FrameSerializationFormat,
+    ) -> (Self, RemoteNetworkingClientDriver) {
+        Self::new_from_async_io(tokio::io::stdout(), tokio::io::stdin(), format)
+    }
+
     /// Creates a new interface on the remote location using
     /// a unique interface ID and a pair of channels
     #[cfg(feature = "hyper")]
@@ -1542,3 +1574,66 @@ impl VirtualTcpSocket for RemoteSocket {
         }
     }
 }
+
+#[cfg(all(test, unix, feature = "remote-ipc", feature = "host-net"))]
+mod tests {
+    use std::mem::MaybeUninit;
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        host::LocalNetworking, RemoteNetworkingServer, VirtualConnectedSocketExt,
+        VirtualTcpListenerExt,
+    };
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn talks_to_a_server_over_a_unix_socket() {
+        let path = std::env::temp_dir().join(format!("virtual-net-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (_server, driver) = RemoteNetworkingServer::new_from_unix_stream(
+                stream,
+                FrameSerializationFormat::Bincode,
+                Arc::new(LocalNetworking::new()),
+            );
+            driver.await;
+        });
+
+        let (client, driver) =
+            RemoteNetworkingClient::connect_unix(&path, FrameSerializationFormat::Bincode)
+                .await
+                .unwrap();
+        tokio::spawn(driver);
+
+        let mut listener = client
+            .listen_tcp(
+                SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                false,
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        let addr = listener.addr_local().unwrap();
+        let mut socket = client
+            .connect_tcp(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), addr)
+            .await
+            .unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        socket.send(b"hello").await.unwrap();
+        let mut buf = [MaybeUninit::uninit(); 5];
+        let mut read = 0;
+        while read < buf.len() {
+            read += accepted.recv(&mut buf[read..]).await.unwrap();
+        }
+        // SAFETY: the socket initialized all of the buffer
+        let buf: [u8; 5] = unsafe { std::mem::transmute(buf) };
+        assert_eq!(&buf, b"hello");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,947 @@
+//! Name resolution that can be configured per environment.
+//!
+//! [`DnsClient`] is a small stub resolver that sends its queries through the
+//! sockets of any [`VirtualNetworking`] implementation. It asks over UDP,
+//! retries over TCP when the answer was truncated and caches the answers
+//! for as long as their TTL allows.
+//!
+//! [`ResolvingNetworking`] wraps another networking implementation and
+//! replaces its `resolve` with a lookup in a set of [`StaticHosts`]
+//! (the equivalent of `/etc/hosts`) followed by a query to the configured
+//! name servers, which makes split-horizon names and a specific upstream
+//! server possible without touching the host configuration.
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{
+    DynVirtualNetworking, ForwardProtocol, IpCidr, IpRoute, NetworkError, PortForward, Result,
+    StreamSecurity, VirtualConnectedSocketExt, VirtualConnectionlessSocketExt, VirtualIcmpSocket,
+    VirtualNetworking, VirtualRawSocket, VirtualTcpListener, VirtualTcpSocket, VirtualUdpSocket,
+};
+
+/// The port name servers listen on
+pub const DNS_PORT: u16 = 53;
+
+pub(crate) const DNS_TYPE_A: u16 = 1;
+pub(crate) const DNS_TYPE_AAAA: u16 = 28;
+const DNS_TYPE_SOA: u16 = 6;
+
+const DNS_FLAG_RESPONSE: u16 = 0x8000;
+const DNS_FLAG_TRUNCATED: u16 = 0x0200;
+const DNS_RCODE_NOERROR: u16 = 0;
+const DNS_RCODE_NXDOMAIN: u16 = 3;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_ATTEMPTS: usize = 2;
+const DEFAULT_MAX_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_UDP_RESPONSE: usize = 1500;
+const MAX_CACHE_ENTRIES: usize = 1024;
+
+/// Names that are always answered locally, whatever the name servers say
+#[derive(Debug, Clone, Default)]
+pub struct StaticHosts {
+    entries: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticHosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an address for a name, a name can have several addresses
+    pub fn insert(&mut self, name: &str, ip: IpAddr) {
+        let addrs = self.entries.entry(normalize_name(name)).or_default();
+        if !addrs.contains(&ip) {
+            addrs.push(ip);
+        }
+    }
+
+    pub fn with_host(mut self, name: &str, ip: IpAddr) -> Self {
+        self.insert(name, ip);
+        self
+    }
+
+    /// Removes a name and all its addresses
+    pub fn remove(&mut self, name: &str) -> Option<Vec<IpAddr>> {
+        self.entries.remove(&normalize_name(name))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&[IpAddr]> {
+        self.entries
+            .get(&normalize_name(name))
+            .map(|addrs| addrs.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    servers: Vec<SocketAddr>,
+    name: String,
+    qtype: u16,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// A stub resolver that queries name servers through a [`VirtualNetworking`]
+/// implementation and caches what they answered
+#[derive(Debug)]
+pub struct DnsClient {
+    timeout: Duration,
+    attempts: usize,
+    max_ttl: Duration,
+    cache: Mutex<HashMap<CacheKey, CacheEntry>>,
+}
+
+impl Default for DnsClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnsClient {
+    pub fn new() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            attempts: DEFAULT_ATTEMPTS,
+            max_ttl: DEFAULT_MAX_TTL,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long to wait for a name server before trying the next one
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many times every name server is tried before giving up
+    pub fn with_attempts(mut self, attempts: usize) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Caps how long an answer is cached, whatever its TTL
+    pub fn with_max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// Forgets all the cached answers
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Resolves the IPv4 and IPv6 addresses of a name by asking the name
+    /// servers in order, the sockets are opened on `net`
+    pub async fn lookup<N>(
+        &self,
+        net: &N,
+        host: &str,
+        servers: &[SocketAddr],
+    ) -> Result<Vec<IpAddr>>
+    where
+        N: VirtualNetworking + ?Sized,
+    {
+        if servers.is_empty() {
+            return Err(NetworkError::AddressNotAvailable);
+        }
+        let name = normalize_name(host);
+        let v4 = self.lookup_type(net, &name, DNS_TYPE_A, servers).await;
+        let v6 = self.lookup_type(net, &name, DNS_TYPE_AAAA, servers).await;
+        match (v4, v6) {
+            (Ok(mut v4), Ok(v6)) => {
+                v4.extend(v6);
+                Ok(v4)
+            }
+            (Ok(addrs), Err(_)) | (Err(_), Ok(addrs)) => Ok(addrs),
+            (Err(err), Err(_)) => Err(err),
+        }
+    }
+
+    async fn lookup_type<N>(
+        &self,
+        net: &N,
+        name: &str,
+        qtype: u16,
+        servers: &[SocketAddr],
+    ) -> Result<Vec<IpAddr>>
+    where
+        N: VirtualNetworking + ?Sized,
+    {
+        let key = CacheKey {
+            servers: servers.to_vec(),
+            name: name.to_string(),
+            qtype,
+        };
+        if let Some(addrs) = self.cached(&key) {
+            return Ok(addrs);
+        }
+
+        let mut last_err = NetworkError::TimedOut;
+        for _ in 0..self.attempts {
+            for server in servers.iter().copied() {
+                let id = random_u16();
+                let query = encode_query(id, name, qtype).ok_or(NetworkError::InvalidInput)?;
+                let response = match self.exchange(net, server, &query, id).await {
+                    Ok(response) => response,
+                    Err(err) => {
+                        tracing::debug!(%server, name, error = %err, "DNS query failed");
+                        last_err = err;
+                        continue;
+                    }
+                };
+                match response.rcode {
+                    DNS_RCODE_NOERROR => {
+                        // an empty answer is cached like a missing name
+                        let ttl = response.ttl.or(response.negative_ttl).unwrap_or_default();
+                        self.store(key, response.addrs.clone(), ttl);
+                        return Ok(response.addrs);
+                    }
+                    DNS_RCODE_NXDOMAIN => {
+                        let ttl = response.negative_ttl.unwrap_or_default();
+                        self.store(key, Vec::new(), ttl);
+                        return Ok(Vec::new());
+                    }
+                    // the server failed or refused, maybe the next one knows
+                    _ => last_err = NetworkError::IOError,
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.addrs.clone()),
+            Some(_) => {
+                cache.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn store(&self, key: CacheKey, addrs: Vec<IpAddr>, ttl: u32) {
+        let ttl = Duration::from_secs(ttl as u64).min(self.max_ttl);
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, entry| entry.expires > now);
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.clear();
+            }
+        }
+        cache.insert(
+            key,
+            CacheEntry {
+                addrs,
+                expires: now + ttl,
+            },
+        );
+    }
+
+    async fn exchange<N>(
+        &self,
+        net: &N,
+        server: SocketAddr,
+        query: &[u8],
+        id: u16,
+    ) -> Result<DnsResponse>
+    where
+        N: VirtualNetworking + ?Sized,
+    {
+        let response = self.exchange_udp(net, server, query, id).await?;
+        if response.truncated {
+            return self.exchange_tcp(net, server, query, id).await;
+        }
+        Ok(response)
+    }
+
+    async fn exchange_udp<N>(
+        &self,
+        net: &N,
+        server: SocketAddr,
+        query: &[u8],
+        id: u16,
+    ) -> Result<DnsResponse>
+    where
+        N: VirtualNetworking + ?Sized,
+    {
+        let deadline = Instant::now() + self.timeout;
+        let mut socket = net.bind_udp(unspecified_addr(server), false, false).await?;
+        tokio::time::timeout_at(deadline, socket.send_to(query, server))
+            .await
+            .map_err(|_| NetworkError::TimedOut)??;
+
+        let mut buf = [MaybeUninit::<u8>::uninit(); MAX_UDP_RESPONSE];
+        loop {
+            let (read, from) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf))
+                .await
+                .map_err(|_| NetworkError::TimedOut)??;
+            if from != server {
+                continue;
+            }
+            let packet: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+            if let Some(response) = decode_response(packet, id) {
+                return Ok(response);
+            }
+        }
+    }
+
+    async fn exchange_tcp<N>(
+        &self,
+        net: &N,
+        server: SocketAddr,
+        query: &[u8],
+        id: u16,
+    ) -> Result<DnsResponse>
+    where
+        N: VirtualNetworking + ?Sized,
+    {
+        let exchange = async {
+            let mut socket = net.connect_tcp(unspecified_addr(server), server).await?;
+
+            // messages over TCP are prefixed with their length
+            let mut message = Vec::with_capacity(query.len() + 2);
+            message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+            message.extend_from_slice(query);
+            let mut sent = 0;
+            while sent < message.len() {
+                sent += socket.send(&message[sent..]).await?;
+            }
+            socket.flush().await?;
+
+            let mut len = [0u8; 2];
+            recv_exact(socket.as_mut(), &mut len).await?;
+            let mut packet = vec![0u8; u16::from_be_bytes(len) as usize];
+            recv_exact(socket.as_mut(), &mut packet).await?;
+            socket.close().ok();
+
+            decode_response(&packet, id).ok_or(NetworkError::InvalidData)
+        };
+        tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| NetworkError::TimedOut)?
+    }
+}
+
+async fn recv_exact(socket: &mut (dyn VirtualTcpSocket + Sync), buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = {
+            let chunk: &mut [MaybeUninit<u8>] = unsafe { std::mem::transmute(&mut buf[filled..]) };
+            socket.recv(chunk).await?
+        };
+        if read == 0 {
+            return Err(NetworkError::UnexpectedEof);
+        }
+        filled += read;
+    }
+    Ok(())
+}
+
+/// Wraps a [`VirtualNetworking`] implementation and resolves names with
+/// [`StaticHosts`] and a [`DnsClient`] instead of the inner implementation
+///
+/// Names that are neither in the static hosts nor can be sent to a name
+/// server (none were configured and the guest did not ask for a specific
+/// one) are still resolved by the inner implementation.
+#[derive(Debug)]
+pub struct ResolvingNetworking {
+    inner: DynVirtualNetworking,
+    hosts: StaticHosts,
+    nameservers: Vec<SocketAddr>,
+    client: DnsClient,
+}
+
+impl ResolvingNetworking {
+    pub fn new(inner: DynVirtualNetworking) -> Self {
+        Self {
+            inner,
+            hosts: StaticHosts::default(),
+            nameservers: Vec::new(),
+            client: DnsClient::default(),
+        }
+    }
+
+    pub fn with_hosts(mut self, hosts: StaticHosts) -> Self {
+        self.hosts = hosts;
+        self
+    }
+
+    /// Sets the name servers that are asked, in order, for every name that
+    /// is not in the static hosts
+    pub fn with_nameservers(mut self, nameservers: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.nameservers = nameservers.into_iter().collect();
+        self
+    }
+
+    pub fn with_client(mut self, client: DnsClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn hosts(&self) -> &StaticHosts {
+        &self.hosts
+    }
+
+    pub fn nameservers(&self) -> &[SocketAddr] {
+        &self.nameservers
+    }
+
+    pub fn client(&self) -> &DnsClient {
+        &self.client
+    }
+
+    pub fn inner(&self) -> &DynVirtualNetworking {
+        &self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for ResolvingNetworking {
+    async fn bridge(
+        &self,
+        network: &str,
+        access_token: &str,
+        security: StreamSecurity,
+    ) -> Result<()> {
+        self.inner.bridge(network, access_token, security).await
+    }
+
+    async fn unbridge(&self) -> Result<()> {
+        self.inner.unbridge().await
+    }
+
+    async fn dhcp_acquire(&self) -> Result<Vec<IpAddr>> {
+        self.inner.dhcp_acquire().await
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> Result<()> {
+        self.inner.ip_add(ip, prefix).await
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> Result<()> {
+        self.inner.ip_remove(ip).await
+    }
+
+    async fn ip_clear(&self) -> Result<()> {
+        self.inner.ip_clear().await
+    }
+
+    async fn ip_list(&self) -> Result<Vec<IpCidr>> {
+        self.inner.ip_list().await
+    }
+
+    async fn mac(&self) -> Result<[u8; 6]> {
+        self.inner.mac().await
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> Result<()> {
+        self.inner.gateway_set(ip).await
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> Result<()> {
+        self.inner
+            .route_add(cidr, via_router, preferred_until, expires_at)
+            .await
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> Result<()> {
+        self.inner.route_remove(cidr).await
+    }
+
+    async fn route_clear(&self) -> Result<()> {
+        self.inner.route_clear().await
+    }
+
+    async fn route_list(&self) -> Result<Vec<IpRoute>> {
+        self.inner.route_list().await
+    }
+
+    async fn bind_raw(&self) -> Result<Box<dyn VirtualRawSocket + Sync>> {
+        self.inner.bind_raw().await
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>> {
+        self.inner
+            .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+            .await
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>> {
+        self.inner.bind_udp(addr, reuse_port, reuse_addr).await
+    }
+
+    async fn bind_icmp(&self, addr: IpAddr) -> Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        self.inner.bind_icmp(addr).await
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        self.inner.connect_tcp(addr, peer).await
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        if let Some(addrs) = self.hosts.lookup(host) {
+            return Ok(addrs.to_vec());
+        }
+        match dns_server {
+            Some(server) => {
+                let server = SocketAddr::new(server, DNS_PORT);
+                self.client.lookup(&*self.inner, host, &[server]).await
+            }
+            None if !self.nameservers.is_empty() => {
+                self.client
+                    .lookup(&*self.inner, host, &self.nameservers)
+                    .await
+            }
+            None => self.inner.resolve(host, port, None).await,
+        }
+    }
+
+    async fn forward_add(&self, forward: PortForward) -> Result<PortForward> {
+        self.inner.forward_add(forward).await
+    }
+
+    async fn forward_remove(&self, protocol: ForwardProtocol, host: SocketAddr) -> Result<()> {
+        self.inner.forward_remove(protocol, host).await
+    }
+
+    async fn forward_list(&self) -> Result<Vec<PortForward>> {
+        self.inner.forward_list().await
+    }
+}
+
+/// What was understood of a response from a name server
+#[derive(Debug)]
+pub(crate) struct DnsResponse {
+    pub truncated: bool,
+    pub rcode: u16,
+    pub addrs: Vec<IpAddr>,
+    /// The smallest TTL of the answers
+    pub ttl: Option<u32>,
+    /// How long the absence of a name may be cached, from the SOA record
+    pub negative_ttl: Option<u32>,
+}
+
+impl DnsResponse {
+    #[cfg(feature = "userspace")]
+    pub fn into_result(self) -> Result<Vec<IpAddr>> {
+        match self.rcode {
+            DNS_RCODE_NOERROR => Ok(self.addrs),
+            // NXDOMAIN means the name does not exist
+            DNS_RCODE_NXDOMAIN => Ok(Vec::new()),
+            _ => Err(NetworkError::IOError),
+        }
+    }
+}
+
+/// Builds a recursive DNS query for a single name
+pub(crate) fn encode_query(id: u16, name: &str, qtype: u16) -> Option<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Some(query)
+}
+
+/// Parses a DNS response, `None` is returned when the packet is not a
+/// response to the query with the given identifier
+pub(crate) fn decode_response(packet: &[u8], id: u16) -> Option<DnsResponse> {
+    let read_u16 = |pos: usize| -> Option<u16> {
+        Some(u16::from_be_bytes([
+            *packet.get(pos)?,
+            *packet.get(pos + 1)?,
+        ]))
+    };
+    let read_u32 = |pos: usize| -> Option<u32> {
+        Some(((read_u16(pos)? as u32) << 16) | read_u16(pos + 2)? as u32)
+    };
+    let skip_name = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *packet.get(pos)? as usize;
+            if len & 0xC0 == 0xC0 {
+                return Some(pos + 2);
+            }
+            pos += 1;
+            if len == 0 {
+                return Some(pos);
+            }
+            pos += len;
+        }
+    };
+
+    if read_u16(0)? != id {
+        return None;
+    }
+    let flags = read_u16(2)?;
+    if flags & DNS_FLAG_RESPONSE == 0 {
+        return None;
+    }
+    let mut response = DnsResponse {
+        truncated: flags & DNS_FLAG_TRUNCATED != 0,
+        rcode: flags & 0x000F,
+        addrs: Vec::new(),
+        ttl: None,
+        negative_ttl: None,
+    };
+    if response.truncated {
+        return Some(response);
+    }
+
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+    let authorities = read_u16(8)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(pos)? + 4;
+    }
+
+    for _ in 0..answers {
+        pos = skip_name(pos)?;
+        let rtype = read_u16(pos)?;
+        let ttl = read_u32(pos + 4)?;
+        let len = read_u16(pos + 8)? as usize;
+        let data = packet.get(pos + 10..pos + 10 + len)?;
+        match (rtype, len) {
+            (DNS_TYPE_A, 4) => {
+                response.addrs.push(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                )));
+            }
+            (DNS_TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                response.addrs.push(IpAddr::V6(octets.into()));
+            }
+            _ => {}
+        }
+        response.ttl = Some(response.ttl.map_or(ttl, |min| min.min(ttl)));
+        pos += 10 + len;
+    }
+
+    // a negative answer may be cached for the smaller of the TTL and the
+    // MINIMUM field of the SOA record in the authority section (RFC 2308)
+    for _ in 0..authorities {
+        pos = skip_name(pos)?;
+        let rtype = read_u16(pos)?;
+        let ttl = read_u32(pos + 4)?;
+        let len = read_u16(pos + 8)? as usize;
+        if rtype == DNS_TYPE_SOA {
+            let rdata = pos + 10;
+            let serial = skip_name(skip_name(rdata)?)?;
+            let minimum = read_u32(serial + 16)?;
+            response.negative_ttl = Some(ttl.min(minimum));
+        }
+        pos += 10 + len;
+    }
+
+    Some(response)
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn unspecified_addr(peer: SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+fn random_u16() -> u16 {
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish() as u16
+}
+
+#[cfg(all(test, feature = "host-net"))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::host::LocalNetworking;
+    use crate::UnsupportedVirtualNetworking;
+
+    use super::*;
+
+    /// A name server that answers from a fixed table, optionally forcing the
+    /// client to retry over TCP
+    struct StubServer {
+        addr: SocketAddr,
+        udp_queries: Arc<AtomicUsize>,
+        tcp_queries: Arc<AtomicUsize>,
+    }
+
+    type Records = Arc<Vec<(&'static str, IpAddr, u32)>>;
+
+    async fn stub_server(records: &[(&'static str, IpAddr, u32)], truncate: bool) -> StubServer {
+        let records: Records = Arc::new(records.to_vec());
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let udp_queries = Arc::new(AtomicUsize::new(0));
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn({
+            let records = records.clone();
+            let queries = udp_queries.clone();
+            async move {
+                let mut buf = [0u8; 512];
+                loop {
+                    let (read, from) = udp.recv_from(&mut buf).await.unwrap();
+                    queries.fetch_add(1, Ordering::SeqCst);
+                    let response = stub_answer(&buf[..read], &records, truncate);
+                    udp.send_to(&response, from).await.unwrap();
+                }
+            }
+        });
+        tokio::spawn({
+            let queries = tcp_queries.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = tcp.accept().await.unwrap();
+                    queries.fetch_add(1, Ordering::SeqCst);
+                    let len = stream.read_u16().await.unwrap() as usize;
+                    let mut query = vec![0u8; len];
+                    stream.read_exact(&mut query).await.unwrap();
+                    let response = stub_answer(&query, &records, false);
+                    stream.write_u16(response.len() as u16).await.unwrap();
+                    stream.write_all(&response).await.unwrap();
+                }
+            }
+        });
+
+        StubServer {
+            addr,
+            udp_queries,
+            tcp_queries,
+        }
+    }
+
+    fn stub_answer(query: &[u8], records: &Records, truncate: bool) -> Vec<u8> {
+        let mut pos = 12;
+        let mut labels = Vec::new();
+        while query[pos] != 0 {
+            let len = query[pos] as usize;
+            labels.push(std::str::from_utf8(&query[pos + 1..pos + 1 + len]).unwrap());
+            pos += 1 + len;
+        }
+        let name = labels.join(".");
+        let qtype = u16::from_be_bytes([query[pos + 1], query[pos + 2]]);
+        let mut response = query[..pos + 5].to_vec();
+
+        if truncate {
+            response[2..4].copy_from_slice(&[0x83, 0x80]);
+            return response;
+        }
+        if !records.iter().any(|(n, _, _)| *n == name) {
+            // NXDOMAIN with a SOA record allowing the answer to be cached
+            response[2..4].copy_from_slice(&[0x81, 0x83]);
+            response[8..10].copy_from_slice(&1u16.to_be_bytes());
+            response.extend_from_slice(&[0xC0, 0x0C, 0, 6, 0, 1, 0, 0, 0, 60, 0, 22]);
+            response.extend_from_slice(&[0, 0]);
+            response.extend_from_slice(&[0; 16]);
+            response.extend_from_slice(&30u32.to_be_bytes());
+            return response;
+        }
+
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        let mut answers = 0u16;
+        for (_, ip, ttl) in records.iter().filter(|(n, _, _)| *n == name) {
+            let data = match (ip, qtype) {
+                (IpAddr::V4(ip), DNS_TYPE_A) => ip.octets().to_vec(),
+                (IpAddr::V6(ip), DNS_TYPE_AAAA) => ip.octets().to_vec(),
+                _ => continue,
+            };
+            response.extend_from_slice(&[0xC0, 0x0C]);
+            response.extend_from_slice(&qtype.to_be_bytes());
+            response.extend_from_slice(&1u16.to_be_bytes());
+            response.extend_from_slice(&ttl.to_be_bytes());
+            response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            response.extend_from_slice(&data);
+            answers += 1;
+        }
+        response[6..8].copy_from_slice(&answers.to_be_bytes());
+        response
+    }
+
+    fn resolving(server: &StubServer) -> ResolvingNetworking {
+        ResolvingNetworking::new(Arc::new(LocalNetworking::new()))
+            .with_nameservers([server.addr])
+            .with_client(DnsClient::new().with_timeout(Duration::from_secs(5)))
+    }
+
+    #[tokio::test]
+    async fn resolves_through_the_configured_nameserver() {
+        let v4: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let v6: IpAddr = "fd00::10".parse().unwrap();
+        let server = stub_server(&[("example.com", v4, 60), ("example.com", v6, 60)], false).await;
+        let net = resolving(&server);
+
+        let ips = net.resolve("Example.com.", None, None).await.unwrap();
+        assert_eq!(ips, vec![v4, v6]);
+        // one query for A and one for AAAA
+        assert_eq!(server.udp_queries.load(Ordering::SeqCst), 2);
+
+        let ips = net
+            .resolve("does-not-exist.test", None, None)
+            .await
+            .unwrap();
+        assert!(ips.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached_for_their_ttl() {
+        let cached: IpAddr = Ipv4Addr::new(10, 0, 0, 1).into();
+        let uncached: IpAddr = Ipv4Addr::new(10, 0, 0, 2).into();
+        let server = stub_server(
+            &[("cached.test", cached, 300), ("uncached.test", uncached, 0)],
+            false,
+        )
+        .await;
+        let net = resolving(&server);
+
+        for _ in 0..3 {
+            assert_eq!(
+                net.resolve("cached.test", None, None).await.unwrap(),
+                vec![cached]
+            );
+        }
+        // the empty AAAA answer carries no TTL so only the A record is cached
+        let after_cached = server.udp_queries.load(Ordering::SeqCst);
+        assert_eq!(after_cached, 4);
+
+        for _ in 0..3 {
+            assert_eq!(
+                net.resolve("uncached.test", None, None).await.unwrap(),
+                vec![uncached]
+            );
+        }
+        assert_eq!(server.udp_queries.load(Ordering::SeqCst), after_cached + 6);
+
+        // NXDOMAIN is cached using the SOA record
+        net.resolve("missing.test", None, None).await.unwrap();
+        let after_missing = server.udp_queries.load(Ordering::SeqCst);
+        net.resolve("missing.test", None, None).await.unwrap();
+        assert_eq!(server.udp_queries.load(Ordering::SeqCst), after_missing);
+
+        net.client().clear_cache();
+        net.resolve("cached.test", None, None).await.unwrap();
+        assert_eq!(server.udp_queries.load(Ordering::SeqCst), after_missing + 2);
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_retried_over_tcp() {
+        let ip: IpAddr = Ipv4Addr::new(172, 16, 0, 1).into();
+        let server = stub_server(&[("big.test", ip, 60)], true).await;
+        let net = resolving(&server);
+
+        let ips = net.resolve("big.test", None, None).await.unwrap();
+        assert_eq!(ips, vec![ip]);
+        assert_eq!(server.udp_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(server.tcp_queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn static_hosts_take_precedence() {
+        let upstream: IpAddr = Ipv4Addr::new(192, 168, 1, 10).into();
+        let pinned: IpAddr = Ipv4Addr::new(10, 1, 2, 3).into();
+        let server = stub_server(&[("api.internal", upstream, 60)], false).await;
+        let net =
+            resolving(&server).with_hosts(StaticHosts::new().with_host("API.internal", pinned));
+
+        assert_eq!(
+            net.resolve("api.internal", None, None).await.unwrap(),
+            vec![pinned]
+        );
+        assert_eq!(
+            net.resolve("10.9.9.9", None, None).await.unwrap(),
+            vec![IpAddr::from(Ipv4Addr::new(10, 9, 9, 9))]
+        );
+        assert_eq!(server.udp_queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_inner_networking() {
+        let net = ResolvingNetworking::new(Arc::new(UnsupportedVirtualNetworking::default()))
+            .with_hosts(StaticHosts::new().with_host("known.test", Ipv4Addr::LOCALHOST.into()));
+
+        assert_eq!(
+            net.resolve("known.test", None, None).await.unwrap(),
+            vec![IpAddr::from(Ipv4Addr::LOCALHOST)]
+        );
+        assert_eq!(
+            net.resolve("unknown.test", None, None).await,
+            Err(NetworkError::Unsupported)
+        );
+    }
+}
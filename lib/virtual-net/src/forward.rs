@@ -0,0 +1,781 @@
+//! Forwards ports of the host into another [`VirtualNetworking`]
+//! implementation.
+//!
+//! A guest that listens on a loopback, userspace or remote network can not
+//! be reached from the host. [`PortForwardingNetworking`] wraps the network
+//! of the guest and binds sockets on the network of the host for every
+//! [`PortForward`] that is added through
+//! [`VirtualNetworking::forward_add`]. TCP connections are relayed in both
+//! directions until both sides have shut down their writing half, UDP
+//! datagrams are relayed through one socket in the guest network per peer
+//! of the host so the replies find their way back.
+//!
+//! All the relaying happens in the [`PortForwardingDriver`] which must be
+//! polled (e.g. spawned on the runtime) for the forwards to make progress.
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::{poll_fn, AbortHandle, Abortable};
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::{
+    DynVirtualNetworking, ForwardProtocol, InterestHandler, IpCidr, IpRoute, NetworkError,
+    PortForward, Result, StreamSecurity, VirtualConnectionlessSocketExt, VirtualIcmpSocket,
+    VirtualNetworking, VirtualRawSocket, VirtualTcpListener, VirtualTcpListenerExt,
+    VirtualTcpSocket, VirtualUdpSocket,
+};
+
+const RELAY_BUFFER_SIZE: usize = 16384;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+type ForwardTask = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Wraps the networking of a guest and forwards ports of the host into it
+#[derive(Debug)]
+pub struct PortForwardingNetworking {
+    inner: DynVirtualNetworking,
+    host: DynVirtualNetworking,
+    guest_route: DynVirtualNetworking,
+    forwards: Mutex<HashMap<(ForwardProtocol, SocketAddr), (PortForward, AbortHandle)>>,
+    tasks: mpsc::UnboundedSender<Abortable<ForwardTask>>,
+}
+
+impl PortForwardingNetworking {
+    /// Creates the wrapper, the ports are bound on `host` and the forwarded
+    /// connections are opened through `inner`
+    pub fn new(
+        inner: DynVirtualNetworking,
+        host: DynVirtualNetworking,
+    ) -> (Self, PortForwardingDriver) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let networking = Self {
+            guest_route: inner.clone(),
+            inner,
+            host,
+            forwards: Mutex::new(HashMap::new()),
+            tasks: tx,
+        };
+        let driver = PortForwardingDriver {
+            tasks: FuturesUnordered::new(),
+            rx,
+            closed: false,
+        };
+        (networking, driver)
+    }
+
+    /// Opens the forwarded connections through another networking
+    /// implementation that can reach the guest (e.g. another host on the
+    /// same [`VirtualSwitch`](crate::VirtualSwitch)), which is needed when
+    /// the network of the guest can not connect to its own addresses
+    pub fn with_guest_route(mut self, guest_route: DynVirtualNetworking) -> Self {
+        self.guest_route = guest_route;
+        self
+    }
+
+    pub fn inner(&self) -> &DynVirtualNetworking {
+        &self.inner
+    }
+}
+
+impl Drop for PortForwardingNetworking {
+    fn drop(&mut self) {
+        let forwards = self.forwards.get_mut().unwrap();
+        for (_, (_, abort)) in forwards.drain() {
+            abort.abort();
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for PortForwardingNetworking {
+    async fn bridge(
+        &self,
+        network: &str,
+        access_token: &str,
+        security: StreamSecurity,
+    ) -> Result<()> {
+        self.inner.bridge(network, access_token, security).await
+    }
+
+    async fn unbridge(&self) -> Result<()> {
+        self.inner.unbridge().await
+    }
+
+    async fn dhcp_acquire(&self) -> Result<Vec<IpAddr>> {
+        self.inner.dhcp_acquire().await
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> Result<()> {
+        self.inner.ip_add(ip, prefix).await
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> Result<()> {
+        self.inner.ip_remove(ip).await
+    }
+
+    async fn ip_clear(&self) -> Result<()> {
+        self.inner.ip_clear().await
+    }
+
+    async fn ip_list(&self) -> Result<Vec<IpCidr>> {
+        self.inner.ip_list().await
+    }
+
+    async fn mac(&self) -> Result<[u8; 6]> {
+        self.inner.mac().await
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> Result<()> {
+        self.inner.gateway_set(ip).await
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> Result<()> {
+        self.inner
+            .route_add(cidr, via_router, preferred_until, expires_at)
+            .await
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> Result<()> {
+        self.inner.route_remove(cidr).await
+    }
+
+    async fn route_clear(&self) -> Result<()> {
+        self.inner.route_clear().await
+    }
+
+    async fn route_list(&self) -> Result<Vec<IpRoute>> {
+        self.inner.route_list().await
+    }
+
+    async fn bind_raw(&self) -> Result<Box<dyn VirtualRawSocket + Sync>> {
+        self.inner.bind_raw().await
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>> {
+        self.inner
+            .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+            .await
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>> {
+        self.inner.bind_udp(addr, reuse_port, reuse_addr).await
+    }
+
+    async fn bind_icmp(&self, addr: IpAddr) -> Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        self.inner.bind_icmp(addr).await
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        self.inner.connect_tcp(addr, peer).await
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>> {
+        self.inner.resolve(host, port, dns_server).await
+    }
+
+    async fn forward_add(&self, mut forward: PortForward) -> Result<PortForward> {
+        if self
+            .forwards
+            .lock()
+            .unwrap()
+            .contains_key(&(forward.protocol, forward.host))
+        {
+            return Err(NetworkError::AddressInUse);
+        }
+
+        let task: ForwardTask = match forward.protocol {
+            ForwardProtocol::Tcp => {
+                let listener = self
+                    .host
+                    .listen_tcp(forward.host, false, false, true)
+                    .await?;
+                forward.host = listener.addr_local()?;
+                Box::pin(forward_tcp(
+                    listener,
+                    self.guest_route.clone(),
+                    forward.guest,
+                ))
+            }
+            ForwardProtocol::Udp => {
+                let socket = self.host.bind_udp(forward.host, false, true).await?;
+                forward.host = socket.addr_local()?;
+                Box::pin(forward_udp(socket, self.guest_route.clone(), forward.guest))
+            }
+        };
+
+        let (abort, registration) = AbortHandle::new_pair();
+        match self
+            .forwards
+            .lock()
+            .unwrap()
+            .entry((forward.protocol, forward.host))
+        {
+            Entry::Occupied(_) => return Err(NetworkError::AddressInUse),
+            Entry::Vacant(entry) => {
+                entry.insert((forward, abort));
+            }
+        }
+        self.tasks
+            .send(Abortable::new(task, registration))
+            .map_err(|_| NetworkError::ConnectionAborted)?;
+        tracing::debug!(?forward, "port forward added");
+        Ok(forward)
+    }
+
+    async fn forward_remove(&self, protocol: ForwardProtocol, host: SocketAddr) -> Result<()> {
+        let (forward, abort) = self
+            .forwards
+            .lock()
+            .unwrap()
+            .remove(&(protocol, host))
+            .ok_or(NetworkError::AddressNotAvailable)?;
+        abort.abort();
+        tracing::debug!(?forward, "port forward removed");
+        Ok(())
+    }
+
+    async fn forward_list(&self) -> Result<Vec<PortForward>> {
+        let forwards = self.forwards.lock().unwrap();
+        Ok(forwards.values().map(|(forward, _)| *forward).collect())
+    }
+}
+
+/// Relays the connections and datagrams of all the forwarded ports, it
+/// completes once the [`PortForwardingNetworking`] was dropped
+#[derive(Debug)]
+pub struct PortForwardingDriver {
+    tasks: FuturesUnordered<Abortable<ForwardTask>>,
+    rx: mpsc::UnboundedReceiver<Abortable<ForwardTask>>,
+    closed: bool,
+}
+
+impl Future for PortForwardingDriver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        while !self.closed {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(task)) => self.tasks.push(task),
+                Poll::Ready(None) => self.closed = true,
+                Poll::Pending => break,
+            }
+        }
+        while let Poll::Ready(Some(_)) = self.tasks.poll_next_unpin(cx) {}
+
+        if self.closed && self.tasks.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+async fn forward_tcp(
+    mut listener: Box<dyn VirtualTcpListener + Sync>,
+    guest: DynVirtualNetworking,
+    target: SocketAddr,
+) {
+    let mut connections = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, peer)) => {
+                    tracing::trace!(%peer, %target, "forwarding connection");
+                    connections.push(relay_tcp(socket, guest.clone(), target));
+                }
+                Err(err) => {
+                    tracing::warn!(%target, error = %err, "port forward stopped accepting connections");
+                    break;
+                }
+            },
+            Some(()) = connections.next(), if !connections.is_empty() => {}
+        }
+    }
+    while connections.next().await.is_some() {}
+}
+
+async fn relay_tcp(
+    mut outside: Box<dyn VirtualTcpSocket + Sync>,
+    guest: DynVirtualNetworking,
+    target: SocketAddr,
+) {
+    let inside = match guest.connect_tcp(unspecified_addr(target), target).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            tracing::debug!(%target, error = %err, "unable to connect into the guest");
+            outside.close().ok();
+            return;
+        }
+    };
+    let mut relay = TcpRelay {
+        sockets: [outside, inside],
+        halves: [RelayHalf::default(), RelayHalf::default()],
+    };
+    poll_fn(|cx| relay.poll(cx)).await
+}
+
+/// The bytes read from one socket that still have to be written to the other
+#[derive(Debug)]
+struct RelayHalf {
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+}
+
+impl Default for RelayHalf {
+    fn default() -> Self {
+        Self {
+            buf: vec![0; RELAY_BUFFER_SIZE],
+            pos: 0,
+            len: 0,
+            eof: false,
+        }
+    }
+}
+
+impl RelayHalf {
+    fn is_done(&self) -> bool {
+        self.eof && self.pos == self.len
+    }
+}
+
+/// Copies the bytes in both directions between two connected sockets and
+/// passes on the shutdown of one side to the other
+#[derive(Debug)]
+struct TcpRelay {
+    sockets: [Box<dyn VirtualTcpSocket + Sync>; 2],
+    halves: [RelayHalf; 2],
+}
+
+impl TcpRelay {
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        for socket in self.sockets.iter_mut() {
+            let handler: Box<dyn InterestHandler + Send + Sync> = cx.waker().into();
+            if socket.set_handler(handler).is_err() {
+                return self.close();
+            }
+        }
+
+        loop {
+            let mut progress = false;
+            for from in 0..2 {
+                match self.pump(from) {
+                    Ok(moved) => progress |= moved,
+                    Err(err) => {
+                        tracing::trace!(error = %err, "forwarded connection failed");
+                        return self.close();
+                    }
+                }
+            }
+            if self.halves.iter().all(RelayHalf::is_done) {
+                return self.close();
+            }
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Moves bytes from the socket `from` to the other one, returns whether
+    /// anything happened
+    fn pump(&mut self, from: usize) -> Result<bool> {
+        let [first, second] = &mut self.sockets;
+        let (src, dst) = if from == 0 {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        let half = &mut self.halves[from];
+
+        if half.pos < half.len {
+            return match dst.try_send(&half.buf[half.pos..half.len]) {
+                Ok(0) | Err(NetworkError::WouldBlock) => Ok(false),
+                Ok(sent) => {
+                    half.pos += sent;
+                    Ok(true)
+                }
+                Err(err) => Err(err),
+            };
+        }
+        if half.eof {
+            return Ok(false);
+        }
+
+        let buf: &mut [MaybeUninit<u8>] = unsafe { std::mem::transmute(half.buf.as_mut_slice()) };
+        match src.try_recv(buf) {
+            Ok(0) => {
+                // the other side keeps on sending until it shuts down too
+                half.eof = true;
+                dst.shutdown(Shutdown::Write).ok();
+                Ok(true)
+            }
+            Ok(read) => {
+                half.pos = 0;
+                half.len = read;
+                Ok(true)
+            }
+            Err(NetworkError::WouldBlock) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn close(&mut self) -> Poll<()> {
+        for socket in self.sockets.iter_mut() {
+            socket.close().ok();
+        }
+        Poll::Ready(())
+    }
+}
+
+/// The socket in the guest network that relays the datagrams of one peer
+#[derive(Debug)]
+struct UdpSession {
+    socket: Box<dyn VirtualUdpSocket + Sync>,
+    last_active: Instant,
+}
+
+async fn forward_udp(
+    mut outside: Box<dyn VirtualUdpSocket + Sync>,
+    guest: DynVirtualNetworking,
+    target: SocketAddr,
+) {
+    let mut sessions: HashMap<SocketAddr, UdpSession> = HashMap::new();
+    let mut request = vec![MaybeUninit::<u8>::uninit(); MAX_DATAGRAM_SIZE];
+    let mut reply = vec![MaybeUninit::<u8>::uninit(); MAX_DATAGRAM_SIZE];
+    let mut expiry = tokio::time::interval(UDP_SESSION_TIMEOUT / 4);
+
+    loop {
+        tokio::select! {
+            received = outside.recv_from(&mut request) => {
+                let (read, peer) = match received {
+                    Ok(received) => received,
+                    Err(err) => {
+                        tracing::warn!(%target, error = %err, "port forward stopped receiving datagrams");
+                        break;
+                    }
+                };
+                let session = match sessions.entry(peer) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        match guest.bind_udp(unspecified_addr(target), false, false).await {
+                            Ok(socket) => entry.insert(UdpSession {
+                                socket,
+                                last_active: Instant::now(),
+                            }),
+                            Err(err) => {
+                                tracing::debug!(%target, error = %err, "unable to bind a socket in the guest");
+                                continue;
+                            }
+                        }
+                    }
+                };
+                session.last_active = Instant::now();
+                let data: &[u8] = unsafe { std::mem::transmute(&request[..read]) };
+                if let Err(err) = session.socket.send_to(data, target).await {
+                    tracing::debug!(%target, error = %err, "unable to forward a datagram");
+                }
+            }
+            (peer, read) = recv_reply(&mut sessions, &mut reply, target) => {
+                let data: &[u8] = unsafe { std::mem::transmute(&reply[..read]) };
+                if let Err(err) = outside.send_to(data, peer).await {
+                    tracing::debug!(%peer, error = %err, "unable to return a datagram");
+                }
+            }
+            _ = expiry.tick() => {
+                let now = Instant::now();
+                sessions.retain(|_, session| now - session.last_active < UDP_SESSION_TIMEOUT);
+            }
+        }
+    }
+}
+
+/// Waits for a datagram from the guest on any of the sessions, returns the
+/// peer of the host it is meant for
+async fn recv_reply(
+    sessions: &mut HashMap<SocketAddr, UdpSession>,
+    buf: &mut [MaybeUninit<u8>],
+    target: SocketAddr,
+) -> (SocketAddr, usize) {
+    poll_fn(|cx| {
+        for (peer, session) in sessions.iter_mut() {
+            let handler: Box<dyn InterestHandler + Send + Sync> = cx.waker().into();
+            if session.socket.set_handler(handler).is_err() {
+                continue;
+            }
+            loop {
+                match session.socket.try_recv_from(buf) {
+                    Ok((read, from)) if from == target => {
+                        session.last_active = Instant::now();
+                        return Poll::Ready((*peer, read));
+                    }
+                    // only the guest may answer through the session
+                    Ok(_) => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+fn unspecified_addr(peer: SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{LoopbackNetworking, VirtualConnectedSocketExt};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn recv_to_end(socket: &mut (dyn VirtualTcpSocket + Sync)) -> Vec<u8> {
+        let mut ret = Vec::new();
+        let mut buf = [MaybeUninit::<u8>::uninit(); 1024];
+        loop {
+            match socket.recv(&mut buf).await {
+                Ok(0) | Err(_) => return ret,
+                Ok(read) => {
+                    let data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+                    ret.extend_from_slice(data);
+                }
+            }
+        }
+    }
+
+    /// The guest reads the whole request before it answers, which only
+    /// works when the shutdown of the client is passed on
+    async fn half_close_server(guest: &LoopbackNetworking, addr: SocketAddr) {
+        let mut listener = guest.listen_tcp(addr, false, false, false).await.unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let request = recv_to_end(socket.as_mut()).await;
+                    let response = format!("got {} bytes", request.len());
+                    socket.send(response.as_bytes()).await.unwrap();
+                    socket.flush().await.unwrap();
+                    socket.shutdown(Shutdown::Write).unwrap();
+                });
+            }
+        });
+    }
+
+    fn tcp_forward(host: SocketAddr, guest: SocketAddr) -> PortForward {
+        PortForward {
+            protocol: ForwardProtocol::Tcp,
+            host,
+            guest,
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_forward_with_half_close() {
+        let guest = LoopbackNetworking::new();
+        let host = LoopbackNetworking::new();
+        let guest_addr: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let host_addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        half_close_server(&guest, guest_addr).await;
+
+        let (networking, driver) =
+            PortForwardingNetworking::new(Arc::new(guest), Arc::new(host.clone()));
+        tokio::spawn(driver);
+        let forward = networking
+            .forward_add(tcp_forward(host_addr, guest_addr))
+            .await
+            .unwrap();
+        assert_eq!(forward.host, host_addr);
+        assert_eq!(networking.forward_list().await.unwrap(), vec![forward]);
+
+        for _ in 0..2 {
+            let mut client = host
+                .loopback_connect_to("127.0.0.1:0".parse().unwrap(), host_addr)
+                .unwrap();
+            let request = [1u8; 100_000];
+            let mut sent = 0;
+            while sent < request.len() {
+                sent += client.send(&request[sent..]).await.unwrap();
+            }
+            client.shutdown(Shutdown::Write).unwrap();
+
+            let response = tokio::time::timeout(TIMEOUT, recv_to_end(&mut client))
+                .await
+                .unwrap();
+            assert_eq!(response, b"got 100000 bytes");
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_can_be_removed_at_runtime() {
+        let guest = LoopbackNetworking::new();
+        let host = LoopbackNetworking::new();
+        let guest_addr: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let host_addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        half_close_server(&guest, guest_addr).await;
+
+        let (networking, driver) =
+            PortForwardingNetworking::new(Arc::new(guest), Arc::new(host.clone()));
+        let driver = tokio::spawn(driver);
+        networking
+            .forward_add(tcp_forward(host_addr, guest_addr))
+            .await
+            .unwrap();
+        assert_eq!(
+            networking
+                .forward_add(tcp_forward(host_addr, guest_addr))
+                .await,
+            Err(NetworkError::AddressInUse)
+        );
+
+        // a connection that is open when the forward goes away is closed
+        let mut client = host
+            .loopback_connect_to("127.0.0.1:0".parse().unwrap(), host_addr)
+            .unwrap();
+        client.send(b"hello").await.unwrap();
+        tokio::task::yield_now().await;
+        networking
+            .forward_remove(ForwardProtocol::Tcp, host_addr)
+            .await
+            .unwrap();
+        let response = tokio::time::timeout(TIMEOUT, recv_to_end(&mut client))
+            .await
+            .unwrap();
+        assert!(response.is_empty());
+
+        assert!(networking.forward_list().await.unwrap().is_empty());
+        assert_eq!(
+            networking
+                .forward_remove(ForwardProtocol::Tcp, host_addr)
+                .await,
+            Err(NetworkError::AddressNotAvailable)
+        );
+
+        // the driver completes once the networking is gone
+        drop(networking);
+        tokio::time::timeout(TIMEOUT, driver)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_connections_are_closed() {
+        let guest = LoopbackNetworking::new();
+        let host = LoopbackNetworking::new();
+        let host_addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        let (networking, driver) =
+            PortForwardingNetworking::new(Arc::new(guest), Arc::new(host.clone()));
+        tokio::spawn(driver);
+        networking
+            .forward_add(tcp_forward(host_addr, "127.0.0.1:80".parse().unwrap()))
+            .await
+            .unwrap();
+
+        let mut client = host
+            .loopback_connect_to("127.0.0.1:0".parse().unwrap(), host_addr)
+            .unwrap();
+        let response = tokio::time::timeout(TIMEOUT, recv_to_end(&mut client))
+            .await
+            .unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[cfg(feature = "host-net")]
+    #[tokio::test]
+    async fn udp_forward() {
+        use crate::host::LocalNetworking;
+
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let guest_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (read, from) = echo.recv_from(&mut buf).await.unwrap();
+                let mut reply = b"echo: ".to_vec();
+                reply.extend_from_slice(&buf[..read]);
+                echo.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        let (networking, driver) = PortForwardingNetworking::new(
+            Arc::new(LocalNetworking::new()),
+            Arc::new(LocalNetworking::new()),
+        );
+        tokio::spawn(driver);
+        let forward = networking
+            .forward_add(PortForward {
+                protocol: ForwardProtocol::Udp,
+                host: "127.0.0.1:0".parse().unwrap(),
+                guest: guest_addr,
+            })
+            .await
+            .unwrap();
+        assert_ne!(forward.host.port(), 0);
+
+        let clients = [
+            tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        for (i, client) in clients.iter().enumerate() {
+            let message = format!("client {i}");
+            client
+                .send_to(message.as_bytes(), forward.host)
+                .await
+                .unwrap();
+
+            let mut buf = [0u8; 1500];
+            let (read, from) = tokio::time::timeout(TIMEOUT, client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(from, forward.host);
+            assert_eq!(&buf[..read], format!("echo: {message}").as_bytes());
+        }
+    }
+}
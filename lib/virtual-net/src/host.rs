@@ -1,4 +1,5 @@
 #![allow(unused_variables)]
+use crate::dns::{DnsClient, DNS_PORT};
 use crate::{io_err_into_net_error, VirtualIoSource};
 #[allow(unused_imports)]
 use crate::{
@@ -31,6 +32,7 @@ use virtual_mio::{
 pub struct LocalNetworking {
     selector: Arc<Selector>,
     handle: Handle,
+    dns: DnsClient,
 }
 
 impl LocalNetworking {
@@ -38,6 +40,7 @@ impl LocalNetworking {
         Self {
             selector: Selector::new(),
             handle: Handle::current(),
+            dns: DnsClient::default(),
         }
     }
 }
@@ -132,6 +135,15 @@ impl VirtualNetworking for LocalNetworking {
         port: Option<u16>,
         dns_server: Option<IpAddr>,
     ) -> Result<Vec<IpAddr>> {
+        // a specific name server bypasses the resolver of the host
+        if let Some(dns_server) = dns_server {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![ip]);
+            }
+            let server = SocketAddr::new(dns_server, DNS_PORT);
+            return self.dns.lookup(self, host, &[server]).await;
+        }
+
         let host_to_lookup = if host.contains(':') {
             host.to_string()
         } else {
@@ -3,28 +3,62 @@
 #[cfg(feature = "remote")]
 pub mod client;
 pub mod composite;
+#[cfg(feature = "dns")]
+pub mod dns;
+#[cfg(feature = "forward")]
+pub mod forward;
 #[cfg(feature = "host-net")]
 pub mod host;
 pub mod loopback;
 pub mod meta;
+pub mod metered;
+pub mod pcap;
+#[cfg(feature = "proxy")]
+pub mod proxy;
 #[cfg(feature = "remote")]
 pub mod rx_tx;
 #[cfg(feature = "remote")]
 pub mod server;
+#[cfg(feature = "shaping")]
+pub mod shaped;
+#[cfg(feature = "userspace")]
+pub mod switch;
 pub mod tcp_pair;
 #[cfg(feature = "tokio")]
 #[cfg(test)]
 mod tests;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "userspace")]
+pub mod userspace;
 
 #[cfg(feature = "remote")]
 pub use client::{RemoteNetworkingClient, RemoteNetworkingClientDriver};
 pub use composite::CompositeTcpListener;
+#[cfg(feature = "dns")]
+pub use dns::{DnsClient, ResolvingNetworking, StaticHosts};
+#[cfg(feature = "forward")]
+pub use forward::{PortForwardingDriver, PortForwardingNetworking};
 pub use loopback::LoopbackNetworking;
+pub use metered::{
+    encode_prometheus, ByteQuota, MeteredNetworking, NetworkMeter, NetworkMetrics, SocketKind,
+    SocketMetrics, TrafficCounters,
+};
+pub use pcap::{PcapCapture, PcapNetworking};
 use pin_project_lite::pin_project;
+#[cfg(feature = "proxy")]
+pub use proxy::{
+    NoProxy, ProxyConfig, ProxyCredentials, ProxyNetworking, ProxyProtocol, ProxyUrlError,
+};
 #[cfg(feature = "rkyv")]
 use rkyv::{Archive, CheckBytes, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
 #[cfg(feature = "remote")]
 pub use server::{RemoteNetworkingServer, RemoteNetworkingServerDriver};
+#[cfg(feature = "shaping")]
+pub use shaped::{
+    DnsImpairment, Impairment, NetworkProfile, NetworkRule, RuleDirection, ShapedNetworking,
+    ShapedNetworkingDriver,
+};
 use std::fmt;
 use std::mem::MaybeUninit;
 use std::net::IpAddr;
@@ -37,11 +71,17 @@ use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 use std::time::Duration;
+#[cfg(feature = "userspace")]
+pub use switch::{SwitchPort, SwitchedNetworking, VirtualSwitch, VirtualSwitchDriver};
 use thiserror::Error;
+#[cfg(feature = "tls")]
+pub use tls::{TlsClientConfig, TlsError, TlsServerConfig, TlsTcpListener, TlsTcpSocket};
 #[cfg(feature = "tokio")]
 use tokio::io::AsyncRead;
 #[cfg(feature = "tokio")]
 use tokio::io::AsyncWrite;
+#[cfg(feature = "userspace")]
+pub use userspace::{FramePipe, LinkMedium, UserspaceNetworking, UserspaceNetworkingDriver};
 
 pub use bytes::Bytes;
 pub use bytes::BytesMut;
@@ -61,6 +101,25 @@ pub struct IpCidr {
     pub prefix: u8,
 }
 
+impl IpCidr {
+    /// Returns true if the address is in this network
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let prefix = self.prefix.min(32) as u32;
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let prefix = self.prefix.min(128) as u32;
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
 /// Represents a routing entry in the routing table of the interface
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "rkyv", derive(RkyvSerialize, RkyvDeserialize, Archive))]
@@ -72,6 +131,27 @@ pub struct IpRoute {
     pub expires_at: Option<Duration>,
 }
 
+/// The transport protocol of a forwarded port
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rkyv", derive(RkyvSerialize, RkyvDeserialize, Archive))]
+#[cfg_attr(feature = "rkyv", archive_attr(derive(CheckBytes)))]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Represents a port of the host that is forwarded into the network
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rkyv", derive(RkyvSerialize, RkyvDeserialize, Archive))]
+#[cfg_attr(feature = "rkyv", archive_attr(derive(CheckBytes)))]
+pub struct PortForward {
+    pub protocol: ForwardProtocol,
+    /// The address the host listens on
+    pub host: SocketAddr,
+    /// Where the connections and datagrams are relayed to in the network
+    pub guest: SocketAddr,
+}
+
 /// Represents an IO source
 pub trait VirtualIoSource: fmt::Debug + Send + Sync + 'static {
     /// Removes a previously registered waker using a token
@@ -220,6 +300,25 @@ pub trait VirtualNetworking: fmt::Debug + Send + Sync + 'static {
     ) -> Result<Vec<IpAddr>> {
         Err(NetworkError::Unsupported)
     }
+
+    /// Starts relaying the connections and datagrams that arrive on an
+    /// address of the host to an address in this network, returns the
+    /// forward as it was set up (with the port picked by the host when
+    /// it was zero)
+    async fn forward_add(&self, forward: PortForward) -> Result<PortForward> {
+        Err(NetworkError::Unsupported)
+    }
+
+    /// Stops forwarding an address of the host, the connections that are
+    /// still being relayed are closed
+    async fn forward_remove(&self, protocol: ForwardProtocol, host: SocketAddr) -> Result<()> {
+        Err(NetworkError::Unsupported)
+    }
+
+    /// Lists the ports of the host that are forwarded into this network
+    async fn forward_list(&self) -> Result<Vec<PortForward>> {
+        Err(NetworkError::Unsupported)
+    }
 }
 
 pub type DynVirtualNetworking = Arc<dyn VirtualNetworking>;
@@ -34,15 +34,30 @@ impl LoopbackNetworking {
 
     pub fn loopback_connect_to(
         &self,
-        mut local_addr: SocketAddr,
+        local_addr: SocketAddr,
         peer_addr: SocketAddr,
     ) -> Option<TcpSocketHalf> {
+        let local_addr = Self::loopback_local_addr(local_addr, peer_addr);
+
+        let state = self.state.lock().unwrap();
+        if let Some(listener) = state.tcp_listeners.get(&peer_addr) {
+            Some(listener.connect_to(local_addr))
+        } else {
+            state
+                .tcp_listeners
+                .iter()
+                .next()
+                .map(|listener| listener.1.connect_to(local_addr))
+        }
+    }
+
+    fn loopback_local_addr(local_addr: SocketAddr, peer_addr: SocketAddr) -> SocketAddr {
         let mut port = local_addr.port();
         if port == 0 {
             port = peer_addr.port();
         }
 
-        local_addr = match local_addr.ip() {
+        match local_addr.ip() {
             IpAddr::V4(Ipv4Addr::UNSPECIFIED) => {
                 SocketAddr::new(Ipv4Addr::new(127, 0, 0, 100).into(), port)
             }
@@ -50,17 +65,6 @@ impl LoopbackNetworking {
                 SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 100).into(), port)
             }
             ip => SocketAddr::new(ip, port),
-        };
-
-        let state = self.state.lock().unwrap();
-        if let Some(listener) = state.tcp_listeners.get(&peer_addr) {
-            Some(listener.connect_to(local_addr))
-        } else {
-            state
-                .tcp_listeners
-                .iter()
-                .next()
-                .map(|listener| listener.1.connect_to(local_addr))
         }
     }
 }
@@ -134,6 +138,23 @@ impl VirtualNetworking for LoopbackNetworking {
 
         Ok(Box::new(listener))
     }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> crate::Result<Box<dyn VirtualTcpSocket + Sync>> {
+        let local_addr = Self::loopback_local_addr(addr, peer);
+
+        // unlike `loopback_connect_to` only a listener on the exact address
+        // accepts the connection
+        let state = self.state.lock().unwrap();
+        let listener = state
+            .tcp_listeners
+            .get(&peer)
+            .ok_or(NetworkError::ConnectionRefused)?;
+        Ok(Box::new(listener.connect_to(local_addr)))
+    }
 }
 
 #[derive(Derivative)]
@@ -0,0 +1,1335 @@
+//! A [`VirtualNetworking`] decorator which accounts for the traffic of a
+//! guest and optionally limits how much of it there may be.
+//!
+//! Every [`MeteredNetworking`] reports into a [`NetworkMeter`], normally one
+//! per instance, which keeps track of:
+//!
+//! - the bytes and packets sent and received, per socket and in total
+//! - the number of active (and total) TCP connections
+//! - failed outbound connections and failed accepts
+//! - the sockets that were closed because they ran over a quota
+//!
+//! For stream sockets a "packet" is a single successful send or receive
+//! that moved data, as the guest never sees the real segments.
+//!
+//! The counters of any number of meters can be exported in the Prometheus
+//! text format with [`encode_prometheus`].
+//!
+//! A [`ByteQuota`] can be set on the whole instance and on each of its
+//! sockets. Sends and receives on stream sockets are cut short so they stay
+//! within the quota, once it is used up the socket is closed and every
+//! operation on it fails with [`NetworkError::ConnectionAborted`]. Datagrams
+//! are never truncated, so a received datagram may overshoot the quota by
+//! up to its size. While the quota of the instance is used up, new sockets
+//! are refused with [`NetworkError::PermissionDenied`].
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::{
+    DynVirtualNetworking, ForwardProtocol, InterestHandler, IpCidr, IpRoute, NetworkError,
+    PortForward, Result, SocketStatus, StreamSecurity, VirtualConnectedSocket,
+    VirtualConnectionlessSocket, VirtualIcmpSocket, VirtualIoSource, VirtualNetworking,
+    VirtualRawSocket, VirtualSocket, VirtualTcpListener, VirtualTcpSocket, VirtualUdpSocket,
+};
+
+/// Limits on the number of bytes that may be transferred
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ByteQuota {
+    /// Most bytes that may be sent, unlimited when `None`
+    pub sent: Option<u64>,
+    /// Most bytes that may be received, unlimited when `None`
+    pub received: Option<u64>,
+}
+
+impl ByteQuota {
+    pub const UNLIMITED: Self = Self {
+        sent: None,
+        received: None,
+    };
+}
+
+/// The type of a metered socket
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SocketKind {
+    Tcp,
+    Udp,
+    Icmp,
+    Raw,
+}
+
+impl std::fmt::Display for SocketKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SocketKind::Tcp => "tcp",
+            SocketKind::Udp => "udp",
+            SocketKind::Icmp => "icmp",
+            SocketKind::Raw => "raw",
+        })
+    }
+}
+
+/// Amount of traffic that went through a socket or an instance
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct TrafficCounters {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+}
+
+/// A snapshot of the counters of a single socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketMetrics {
+    /// Identifies the socket within its [`NetworkMeter`]
+    pub id: u64,
+    pub kind: SocketKind,
+    pub local: Option<SocketAddr>,
+    pub peer: Option<SocketAddr>,
+    pub traffic: TrafficCounters,
+    /// Whether the socket was closed for running over a quota
+    pub quota_exceeded: bool,
+}
+
+/// A snapshot of the counters of a whole instance
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NetworkMetrics {
+    pub traffic: TrafficCounters,
+    /// TCP connections that are currently open
+    pub active_connections: u64,
+    /// TCP connections that were made or accepted
+    pub connections: u64,
+    pub connect_failures: u64,
+    pub accept_failures: u64,
+    /// Sockets that were closed because they ran over a quota
+    pub quota_exceeded: u64,
+}
+
+#[derive(Debug, Default)]
+struct AtomicTraffic {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+}
+
+impl AtomicTraffic {
+    fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> TrafficCounters {
+        TrafficCounters {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A [`ByteQuota`] that can be changed while it is in use (`u64::MAX`
+/// stands for unlimited)
+#[derive(Debug)]
+struct AtomicQuota {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl AtomicQuota {
+    fn new(quota: ByteQuota) -> Self {
+        let atomic = Self {
+            sent: AtomicU64::new(u64::MAX),
+            received: AtomicU64::new(u64::MAX),
+        };
+        atomic.store(quota);
+        atomic
+    }
+
+    fn store(&self, quota: ByteQuota) {
+        self.sent
+            .store(quota.sent.unwrap_or(u64::MAX), Ordering::Relaxed);
+        self.received
+            .store(quota.received.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    fn load(&self) -> ByteQuota {
+        let limit = |value: u64| (value != u64::MAX).then_some(value);
+        ByteQuota {
+            sent: limit(self.sent.load(Ordering::Relaxed)),
+            received: limit(self.received.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MeterState {
+    instance: String,
+    traffic: AtomicTraffic,
+    active_connections: AtomicU64,
+    connections: AtomicU64,
+    connect_failures: AtomicU64,
+    accept_failures: AtomicU64,
+    quota_exceeded: AtomicU64,
+    instance_quota: AtomicQuota,
+    socket_quota: AtomicQuota,
+    next_socket_id: AtomicU64,
+    sockets: Mutex<HashMap<u64, Arc<SocketState>>>,
+}
+
+#[derive(Debug)]
+struct SocketState {
+    id: u64,
+    kind: SocketKind,
+    local: Option<SocketAddr>,
+    peer: Option<SocketAddr>,
+    traffic: AtomicTraffic,
+    exceeded: AtomicBool,
+}
+
+/// The counters and quotas of an instance, shared by all its sockets
+///
+/// Cloning the meter gives another handle to the same counters.
+#[derive(Debug, Clone)]
+pub struct NetworkMeter {
+    state: Arc<MeterState>,
+}
+
+impl NetworkMeter {
+    /// Creates a meter for an instance, the name is used to label its
+    /// metrics
+    pub fn new(instance: impl Into<String>) -> Self {
+        Self {
+            state: Arc::new(MeterState {
+                instance: instance.into(),
+                traffic: AtomicTraffic::default(),
+                active_connections: AtomicU64::new(0),
+                connections: AtomicU64::new(0),
+                connect_failures: AtomicU64::new(0),
+                accept_failures: AtomicU64::new(0),
+                quota_exceeded: AtomicU64::new(0),
+                instance_quota: AtomicQuota::new(ByteQuota::UNLIMITED),
+                socket_quota: AtomicQuota::new(ByteQuota::UNLIMITED),
+                next_socket_id: AtomicU64::new(1),
+                sockets: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn instance(&self) -> &str {
+        &self.state.instance
+    }
+
+    /// Limits the traffic of the instance as a whole
+    pub fn set_instance_quota(&self, quota: ByteQuota) {
+        self.state.instance_quota.store(quota);
+    }
+
+    pub fn instance_quota(&self) -> ByteQuota {
+        self.state.instance_quota.load()
+    }
+
+    /// Limits the traffic of each socket of the instance
+    pub fn set_socket_quota(&self, quota: ByteQuota) {
+        self.state.socket_quota.store(quota);
+    }
+
+    pub fn socket_quota(&self) -> ByteQuota {
+        self.state.socket_quota.load()
+    }
+
+    /// Takes a snapshot of the counters of the instance
+    pub fn metrics(&self) -> NetworkMetrics {
+        let state = &self.state;
+        NetworkMetrics {
+            traffic: state.traffic.load(),
+            active_connections: state.active_connections.load(Ordering::Relaxed),
+            connections: state.connections.load(Ordering::Relaxed),
+            connect_failures: state.connect_failures.load(Ordering::Relaxed),
+            accept_failures: state.accept_failures.load(Ordering::Relaxed),
+            quota_exceeded: state.quota_exceeded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Takes a snapshot of the counters of every open socket, ordered by
+    /// the time they were opened
+    pub fn sockets(&self) -> Vec<SocketMetrics> {
+        let mut sockets: Vec<_> = self
+            .state
+            .sockets
+            .lock()
+            .unwrap()
+            .values()
+            .map(|socket| SocketMetrics {
+                id: socket.id,
+                kind: socket.kind,
+                local: socket.local,
+                peer: socket.peer,
+                traffic: socket.traffic.load(),
+                quota_exceeded: socket.exceeded.load(Ordering::Relaxed),
+            })
+            .collect();
+        sockets.sort_by_key(|socket| socket.id);
+        sockets
+    }
+
+    /// Fails when the instance has used up its quota, in which case no new
+    /// sockets are handed out
+    fn check_instance(&self) -> Result<()> {
+        let traffic = self.state.traffic.load();
+        let quota = self.state.instance_quota.load();
+        if exhausted(quota.sent, traffic.bytes_sent)
+            || exhausted(quota.received, traffic.bytes_received)
+        {
+            return Err(NetworkError::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    fn register(
+        &self,
+        kind: SocketKind,
+        local: Option<SocketAddr>,
+        peer: Option<SocketAddr>,
+    ) -> SocketMeter {
+        let socket = Arc::new(SocketState {
+            id: self.state.next_socket_id.fetch_add(1, Ordering::Relaxed),
+            kind,
+            local,
+            peer,
+            traffic: AtomicTraffic::default(),
+            exceeded: AtomicBool::new(false),
+        });
+        self.state
+            .sockets
+            .lock()
+            .unwrap()
+            .insert(socket.id, socket.clone());
+
+        let connection = kind == SocketKind::Tcp;
+        if connection {
+            self.state.connections.fetch_add(1, Ordering::Relaxed);
+            self.state
+                .active_connections
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        SocketMeter {
+            meter: self.clone(),
+            socket,
+            connection,
+        }
+    }
+}
+
+fn exhausted(limit: Option<u64>, used: u64) -> bool {
+    limit.map(|limit| used >= limit).unwrap_or(false)
+}
+
+fn remaining(limit: Option<u64>, used: u64) -> u64 {
+    limit
+        .map(|limit| limit.saturating_sub(used))
+        .unwrap_or(u64::MAX)
+}
+
+/// The accounting of a single socket, which unregisters it when dropped
+#[derive(Debug)]
+struct SocketMeter {
+    meter: NetworkMeter,
+    socket: Arc<SocketState>,
+    connection: bool,
+}
+
+impl SocketMeter {
+    /// How many bytes may still be sent before a quota is hit
+    fn send_allowance(&self) -> Result<usize> {
+        self.allowance(|quota| quota.sent, |traffic| traffic.bytes_sent)
+    }
+
+    /// How many bytes may still be received before a quota is hit
+    fn recv_allowance(&self) -> Result<usize> {
+        self.allowance(|quota| quota.received, |traffic| traffic.bytes_received)
+    }
+
+    fn allowance(
+        &self,
+        limit: impl Fn(&ByteQuota) -> Option<u64>,
+        used: impl Fn(&TrafficCounters) -> u64,
+    ) -> Result<usize> {
+        if self.socket.exceeded.load(Ordering::Relaxed) {
+            return Err(NetworkError::ConnectionAborted);
+        }
+
+        let state = &self.meter.state;
+        let instance = remaining(
+            limit(&state.instance_quota.load()),
+            used(&state.traffic.load()),
+        );
+        let socket = remaining(
+            limit(&state.socket_quota.load()),
+            used(&self.socket.traffic.load()),
+        );
+        match instance.min(socket) {
+            0 => Err(self.exceeded()),
+            allowed => Ok(allowed.try_into().unwrap_or(usize::MAX)),
+        }
+    }
+
+    /// Marks the socket as having run over its quota
+    fn exceeded(&self) -> NetworkError {
+        if !self.socket.exceeded.swap(true, Ordering::Relaxed) {
+            self.meter
+                .state
+                .quota_exceeded
+                .fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(
+                instance = %self.meter.instance(),
+                socket = self.socket.id,
+                "Closing a socket that ran over its quota",
+            );
+        }
+        NetworkError::ConnectionAborted
+    }
+
+    fn sent(&self, bytes: usize) {
+        if bytes > 0 {
+            self.socket.traffic.sent(bytes);
+            self.meter.state.traffic.sent(bytes);
+        }
+    }
+
+    fn received(&self, bytes: usize) {
+        if bytes > 0 {
+            self.socket.traffic.received(bytes);
+            self.meter.state.traffic.received(bytes);
+        }
+    }
+}
+
+impl Drop for SocketMeter {
+    fn drop(&mut self) {
+        let state = &self.meter.state;
+        state.sockets.lock().unwrap().remove(&self.socket.id);
+        if self.connection {
+            state.active_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writes the counters of the meters in the Prometheus text exposition
+/// format, labelling them with the name of their instance
+///
+/// Only the counters of the instances are exported, the ones of individual
+/// sockets are available from [`NetworkMeter::sockets`].
+pub fn encode_prometheus<'a>(meters: impl IntoIterator<Item = &'a NetworkMeter>) -> String {
+    let meters: Vec<_> = meters
+        .into_iter()
+        .map(|meter| (escape_label(meter.instance()), meter.metrics()))
+        .collect();
+
+    type Getter = fn(&NetworkMetrics) -> u64;
+    let families: [(&str, &str, &str, Getter); 9] = [
+        (
+            "wasix_net_sent_bytes_total",
+            "counter",
+            "Bytes sent by the instance",
+            |m| m.traffic.bytes_sent,
+        ),
+        (
+            "wasix_net_received_bytes_total",
+            "counter",
+            "Bytes received by the instance",
+            |m| m.traffic.bytes_received,
+        ),
+        (
+            "wasix_net_sent_packets_total",
+            "counter",
+            "Packets (or stream writes) sent by the instance",
+            |m| m.traffic.packets_sent,
+        ),
+        (
+            "wasix_net_received_packets_total",
+            "counter",
+            "Packets (or stream reads) received by the instance",
+            |m| m.traffic.packets_received,
+        ),
+        (
+            "wasix_net_active_connections",
+            "gauge",
+            "TCP connections that are currently open",
+            |m| m.active_connections,
+        ),
+        (
+            "wasix_net_connections_total",
+            "counter",
+            "TCP connections that were made or accepted",
+            |m| m.connections,
+        ),
+        (
+            "wasix_net_connect_failures_total",
+            "counter",
+            "Outbound TCP connections that failed",
+            |m| m.connect_failures,
+        ),
+        (
+            "wasix_net_accept_failures_total",
+            "counter",
+            "Inbound TCP connections that could not be accepted",
+            |m| m.accept_failures,
+        ),
+        (
+            "wasix_net_quota_exceeded_total",
+            "counter",
+            "Sockets that were closed for running over a quota",
+            |m| m.quota_exceeded,
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in families {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (instance, metrics) in &meters {
+            let _ = writeln!(out, "{name}{{instance=\"{instance}\"}} {}", value(metrics));
+        }
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A networking implementation that meters the traffic of another
+/// implementation into a [`NetworkMeter`]
+#[derive(Debug, Clone)]
+pub struct MeteredNetworking {
+    inner: DynVirtualNetworking,
+    meter: NetworkMeter,
+}
+
+impl MeteredNetworking {
+    pub fn new(inner: DynVirtualNetworking, meter: NetworkMeter) -> Self {
+        Self { inner, meter }
+    }
+
+    pub fn meter(&self) -> &NetworkMeter {
+        &self.meter
+    }
+
+    pub fn inner(&self) -> &DynVirtualNetworking {
+        &self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for MeteredNetworking {
+    async fn bridge(
+        &self,
+        network: &str,
+        access_token: &str,
+        security: StreamSecurity,
+    ) -> Result<()> {
+        self.inner.bridge(network, access_token, security).await
+    }
+
+    async fn unbridge(&self) -> Result<()> {
+        self.inner.unbridge().await
+    }
+
+    async fn dhcp_acquire(&self) -> Result<Vec<IpAddr>> {
+        self.inner.dhcp_acquire().await
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> Result<()> {
+        self.inner.ip_add(ip, prefix).await
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> Result<()> {
+        self.inner.ip_remove(ip).await
+    }
+
+    async fn ip_clear(&self) -> Result<()> {
+        self.inner.ip_clear().await
+    }
+
+    async fn ip_list(&self) -> Result<Vec<IpCidr>> {
+        self.inner.ip_list().await
+    }
+
+    async fn mac(&self) -> Result<[u8; 6]> {
+        self.inner.mac().await
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> Result<()> {
+        self.inner.gateway_set(ip).await
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> Result<()> {
+        self.inner
+            .route_add(cidr, via_router, preferred_until, expires_at)
+            .await
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> Result<()> {
+        self.inner.route_remove(cidr).await
+    }
+
+    async fn route_clear(&self) -> Result<()> {
+        self.inner.route_clear().await
+    }
+
+    async fn route_list(&self) -> Result<Vec<IpRoute>> {
+        self.inner.route_list().await
+    }
+
+    async fn bind_raw(&self) -> Result<Box<dyn VirtualRawSocket + Sync>> {
+        self.meter.check_instance()?;
+        let inner = self.inner.bind_raw().await?;
+        let meter = self
+            .meter
+            .register(SocketKind::Raw, inner.addr_local().ok(), None);
+        Ok(Box::new(MeteredRawSocket { inner, meter }))
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>> {
+        self.meter.check_instance()?;
+        let inner = self
+            .inner
+            .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+            .await?;
+        Ok(Box::new(MeteredTcpListener {
+            inner,
+            meter: self.meter.clone(),
+        }))
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>> {
+        self.meter.check_instance()?;
+        let inner = self.inner.bind_udp(addr, reuse_port, reuse_addr).await?;
+        let local = inner.addr_local().unwrap_or(addr);
+        let meter = self.meter.register(SocketKind::Udp, Some(local), None);
+        Ok(Box::new(MeteredUdpSocket { inner, meter }))
+    }
+
+    async fn bind_icmp(&self, addr: IpAddr) -> Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        self.meter.check_instance()?;
+        let inner = self.inner.bind_icmp(addr).await?;
+        let local = inner.addr_local().ok();
+        let meter = self.meter.register(SocketKind::Icmp, local, None);
+        Ok(Box::new(MeteredIcmpSocket { inner, meter }))
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        self.meter.check_instance()?;
+        let inner = match self.inner.connect_tcp(addr, peer).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                self.meter
+                    .state
+                    .connect_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+        };
+        let local = inner.addr_local().unwrap_or(addr);
+        let peer = inner.addr_peer().unwrap_or(peer);
+        let meter = self
+            .meter
+            .register(SocketKind::Tcp, Some(local), Some(peer));
+        Ok(Box::new(MeteredTcpSocket { inner, meter }))
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>> {
+        self.inner.resolve(host, port, dns_server).await
+    }
+
+    async fn forward_add(&self, forward: PortForward) -> Result<PortForward> {
+        self.inner.forward_add(forward).await
+    }
+
+    async fn forward_remove(&self, protocol: ForwardProtocol, host: SocketAddr) -> Result<()> {
+        self.inner.forward_remove(protocol, host).await
+    }
+
+    async fn forward_list(&self) -> Result<Vec<PortForward>> {
+        self.inner.forward_list().await
+    }
+}
+
+#[derive(Debug)]
+struct MeteredTcpListener {
+    inner: Box<dyn VirtualTcpListener + Sync>,
+    meter: NetworkMeter,
+}
+
+impl VirtualIoSource for MeteredTcpListener {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualTcpListener for MeteredTcpListener {
+    fn try_accept(&mut self) -> Result<(Box<dyn VirtualTcpSocket + Sync>, SocketAddr)> {
+        let accept_failures = &self.meter.state.accept_failures;
+        let (socket, peer) = match self.inner.try_accept() {
+            Ok(accepted) => accepted,
+            Err(NetworkError::WouldBlock) => return Err(NetworkError::WouldBlock),
+            Err(err) => {
+                accept_failures.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+        };
+
+        // the connection is dropped (and thereby closed) straight away when
+        // there is no quota left for it
+        if self.meter.check_instance().is_err() {
+            accept_failures.fetch_add(1, Ordering::Relaxed);
+            return Err(NetworkError::ConnectionAborted);
+        }
+
+        let local = socket.addr_local().ok();
+        let meter = self.meter.register(SocketKind::Tcp, local, Some(peer));
+        Ok((
+            Box::new(MeteredTcpSocket {
+                inner: socket,
+                meter,
+            }),
+            peer,
+        ))
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u8> {
+        self.inner.ttl()
+    }
+}
+
+#[derive(Debug)]
+struct MeteredTcpSocket {
+    inner: Box<dyn VirtualTcpSocket + Sync>,
+    meter: SocketMeter,
+}
+
+impl MeteredTcpSocket {
+    /// Closes the socket as it ran over its quota
+    fn abort(&mut self, err: NetworkError) -> NetworkError {
+        let _ = self.inner.close();
+        err
+    }
+}
+
+impl VirtualIoSource for MeteredTcpSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualSocket for MeteredTcpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualConnectedSocket for MeteredTcpSocket {
+    fn set_linger(&mut self, linger: Option<Duration>) -> Result<()> {
+        self.inner.set_linger(linger)
+    }
+
+    fn linger(&self) -> Result<Option<Duration>> {
+        self.inner.linger()
+    }
+
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        let allowed = self.meter.send_allowance().map_err(|err| self.abort(err))?;
+        let sent = self.inner.try_send(&data[..data.len().min(allowed)])?;
+        self.meter.sent(sent);
+        Ok(sent)
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        self.inner.try_flush()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.inner.close()
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let allowed = self.meter.recv_allowance().map_err(|err| self.abort(err))?;
+        let len = buf.len().min(allowed);
+        let read = self.inner.try_recv(&mut buf[..len])?;
+        self.meter.received(read);
+        Ok(read)
+    }
+}
+
+impl VirtualTcpSocket for MeteredTcpSocket {
+    fn set_recv_buf_size(&mut self, size: usize) -> Result<()> {
+        self.inner.set_recv_buf_size(size)
+    }
+
+    fn recv_buf_size(&self) -> Result<usize> {
+        self.inner.recv_buf_size()
+    }
+
+    fn set_send_buf_size(&mut self, size: usize) -> Result<()> {
+        self.inner.set_send_buf_size(size)
+    }
+
+    fn send_buf_size(&self) -> Result<usize> {
+        self.inner.send_buf_size()
+    }
+
+    fn set_nodelay(&mut self, reuse: bool) -> Result<()> {
+        self.inner.set_nodelay(reuse)
+    }
+
+    fn nodelay(&self) -> Result<bool> {
+        self.inner.nodelay()
+    }
+
+    fn set_keepalive(&mut self, keepalive: bool) -> Result<()> {
+        self.inner.set_keepalive(keepalive)
+    }
+
+    fn keepalive(&self) -> Result<bool> {
+        self.inner.keepalive()
+    }
+
+    fn set_dontroute(&mut self, keepalive: bool) -> Result<()> {
+        self.inner.set_dontroute(keepalive)
+    }
+
+    fn dontroute(&self) -> Result<bool> {
+        self.inner.dontroute()
+    }
+
+    fn addr_peer(&self) -> Result<SocketAddr> {
+        self.inner.addr_peer()
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+#[derive(Debug)]
+struct MeteredUdpSocket {
+    inner: Box<dyn VirtualUdpSocket + Sync>,
+    meter: SocketMeter,
+}
+
+impl VirtualIoSource for MeteredUdpSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualSocket for MeteredUdpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualConnectionlessSocket for MeteredUdpSocket {
+    fn try_send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize> {
+        send_datagram(&self.meter, data, |data| self.inner.try_send_to(data, addr))
+    }
+
+    fn try_recv_from(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<(usize, SocketAddr)> {
+        self.meter.recv_allowance()?;
+        let (read, peer) = self.inner.try_recv_from(buf)?;
+        self.meter.received(read);
+        Ok((read, peer))
+    }
+}
+
+impl VirtualUdpSocket for MeteredUdpSocket {
+    fn set_broadcast(&mut self, broadcast: bool) -> Result<()> {
+        self.inner.set_broadcast(broadcast)
+    }
+
+    fn broadcast(&self) -> Result<bool> {
+        self.inner.broadcast()
+    }
+
+    fn set_multicast_loop_v4(&mut self, val: bool) -> Result<()> {
+        self.inner.set_multicast_loop_v4(val)
+    }
+
+    fn multicast_loop_v4(&self) -> Result<bool> {
+        self.inner.multicast_loop_v4()
+    }
+
+    fn set_multicast_loop_v6(&mut self, val: bool) -> Result<()> {
+        self.inner.set_multicast_loop_v6(val)
+    }
+
+    fn multicast_loop_v6(&self) -> Result<bool> {
+        self.inner.multicast_loop_v6()
+    }
+
+    fn set_multicast_ttl_v4(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_multicast_ttl_v4(ttl)
+    }
+
+    fn multicast_ttl_v4(&self) -> Result<u32> {
+        self.inner.multicast_ttl_v4()
+    }
+
+    fn join_multicast_v4(&mut self, multiaddr: Ipv4Addr, iface: Ipv4Addr) -> Result<()> {
+        self.inner.join_multicast_v4(multiaddr, iface)
+    }
+
+    fn leave_multicast_v4(&mut self, multiaddr: Ipv4Addr, iface: Ipv4Addr) -> Result<()> {
+        self.inner.leave_multicast_v4(multiaddr, iface)
+    }
+
+    fn join_multicast_v6(&mut self, multiaddr: Ipv6Addr, iface: u32) -> Result<()> {
+        self.inner.join_multicast_v6(multiaddr, iface)
+    }
+
+    fn leave_multicast_v6(&mut self, multiaddr: Ipv6Addr, iface: u32) -> Result<()> {
+        self.inner.leave_multicast_v6(multiaddr, iface)
+    }
+
+    fn addr_peer(&self) -> Result<Option<SocketAddr>> {
+        self.inner.addr_peer()
+    }
+}
+
+/// Sends a datagram if it fits within the quota, datagrams are never cut
+/// short
+fn send_datagram(
+    meter: &SocketMeter,
+    data: &[u8],
+    send: impl FnOnce(&[u8]) -> Result<usize>,
+) -> Result<usize> {
+    if meter.send_allowance()? < data.len() {
+        return Err(meter.exceeded());
+    }
+    let sent = send(data)?;
+    meter.sent(sent);
+    Ok(sent)
+}
+
+#[derive(Debug)]
+struct MeteredIcmpSocket {
+    inner: Box<dyn VirtualIcmpSocket + Sync>,
+    meter: SocketMeter,
+}
+
+impl VirtualIoSource for MeteredIcmpSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualSocket for MeteredIcmpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualConnectionlessSocket for MeteredIcmpSocket {
+    fn try_send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize> {
+        send_datagram(&self.meter, data, |data| self.inner.try_send_to(data, addr))
+    }
+
+    fn try_recv_from(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<(usize, SocketAddr)> {
+        self.meter.recv_allowance()?;
+        let (read, peer) = self.inner.try_recv_from(buf)?;
+        self.meter.received(read);
+        Ok((read, peer))
+    }
+}
+
+impl VirtualIcmpSocket for MeteredIcmpSocket {}
+
+#[derive(Debug)]
+struct MeteredRawSocket {
+    inner: Box<dyn VirtualRawSocket + Sync>,
+    meter: SocketMeter,
+}
+
+impl VirtualIoSource for MeteredRawSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualSocket for MeteredRawSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualRawSocket for MeteredRawSocket {
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        send_datagram(&self.meter, data, |data| self.inner.try_send(data))
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        self.inner.try_flush()
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        self.meter.recv_allowance()?;
+        let read = self.inner.try_recv(buf)?;
+        self.meter.received(read);
+        Ok(read)
+    }
+
+    fn set_promiscuous(&mut self, promiscuous: bool) -> Result<()> {
+        self.inner.set_promiscuous(promiscuous)
+    }
+
+    fn promiscuous(&self) -> Result<bool> {
+        self.inner.promiscuous()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{LoopbackNetworking, VirtualConnectedSocketExt, VirtualTcpListenerExt};
+
+    use super::*;
+
+    fn filled(buf: &[MaybeUninit<u8>], len: usize) -> Vec<u8> {
+        buf[..len]
+            .iter()
+            // SAFETY: the socket initialized the first `len` bytes
+            .map(|b| unsafe { b.assume_init() })
+            .collect()
+    }
+
+    async fn connected_pair(
+        networking: &MeteredNetworking,
+    ) -> (
+        Box<dyn VirtualTcpSocket + Sync>,
+        Box<dyn VirtualTcpSocket + Sync>,
+    ) {
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut listener = networking
+            .listen_tcp(addr, false, false, false)
+            .await
+            .unwrap();
+        let client = networking
+            .connect_tcp("127.0.0.1:5000".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn counts_the_traffic_of_connections() {
+        let meter = NetworkMeter::new("app");
+        let networking = MeteredNetworking::new(Arc::new(LoopbackNetworking::new()), meter.clone());
+
+        let (mut client, mut server) = connected_pair(&networking).await;
+        client.send(b"ping").await.unwrap();
+        let mut buf = [MaybeUninit::uninit(); 16];
+        let read = server.recv(&mut buf).await.unwrap();
+        assert_eq!(filled(&buf, read), b"ping");
+
+        let metrics = meter.metrics();
+        assert_eq!(metrics.active_connections, 2);
+        assert_eq!(metrics.connections, 2);
+        assert_eq!(
+            metrics.traffic,
+            TrafficCounters {
+                bytes_sent: 4,
+                bytes_received: 4,
+                packets_sent: 1,
+                packets_received: 1,
+            }
+        );
+
+        let sockets = meter.sockets();
+        assert_eq!(sockets.len(), 2);
+        assert_eq!(sockets[0].kind, SocketKind::Tcp);
+        assert_eq!(sockets[0].traffic.bytes_sent, 4);
+        assert_eq!(sockets[1].traffic.bytes_received, 4);
+
+        drop(client);
+        assert_eq!(meter.metrics().active_connections, 1);
+        assert_eq!(meter.sockets().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn counts_failed_connections() {
+        let meter = NetworkMeter::new("app");
+        let networking = MeteredNetworking::new(Arc::new(LoopbackNetworking::new()), meter.clone());
+
+        let err = networking
+            .connect_tcp(
+                "127.0.0.1:5000".parse().unwrap(),
+                "127.0.0.1:9999".parse().unwrap(),
+            )
+            .await
+            .unwrap_err();
+
+        assert_eq!(err, NetworkError::ConnectionRefused);
+        let metrics = meter.metrics();
+        assert_eq!(metrics.connect_failures, 1);
+        assert_eq!(metrics.connections, 0);
+    }
+
+    #[tokio::test]
+    async fn sockets_over_their_quota_are_closed() {
+        let meter = NetworkMeter::new("app");
+        meter.set_socket_quota(ByteQuota {
+            sent: Some(6),
+            received: None,
+        });
+        let networking = MeteredNetworking::new(Arc::new(LoopbackNetworking::new()), meter.clone());
+        let (mut client, mut server) = connected_pair(&networking).await;
+
+        // the send is cut short at the quota
+        assert_eq!(client.try_send(b"0123456789").unwrap(), 6);
+        assert_eq!(
+            client.try_send(b"more").unwrap_err(),
+            NetworkError::ConnectionAborted
+        );
+        assert_eq!(
+            client.try_send(b"more").unwrap_err(),
+            NetworkError::ConnectionAborted
+        );
+
+        let mut buf = [MaybeUninit::uninit(); 16];
+        let read = server.recv(&mut buf).await.unwrap();
+        assert_eq!(filled(&buf, read), b"012345");
+
+        let metrics = meter.metrics();
+        assert_eq!(metrics.quota_exceeded, 1);
+        assert!(meter.sockets()[0].quota_exceeded);
+        assert!(!meter.sockets()[1].quota_exceeded);
+    }
+
+    #[tokio::test]
+    async fn an_exhausted_instance_gets_no_new_sockets() {
+        let meter = NetworkMeter::new("app");
+        meter.set_instance_quota(ByteQuota {
+            sent: None,
+            received: Some(4),
+        });
+        let networking = MeteredNetworking::new(Arc::new(LoopbackNetworking::new()), meter.clone());
+        let (mut client, mut server) = connected_pair(&networking).await;
+
+        client.send(b"ping").await.unwrap();
+        let mut buf = [MaybeUninit::uninit(); 16];
+        let read = server.recv(&mut buf).await.unwrap();
+        assert_eq!(read, 4);
+
+        let err = networking
+            .connect_tcp(
+                "127.0.0.1:5001".parse().unwrap(),
+                "127.0.0.1:8080".parse().unwrap(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err, NetworkError::PermissionDenied);
+
+        // raising the quota lets the instance carry on
+        meter.set_instance_quota(ByteQuota::UNLIMITED);
+        networking
+            .connect_tcp(
+                "127.0.0.1:5001".parse().unwrap(),
+                "127.0.0.1:8080".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn encodes_prometheus_metrics() {
+        let first = NetworkMeter::new("first");
+        let second = NetworkMeter::new("with \"quotes\"");
+        first.state.traffic.sent(100);
+        second.state.traffic.received(42);
+
+        let text = encode_prometheus([&first, &second]);
+
+        assert!(text.contains(
+            "# HELP wasix_net_sent_bytes_total Bytes sent by the instance\n\
+             # TYPE wasix_net_sent_bytes_total counter\n\
+             wasix_net_sent_bytes_total{instance=\"first\"} 100\n\
+             wasix_net_sent_bytes_total{instance=\"with \\\"quotes\\\"\"} 0\n"
+        ));
+        assert!(text.contains("wasix_net_received_bytes_total{instance=\"first\"} 0\n"));
+        assert!(
+            text.contains("wasix_net_received_bytes_total{instance=\"with \\\"quotes\\\"\"} 42\n")
+        );
+        assert!(text.contains("# TYPE wasix_net_active_connections gauge\n"));
+    }
+}
@@ -0,0 +1,1609 @@
+//! Records the traffic of a [`VirtualNetworking`] implementation into a
+//! pcapng file that can be opened directly with Wireshark or `tcpdump`.
+//!
+//! [`PcapNetworking`] wraps any other networking implementation (host,
+//! userspace, remote, ...) and writes every TCP stream, UDP datagram, ICMP
+//! packet and raw frame the guest sends or receives to a [`PcapCapture`].
+//! Stream sockets never see real TCP segments, so the capture synthesizes
+//! the IP and TCP headers (including the handshake and the FINs) from the
+//! bytes that were sent and received on the connection.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use derivative::Derivative;
+
+use crate::{
+    DynVirtualNetworking, ForwardProtocol, InterestHandler, IpCidr, IpRoute, NetworkError,
+    PortForward, Result, SocketStatus, StreamSecurity, VirtualConnectedSocket,
+    VirtualConnectionlessSocket, VirtualIcmpSocket, VirtualIoSource, VirtualNetworking,
+    VirtualRawSocket, VirtualSocket, VirtualTcpListener, VirtualTcpSocket, VirtualUdpSocket,
+};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+
+/// Interface used for the IP packets synthesized from sockets
+const IFACE_IP: u32 = 0;
+/// Interface used for the frames of raw sockets
+const IFACE_ETHERNET: u32 = 1;
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_ICMPV6: u8 = 58;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Largest amount of stream data that is put into a single synthesized
+/// TCP segment (it has to fit into an IPv4 packet)
+const MAX_SEGMENT_SIZE: usize = 65_000;
+
+const DEFAULT_TTL: u8 = 64;
+
+/// Which way a packet travelled from the point of view of the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received by the guest
+    Inbound,
+    /// Sent by the guest
+    Outbound,
+}
+
+impl Direction {
+    fn epb_flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+}
+
+/// A pcapng file that packets are written to
+///
+/// The capture can be cloned and shared by several [`PcapNetworking`]
+/// instances so that the traffic of multiple guests ends up in one file.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct PcapCapture {
+    #[derivative(Debug = "ignore")]
+    writer: Arc<Mutex<PcapWriter>>,
+}
+
+struct PcapWriter {
+    out: Box<dyn Write + Send>,
+    /// Set once a write fails, after which the capture is abandoned
+    failed: bool,
+    next_ip_id: u16,
+}
+
+impl PcapCapture {
+    /// Starts a new capture that writes to `out`
+    pub fn new(out: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut writer = PcapWriter {
+            out: Box::new(out),
+            failed: false,
+            next_ip_id: 0,
+        };
+        writer.write_header()?;
+
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Creates (or truncates) a pcapng file and starts a capture into it
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Self::new(BufWriter::new(file))
+    }
+
+    /// Records an Ethernet frame
+    pub fn record_frame(&self, direction: Direction, frame: &[u8]) {
+        self.writer
+            .lock()
+            .unwrap()
+            .write_packet(IFACE_ETHERNET, direction, frame);
+    }
+
+    /// Records an IP packet with a synthesized IP header
+    fn record_ip(&self, direction: Direction, src: IpAddr, dst: IpAddr, proto: u8, payload: &[u8]) {
+        let mut writer = self.writer.lock().unwrap();
+        let id = writer.next_ip_id;
+        writer.next_ip_id = id.wrapping_add(1);
+
+        let packet = ip_packet(id, src, dst, proto, payload);
+        writer.write_packet(IFACE_IP, direction, &packet);
+    }
+
+    fn record_udp(&self, direction: Direction, src: SocketAddr, dst: SocketAddr, data: &[u8]) {
+        let (src_ip, dst_ip) = same_family(src.ip(), dst.ip());
+        let data = &data[..data.len().min(u16::MAX as usize - 48)];
+
+        let mut datagram = Vec::with_capacity(8 + data.len());
+        datagram.extend_from_slice(&src.port().to_be_bytes());
+        datagram.extend_from_slice(&dst.port().to_be_bytes());
+        datagram.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+
+        let mut checksum = transport_checksum(src_ip, dst_ip, PROTO_UDP, &datagram);
+        if checksum == 0 {
+            checksum = 0xFFFF;
+        }
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+        self.record_ip(direction, src_ip, dst_ip, PROTO_UDP, &datagram);
+    }
+
+    fn record_icmp(&self, direction: Direction, src: IpAddr, dst: IpAddr, message: &[u8]) {
+        let (src, dst) = same_family(src, dst);
+        let proto = match dst {
+            IpAddr::V4(_) => PROTO_ICMP,
+            IpAddr::V6(_) => PROTO_ICMPV6,
+        };
+        self.record_ip(direction, src, dst, proto, message);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn record_tcp(
+        &self,
+        direction: Direction,
+        src: SocketAddr,
+        dst: SocketAddr,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        data: &[u8],
+    ) {
+        let (src_ip, dst_ip) = same_family(src.ip(), dst.ip());
+
+        let mut segment = Vec::with_capacity(20 + data.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        segment.push(5 << 4);
+        segment.push(flags);
+        segment.extend_from_slice(&u16::MAX.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        segment.extend_from_slice(data);
+
+        let checksum = transport_checksum(src_ip, dst_ip, PROTO_TCP, &segment);
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+        self.record_ip(direction, src_ip, dst_ip, PROTO_TCP, &segment);
+    }
+}
+
+impl PcapWriter {
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut shb, OPT_SHB_USERAPPL, b"wasmer");
+        push_option(&mut shb, OPT_END, &[]);
+        write_block(&mut self.out, BLOCK_SECTION_HEADER, &shb)?;
+
+        for (linktype, name) in [(LINKTYPE_RAW, "guest"), (LINKTYPE_ETHERNET, "guest-raw")] {
+            let mut idb = Vec::new();
+            idb.extend_from_slice(&linktype.to_le_bytes());
+            idb.extend_from_slice(&0u16.to_le_bytes());
+            // a snap length of zero means that packets are never truncated
+            idb.extend_from_slice(&0u32.to_le_bytes());
+            push_option(&mut idb, OPT_IF_NAME, name.as_bytes());
+            push_option(&mut idb, OPT_END, &[]);
+            write_block(&mut self.out, BLOCK_INTERFACE_DESCRIPTION, &idb)?;
+        }
+
+        self.out.flush()
+    }
+
+    fn write_packet(&mut self, iface: u32, direction: Direction, data: &[u8]) {
+        if self.failed {
+            return;
+        }
+
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_micros() as u64;
+
+        let mut epb = Vec::with_capacity(32 + data.len());
+        epb.extend_from_slice(&iface.to_le_bytes());
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(data);
+        pad(&mut epb);
+        push_option(
+            &mut epb,
+            OPT_EPB_FLAGS,
+            &direction.epb_flags().to_le_bytes(),
+        );
+        push_option(&mut epb, OPT_END, &[]);
+
+        // flush every packet so the file is usable even if the process
+        // exits without dropping the capture
+        let result =
+            write_block(&mut self.out, BLOCK_ENHANCED_PACKET, &epb).and_then(|_| self.out.flush());
+        if let Err(err) = result {
+            tracing::warn!(
+                error = &err as &dyn std::error::Error,
+                "Packet capture failed, no more packets will be recorded"
+            );
+            self.failed = true;
+        }
+    }
+}
+
+fn write_block(out: &mut dyn Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let len = (12 + body.len()) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&len.to_le_bytes())
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+/// IP headers can only carry addresses of one family, so IPv4 addresses
+/// are mapped into IPv6 when the two ends disagree
+fn same_family(src: IpAddr, dst: IpAddr) -> (IpAddr, IpAddr) {
+    match (src, dst) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (src, dst),
+        _ => (to_ipv6(src).into(), to_ipv6(dst).into()),
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn ip_packet(id: u16, src: IpAddr, dst: IpAddr, proto: u8, payload: &[u8]) -> Vec<u8> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => ipv4_packet(id, src, dst, proto, payload),
+        (src, dst) => ipv6_packet(to_ipv6(src), to_ipv6(dst), proto, payload),
+    }
+}
+
+fn ipv4_packet(id: u16, src: Ipv4Addr, dst: Ipv4Addr, proto: u8, payload: &[u8]) -> Vec<u8> {
+    let payload = &payload[..payload.len().min(u16::MAX as usize - 20)];
+
+    let mut packet = Vec::with_capacity(20 + payload.len());
+    packet.push(0x45);
+    packet.push(0);
+    packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    // don't fragment
+    packet.extend_from_slice(&0x4000u16.to_be_bytes());
+    packet.push(DEFAULT_TTL);
+    packet.push(proto);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+
+    let checksum = finish_checksum(sum_words(0, &packet));
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv6_packet(src: Ipv6Addr, dst: Ipv6Addr, proto: u8, payload: &[u8]) -> Vec<u8> {
+    let payload = &payload[..payload.len().min(u16::MAX as usize)];
+
+    let mut packet = Vec::with_capacity(40 + payload.len());
+    packet.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.push(proto);
+    packet.push(DEFAULT_TTL);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Checksum of a TCP or UDP header and payload including the pseudo header
+fn transport_checksum(src: IpAddr, dst: IpAddr, proto: u8, segment: &[u8]) -> u16 {
+    let mut sum = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let sum = sum_words(0, &src.octets());
+            sum_words(sum, &dst.octets())
+        }
+        (src, dst) => {
+            let sum = sum_words(0, &to_ipv6(src).octets());
+            sum_words(sum, &to_ipv6(dst).octets())
+        }
+    };
+    sum += proto as u32;
+    sum += segment.len() as u32;
+    finish_checksum(sum_words(sum, segment))
+}
+
+fn sum_words(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn finish_checksum(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the part of a receive buffer that was filled in by a socket
+fn received(buf: &[MaybeUninit<u8>], len: usize) -> &[u8] {
+    let filled = &buf[..len.min(buf.len())];
+    // SAFETY: the socket initialized the first `len` bytes of the buffer
+    unsafe { &*(filled as *const [MaybeUninit<u8>] as *const [u8]) }
+}
+
+/// A networking implementation that records all the traffic of another
+/// implementation into a [`PcapCapture`]
+#[derive(Debug, Clone)]
+pub struct PcapNetworking {
+    inner: DynVirtualNetworking,
+    capture: PcapCapture,
+}
+
+impl PcapNetworking {
+    pub fn new(inner: DynVirtualNetworking, capture: PcapCapture) -> Self {
+        Self { inner, capture }
+    }
+
+    /// Wraps a networking implementation and records its traffic into a
+    /// new pcapng file at `path`
+    pub fn create(inner: DynVirtualNetworking, path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(inner, PcapCapture::create(path)?))
+    }
+
+    pub fn capture(&self) -> &PcapCapture {
+        &self.capture
+    }
+
+    pub fn inner(&self) -> &DynVirtualNetworking {
+        &self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for PcapNetworking {
+    async fn bridge(
+        &self,
+        network: &str,
+        access_token: &str,
+        security: StreamSecurity,
+    ) -> Result<()> {
+        self.inner.bridge(network, access_token, security).await
+    }
+
+    async fn unbridge(&self) -> Result<()> {
+        self.inner.unbridge().await
+    }
+
+    async fn dhcp_acquire(&self) -> Result<Vec<IpAddr>> {
+        self.inner.dhcp_acquire().await
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> Result<()> {
+        self.inner.ip_add(ip, prefix).await
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> Result<()> {
+        self.inner.ip_remove(ip).await
+    }
+
+    async fn ip_clear(&self) -> Result<()> {
+        self.inner.ip_clear().await
+    }
+
+    async fn ip_list(&self) -> Result<Vec<IpCidr>> {
+        self.inner.ip_list().await
+    }
+
+    async fn mac(&self) -> Result<[u8; 6]> {
+        self.inner.mac().await
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> Result<()> {
+        self.inner.gateway_set(ip).await
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> Result<()> {
+        self.inner
+            .route_add(cidr, via_router, preferred_until, expires_at)
+            .await
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> Result<()> {
+        self.inner.route_remove(cidr).await
+    }
+
+    async fn route_clear(&self) -> Result<()> {
+        self.inner.route_clear().await
+    }
+
+    async fn route_list(&self) -> Result<Vec<IpRoute>> {
+        self.inner.route_list().await
+    }
+
+    async fn bind_raw(&self) -> Result<Box<dyn VirtualRawSocket + Sync>> {
+        let inner = self.inner.bind_raw().await?;
+        Ok(Box::new(PcapRawSocket {
+            inner,
+            capture: self.capture.clone(),
+        }))
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>> {
+        let inner = self
+            .inner
+            .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+            .await?;
+        Ok(Box::new(PcapTcpListener {
+            inner,
+            capture: self.capture.clone(),
+        }))
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>> {
+        let inner = self.inner.bind_udp(addr, reuse_port, reuse_addr).await?;
+        Ok(Box::new(PcapUdpSocket {
+            inner,
+            capture: self.capture.clone(),
+        }))
+    }
+
+    async fn bind_icmp(&self, addr: IpAddr) -> Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        let inner = self.inner.bind_icmp(addr).await?;
+        Ok(Box::new(PcapIcmpSocket {
+            inner,
+            capture: self.capture.clone(),
+            addr,
+        }))
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        let inner = self.inner.connect_tcp(addr, peer).await?;
+        let local = inner.addr_local().unwrap_or(addr);
+        let peer = inner.addr_peer().unwrap_or(peer);
+        Ok(Box::new(PcapTcpSocket::new(
+            inner,
+            self.capture.clone(),
+            local,
+            peer,
+            Direction::Outbound,
+        )))
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>> {
+        self.inner.resolve(host, port, dns_server).await
+    }
+
+    async fn forward_add(&self, forward: PortForward) -> Result<PortForward> {
+        self.inner.forward_add(forward).await
+    }
+
+    async fn forward_remove(&self, protocol: ForwardProtocol, host: SocketAddr) -> Result<()> {
+        self.inner.forward_remove(protocol, host).await
+    }
+
+    async fn forward_list(&self) -> Result<Vec<PortForward>> {
+        self.inner.forward_list().await
+    }
+}
+
+#[derive(Debug)]
+struct PcapTcpListener {
+    inner: Box<dyn VirtualTcpListener + Sync>,
+    capture: PcapCapture,
+}
+
+impl VirtualIoSource for PcapTcpListener {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualTcpListener for PcapTcpListener {
+    fn try_accept(&mut self) -> Result<(Box<dyn VirtualTcpSocket + Sync>, SocketAddr)> {
+        let (socket, peer) = self.inner.try_accept()?;
+        let local = match socket.addr_local() {
+            Ok(addr) => addr,
+            Err(_) => self.inner.addr_local()?,
+        };
+        let socket = PcapTcpSocket::new(
+            socket,
+            self.capture.clone(),
+            local,
+            peer,
+            Direction::Inbound,
+        );
+        Ok((Box::new(socket), peer))
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u8> {
+        self.inner.ttl()
+    }
+}
+
+/// The sequence numbers of a synthesized TCP connection
+#[derive(Debug)]
+struct TcpStream {
+    capture: PcapCapture,
+    local: SocketAddr,
+    peer: SocketAddr,
+    /// Next sequence number sent by the guest
+    local_seq: u32,
+    /// Next sequence number sent by the peer
+    peer_seq: u32,
+    local_fin: bool,
+    peer_fin: bool,
+    reset: bool,
+}
+
+impl TcpStream {
+    /// Synthesizes the three way handshake of a connection that was opened
+    /// by `opened_by`
+    fn open(
+        capture: PcapCapture,
+        local: SocketAddr,
+        peer: SocketAddr,
+        opened_by: Direction,
+    ) -> Self {
+        let mut stream = Self {
+            capture,
+            local,
+            peer,
+            local_seq: 0,
+            peer_seq: 0,
+            local_fin: false,
+            peer_fin: false,
+            reset: false,
+        };
+
+        match opened_by {
+            Direction::Outbound => {
+                stream.segment(Direction::Outbound, TCP_SYN, &[]);
+                stream.segment(Direction::Inbound, TCP_SYN | TCP_ACK, &[]);
+                stream.segment(Direction::Outbound, TCP_ACK, &[]);
+            }
+            Direction::Inbound => {
+                stream.segment(Direction::Inbound, TCP_SYN, &[]);
+                stream.segment(Direction::Outbound, TCP_SYN | TCP_ACK, &[]);
+                stream.segment(Direction::Inbound, TCP_ACK, &[]);
+            }
+        }
+        stream
+    }
+
+    fn data(&mut self, direction: Direction, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT_SIZE) {
+            self.segment(direction, TCP_PSH | TCP_ACK, chunk);
+        }
+    }
+
+    fn fin(&mut self, direction: Direction) {
+        let sent = match direction {
+            Direction::Inbound => &mut self.peer_fin,
+            Direction::Outbound => &mut self.local_fin,
+        };
+        if !*sent && !self.reset {
+            *sent = true;
+            self.segment(direction, TCP_FIN | TCP_ACK, &[]);
+        }
+    }
+
+    fn reset(&mut self, direction: Direction) {
+        if !self.reset {
+            self.reset = true;
+            self.segment(direction, TCP_RST | TCP_ACK, &[]);
+        }
+    }
+
+    fn segment(&mut self, direction: Direction, flags: u8, data: &[u8]) {
+        let (src, dst, seq, ack) = match direction {
+            Direction::Outbound => (&self.local, &self.peer, &mut self.local_seq, self.peer_seq),
+            Direction::Inbound => (&self.peer, &self.local, &mut self.peer_seq, self.local_seq),
+        };
+        // the initial SYN doesn't acknowledge anything yet
+        let (ack, flags) = if flags == TCP_SYN {
+            (0, flags)
+        } else {
+            (ack, flags)
+        };
+
+        self.capture
+            .record_tcp(direction, *src, *dst, *seq, ack, flags, data);
+
+        let mut consumed = data.len() as u32;
+        if flags & (TCP_SYN | TCP_FIN) != 0 {
+            consumed += 1;
+        }
+        *seq = seq.wrapping_add(consumed);
+    }
+}
+
+#[derive(Debug)]
+struct PcapTcpSocket {
+    inner: Box<dyn VirtualTcpSocket + Sync>,
+    stream: TcpStream,
+}
+
+impl PcapTcpSocket {
+    fn new(
+        inner: Box<dyn VirtualTcpSocket + Sync>,
+        capture: PcapCapture,
+        local: SocketAddr,
+        peer: SocketAddr,
+        opened_by: Direction,
+    ) -> Self {
+        Self {
+            inner,
+            stream: TcpStream::open(capture, local, peer, opened_by),
+        }
+    }
+
+    fn record_error(&mut self, err: NetworkError) {
+        if matches!(
+            err,
+            NetworkError::ConnectionReset | NetworkError::ConnectionAborted
+        ) {
+            self.stream.reset(Direction::Inbound);
+        }
+    }
+}
+
+impl Drop for PcapTcpSocket {
+    fn drop(&mut self) {
+        self.stream.fin(Direction::Outbound);
+    }
+}
+
+impl VirtualIoSource for PcapTcpSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualSocket for PcapTcpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualConnectedSocket for PcapTcpSocket {
+    fn set_linger(&mut self, linger: Option<Duration>) -> Result<()> {
+        self.inner.set_linger(linger)
+    }
+
+    fn linger(&self) -> Result<Option<Duration>> {
+        self.inner.linger()
+    }
+
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        match self.inner.try_send(data) {
+            Ok(sent) => {
+                self.stream.data(Direction::Outbound, &data[..sent]);
+                Ok(sent)
+            }
+            Err(err) => {
+                self.record_error(err);
+                Err(err)
+            }
+        }
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        self.inner.try_flush()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.stream.fin(Direction::Outbound);
+        self.inner.close()
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        match self.inner.try_recv(buf) {
+            Ok(0) if !buf.is_empty() => {
+                self.stream.fin(Direction::Inbound);
+                Ok(0)
+            }
+            Ok(read) => {
+                self.stream.data(Direction::Inbound, received(buf, read));
+                Ok(read)
+            }
+            Err(err) => {
+                self.record_error(err);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl VirtualTcpSocket for PcapTcpSocket {
+    fn set_recv_buf_size(&mut self, size: usize) -> Result<()> {
+        self.inner.set_recv_buf_size(size)
+    }
+
+    fn recv_buf_size(&self) -> Result<usize> {
+        self.inner.recv_buf_size()
+    }
+
+    fn set_send_buf_size(&mut self, size: usize) -> Result<()> {
+        self.inner.set_send_buf_size(size)
+    }
+
+    fn send_buf_size(&self) -> Result<usize> {
+        self.inner.send_buf_size()
+    }
+
+    fn set_nodelay(&mut self, reuse: bool) -> Result<()> {
+        self.inner.set_nodelay(reuse)
+    }
+
+    fn nodelay(&self) -> Result<bool> {
+        self.inner.nodelay()
+    }
+
+    fn set_keepalive(&mut self, keepalive: bool) -> Result<()> {
+        self.inner.set_keepalive(keepalive)
+    }
+
+    fn keepalive(&self) -> Result<bool> {
+        self.inner.keepalive()
+    }
+
+    fn set_dontroute(&mut self, keepalive: bool) -> Result<()> {
+        self.inner.set_dontroute(keepalive)
+    }
+
+    fn dontroute(&self) -> Result<bool> {
+        self.inner.dontroute()
+    }
+
+    fn addr_peer(&self) -> Result<SocketAddr> {
+        self.inner.addr_peer()
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.stream.fin(Direction::Outbound);
+        }
+        self.inner.shutdown(how)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+#[derive(Debug)]
+struct PcapUdpSocket {
+    inner: Box<dyn VirtualUdpSocket + Sync>,
+    capture: PcapCapture,
+}
+
+impl VirtualIoSource for PcapUdpSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualSocket for PcapUdpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualConnectionlessSocket for PcapUdpSocket {
+    fn try_send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize> {
+        let sent = self.inner.try_send_to(data, addr)?;
+        if let Ok(local) = self.inner.addr_local() {
+            self.capture
+                .record_udp(Direction::Outbound, local, addr, &data[..sent]);
+        }
+        Ok(sent)
+    }
+
+    fn try_recv_from(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<(usize, SocketAddr)> {
+        let (read, peer) = self.inner.try_recv_from(buf)?;
+        if let Ok(local) = self.inner.addr_local() {
+            self.capture
+                .record_udp(Direction::Inbound, peer, local, received(buf, read));
+        }
+        Ok((read, peer))
+    }
+}
+
+impl VirtualUdpSocket for PcapUdpSocket {
+    fn set_broadcast(&mut self, broadcast: bool) -> Result<()> {
+        self.inner.set_broadcast(broadcast)
+    }
+
+    fn broadcast(&self) -> Result<bool> {
+        self.inner.broadcast()
+    }
+
+    fn set_multicast_loop_v4(&mut self, val: bool) -> Result<()> {
+        self.inner.set_multicast_loop_v4(val)
+    }
+
+    fn multicast_loop_v4(&self) -> Result<bool> {
+        self.inner.multicast_loop_v4()
+    }
+
+    fn set_multicast_loop_v6(&mut self, val: bool) -> Result<()> {
+        self.inner.set_multicast_loop_v6(val)
+    }
+
+    fn multicast_loop_v6(&self) -> Result<bool> {
+        self.inner.multicast_loop_v6()
+    }
+
+    fn set_multicast_ttl_v4(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_multicast_ttl_v4(ttl)
+    }
+
+    fn multicast_ttl_v4(&self) -> Result<u32> {
+        self.inner.multicast_ttl_v4()
+    }
+
+    fn join_multicast_v4(&mut self, multiaddr: Ipv4Addr, iface: Ipv4Addr) -> Result<()> {
+        self.inner.join_multicast_v4(multiaddr, iface)
+    }
+
+    fn leave_multicast_v4(&mut self, multiaddr: Ipv4Addr, iface: Ipv4Addr) -> Result<()> {
+        self.inner.leave_multicast_v4(multiaddr, iface)
+    }
+
+    fn join_multicast_v6(&mut self, multiaddr: Ipv6Addr, iface: u32) -> Result<()> {
+        self.inner.join_multicast_v6(multiaddr, iface)
+    }
+
+    fn leave_multicast_v6(&mut self, multiaddr: Ipv6Addr, iface: u32) -> Result<()> {
+        self.inner.leave_multicast_v6(multiaddr, iface)
+    }
+
+    fn addr_peer(&self) -> Result<Option<SocketAddr>> {
+        self.inner.addr_peer()
+    }
+}
+
+#[derive(Debug)]
+struct PcapIcmpSocket {
+    inner: Box<dyn VirtualIcmpSocket + Sync>,
+    capture: PcapCapture,
+    /// Address the socket was bound to
+    addr: IpAddr,
+}
+
+impl VirtualIoSource for PcapIcmpSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualSocket for PcapIcmpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualConnectionlessSocket for PcapIcmpSocket {
+    fn try_send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize> {
+        let sent = self.inner.try_send_to(data, addr)?;
+        self.capture
+            .record_icmp(Direction::Outbound, self.addr, addr.ip(), &data[..sent]);
+        Ok(sent)
+    }
+
+    fn try_recv_from(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<(usize, SocketAddr)> {
+        let (read, peer) = self.inner.try_recv_from(buf)?;
+        self.capture.record_icmp(
+            Direction::Inbound,
+            peer.ip(),
+            self.addr,
+            received(buf, read),
+        );
+        Ok((read, peer))
+    }
+}
+
+impl VirtualIcmpSocket for PcapIcmpSocket {}
+
+#[derive(Debug)]
+struct PcapRawSocket {
+    inner: Box<dyn VirtualRawSocket + Sync>,
+    capture: PcapCapture,
+}
+
+impl VirtualIoSource for PcapRawSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualSocket for PcapRawSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualRawSocket for PcapRawSocket {
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        let sent = self.inner.try_send(data)?;
+        self.capture
+            .record_frame(Direction::Outbound, &data[..sent]);
+        Ok(sent)
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        self.inner.try_flush()
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let read = self.inner.try_recv(buf)?;
+        self.capture
+            .record_frame(Direction::Inbound, received(buf, read));
+        Ok(read)
+    }
+
+    fn set_promiscuous(&mut self, promiscuous: bool) -> Result<()> {
+        self.inner.set_promiscuous(promiscuous)
+    }
+
+    fn promiscuous(&self) -> Result<bool> {
+        self.inner.promiscuous()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{LoopbackNetworking, VirtualConnectedSocketExt, VirtualTcpListenerExt};
+
+    use super::*;
+
+    /// A writer that keeps the capture in memory so the test can inspect it
+    #[derive(Debug, Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct Packet {
+        iface: u32,
+        flags: u32,
+        data: Vec<u8>,
+    }
+
+    /// Splits a pcapng file into its blocks
+    fn blocks(file: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut rest = file;
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(&rest[4..8], &rest[len - 4..len]);
+            blocks.push((block_type, &rest[8..len - 4]));
+            rest = &rest[len..];
+        }
+        blocks
+    }
+
+    /// Splits the options at the end of a block into `(code, value)` pairs
+    fn options(mut body: &[u8]) -> Vec<(u16, &[u8])> {
+        let mut options = Vec::new();
+        loop {
+            let code = u16::from_le_bytes(body[0..2].try_into().unwrap());
+            let len = u16::from_le_bytes(body[2..4].try_into().unwrap()) as usize;
+            if code == OPT_END {
+                assert_eq!(body.len(), 4);
+                return options;
+            }
+            options.push((code, &body[4..4 + len]));
+            body = &body[4 + (len + 3) / 4 * 4..];
+        }
+    }
+
+    /// Returns the packets of a pcapng file
+    fn parse(file: &[u8]) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        let mut interfaces = 0;
+        for (block_type, body) in blocks(file) {
+            match block_type {
+                BLOCK_SECTION_HEADER => {
+                    assert_eq!(&body[0..4], &BYTE_ORDER_MAGIC.to_le_bytes());
+                }
+                BLOCK_INTERFACE_DESCRIPTION => interfaces += 1,
+                BLOCK_ENHANCED_PACKET => {
+                    let iface = u32::from_le_bytes(body[0..4].try_into().unwrap());
+                    assert!(iface < interfaces);
+                    let captured = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+                    let original = u32::from_le_bytes(body[16..20].try_into().unwrap()) as usize;
+                    assert_eq!(captured, original);
+                    let data = body[20..20 + captured].to_vec();
+                    let options = options(&body[20 + (captured + 3) / 4 * 4..]);
+                    assert_eq!(options.len(), 1);
+                    assert_eq!(options[0].0, OPT_EPB_FLAGS);
+                    let flags = u32::from_le_bytes(options[0].1.try_into().unwrap());
+                    packets.push(Packet { iface, flags, data });
+                }
+                other => panic!("Unexpected block type {other:#x}"),
+            }
+        }
+        packets
+    }
+
+    /// Returns the TCP flags and payload of a synthesized IPv4 packet after
+    /// checking its checksums
+    fn tcp_segment(packet: &[u8]) -> (u8, &[u8]) {
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(packet[9], PROTO_TCP);
+        assert_eq!(finish_checksum(sum_words(0, &packet[..20])), 0);
+        let src: [u8; 4] = packet[12..16].try_into().unwrap();
+        let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+
+        let segment = &packet[20..];
+        let mut sum = sum_words(0, &src);
+        sum = sum_words(sum, &dst);
+        sum += PROTO_TCP as u32 + segment.len() as u32;
+        assert_eq!(finish_checksum(sum_words(sum, segment)), 0);
+
+        (segment[13], &segment[20..])
+    }
+
+    #[tokio::test]
+    async fn records_tcp_streams() {
+        let buffer = SharedBuffer::default();
+        let loopback = LoopbackNetworking::new();
+        let networking = PcapNetworking::new(
+            Arc::new(loopback.clone()),
+            PcapCapture::new(buffer.clone()).unwrap(),
+        );
+
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut listener = networking
+            .listen_tcp(addr, false, false, false)
+            .await
+            .unwrap();
+        let mut client = loopback
+            .loopback_connect_to("127.0.0.1:5000".parse().unwrap(), addr)
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client.send(b"ping").await.unwrap();
+        let mut buf = [MaybeUninit::uninit(); 16];
+        let read = server.recv(&mut buf).await.unwrap();
+        assert_eq!(received(&buf, read), b"ping");
+        server.send(b"pong").await.unwrap();
+        server.close().unwrap();
+
+        let file = buffer.0.lock().unwrap().clone();
+        let packets = parse(&file);
+        let segments: Vec<_> = packets
+            .iter()
+            .map(|p| {
+                assert_eq!(p.iface, IFACE_IP);
+                let (flags, payload) = tcp_segment(&p.data);
+                (p.flags, flags, payload.to_vec())
+            })
+            .collect();
+
+        let inbound = Direction::Inbound.epb_flags();
+        let outbound = Direction::Outbound.epb_flags();
+        assert_eq!(
+            segments,
+            vec![
+                (inbound, TCP_SYN, vec![]),
+                (outbound, TCP_SYN | TCP_ACK, vec![]),
+                (inbound, TCP_ACK, vec![]),
+                (inbound, TCP_PSH | TCP_ACK, b"ping".to_vec()),
+                (outbound, TCP_PSH | TCP_ACK, b"pong".to_vec()),
+                (outbound, TCP_FIN | TCP_ACK, vec![]),
+            ]
+        );
+
+        // the sequence numbers continue where the handshake left off
+        let seq = |p: &Packet| u32::from_be_bytes(p.data[24..28].try_into().unwrap());
+        let ack = |p: &Packet| u32::from_be_bytes(p.data[28..32].try_into().unwrap());
+        assert_eq!(seq(&packets[3]), 1);
+        assert_eq!(ack(&packets[4]), 5);
+        assert_eq!(seq(&packets[5]), 5);
+
+        // dropping the socket doesn't close it a second time
+        drop(server);
+        assert_eq!(parse(&buffer.0.lock().unwrap()).len(), 6);
+    }
+
+    #[test]
+    fn udp_checksums_are_valid() {
+        let buffer = SharedBuffer::default();
+        let capture = PcapCapture::new(buffer.clone()).unwrap();
+        capture.record_udp(
+            Direction::Outbound,
+            "10.0.0.2:1000".parse().unwrap(),
+            "[2001:db8::1]:53".parse().unwrap(),
+            b"odd",
+        );
+
+        let packets = parse(&buffer.0.lock().unwrap());
+        let packet = &packets[0].data;
+        // mixed families are mapped onto IPv6
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(packet[6], PROTO_UDP);
+        let src: Ipv6Addr = <[u8; 16]>::try_from(&packet[8..24]).unwrap().into();
+        assert_eq!(src, Ipv4Addr::new(10, 0, 0, 2).to_ipv6_mapped());
+
+        let datagram = &packet[40..];
+        let mut sum = sum_words(0, &packet[8..40]);
+        sum += PROTO_UDP as u32 + datagram.len() as u32;
+        assert_eq!(finish_checksum(sum_words(sum, datagram)), 0);
+        assert_eq!(&datagram[8..], b"odd");
+    }
+
+    #[test]
+    fn starts_with_a_section_header_and_interfaces() {
+        let buffer = SharedBuffer::default();
+        let _capture = PcapCapture::new(buffer.clone()).unwrap();
+
+        let file = buffer.0.lock().unwrap().clone();
+        let blocks = blocks(&file);
+        assert_eq!(blocks.len(), 3);
+
+        let (block_type, shb) = blocks[0];
+        assert_eq!(block_type, BLOCK_SECTION_HEADER);
+        assert_eq!(&shb[0..4], &BYTE_ORDER_MAGIC.to_le_bytes());
+        // version 1.0 with an unknown section length
+        assert_eq!(u16::from_le_bytes(shb[4..6].try_into().unwrap()), 1);
+        assert_eq!(u16::from_le_bytes(shb[6..8].try_into().unwrap()), 0);
+        assert_eq!(i64::from_le_bytes(shb[8..16].try_into().unwrap()), -1);
+        assert_eq!(
+            options(&shb[16..]),
+            vec![(OPT_SHB_USERAPPL, b"wasmer".as_slice())]
+        );
+
+        let interfaces: Vec<_> = blocks[1..]
+            .iter()
+            .map(|(block_type, idb)| {
+                assert_eq!(*block_type, BLOCK_INTERFACE_DESCRIPTION);
+                let linktype = u16::from_le_bytes(idb[0..2].try_into().unwrap());
+                let snaplen = u32::from_le_bytes(idb[4..8].try_into().unwrap());
+                let options = options(&idb[8..]);
+                assert_eq!(options.len(), 1);
+                assert_eq!(options[0].0, OPT_IF_NAME);
+                (linktype, snaplen, options[0].1.to_vec())
+            })
+            .collect();
+        // the order matters because packets refer to interfaces by index
+        assert_eq!(
+            interfaces[IFACE_IP as usize],
+            (LINKTYPE_RAW, 0, b"guest".to_vec())
+        );
+        assert_eq!(
+            interfaces[IFACE_ETHERNET as usize],
+            (LINKTYPE_ETHERNET, 0, b"guest-raw".to_vec())
+        );
+    }
+
+    #[test]
+    fn frames_are_recorded_in_both_directions() {
+        let buffer = SharedBuffer::default();
+        let capture = PcapCapture::new(buffer.clone()).unwrap();
+
+        capture.record_frame(Direction::Outbound, &[1, 2, 3, 4, 5]);
+        capture.record_frame(Direction::Inbound, &[6; 64]);
+
+        let packets = parse(&buffer.0.lock().unwrap());
+        let packets: Vec<_> = packets
+            .iter()
+            .map(|p| (p.iface, p.flags, p.data.clone()))
+            .collect();
+        assert_eq!(
+            packets,
+            vec![
+                (
+                    IFACE_ETHERNET,
+                    Direction::Outbound.epb_flags(),
+                    vec![1, 2, 3, 4, 5]
+                ),
+                (IFACE_ETHERNET, Direction::Inbound.epb_flags(), vec![6; 64]),
+            ]
+        );
+    }
+
+    #[test]
+    fn datagrams_are_recorded_in_both_directions() {
+        let buffer = SharedBuffer::default();
+        let capture = PcapCapture::new(buffer.clone()).unwrap();
+        let guest: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.1:53".parse().unwrap();
+
+        capture.record_udp(Direction::Outbound, guest, peer, b"query");
+        capture.record_udp(Direction::Inbound, peer, guest, b"answer");
+        capture.record_icmp(Direction::Outbound, guest.ip(), peer.ip(), &[8, 0, 0, 0]);
+        capture.record_icmp(
+            Direction::Inbound,
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+            &[129, 0, 0, 0],
+        );
+
+        let packets = parse(&buffer.0.lock().unwrap());
+        assert_eq!(packets.len(), 4);
+        let flags: Vec<_> = packets.iter().map(|p| p.flags).collect();
+        assert_eq!(
+            flags,
+            vec![
+                Direction::Outbound.epb_flags(),
+                Direction::Inbound.epb_flags(),
+                Direction::Outbound.epb_flags(),
+                Direction::Inbound.epb_flags(),
+            ]
+        );
+        assert!(packets.iter().all(|p| p.iface == IFACE_IP));
+
+        for (packet, src, dst, payload) in [
+            (&packets[0], guest, peer, b"query".as_slice()),
+            (&packets[1], peer, guest, b"answer".as_slice()),
+        ] {
+            let packet = &packet.data;
+            assert_eq!(packet[9], PROTO_UDP);
+            assert_eq!(finish_checksum(sum_words(0, &packet[..20])), 0);
+            assert_eq!(&packet[12..16], &ipv4_octets(src));
+            assert_eq!(&packet[16..20], &ipv4_octets(dst));
+            let datagram = &packet[20..];
+            assert_eq!(u16::from_be_bytes([datagram[0], datagram[1]]), src.port());
+            assert_eq!(u16::from_be_bytes([datagram[2], datagram[3]]), dst.port());
+            assert_eq!(&datagram[8..], payload);
+        }
+        // every packet gets its own IP identification
+        assert_ne!(packets[0].data[4..6], packets[1].data[4..6]);
+
+        assert_eq!(packets[2].data[9], PROTO_ICMP);
+        assert_eq!(&packets[2].data[20..], &[8, 0, 0, 0]);
+        assert_eq!(packets[3].data[0] >> 4, 6);
+        assert_eq!(packets[3].data[6], PROTO_ICMPV6);
+        assert_eq!(&packets[3].data[40..], &[129, 0, 0, 0]);
+    }
+
+    fn ipv4_octets(addr: SocketAddr) -> [u8; 4] {
+        match addr.ip() {
+            IpAddr::V4(ip) => ip.octets(),
+            IpAddr::V6(_) => panic!("Expected an IPv4 address"),
+        }
+    }
+
+    #[tokio::test]
+    async fn records_outbound_tcp_connections() {
+        let buffer = SharedBuffer::default();
+        let loopback = LoopbackNetworking::new();
+        let networking = PcapNetworking::new(
+            Arc::new(loopback.clone()),
+            PcapCapture::new(buffer.clone()).unwrap(),
+        );
+
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut listener = loopback
+            .listen_tcp(addr, false, false, false)
+            .await
+            .unwrap();
+        let mut client = networking
+            .connect_tcp("127.0.0.1:5000".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client.send(b"hello").await.unwrap();
+        let mut buf = [MaybeUninit::uninit(); 16];
+        let read = server.recv(&mut buf).await.unwrap();
+        assert_eq!(received(&buf, read), b"hello");
+        server.send(b"world").await.unwrap();
+        let read = client.recv(&mut buf).await.unwrap();
+        assert_eq!(received(&buf, read), b"world");
+        drop(client);
+
+        let packets = parse(&buffer.0.lock().unwrap());
+        let segments: Vec<_> = packets
+            .iter()
+            .map(|p| {
+                let (flags, payload) = tcp_segment(&p.data);
+                (p.flags, flags, payload.to_vec())
+            })
+            .collect();
+
+        let inbound = Direction::Inbound.epb_flags();
+        let outbound = Direction::Outbound.epb_flags();
+        assert_eq!(
+            segments,
+            vec![
+                (outbound, TCP_SYN, vec![]),
+                (inbound, TCP_SYN | TCP_ACK, vec![]),
+                (outbound, TCP_ACK, vec![]),
+                (outbound, TCP_PSH | TCP_ACK, b"hello".to_vec()),
+                (inbound, TCP_PSH | TCP_ACK, b"world".to_vec()),
+                (outbound, TCP_FIN | TCP_ACK, vec![]),
+            ]
+        );
+        // the guest is the source of everything it sent
+        let src_port = |p: &Packet| u16::from_be_bytes([p.data[20], p.data[21]]);
+        assert_eq!(src_port(&packets[0]), 5000);
+        assert_eq!(src_port(&packets[1]), 8080);
+    }
+
+    #[test]
+    fn large_writes_are_split_into_segments() {
+        let buffer = SharedBuffer::default();
+        let capture = PcapCapture::new(buffer.clone()).unwrap();
+        let mut stream = TcpStream::open(
+            capture,
+            "10.0.0.2:1000".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+            Direction::Outbound,
+        );
+
+        stream.data(Direction::Outbound, &vec![7; MAX_SEGMENT_SIZE * 2 + 1]);
+        stream.reset(Direction::Inbound);
+        // nothing is sent after a reset
+        stream.fin(Direction::Outbound);
+
+        let packets = parse(&buffer.0.lock().unwrap());
+        let segments: Vec<_> = packets[3..]
+            .iter()
+            .map(|p| {
+                let (flags, payload) = tcp_segment(&p.data);
+                let seq = u32::from_be_bytes(p.data[24..28].try_into().unwrap());
+                (flags, seq, payload.len())
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            vec![
+                (TCP_PSH | TCP_ACK, 1, MAX_SEGMENT_SIZE),
+                (
+                    TCP_PSH | TCP_ACK,
+                    1 + MAX_SEGMENT_SIZE as u32,
+                    MAX_SEGMENT_SIZE
+                ),
+                (TCP_PSH | TCP_ACK, 1 + 2 * MAX_SEGMENT_SIZE as u32, 1),
+                (TCP_RST | TCP_ACK, 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_writes_stop_the_capture() {
+        /// Fails every write once it has been unplugged
+        #[derive(Debug, Clone, Default)]
+        struct Unplugged {
+            unplugged: Arc<Mutex<bool>>,
+            written: Arc<Mutex<usize>>,
+        }
+
+        impl Write for Unplugged {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if *self.unplugged.lock().unwrap() {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                *self.written.lock().unwrap() += buf.len();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Unplugged::default();
+        let capture = PcapCapture::new(out.clone()).unwrap();
+        *out.unplugged.lock().unwrap() = true;
+
+        capture.record_frame(Direction::Outbound, &[0; 32]);
+        assert!(capture.writer.lock().unwrap().failed);
+
+        // plugging it back in doesn't resume a capture that lost packets
+        *out.unplugged.lock().unwrap() = false;
+        let header = *out.written.lock().unwrap();
+        capture.record_frame(Direction::Inbound, &[0; 32]);
+        assert_eq!(*out.written.lock().unwrap(), header);
+    }
+}
@@ -162,6 +162,27 @@ impl RemoteNetworkingServer {
         Self::new(tx, rx, rx_work, inner)
     }
 
+    /// Serves the networking to a client connected over a Unix socket
+    #[cfg(all(unix, feature = "remote-ipc"))]
+    pub fn new_from_unix_stream(
+        stream: tokio::net::UnixStream,
+        format: FrameSerializationFormat,
+        inner: Arc<dyn VirtualNetworking + Send + Sync + 'static>,
+    ) -> (Self, RemoteNetworkingServerDriver) {
+        let (rx, tx) = stream.into_split();
+        Self::new_from_async_io(tx, rx, format, inner)
+    }
+
+    /// Serves the networking to a client connected to the standard input
+    /// and output of this process, usually the process that spawned it
+    #[cfg(feature = "remote-ipc")]
+    pub fn new_from_stdio(
+        format: FrameSerializationFormat,
+        inner: Arc<dyn VirtualNetworking + Send + Sync + 'static>,
+    ) -> (Self, RemoteNetworkingServerDriver) {
+        Self::new_from_async_io(tokio::io::stdout(), tokio::io::stdin(), format, inner)
+    }
+
     /// Creates a new interface on the remote location using
     /// a unique interface ID and a pair of channels
     #[cfg(feature = "hyper")]
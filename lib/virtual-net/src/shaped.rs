@@ -0,0 +1,1779 @@
+//! A [`VirtualNetworking`] decorator which makes the network behave badly on
+//! purpose so it is possible to test how a guest copes with latency, jitter,
+//! slow links, packet loss, connection resets and DNS failures.
+//!
+//! Impairments are described by a [`NetworkProfile`], usually loaded from a
+//! file like this:
+//!
+//! ```toml
+//! seed = 42
+//!
+//! [[rules]]
+//! cidr = "10.0.0.0/8"
+//! latency_ms = 5
+//!
+//! [[rules]]
+//! direction = "inbound"
+//! latency_ms = 100
+//! jitter_ms = 20
+//! bandwidth = 125000
+//! loss = 0.01
+//!
+//! [[rules]]
+//! direction = "outbound"
+//! latency_ms = 100
+//! reset = 0.001
+//! refuse = 0.05
+//!
+//! [dns]
+//! latency_ms = 50
+//! failure = 0.1
+//! ```
+//!
+//! For every remote address and direction the first matching rule is used.
+//! Datagrams that are "lost" are dropped, while lost stream segments are
+//! delayed by a retransmission timeout just like a real TCP stack would.
+//! All the random decisions come from a generator seeded by `seed`, so a
+//! guest that does the same thing gets the same faults on every run.
+//!
+//! Delayed data is moved between the guest and the wrapped implementation
+//! by a [`ShapedNetworkingDriver`] which must be polled for the sockets to
+//! make progress. ICMP and raw sockets are passed through untouched.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use derivative::Derivative;
+use serde::{Deserialize, Deserializer};
+use tokio::time::{Instant, Sleep};
+use virtual_mio::InterestType;
+
+use crate::{
+    DynVirtualNetworking, ForwardProtocol, InterestHandler, IpCidr, IpRoute, NetworkError,
+    PortForward, Result, SocketStatus, StreamSecurity, VirtualConnectedSocket,
+    VirtualConnectionlessSocket, VirtualIcmpSocket, VirtualIoSource, VirtualNetworking,
+    VirtualRawSocket, VirtualSocket, VirtualTcpListener, VirtualTcpSocket, VirtualUdpSocket,
+};
+
+/// How long a lost stream segment is held back for
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+/// Most bytes buffered in each direction of a shaped socket
+const MAX_QUEUED_BYTES: usize = 256 * 1024;
+const RECV_CHUNK_SIZE: usize = 16 * 1024;
+const MAX_DATAGRAM_SIZE: usize = 65_536;
+
+/// Which traffic a [`NetworkRule`] applies to, from the point of view of
+/// the guest
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleDirection {
+    /// Traffic received by the guest
+    Inbound,
+    /// Traffic sent by the guest
+    Outbound,
+    #[default]
+    Both,
+}
+
+/// How badly traffic is treated
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Impairment {
+    /// Fixed delay added to everything, in milliseconds
+    pub latency_ms: u64,
+    /// Up to this many milliseconds of extra random delay
+    pub jitter_ms: u64,
+    /// Maximum throughput in bytes per second
+    pub bandwidth: Option<u64>,
+    /// Chance (between 0 and 1) that a datagram or stream segment is lost
+    pub loss: f64,
+    /// Chance that any given read or write resets a TCP connection
+    pub reset: f64,
+    /// Chance that an outgoing TCP connection is refused
+    pub refuse: f64,
+}
+
+impl Impairment {
+    fn is_noop(&self) -> bool {
+        self.latency_ms == 0
+            && self.jitter_ms == 0
+            && self.bandwidth.is_none()
+            && self.loss <= 0.0
+            && self.reset <= 0.0
+            && self.refuse <= 0.0
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::from_millis(self.latency_ms)
+    }
+}
+
+/// Impairments for the traffic to and from a range of addresses
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct NetworkRule {
+    /// The remote addresses this rule applies to, or all of them if empty
+    #[serde(default, deserialize_with = "deserialize_cidr")]
+    pub cidr: Option<IpCidr>,
+    #[serde(default)]
+    pub direction: RuleDirection,
+    #[serde(flatten)]
+    pub impairment: Impairment,
+}
+
+impl NetworkRule {
+    pub fn new(impairment: Impairment) -> Self {
+        NetworkRule {
+            cidr: None,
+            direction: RuleDirection::Both,
+            impairment,
+        }
+    }
+
+    pub fn with_cidr(mut self, cidr: IpCidr) -> Self {
+        self.cidr = Some(cidr);
+        self
+    }
+
+    pub fn with_direction(mut self, direction: RuleDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    fn matches(&self, ip: IpAddr, direction: RuleDirection) -> bool {
+        (self.direction == RuleDirection::Both || self.direction == direction)
+            && self.cidr.map_or(true, |cidr| cidr.contains(ip))
+    }
+}
+
+/// Failures injected into name resolution
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DnsImpairment {
+    /// Delay added to every lookup, in milliseconds
+    pub latency_ms: u64,
+    /// Chance (between 0 and 1) that a lookup times out
+    pub failure: f64,
+}
+
+/// A set of [`NetworkRule`]s.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct NetworkProfile {
+    /// Seed for the random decisions, a random one is used if not set
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub rules: Vec<NetworkRule>,
+    #[serde(default)]
+    pub dns: DnsImpairment,
+}
+
+impl NetworkProfile {
+    pub fn new() -> Self {
+        NetworkProfile::default()
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn with_rule(mut self, rule: NetworkRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn with_dns(mut self, dns: DnsImpairment) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    /// Finds the impairment for traffic to or from `ip`, if there is any
+    fn impairment(&self, ip: IpAddr, direction: RuleDirection) -> Option<&Impairment> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(ip, direction))
+            .map(|rule| &rule.impairment)
+            .filter(|impairment| !impairment.is_noop())
+    }
+}
+
+fn deserialize_cidr<'de, D>(deserializer: D) -> std::result::Result<Option<IpCidr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+    let (ip, prefix) = match raw.split_once('/') {
+        Some((ip, prefix)) => (ip, Some(prefix)),
+        None => (raw.as_str(), None),
+    };
+    let ip: IpAddr = ip.parse().map_err(serde::de::Error::custom)?;
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse().map_err(serde::de::Error::custom)?,
+        None if ip.is_ipv4() => 32,
+        None => 128,
+    };
+    Ok(Some(IpCidr { ip, prefix }))
+}
+
+/// A small deterministic random number generator (SplitMix64)
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        let sample = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        sample < probability
+    }
+
+    /// Total delay for one packet (latency plus a random amount of jitter)
+    fn delay(&mut self, impairment: &Impairment) -> Duration {
+        let jitter = match impairment.jitter_ms {
+            0 => 0,
+            max => self.next_u64() % (max + 1),
+        };
+        impairment.latency() + Duration::from_millis(jitter)
+    }
+}
+
+fn random_seed() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+/// Something the driver moves data through
+trait Shaper: Send {
+    /// Moves any data that is due and returns when it needs to run again
+    fn pump(&mut self, shared: &Shared, now: Instant) -> Option<Instant>;
+
+    /// Whether a socket that was dropped by the guest has finished sending
+    fn drained(&self) -> bool;
+}
+
+type SharedShaper = Arc<Mutex<dyn Shaper>>;
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct Shared {
+    profile: NetworkProfile,
+    rng: Mutex<Rng>,
+    #[derivative(Debug = "ignore")]
+    driver: Mutex<DriverState>,
+}
+
+#[derive(Default)]
+struct DriverState {
+    shapers: Vec<Weak<Mutex<dyn Shaper>>>,
+    /// Sockets the guest closed which still have data to send
+    lingering: Vec<SharedShaper>,
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn wake(&self) {
+        if let Some(waker) = self.driver.lock().unwrap().waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn register(&self, shaper: &SharedShaper) {
+        self.driver
+            .lock()
+            .unwrap()
+            .shapers
+            .push(Arc::downgrade(shaper));
+        self.wake();
+    }
+
+    fn linger(&self, shaper: SharedShaper) {
+        self.driver.lock().unwrap().lingering.push(shaper);
+        self.wake();
+    }
+
+    fn chance(&self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.lock().unwrap().chance(probability)
+    }
+
+    fn delay(&self, impairment: &Impairment) -> Duration {
+        self.rng.lock().unwrap().delay(impairment)
+    }
+
+    fn impairment(&self, ip: IpAddr, direction: RuleDirection) -> Option<Impairment> {
+        self.profile.impairment(ip, direction).cloned()
+    }
+}
+
+/// Installed on the wrapped sockets so the driver runs when they are ready
+struct DriverHandler {
+    shared: Arc<Shared>,
+}
+
+impl InterestHandler for DriverHandler {
+    fn push_interest(&mut self, _interest: InterestType) {
+        self.shared.wake();
+    }
+
+    fn pop_interest(&mut self, _interest: InterestType) -> bool {
+        false
+    }
+
+    fn has_interest(&self, _interest: InterestType) -> bool {
+        false
+    }
+}
+
+/// A networking implementation that impairs the traffic of another
+/// implementation according to a [`NetworkProfile`]
+#[derive(Debug, Clone)]
+pub struct ShapedNetworking {
+    inner: DynVirtualNetworking,
+    shared: Arc<Shared>,
+}
+
+impl ShapedNetworking {
+    /// Wraps a networking implementation, the returned driver needs to be
+    /// polled for data to flow through shaped sockets
+    pub fn new(
+        inner: DynVirtualNetworking,
+        profile: NetworkProfile,
+    ) -> (Self, ShapedNetworkingDriver) {
+        let seed = profile.seed.unwrap_or_else(random_seed);
+        let shared = Arc::new(Shared {
+            profile,
+            rng: Mutex::new(Rng(seed)),
+            driver: Mutex::new(DriverState::default()),
+        });
+
+        let driver = ShapedNetworkingDriver {
+            shared: shared.clone(),
+            sleep: None,
+        };
+        (ShapedNetworking { inner, shared }, driver)
+    }
+
+    pub fn profile(&self) -> &NetworkProfile {
+        &self.shared.profile
+    }
+
+    pub fn inner(&self) -> &DynVirtualNetworking {
+        &self.inner
+    }
+
+    fn wrap_tcp(
+        &self,
+        mut socket: Box<dyn VirtualTcpSocket + Sync>,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        let outbound = self.shared.impairment(peer.ip(), RuleDirection::Outbound);
+        let inbound = self.shared.impairment(peer.ip(), RuleDirection::Inbound);
+        if outbound.is_none() && inbound.is_none() {
+            return Ok(socket);
+        }
+
+        socket.set_handler(Box::new(DriverHandler {
+            shared: self.shared.clone(),
+        }))?;
+
+        let now = Instant::now();
+        let state = Arc::new(Mutex::new(TcpShaper {
+            inner: socket,
+            outbound: outbound.unwrap_or_default(),
+            inbound: inbound.unwrap_or_default(),
+            tx: StreamQueue::new(now),
+            rx: StreamQueue::new(now),
+            inner_eof: false,
+            error: None,
+            pending: None,
+            handler: None,
+            read_wakers: Vec::new(),
+            write_wakers: Vec::new(),
+            rx_notified: false,
+            tx_blocked: false,
+        }));
+        let shaper: SharedShaper = state.clone();
+        self.shared.register(&shaper);
+
+        Ok(Box::new(ShapedTcpSocket {
+            state,
+            shared: self.shared.clone(),
+        }))
+    }
+}
+
+impl Drop for ShapedNetworking {
+    fn drop(&mut self) {
+        // lets the driver notice when it is no longer needed
+        self.shared.wake();
+    }
+}
+
+/// Moves the delayed data of the sockets created by a [`ShapedNetworking`]
+///
+/// The driver finishes once the networking implementation and all of its
+/// sockets have been dropped.
+#[derive(Debug)]
+pub struct ShapedNetworkingDriver {
+    shared: Arc<Shared>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Future for ShapedNetworkingDriver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            let shapers: Vec<SharedShaper> = {
+                let mut driver = self.shared.driver.lock().unwrap();
+                driver.waker = Some(cx.waker().clone());
+                driver.shapers.retain(|s| s.strong_count() > 0);
+                driver
+                    .shapers
+                    .iter()
+                    .filter_map(|s| s.upgrade())
+                    .chain(driver.lingering.iter().cloned())
+                    .collect()
+            };
+
+            let now = Instant::now();
+            let mut next: Option<Instant> = None;
+            for shaper in shapers.iter() {
+                let mut shaper = shaper.lock().unwrap();
+                if let Some(deadline) = shaper.pump(&self.shared, now) {
+                    next = Some(next.map_or(deadline, |next| next.min(deadline)));
+                }
+            }
+            drop(shapers);
+
+            let idle = {
+                let mut driver = self.shared.driver.lock().unwrap();
+                driver
+                    .lingering
+                    .retain(|shaper| !shaper.lock().unwrap().drained());
+                driver.shapers.retain(|s| s.strong_count() > 0);
+                driver.shapers.is_empty() && driver.lingering.is_empty()
+            };
+            if idle && Arc::strong_count(&self.shared) == 1 {
+                return Poll::Ready(());
+            }
+
+            match next {
+                Some(deadline) => {
+                    let sleep = self
+                        .sleep
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+                    sleep.as_mut().reset(deadline);
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        continue;
+                    }
+                }
+                None => self.sleep = None,
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for ShapedNetworking {
+    async fn bridge(
+        &self,
+        network: &str,
+        access_token: &str,
+        security: StreamSecurity,
+    ) -> Result<()> {
+        self.inner.bridge(network, access_token, security).await
+    }
+
+    async fn unbridge(&self) -> Result<()> {
+        self.inner.unbridge().await
+    }
+
+    async fn dhcp_acquire(&self) -> Result<Vec<IpAddr>> {
+        self.inner.dhcp_acquire().await
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> Result<()> {
+        self.inner.ip_add(ip, prefix).await
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> Result<()> {
+        self.inner.ip_remove(ip).await
+    }
+
+    async fn ip_clear(&self) -> Result<()> {
+        self.inner.ip_clear().await
+    }
+
+    async fn ip_list(&self) -> Result<Vec<IpCidr>> {
+        self.inner.ip_list().await
+    }
+
+    async fn mac(&self) -> Result<[u8; 6]> {
+        self.inner.mac().await
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> Result<()> {
+        self.inner.gateway_set(ip).await
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> Result<()> {
+        self.inner
+            .route_add(cidr, via_router, preferred_until, expires_at)
+            .await
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> Result<()> {
+        self.inner.route_remove(cidr).await
+    }
+
+    async fn route_clear(&self) -> Result<()> {
+        self.inner.route_clear().await
+    }
+
+    async fn route_list(&self) -> Result<Vec<IpRoute>> {
+        self.inner.route_list().await
+    }
+
+    async fn bind_raw(&self) -> Result<Box<dyn VirtualRawSocket + Sync>> {
+        self.inner.bind_raw().await
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>> {
+        let inner = self
+            .inner
+            .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+            .await?;
+        if self.shared.profile.rules.is_empty() {
+            return Ok(inner);
+        }
+        Ok(Box::new(ShapedTcpListener {
+            inner,
+            networking: self.clone(),
+        }))
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>> {
+        let mut inner = self.inner.bind_udp(addr, reuse_port, reuse_addr).await?;
+        if self.shared.profile.rules.is_empty() {
+            return Ok(inner);
+        }
+
+        inner.set_handler(Box::new(DriverHandler {
+            shared: self.shared.clone(),
+        }))?;
+
+        let now = Instant::now();
+        let state = Arc::new(Mutex::new(UdpShaper {
+            inner,
+            tx: DatagramQueue::new(now),
+            rx: DatagramQueue::new(now),
+            handler: None,
+            read_wakers: Vec::new(),
+            write_wakers: Vec::new(),
+            rx_notified: false,
+            tx_blocked: false,
+        }));
+        let shaper: SharedShaper = state.clone();
+        self.shared.register(&shaper);
+
+        Ok(Box::new(ShapedUdpSocket {
+            state,
+            shared: self.shared.clone(),
+        }))
+    }
+
+    async fn bind_icmp(&self, addr: IpAddr) -> Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        self.inner.bind_icmp(addr).await
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        let outbound = self.shared.impairment(peer.ip(), RuleDirection::Outbound);
+        let inbound = self.shared.impairment(peer.ip(), RuleDirection::Inbound);
+
+        // the handshake takes a full round trip
+        let mut round_trip = Duration::ZERO;
+        if let Some(outbound) = &outbound {
+            round_trip += self.shared.delay(outbound);
+        }
+        if let Some(inbound) = &inbound {
+            round_trip += self.shared.delay(inbound);
+        }
+        if !round_trip.is_zero() {
+            tokio::time::sleep(round_trip).await;
+        }
+
+        if let Some(outbound) = &outbound {
+            if self.shared.chance(outbound.refuse) {
+                return Err(NetworkError::ConnectionRefused);
+            }
+        }
+
+        let socket = self.inner.connect_tcp(addr, peer).await?;
+        self.wrap_tcp(socket, peer)
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>> {
+        let dns = &self.shared.profile.dns;
+        if dns.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(dns.latency_ms)).await;
+        }
+        if self.shared.chance(dns.failure) {
+            return Err(NetworkError::TimedOut);
+        }
+        self.inner.resolve(host, port, dns_server).await
+    }
+
+    async fn forward_add(&self, forward: PortForward) -> Result<PortForward> {
+        self.inner.forward_add(forward).await
+    }
+
+    async fn forward_remove(&self, protocol: ForwardProtocol, host: SocketAddr) -> Result<()> {
+        self.inner.forward_remove(protocol, host).await
+    }
+
+    async fn forward_list(&self) -> Result<Vec<PortForward>> {
+        self.inner.forward_list().await
+    }
+}
+
+#[derive(Debug)]
+struct ShapedTcpListener {
+    inner: Box<dyn VirtualTcpListener + Sync>,
+    networking: ShapedNetworking,
+}
+
+impl VirtualIoSource for ShapedTcpListener {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualTcpListener for ShapedTcpListener {
+    fn try_accept(&mut self) -> Result<(Box<dyn VirtualTcpSocket + Sync>, SocketAddr)> {
+        let (socket, peer) = self.inner.try_accept()?;
+        Ok((self.networking.wrap_tcp(socket, peer)?, peer))
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u8> {
+        self.inner.ttl()
+    }
+}
+
+#[derive(Debug)]
+enum Payload {
+    Data(Bytes),
+    Eof,
+    Error(NetworkError),
+}
+
+#[derive(Debug)]
+struct Chunk {
+    due: Instant,
+    payload: Payload,
+}
+
+/// Data of one direction of a stream waiting for its time to come
+#[derive(Debug)]
+struct StreamQueue {
+    chunks: VecDeque<Chunk>,
+    queued: usize,
+    /// When the link is free again after sending the data already queued
+    link_free: Instant,
+    /// Stream data can't overtake itself, whatever the jitter says
+    last_due: Instant,
+}
+
+impl StreamQueue {
+    fn new(now: Instant) -> Self {
+        StreamQueue {
+            chunks: VecDeque::new(),
+            queued: 0,
+            link_free: now,
+            last_due: now,
+        }
+    }
+
+    fn space(&self) -> usize {
+        MAX_QUEUED_BYTES.saturating_sub(self.queued)
+    }
+
+    fn push(&mut self, shared: &Shared, impairment: &Impairment, now: Instant, payload: Payload) {
+        let len = match &payload {
+            Payload::Data(data) => data.len(),
+            _ => 0,
+        };
+        let sent = transmit(&mut self.link_free, impairment, now, len);
+        let mut due = sent + shared.delay(impairment);
+        if len > 0 && shared.chance(impairment.loss) {
+            due += RETRANSMIT_TIMEOUT;
+        }
+        let due = due.max(self.last_due);
+        self.last_due = due;
+
+        self.queued += len;
+        self.chunks.push_back(Chunk { due, payload });
+    }
+
+    fn is_ready(&self, now: Instant) -> bool {
+        self.chunks.front().map_or(false, |c| c.due <= now)
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.chunks.front().map(|c| c.due)
+    }
+
+    fn clear(&mut self) {
+        self.chunks.clear();
+        self.queued = 0;
+    }
+}
+
+/// Works out when the last byte of a packet has left a bandwidth limited
+/// link
+fn transmit(link_free: &mut Instant, impairment: &Impairment, now: Instant, len: usize) -> Instant {
+    match impairment.bandwidth {
+        Some(bandwidth) if bandwidth > 0 => {
+            let start = (*link_free).max(now);
+            *link_free = start + Duration::from_secs_f64(len as f64 / bandwidth as f64);
+            *link_free
+        }
+        _ => now,
+    }
+}
+
+/// Something the guest asked for that has to wait until all the queued
+/// data was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingClose {
+    Shutdown,
+    Close,
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct TcpShaper {
+    inner: Box<dyn VirtualTcpSocket + Sync>,
+    outbound: Impairment,
+    inbound: Impairment,
+    tx: StreamQueue,
+    rx: StreamQueue,
+    /// The wrapped socket has no more data
+    inner_eof: bool,
+    /// Fails every further operation (e.g. after an injected reset)
+    error: Option<NetworkError>,
+    pending: Option<PendingClose>,
+    #[derivative(Debug = "ignore")]
+    handler: Option<Box<dyn InterestHandler + Send + Sync>>,
+    read_wakers: Vec<Waker>,
+    write_wakers: Vec<Waker>,
+    rx_notified: bool,
+    tx_blocked: bool,
+}
+
+impl TcpShaper {
+    fn reset(&mut self) {
+        tracing::debug!(peer = ?self.inner.addr_peer().ok(), "Injecting a connection reset");
+        self.error = Some(NetworkError::ConnectionReset);
+        self.tx.clear();
+        self.rx.clear();
+        let _ = self.inner.shutdown(Shutdown::Both);
+        self.notify(InterestType::Closed);
+        self.notify(InterestType::Readable);
+        self.notify(InterestType::Writable);
+    }
+
+    fn notify(&mut self, interest: InterestType) {
+        if let Some(handler) = self.handler.as_mut() {
+            handler.push_interest(interest);
+        }
+        let wakers = match interest {
+            InterestType::Writable => &mut self.write_wakers,
+            _ => &mut self.read_wakers,
+        };
+        wakers.drain(..).for_each(|w| w.wake());
+    }
+
+    fn flush_tx(&mut self, now: Instant) {
+        while let Some(chunk) = self.tx.chunks.front_mut() {
+            if chunk.due > now {
+                break;
+            }
+            let Payload::Data(data) = &mut chunk.payload else {
+                self.tx.chunks.pop_front();
+                continue;
+            };
+            match self.inner.try_send(data) {
+                Ok(sent) => {
+                    data.advance(sent);
+                    self.tx.queued -= sent;
+                    if data.is_empty() {
+                        self.tx.chunks.pop_front();
+                    }
+                }
+                Err(NetworkError::WouldBlock) => break,
+                Err(err) => {
+                    self.error = Some(err);
+                    self.tx.clear();
+                    self.notify(InterestType::Writable);
+                    break;
+                }
+            }
+        }
+
+        if self.tx.chunks.is_empty() {
+            match self.pending.take() {
+                Some(PendingClose::Shutdown) => {
+                    let _ = self.inner.shutdown(Shutdown::Write);
+                }
+                Some(PendingClose::Close) => {
+                    let _ = self.inner.close();
+                }
+                None => {}
+            }
+        }
+        if self.tx_blocked && self.tx.space() > 0 {
+            self.tx_blocked = false;
+            self.notify(InterestType::Writable);
+        }
+    }
+
+    fn fill_rx(&mut self, shared: &Shared, now: Instant) {
+        let mut buf = [MaybeUninit::<u8>::uninit(); RECV_CHUNK_SIZE];
+        while !self.inner_eof && self.rx.space() > 0 {
+            let payload = match self.inner.try_recv(&mut buf) {
+                Ok(0) => {
+                    self.inner_eof = true;
+                    Payload::Eof
+                }
+                Ok(read) => {
+                    if shared.chance(self.inbound.reset) {
+                        self.reset();
+                        return;
+                    }
+                    // SAFETY: the socket initialized the first `read` bytes
+                    let data =
+                        unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, read) };
+                    Payload::Data(Bytes::copy_from_slice(data))
+                }
+                Err(NetworkError::WouldBlock) => break,
+                Err(err) => {
+                    self.inner_eof = true;
+                    Payload::Error(err)
+                }
+            };
+            let inbound = self.inbound.clone();
+            self.rx.push(shared, &inbound, now, payload);
+        }
+    }
+}
+
+impl Shaper for TcpShaper {
+    fn pump(&mut self, shared: &Shared, now: Instant) -> Option<Instant> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        self.flush_tx(now);
+        self.fill_rx(shared, now);
+        if self.error.is_some() {
+            return None;
+        }
+
+        if self.rx.is_ready(now) && !self.rx_notified {
+            self.rx_notified = true;
+            self.notify(InterestType::Readable);
+        }
+
+        let tx_due = self.tx.next_due().filter(|due| *due > now);
+        let rx_due = self.rx.next_due().filter(|due| *due > now);
+        match (tx_due, rx_due) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn drained(&self) -> bool {
+        self.tx.chunks.is_empty() || self.error.is_some()
+    }
+}
+
+#[derive(Debug)]
+struct ShapedTcpSocket {
+    state: Arc<Mutex<TcpShaper>>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for ShapedTcpSocket {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.handler.take();
+        if !state.drained() {
+            state.pending = Some(PendingClose::Close);
+            drop(state);
+            self.shared.linger(self.state.clone());
+        } else {
+            drop(state);
+            self.shared.wake();
+        }
+    }
+}
+
+impl VirtualIoSource for ShapedTcpSocket {
+    fn remove_handler(&mut self) {
+        self.state.lock().unwrap().handler.take();
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.error {
+            return Poll::Ready(Err(err));
+        }
+        let now = Instant::now();
+        if state.rx.is_ready(now) {
+            return Poll::Ready(Ok(state.rx.queued));
+        }
+        if !state.read_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.read_wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.error {
+            return Poll::Ready(Err(err));
+        }
+        let space = state.tx.space();
+        if space > 0 {
+            return Poll::Ready(Ok(space));
+        }
+        state.tx_blocked = true;
+        if !state.write_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.write_wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl VirtualSocket for ShapedTcpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.state.lock().unwrap().inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.state.lock().unwrap().inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.state.lock().unwrap().inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        let state = self.state.lock().unwrap();
+        match state.error {
+            Some(_) => Ok(SocketStatus::Failed),
+            None => state.inner.status(),
+        }
+    }
+
+    fn set_handler(&mut self, mut handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.error.is_some() {
+            handler.push_interest(InterestType::Closed);
+        }
+        if state.rx.is_ready(Instant::now()) {
+            handler.push_interest(InterestType::Readable);
+        }
+        if state.tx.space() > 0 && state.handler.is_none() {
+            handler.push_interest(InterestType::Writable);
+        }
+        state.handler = Some(handler);
+        Ok(())
+    }
+}
+
+impl VirtualConnectedSocket for ShapedTcpSocket {
+    fn set_linger(&mut self, linger: Option<Duration>) -> Result<()> {
+        self.state.lock().unwrap().inner.set_linger(linger)
+    }
+
+    fn linger(&self) -> Result<Option<Duration>> {
+        self.state.lock().unwrap().inner.linger()
+    }
+
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.error {
+            return Err(err);
+        }
+        if state.pending.is_some() {
+            return Err(NetworkError::BrokenPipe);
+        }
+
+        let amount = data.len().min(state.tx.space());
+        if amount == 0 {
+            state.tx_blocked = true;
+            return Err(NetworkError::WouldBlock);
+        }
+        if self.shared.chance(state.outbound.reset) {
+            state.reset();
+            return Err(NetworkError::ConnectionReset);
+        }
+
+        let outbound = state.outbound.clone();
+        let payload = Payload::Data(Bytes::copy_from_slice(&data[..amount]));
+        state
+            .tx
+            .push(&self.shared, &outbound, Instant::now(), payload);
+        drop(state);
+
+        self.shared.wake();
+        Ok(amount)
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.error {
+            return Err(err);
+        }
+        if !state.tx.chunks.is_empty() {
+            state.tx_blocked = true;
+            return Err(NetworkError::WouldBlock);
+        }
+        state.inner.try_flush()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.tx.chunks.is_empty() {
+            state.inner.close()
+        } else {
+            state.pending = Some(PendingClose::Close);
+            Ok(())
+        }
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.error {
+            return Err(err);
+        }
+
+        let now = Instant::now();
+        let rx = &mut state.rx;
+        let mut read = 0;
+        while read < buf.len() && rx.is_ready(now) {
+            let chunk = rx.chunks.front_mut().unwrap();
+            match &mut chunk.payload {
+                Payload::Data(data) => {
+                    let amount = data.len().min(buf.len() - read);
+                    for (dst, src) in buf[read..read + amount].iter_mut().zip(&data[..amount]) {
+                        dst.write(*src);
+                    }
+                    data.advance(amount);
+                    read += amount;
+                    rx.queued -= amount;
+                    if data.is_empty() {
+                        rx.chunks.pop_front();
+                    }
+                }
+                // end of stream and errors are only reported once the data
+                // before them was read
+                Payload::Eof if read == 0 => return Ok(0),
+                Payload::Error(err) if read == 0 => return Err(*err),
+                _ => break,
+            }
+        }
+
+        if !state.rx.is_ready(now) {
+            state.rx_notified = false;
+        }
+        drop(state);
+        self.shared.wake();
+
+        if read == 0 && !buf.is_empty() {
+            return Err(NetworkError::WouldBlock);
+        }
+        Ok(read)
+    }
+}
+
+impl VirtualTcpSocket for ShapedTcpSocket {
+    fn set_recv_buf_size(&mut self, size: usize) -> Result<()> {
+        self.state.lock().unwrap().inner.set_recv_buf_size(size)
+    }
+
+    fn recv_buf_size(&self) -> Result<usize> {
+        self.state.lock().unwrap().inner.recv_buf_size()
+    }
+
+    fn set_send_buf_size(&mut self, size: usize) -> Result<()> {
+        self.state.lock().unwrap().inner.set_send_buf_size(size)
+    }
+
+    fn send_buf_size(&self) -> Result<usize> {
+        self.state.lock().unwrap().inner.send_buf_size()
+    }
+
+    fn set_nodelay(&mut self, reuse: bool) -> Result<()> {
+        self.state.lock().unwrap().inner.set_nodelay(reuse)
+    }
+
+    fn nodelay(&self) -> Result<bool> {
+        self.state.lock().unwrap().inner.nodelay()
+    }
+
+    fn set_keepalive(&mut self, keepalive: bool) -> Result<()> {
+        self.state.lock().unwrap().inner.set_keepalive(keepalive)
+    }
+
+    fn keepalive(&self) -> Result<bool> {
+        self.state.lock().unwrap().inner.keepalive()
+    }
+
+    fn set_dontroute(&mut self, keepalive: bool) -> Result<()> {
+        self.state.lock().unwrap().inner.set_dontroute(keepalive)
+    }
+
+    fn dontroute(&self) -> Result<bool> {
+        self.state.lock().unwrap().inner.dontroute()
+    }
+
+    fn addr_peer(&self) -> Result<SocketAddr> {
+        self.state.lock().unwrap().inner.addr_peer()
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            state.rx.clear();
+            state.inner.shutdown(Shutdown::Read)?;
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            if state.tx.chunks.is_empty() {
+                state.inner.shutdown(Shutdown::Write)?;
+            } else {
+                state.pending = Some(PendingClose::Shutdown);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_closed(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.error.is_some() || state.inner.is_closed()
+    }
+}
+
+#[derive(Debug)]
+struct Datagram {
+    due: Instant,
+    addr: SocketAddr,
+    data: Bytes,
+}
+
+/// Datagrams in one direction, jitter is allowed to reorder them
+#[derive(Debug)]
+struct DatagramQueue {
+    datagrams: VecDeque<Datagram>,
+    queued: usize,
+    link_free: Instant,
+}
+
+impl DatagramQueue {
+    fn new(now: Instant) -> Self {
+        DatagramQueue {
+            datagrams: VecDeque::new(),
+            queued: 0,
+            link_free: now,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.queued >= MAX_QUEUED_BYTES
+    }
+
+    /// Queues a datagram, returns `false` if it was lost on the way
+    fn push(
+        &mut self,
+        shared: &Shared,
+        impairment: &Impairment,
+        now: Instant,
+        addr: SocketAddr,
+        data: &[u8],
+    ) -> bool {
+        if shared.chance(impairment.loss) {
+            return false;
+        }
+        let sent = transmit(&mut self.link_free, impairment, now, data.len());
+        let due = sent + shared.delay(impairment);
+
+        let index = self.datagrams.partition_point(|d| d.due <= due);
+        self.queued += data.len();
+        self.datagrams.insert(
+            index,
+            Datagram {
+                due,
+                addr,
+                data: Bytes::copy_from_slice(data),
+            },
+        );
+        true
+    }
+
+    fn pop_ready(&mut self, now: Instant) -> Option<Datagram> {
+        if self.datagrams.front()?.due > now {
+            return None;
+        }
+        let datagram = self.datagrams.pop_front()?;
+        self.queued -= datagram.data.len();
+        Some(datagram)
+    }
+
+    fn is_ready(&self, now: Instant) -> bool {
+        self.datagrams.front().map_or(false, |d| d.due <= now)
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.datagrams.front().map(|d| d.due)
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct UdpShaper {
+    inner: Box<dyn VirtualUdpSocket + Sync>,
+    tx: DatagramQueue,
+    rx: DatagramQueue,
+    #[derivative(Debug = "ignore")]
+    handler: Option<Box<dyn InterestHandler + Send + Sync>>,
+    read_wakers: Vec<Waker>,
+    write_wakers: Vec<Waker>,
+    rx_notified: bool,
+    tx_blocked: bool,
+}
+
+impl UdpShaper {
+    fn notify(&mut self, interest: InterestType) {
+        if let Some(handler) = self.handler.as_mut() {
+            handler.push_interest(interest);
+        }
+        let wakers = match interest {
+            InterestType::Writable => &mut self.write_wakers,
+            _ => &mut self.read_wakers,
+        };
+        wakers.drain(..).for_each(|w| w.wake());
+    }
+}
+
+impl Shaper for UdpShaper {
+    fn pump(&mut self, shared: &Shared, now: Instant) -> Option<Instant> {
+        while let Some(datagram) = self.tx.datagrams.front() {
+            if datagram.due > now {
+                break;
+            }
+            match self.inner.try_send_to(&datagram.data, datagram.addr) {
+                Err(NetworkError::WouldBlock) => break,
+                Ok(_) | Err(_) => {
+                    // datagrams that can't be sent are dropped, like UDP does
+                    self.tx.pop_ready(now);
+                }
+            }
+        }
+        if self.tx_blocked && !self.tx.is_full() {
+            self.tx_blocked = false;
+            self.notify(InterestType::Writable);
+        }
+
+        let mut buf = vec![MaybeUninit::<u8>::uninit(); MAX_DATAGRAM_SIZE];
+        while !self.rx.is_full() {
+            match self.inner.try_recv_from(&mut buf) {
+                Ok((read, addr)) => {
+                    // SAFETY: the socket initialized the first `read` bytes
+                    let data =
+                        unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, read) };
+                    let impairment = shared
+                        .impairment(addr.ip(), RuleDirection::Inbound)
+                        .unwrap_or_default();
+                    self.rx.push(shared, &impairment, now, addr, data);
+                }
+                Err(_) => break,
+            }
+        }
+
+        if self.rx.is_ready(now) && !self.rx_notified {
+            self.rx_notified = true;
+            self.notify(InterestType::Readable);
+        }
+
+        let tx_due = self.tx.next_due().filter(|due| *due > now);
+        let rx_due = self.rx.next_due().filter(|due| *due > now);
+        match (tx_due, rx_due) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn drained(&self) -> bool {
+        self.tx.datagrams.is_empty()
+    }
+}
+
+#[derive(Debug)]
+struct ShapedUdpSocket {
+    state: Arc<Mutex<UdpShaper>>,
+    shared: Arc<Shared>,
+}
+
+impl Drop for ShapedUdpSocket {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.handler.take();
+        if !state.drained() {
+            drop(state);
+            self.shared.linger(self.state.clone());
+        } else {
+            drop(state);
+            self.shared.wake();
+        }
+    }
+}
+
+impl VirtualIoSource for ShapedUdpSocket {
+    fn remove_handler(&mut self) {
+        self.state.lock().unwrap().handler.take();
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if state.rx.is_ready(Instant::now()) {
+            return Poll::Ready(Ok(state.rx.queued));
+        }
+        if !state.read_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.read_wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.tx.is_full() {
+            return Poll::Ready(Ok(MAX_QUEUED_BYTES - state.tx.queued));
+        }
+        state.tx_blocked = true;
+        if !state.write_wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.write_wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl VirtualSocket for ShapedUdpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.state.lock().unwrap().inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.state.lock().unwrap().inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.state.lock().unwrap().inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.state.lock().unwrap().inner.status()
+    }
+
+    fn set_handler(&mut self, mut handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.rx.is_ready(Instant::now()) {
+            handler.push_interest(InterestType::Readable);
+        }
+        if !state.tx.is_full() && state.handler.is_none() {
+            handler.push_interest(InterestType::Writable);
+        }
+        state.handler = Some(handler);
+        Ok(())
+    }
+}
+
+impl VirtualConnectionlessSocket for ShapedUdpSocket {
+    fn try_send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let impairment = self.shared.impairment(addr.ip(), RuleDirection::Outbound);
+        if impairment.is_none() && state.tx.datagrams.is_empty() {
+            return state.inner.try_send_to(data, addr);
+        }
+        if state.tx.is_full() {
+            state.tx_blocked = true;
+            return Err(NetworkError::WouldBlock);
+        }
+
+        let impairment = impairment.unwrap_or_default();
+        let now = Instant::now();
+        if state.tx.push(&self.shared, &impairment, now, addr, data) {
+            drop(state);
+            self.shared.wake();
+        }
+        Ok(data.len())
+    }
+
+    fn try_recv_from(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<(usize, SocketAddr)> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let datagram = state.rx.pop_ready(now);
+        if !state.rx.is_ready(now) {
+            state.rx_notified = false;
+        }
+        drop(state);
+
+        let Some(datagram) = datagram else {
+            return Err(NetworkError::WouldBlock);
+        };
+        self.shared.wake();
+
+        // like a real UDP socket the rest of a datagram that doesn't fit
+        // into the buffer is discarded
+        let amount = datagram.data.len().min(buf.len());
+        for (dst, src) in buf[..amount].iter_mut().zip(&datagram.data[..amount]) {
+            dst.write(*src);
+        }
+        Ok((amount, datagram.addr))
+    }
+}
+
+impl VirtualUdpSocket for ShapedUdpSocket {
+    fn set_broadcast(&mut self, broadcast: bool) -> Result<()> {
+        self.state.lock().unwrap().inner.set_broadcast(broadcast)
+    }
+
+    fn broadcast(&self) -> Result<bool> {
+        self.state.lock().unwrap().inner.broadcast()
+    }
+
+    fn set_multicast_loop_v4(&mut self, val: bool) -> Result<()> {
+        self.state.lock().unwrap().inner.set_multicast_loop_v4(val)
+    }
+
+    fn multicast_loop_v4(&self) -> Result<bool> {
+        self.state.lock().unwrap().inner.multicast_loop_v4()
+    }
+
+    fn set_multicast_loop_v6(&mut self, val: bool) -> Result<()> {
+        self.state.lock().unwrap().inner.set_multicast_loop_v6(val)
+    }
+
+    fn multicast_loop_v6(&self) -> Result<bool> {
+        self.state.lock().unwrap().inner.multicast_loop_v6()
+    }
+
+    fn set_multicast_ttl_v4(&mut self, ttl: u32) -> Result<()> {
+        self.state.lock().unwrap().inner.set_multicast_ttl_v4(ttl)
+    }
+
+    fn multicast_ttl_v4(&self) -> Result<u32> {
+        self.state.lock().unwrap().inner.multicast_ttl_v4()
+    }
+
+    fn join_multicast_v4(&mut self, multiaddr: Ipv4Addr, iface: Ipv4Addr) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .inner
+            .join_multicast_v4(multiaddr, iface)
+    }
+
+    fn leave_multicast_v4(&mut self, multiaddr: Ipv4Addr, iface: Ipv4Addr) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .inner
+            .leave_multicast_v4(multiaddr, iface)
+    }
+
+    fn join_multicast_v6(&mut self, multiaddr: Ipv6Addr, iface: u32) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .inner
+            .join_multicast_v6(multiaddr, iface)
+    }
+
+    fn leave_multicast_v6(&mut self, multiaddr: Ipv6Addr, iface: u32) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .inner
+            .leave_multicast_v6(multiaddr, iface)
+    }
+
+    fn addr_peer(&self) -> Result<Option<SocketAddr>> {
+        self.state.lock().unwrap().inner.addr_peer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{LoopbackNetworking, VirtualConnectedSocketExt, VirtualTcpListenerExt};
+
+    use super::*;
+
+    const SERVER: &str = "127.0.0.1:8080";
+
+    /// Accepts a connection through the shaped networking and returns the
+    /// shaped server side along with the unshaped client side
+    async fn connect(
+        profile: NetworkProfile,
+    ) -> (
+        ShapedNetworking,
+        Box<dyn VirtualTcpSocket + Sync>,
+        Box<dyn VirtualTcpSocket + Sync>,
+    ) {
+        let loopback = LoopbackNetworking::new();
+        let (networking, driver) = ShapedNetworking::new(Arc::new(loopback.clone()), profile);
+        tokio::spawn(driver);
+
+        let addr: SocketAddr = SERVER.parse().unwrap();
+        let mut listener = networking
+            .listen_tcp(addr, false, false, false)
+            .await
+            .unwrap();
+        let client = loopback
+            .loopback_connect_to("127.0.0.1:5000".parse().unwrap(), addr)
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        (networking, server, Box::new(client))
+    }
+
+    async fn recv_exact(socket: &mut Box<dyn VirtualTcpSocket + Sync>, len: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        while received.len() < len {
+            let mut buf = [MaybeUninit::uninit(); 1024];
+            let read = socket.recv(&mut buf).await.unwrap();
+            assert_ne!(read, 0, "unexpected end of stream");
+            received.extend(buf[..read].iter().map(|b| unsafe { b.assume_init() }));
+        }
+        received
+    }
+
+    fn profile(impairment: Impairment) -> NetworkProfile {
+        NetworkProfile::new()
+            .with_seed(1)
+            .with_rule(NetworkRule::new(impairment))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_delays_both_directions() {
+        let (_networking, mut server, mut client) = connect(profile(Impairment {
+            latency_ms: 100,
+            ..Default::default()
+        }))
+        .await;
+
+        let start = Instant::now();
+        client.send(b"ping").await.unwrap();
+        assert_eq!(recv_exact(&mut server, 4).await, b"ping");
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+        server.send(b"pong").await.unwrap();
+        assert_eq!(recv_exact(&mut client, 4).await, b"pong");
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bandwidth_limits_throughput() {
+        let (_networking, mut server, mut client) = connect(profile(Impairment {
+            bandwidth: Some(1000),
+            ..Default::default()
+        }))
+        .await;
+
+        let start = Instant::now();
+        server.send(&[7; 500]).await.unwrap();
+        assert_eq!(recv_exact(&mut client, 500).await, vec![7; 500]);
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_segments_are_retransmitted() {
+        let (_networking, mut server, mut client) = connect(profile(Impairment {
+            loss: 1.0,
+            ..Default::default()
+        }))
+        .await;
+
+        let start = Instant::now();
+        server.send(b"hello").await.unwrap();
+        assert_eq!(recv_exact(&mut client, 5).await, b"hello");
+        assert_eq!(start.elapsed(), RETRANSMIT_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resets_are_reproducible() {
+        async fn sends_before_reset(seed: u64) -> usize {
+            let profile = NetworkProfile::new().with_seed(seed).with_rule(
+                NetworkRule::new(Impairment {
+                    reset: 0.2,
+                    ..Default::default()
+                })
+                .with_direction(RuleDirection::Outbound),
+            );
+            let (_networking, mut server, _client) = connect(profile).await;
+
+            let mut sends = 0;
+            loop {
+                match server.send(b"x").await {
+                    Ok(_) => sends += 1,
+                    Err(err) => {
+                        assert_eq!(err, NetworkError::ConnectionReset);
+                        break;
+                    }
+                }
+            }
+            // the connection stays broken
+            assert_eq!(server.send(b"x").await, Err(NetworkError::ConnectionReset));
+            sends
+        }
+
+        let first = sends_before_reset(42).await;
+        assert_eq!(sends_before_reset(42).await, first);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_connections_and_dns_failures() {
+        let profile = profile(Impairment {
+            latency_ms: 10,
+            refuse: 1.0,
+            ..Default::default()
+        })
+        .with_dns(DnsImpairment {
+            latency_ms: 50,
+            failure: 1.0,
+        });
+        let (networking, driver) =
+            ShapedNetworking::new(Arc::new(LoopbackNetworking::new()), profile);
+        tokio::spawn(driver);
+
+        let start = Instant::now();
+        let result = networking
+            .connect_tcp("0.0.0.0:0".parse().unwrap(), SERVER.parse().unwrap())
+            .await;
+        assert_eq!(result.err(), Some(NetworkError::ConnectionRefused));
+        // the refusal takes a round trip to arrive
+        assert_eq!(start.elapsed(), Duration::from_millis(20));
+
+        let start = Instant::now();
+        let result = networking.resolve("example.com", None, None).await;
+        assert_eq!(result, Err(NetworkError::TimedOut));
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let local = IpCidr {
+            ip: Ipv4Addr::new(10, 0, 0, 0).into(),
+            prefix: 8,
+        };
+        let profile = NetworkProfile::new()
+            .with_rule(
+                NetworkRule::new(Impairment {
+                    latency_ms: 1,
+                    ..Default::default()
+                })
+                .with_cidr(local),
+            )
+            .with_rule(
+                NetworkRule::new(Impairment {
+                    latency_ms: 100,
+                    ..Default::default()
+                })
+                .with_direction(RuleDirection::Inbound),
+            );
+
+        let latency = |ip: [u8; 4], direction| {
+            profile
+                .impairment(Ipv4Addr::from(ip).into(), direction)
+                .map(|i| i.latency_ms)
+        };
+        assert_eq!(latency([10, 1, 2, 3], RuleDirection::Outbound), Some(1));
+        assert_eq!(latency([10, 1, 2, 3], RuleDirection::Inbound), Some(1));
+        assert_eq!(latency([8, 8, 8, 8], RuleDirection::Inbound), Some(100));
+        assert_eq!(latency([8, 8, 8, 8], RuleDirection::Outbound), None);
+        assert_eq!(
+            profile.impairment(Ipv6Addr::LOCALHOST.into(), RuleDirection::Outbound),
+            None
+        );
+    }
+}
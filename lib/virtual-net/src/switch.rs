@@ -0,0 +1,694 @@
+//! An in-memory Ethernet switch that connects multiple isolated
+//! [`UserspaceNetworking`] stacks to each other.
+//!
+//! Every host that is added to the [`VirtualSwitch`] gets its own
+//! networking implementation with a distinct IP address from the subnet of
+//! the switch, and the hosts are able to reach each other over TCP and UDP
+//! (including broadcast and multicast) as well as resolve each other by
+//! hostname. This makes it possible to run several WASIX instances that
+//! talk to each other without touching the host network.
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use derivative::Derivative;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use virtual_mio::InterestType;
+
+use crate::userspace::{LinkMedium, UserspaceNetworking, UserspaceNetworkingDriver};
+use crate::{
+    InterestHandler, IpCidr, IpRoute, NetworkError, Result, SocketStatus, VirtualIcmpSocket,
+    VirtualIoSource, VirtualNetworking, VirtualRawSocket, VirtualSocket, VirtualTcpListener,
+    VirtualTcpSocket, VirtualUdpSocket,
+};
+
+const MAX_QUEUED_FRAMES: usize = 1024;
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// An in-memory switch that hands out networking implementations for
+/// hosts on a shared IPv4 subnet
+#[derive(Debug, Clone)]
+pub struct VirtualSwitch {
+    state: Arc<Mutex<SwitchState>>,
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct SwitchState {
+    subnet: IpCidr,
+    next_host: u32,
+    ports: HashMap<u64, PortState>,
+    next_port: u64,
+    /// Which port each hardware address was last seen on
+    mac_table: HashMap<[u8; 6], u64>,
+    /// Drivers of new hosts that the switch driver has not picked up yet
+    #[derivative(Debug = "ignore")]
+    new_drivers: Vec<UserspaceNetworkingDriver>,
+    #[derivative(Debug = "ignore")]
+    driver_waker: Option<Waker>,
+}
+
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+struct PortState {
+    hostname: Option<String>,
+    ip: Option<IpAddr>,
+    #[derivative(Debug = "ignore")]
+    queue: VecDeque<Vec<u8>>,
+    #[derivative(Debug = "ignore")]
+    handler: Option<Box<dyn InterestHandler + Send + Sync>>,
+    #[derivative(Debug = "ignore")]
+    wakers: Vec<Waker>,
+}
+
+impl PortState {
+    fn deliver(&mut self, frame: &[u8]) {
+        if self.queue.len() >= MAX_QUEUED_FRAMES {
+            return;
+        }
+        self.queue.push_back(frame.to_vec());
+        if let Some(handler) = self.handler.as_mut() {
+            handler.push_interest(InterestType::Readable);
+        }
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl VirtualSwitch {
+    /// Creates a switch for an IPv4 subnet (e.g. `10.0.0.0/24`). Hosts are
+    /// given the addresses of the subnet in order, skipping the network
+    /// and broadcast addresses.
+    pub fn new(subnet: IpCidr) -> Result<(Self, VirtualSwitchDriver)> {
+        match subnet.ip {
+            IpAddr::V4(_) if subnet.prefix <= 30 => {}
+            _ => return Err(NetworkError::InvalidInput),
+        }
+
+        let state = Arc::new(Mutex::new(SwitchState {
+            subnet,
+            next_host: 1,
+            ports: HashMap::new(),
+            next_port: 1,
+            mac_table: HashMap::new(),
+            new_drivers: Vec::new(),
+            driver_waker: None,
+        }));
+        let driver = VirtualSwitchDriver {
+            state: state.clone(),
+            drivers: FuturesUnordered::new(),
+        };
+        Ok((Self { state }, driver))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SwitchState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Returns the subnet that the hosts of this switch are on
+    pub fn subnet(&self) -> IpCidr {
+        self.lock().subnet
+    }
+
+    /// Lists the hosts that are currently connected to the switch
+    pub fn hosts(&self) -> Vec<(String, IpAddr)> {
+        let state = self.lock();
+        let mut hosts: Vec<_> = state
+            .ports
+            .values()
+            .filter_map(|p| Some((p.hostname.clone()?, p.ip?)))
+            .collect();
+        hosts.sort();
+        hosts
+    }
+
+    /// Resolves the hostname of a host connected to the switch
+    pub fn lookup(&self, hostname: &str) -> Option<IpAddr> {
+        let hostname = hostname.trim_end_matches('.');
+        let state = self.lock();
+        state
+            .ports
+            .values()
+            .find(|p| {
+                p.hostname
+                    .as_deref()
+                    .map(|h| h.eq_ignore_ascii_case(hostname))
+                    .unwrap_or_default()
+            })
+            .and_then(|p| p.ip)
+    }
+
+    /// Connects a new host to the switch and returns the networking
+    /// implementation that it should use. The host stays on the switch
+    /// (and keeps its name and address) until the networking
+    /// implementation and all of its sockets are dropped.
+    pub fn add_host(&self, hostname: &str) -> Result<SwitchedNetworking> {
+        if hostname.is_empty() || hostname.parse::<IpAddr>().is_ok() {
+            return Err(NetworkError::InvalidInput);
+        }
+        if self.lookup(hostname).is_some() {
+            return Err(NetworkError::AlreadyExists);
+        }
+
+        let (id, ip, prefix) = {
+            let mut state = self.lock();
+            let ip = state.allocate_ip()?;
+            let id = state.add_port(PortState {
+                hostname: Some(hostname.to_string()),
+                ip: Some(ip),
+                ..Default::default()
+            });
+            (id, ip, state.subnet.prefix)
+        };
+
+        let port = SwitchPort {
+            state: self.state.clone(),
+            id,
+            promiscuous: false,
+        };
+        let (inner, driver) = UserspaceNetworking::new(Box::new(port), LinkMedium::ethernet());
+        inner.add_ip(ip, prefix)?;
+
+        let mut state = self.lock();
+        state.new_drivers.push(driver);
+        if let Some(waker) = state.driver_waker.as_ref() {
+            waker.wake_by_ref();
+        }
+
+        Ok(SwitchedNetworking {
+            switch: self.clone(),
+            inner,
+        })
+    }
+
+    /// Creates a raw port on the switch which receives the frames that are
+    /// flooded (or all frames when in promiscuous mode) and can inject
+    /// frames of its own. This can be used to bridge the switch with a
+    /// TAP device or to observe the traffic.
+    pub fn add_port(&self) -> SwitchPort {
+        let id = self.lock().add_port(PortState::default());
+        SwitchPort {
+            state: self.state.clone(),
+            id,
+            promiscuous: false,
+        }
+    }
+}
+
+impl SwitchState {
+    fn add_port(&mut self, port: PortState) -> u64 {
+        let id = self.next_port;
+        self.next_port += 1;
+        self.ports.insert(id, port);
+        id
+    }
+
+    fn allocate_ip(&mut self) -> Result<IpAddr> {
+        let base = match self.subnet.ip {
+            IpAddr::V4(ip) => u32::from(ip) & (u32::MAX << (32 - self.subnet.prefix)),
+            IpAddr::V6(_) => return Err(NetworkError::Unsupported),
+        };
+        let size = 1u32 << (32 - self.subnet.prefix);
+        for _ in 1..size - 1 {
+            let offset = self.next_host;
+            self.next_host = match self.next_host + 1 {
+                next if next >= size - 1 => 1,
+                next => next,
+            };
+            let ip = IpAddr::V4(Ipv4Addr::from(base + offset));
+            if self.ports.values().all(|p| p.ip != Some(ip)) {
+                return Ok(ip);
+            }
+        }
+        Err(NetworkError::AddressInUse)
+    }
+
+    /// Forwards a frame to the port that owns the destination address or
+    /// floods it to all the other ports when it is unknown, broadcast or
+    /// multicast
+    fn forward(&mut self, from: u64, frame: &[u8]) {
+        if frame.len() < 12 {
+            return;
+        }
+        let mut dst = [0u8; 6];
+        dst.copy_from_slice(&frame[..6]);
+        let mut src = [0u8; 6];
+        src.copy_from_slice(&frame[6..12]);
+        if src[0] & 0x01 == 0 {
+            self.mac_table.insert(src, from);
+        }
+
+        let target = match dst[0] & 0x01 {
+            0 => self.mac_table.get(&dst).copied(),
+            _ => None,
+        };
+        match target {
+            Some(id) if id != from => {
+                if let Some(port) = self.ports.get_mut(&id) {
+                    port.deliver(frame);
+                }
+            }
+            Some(_) => {}
+            None => {
+                for (id, port) in self.ports.iter_mut() {
+                    if *id != from {
+                        port.deliver(frame);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Future that drives the network stacks of all the hosts on the switch,
+/// it must be polled (or spawned onto a runtime) for any traffic to flow
+#[derive(Debug)]
+pub struct VirtualSwitchDriver {
+    state: Arc<Mutex<SwitchState>>,
+    drivers: FuturesUnordered<UserspaceNetworkingDriver>,
+}
+
+impl Future for VirtualSwitchDriver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                match state.driver_waker.as_ref() {
+                    Some(waker) if waker.will_wake(cx.waker()) => {}
+                    _ => state.driver_waker = Some(cx.waker().clone()),
+                }
+                let new_drivers: Vec<_> = state.new_drivers.drain(..).collect();
+                drop(state);
+                self.drivers.extend(new_drivers);
+            }
+
+            match self.drivers.poll_next_unpin(cx) {
+                Poll::Ready(Some(())) => continue,
+                Poll::Ready(None) if Arc::strong_count(&self.state) == 1 => {
+                    return Poll::Ready(());
+                }
+                _ => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// A port on the [`VirtualSwitch`] that sends and receives Ethernet frames
+#[derive(Debug)]
+pub struct SwitchPort {
+    state: Arc<Mutex<SwitchState>>,
+    id: u64,
+    promiscuous: bool,
+}
+
+impl SwitchPort {
+    fn lock(&self) -> MutexGuard<'_, SwitchState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for SwitchPort {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.ports.remove(&self.id);
+            let id = self.id;
+            state.mac_table.retain(|_, port| *port != id);
+        }
+    }
+}
+
+impl VirtualRawSocket for SwitchPort {
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        if data.len() > MAX_FRAME_SIZE {
+            return Err(NetworkError::InvalidInput);
+        }
+        self.lock().forward(self.id, data);
+        Ok(data.len())
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let mut state = self.lock();
+        let port = state.ports.get_mut(&self.id).unwrap();
+        match port.queue.pop_front() {
+            Some(frame) => {
+                let len = frame.len().min(buf.len());
+                let buf: &mut [u8] = unsafe { std::mem::transmute(&mut buf[..len]) };
+                buf.copy_from_slice(&frame[..len]);
+                Ok(len)
+            }
+            None => Err(NetworkError::WouldBlock),
+        }
+    }
+
+    fn set_promiscuous(&mut self, promiscuous: bool) -> Result<()> {
+        // Frames for other hosts are never sent to this port once the
+        // switch has learned where they live, so promiscuous mode is
+        // tracked but does not change the forwarding
+        self.promiscuous = promiscuous;
+        Ok(())
+    }
+
+    fn promiscuous(&self) -> Result<bool> {
+        Ok(self.promiscuous)
+    }
+}
+
+impl VirtualSocket for SwitchPort {
+    fn set_ttl(&mut self, _ttl: u32) -> Result<()> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        let ip = self.lock().ports[&self.id]
+            .ip
+            .unwrap_or(Ipv4Addr::UNSPECIFIED.into());
+        Ok(SocketAddr::new(ip, 0))
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        Ok(SocketStatus::Opened)
+    }
+
+    fn set_handler(&mut self, mut handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        let mut state = self.lock();
+        let port = state.ports.get_mut(&self.id).unwrap();
+        if !port.queue.is_empty() {
+            handler.push_interest(InterestType::Readable);
+        }
+        handler.push_interest(InterestType::Writable);
+        port.handler.replace(handler);
+        Ok(())
+    }
+}
+
+impl VirtualIoSource for SwitchPort {
+    fn remove_handler(&mut self) {
+        let mut state = self.lock();
+        state.ports.get_mut(&self.id).unwrap().handler.take();
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut state = self.lock();
+        let port = state.ports.get_mut(&self.id).unwrap();
+        if let Some(frame) = port.queue.front() {
+            return Poll::Ready(Ok(frame.len()));
+        }
+        if !port.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            port.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        Poll::Ready(Ok(MAX_FRAME_SIZE))
+    }
+}
+
+/// The networking implementation of a host on a [`VirtualSwitch`]. It is
+/// a [`UserspaceNetworking`] stack that can also resolve the names of the
+/// other hosts on the switch.
+#[derive(Debug, Clone)]
+pub struct SwitchedNetworking {
+    switch: VirtualSwitch,
+    inner: UserspaceNetworking,
+}
+
+impl SwitchedNetworking {
+    /// Returns the switch this host is connected to
+    pub fn switch(&self) -> &VirtualSwitch {
+        &self.switch
+    }
+
+    /// Returns the network stack of this host
+    pub fn inner(&self) -> &UserspaceNetworking {
+        &self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for SwitchedNetworking {
+    async fn dhcp_acquire(&self) -> Result<Vec<IpAddr>> {
+        // addresses are assigned statically by the switch
+        self.inner
+            .ip_list()
+            .await
+            .map(|ips| ips.into_iter().map(|c| c.ip).collect())
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> Result<()> {
+        self.inner.ip_add(ip, prefix).await
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> Result<()> {
+        self.inner.ip_remove(ip).await
+    }
+
+    async fn ip_clear(&self) -> Result<()> {
+        self.inner.ip_clear().await
+    }
+
+    async fn ip_list(&self) -> Result<Vec<IpCidr>> {
+        self.inner.ip_list().await
+    }
+
+    async fn mac(&self) -> Result<[u8; 6]> {
+        self.inner.mac().await
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> Result<()> {
+        self.inner.gateway_set(ip).await
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> Result<()> {
+        self.inner
+            .route_add(cidr, via_router, preferred_until, expires_at)
+            .await
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> Result<()> {
+        self.inner.route_remove(cidr).await
+    }
+
+    async fn route_clear(&self) -> Result<()> {
+        self.inner.route_clear().await
+    }
+
+    async fn route_list(&self) -> Result<Vec<IpRoute>> {
+        self.inner.route_list().await
+    }
+
+    async fn bind_raw(&self) -> Result<Box<dyn VirtualRawSocket + Sync>> {
+        self.inner.bind_raw().await
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>> {
+        self.inner
+            .listen_tcp(addr, only_v6, reuse_port, reuse_addr)
+            .await
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>> {
+        self.inner.bind_udp(addr, reuse_port, reuse_addr).await
+    }
+
+    async fn bind_icmp(&self, addr: IpAddr) -> Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        self.inner.bind_icmp(addr).await
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        self.inner.connect_tcp(addr, peer).await
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>> {
+        if let Some(ip) = self.switch.lookup(host) {
+            return Ok(vec![ip]);
+        }
+        self.inner.resolve(host, port, dns_server).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{VirtualConnectedSocketExt, VirtualConnectionlessSocketExt, VirtualTcpListenerExt};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn switch() -> VirtualSwitch {
+        let subnet = IpCidr {
+            ip: Ipv4Addr::new(10, 1, 0, 0).into(),
+            prefix: 24,
+        };
+        let (switch, driver) = VirtualSwitch::new(subnet).unwrap();
+        tokio::task::spawn(driver);
+        switch
+    }
+
+    fn any_addr(port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)
+    }
+
+    async fn recv_text(socket: &mut Box<dyn VirtualUdpSocket + Sync>) -> String {
+        let mut buf = [MaybeUninit::uninit(); 64];
+        let (read, _) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+        String::from_utf8(data.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn hosts_get_distinct_addresses_and_names() {
+        let switch = switch();
+        let web = switch.add_host("web").unwrap();
+        let db = switch.add_host("db").unwrap();
+
+        let web_ip = web.ip_list().await.unwrap()[0];
+        let db_ip = db.ip_list().await.unwrap()[0];
+        assert_eq!(web_ip.ip, IpAddr::from(Ipv4Addr::new(10, 1, 0, 1)));
+        assert_eq!(db_ip.ip, IpAddr::from(Ipv4Addr::new(10, 1, 0, 2)));
+        assert_eq!(web_ip.prefix, 24);
+
+        assert_eq!(web.resolve("DB", None, None).await.unwrap(), vec![db_ip.ip]);
+        assert_eq!(
+            switch.add_host("db").unwrap_err(),
+            NetworkError::AlreadyExists
+        );
+
+        drop(db);
+        tokio::time::timeout(TIMEOUT, async {
+            while switch.lookup("db").is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(switch.hosts().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_between_hosts_by_name() {
+        let switch = switch();
+        let server = switch.add_host("server").unwrap();
+        let client = switch.add_host("client").unwrap();
+
+        let mut listener = server
+            .listen_tcp(any_addr(8080), false, false, false)
+            .await
+            .unwrap();
+        tokio::task::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.send(b"hello from server").await.unwrap();
+            // keep the connection open until the client is done
+            let mut buf = [MaybeUninit::uninit(); 1];
+            let _ = socket.recv(&mut buf).await;
+        });
+
+        let ip = client.resolve("server", None, None).await.unwrap()[0];
+        let mut socket = tokio::time::timeout(
+            TIMEOUT,
+            client.connect_tcp(any_addr(0), SocketAddr::new(ip, 8080)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let mut buf = [MaybeUninit::uninit(); 64];
+        let read = tokio::time::timeout(TIMEOUT, socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+        assert_eq!(data, b"hello from server");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn udp_broadcast_reaches_every_host() {
+        let switch = switch();
+        let a = switch.add_host("a").unwrap();
+        let b = switch.add_host("b").unwrap();
+        let c = switch.add_host("c").unwrap();
+
+        let mut rx_b = b.bind_udp(any_addr(9000), false, false).await.unwrap();
+        let mut rx_c = c.bind_udp(any_addr(9000), false, false).await.unwrap();
+        let mut tx = a.bind_udp(any_addr(0), false, false).await.unwrap();
+
+        let broadcast = SocketAddr::new(Ipv4Addr::new(10, 1, 0, 255).into(), 9000);
+        assert_eq!(
+            tx.send_to(b"all", broadcast).await.unwrap_err(),
+            NetworkError::PermissionDenied
+        );
+        tx.set_broadcast(true).unwrap();
+        tx.send_to(b"all", broadcast).await.unwrap();
+
+        assert_eq!(recv_text(&mut rx_b).await, "all");
+        assert_eq!(recv_text(&mut rx_c).await, "all");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn udp_multicast_reaches_members_only() {
+        let switch = switch();
+        let a = switch.add_host("a").unwrap();
+        let b = switch.add_host("b").unwrap();
+        let c = switch.add_host("c").unwrap();
+
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let mut member = b.bind_udp(any_addr(9001), false, false).await.unwrap();
+        member
+            .join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)
+            .unwrap();
+        let mut outsider = c.bind_udp(any_addr(9001), false, false).await.unwrap();
+        let mut tx = a.bind_udp(any_addr(0), false, false).await.unwrap();
+
+        tx.send_to(b"group", SocketAddr::new(group.into(), 9001))
+            .await
+            .unwrap();
+        assert_eq!(recv_text(&mut member).await, "group");
+
+        let mut buf = [MaybeUninit::uninit(); 64];
+        let ret = tokio::time::timeout(Duration::from_millis(200), outsider.recv_from(&mut buf));
+        assert!(ret.await.is_err());
+    }
+}
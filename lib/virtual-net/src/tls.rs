@@ -0,0 +1,801 @@
+//! TLS for the sockets of a guest, implemented on the host with `rustls`.
+//!
+//! Instead of compiling a TLS stack to WebAssembly a guest can ask for a
+//! connected [`VirtualTcpSocket`] to be upgraded with [`TlsClientConfig`]
+//! (TLS origination) or for the connections accepted by a
+//! [`VirtualTcpListener`] to be upgraded with [`TlsServerConfig`] (TLS
+//! termination). The guest then only ever sees the plaintext.
+//!
+//! The handshake is driven by the reads and writes on the upgraded socket,
+//! [`TlsTcpSocket::handshake`] waits for it explicitly.
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use rustls::{Certificate, ClientConfig, Connection, PrivateKey, RootCertStore, ServerConfig};
+use thiserror::Error;
+
+use crate::{
+    io_err_into_net_error, InterestHandler, NetworkError, Result, SocketStatus,
+    VirtualConnectedSocket, VirtualIoSource, VirtualSocket, VirtualTcpListener, VirtualTcpSocket,
+};
+
+/// How much ciphertext is read from the socket at once, a bit more than
+/// the largest TLS record
+const TLS_READ_CHUNK: usize = 17 * 1024;
+/// Writes are refused while this much ciphertext is waiting to be sent
+const MAX_OUTGOING: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("unable to load the root certificates of the system")]
+    SystemRoots(#[source] io::Error),
+    #[error("no usable certificate was found")]
+    NoCertificates,
+    #[error("no private key was found")]
+    NoPrivateKey,
+    #[error("\"{0}\" is not a valid server name")]
+    InvalidServerName(String),
+    #[error("unable to read the PEM data")]
+    Pem(#[source] io::Error),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error(transparent)]
+    Network(#[from] NetworkError),
+}
+
+impl From<TlsError> for NetworkError {
+    fn from(err: TlsError) -> Self {
+        match err {
+            TlsError::Network(err) => err,
+            TlsError::Rustls(err) => tls_err_into_net_error(err),
+            _ => NetworkError::InvalidInput,
+        }
+    }
+}
+
+/// Configuration for connecting to TLS servers, it verifies the
+/// certificate of the server against a set of root certificates
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    config: Arc<ClientConfig>,
+}
+
+impl fmt::Debug for TlsClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsClientConfig")
+            .field("alpn_protocols", &self.config.alpn_protocols)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsClientConfig {
+    /// Trusts the root certificates of the operating system
+    pub fn system_roots() -> std::result::Result<Self, TlsError> {
+        let certs = rustls_native_certs::load_native_certs().map_err(TlsError::SystemRoots)?;
+        let mut roots = RootCertStore::empty();
+        let certs: Vec<Vec<u8>> = certs.into_iter().map(|cert| cert.0).collect();
+        let (added, _) = roots.add_parsable_certificates(&certs);
+        if added == 0 {
+            return Err(TlsError::NoCertificates);
+        }
+        Ok(Self::from_roots(roots))
+    }
+
+    /// Trusts the root certificates of the Mozilla CA program that are
+    /// built into the binary
+    pub fn webpki_roots() -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        Self::from_roots(roots)
+    }
+
+    /// Only trusts the root certificates in PEM format
+    pub fn from_pem(roots_pem: &[u8]) -> std::result::Result<Self, TlsError> {
+        let certs = read_certificates(roots_pem)?;
+        let mut roots = RootCertStore::empty();
+        for cert in &certs {
+            roots.add(cert)?;
+        }
+        Ok(Self::from_roots(roots))
+    }
+
+    pub fn from_rustls(config: ClientConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    fn from_roots(roots: RootCertStore) -> Self {
+        Self::from_rustls(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    /// The protocols offered with ALPN (e.g. `h2` and `http/1.1`)
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        Arc::make_mut(&mut self.config).alpn_protocols = protocols;
+        self
+    }
+
+    pub fn rustls_config(&self) -> &Arc<ClientConfig> {
+        &self.config
+    }
+
+    /// Starts a TLS session with the server on the other side of the
+    /// socket, the certificate of the server must be valid for `server_name`
+    pub fn connect(
+        &self,
+        socket: Box<dyn VirtualTcpSocket + Sync>,
+        server_name: &str,
+    ) -> std::result::Result<TlsTcpSocket, TlsError> {
+        let name = rustls::ServerName::try_from(server_name)
+            .map_err(|_| TlsError::InvalidServerName(server_name.to_string()))?;
+        let conn = rustls::ClientConnection::new(self.config.clone(), name)?;
+        Ok(TlsTcpSocket::new(socket, conn.into()))
+    }
+}
+
+/// Configuration for accepting TLS connections with a certificate
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl fmt::Debug for TlsServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsServerConfig")
+            .field("alpn_protocols", &self.config.alpn_protocols)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TlsServerConfig {
+    /// Uses a certificate chain (leaf first) and its private key, both in
+    /// PEM format
+    pub fn from_pem(
+        cert_chain_pem: &[u8],
+        private_key_pem: &[u8],
+    ) -> std::result::Result<Self, TlsError> {
+        let certs = read_certificates(cert_chain_pem)?;
+        let key = read_private_key(private_key_pem)?;
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(Self::from_rustls(config))
+    }
+
+    pub fn from_rustls(config: ServerConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    /// The protocols accepted with ALPN, in order of preference
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        Arc::make_mut(&mut self.config).alpn_protocols = protocols;
+        self
+    }
+
+    pub fn rustls_config(&self) -> &Arc<ServerConfig> {
+        &self.config
+    }
+
+    /// Starts a TLS session with the client on the other side of the socket
+    pub fn accept(
+        &self,
+        socket: Box<dyn VirtualTcpSocket + Sync>,
+    ) -> std::result::Result<TlsTcpSocket, TlsError> {
+        let conn = rustls::ServerConnection::new(self.config.clone())?;
+        Ok(TlsTcpSocket::new(socket, conn.into()))
+    }
+
+    /// Upgrades every connection the listener accepts
+    pub fn listener(&self, listener: Box<dyn VirtualTcpListener + Sync>) -> TlsTcpListener {
+        TlsTcpListener {
+            inner: listener,
+            config: self.clone(),
+        }
+    }
+}
+
+fn read_certificates(pem: &[u8]) -> std::result::Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut &*pem).map_err(TlsError::Pem)?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates);
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(pem: &[u8]) -> std::result::Result<PrivateKey, TlsError> {
+    let mut reader = pem;
+    while let Some(item) = rustls_pemfile::read_one(&mut reader).map_err(TlsError::Pem)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(TlsError::NoPrivateKey)
+}
+
+fn tls_err_into_net_error(err: rustls::Error) -> NetworkError {
+    match err {
+        rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented => {
+            NetworkError::PermissionDenied
+        }
+        rustls::Error::AlertReceived(_) => NetworkError::ConnectionAborted,
+        _ => NetworkError::InvalidData,
+    }
+}
+
+/// A TCP socket that encrypts everything written to it and decrypts
+/// everything read from it
+#[derive(Debug)]
+pub struct TlsTcpSocket {
+    inner: Box<dyn VirtualTcpSocket + Sync>,
+    conn: Connection,
+    /// Ciphertext waiting to be sent
+    outgoing: Vec<u8>,
+    /// The peer closed the TCP connection
+    eof: bool,
+}
+
+impl TlsTcpSocket {
+    fn new(inner: Box<dyn VirtualTcpSocket + Sync>, conn: Connection) -> Self {
+        let mut ret = Self {
+            inner,
+            conn,
+            outgoing: Vec::new(),
+            eof: false,
+        };
+        // the client hello
+        ret.queue_tls();
+        ret
+    }
+
+    /// Waits for the handshake to complete
+    pub async fn handshake(&mut self) -> Result<()> {
+        std::future::poll_fn(|cx| self.poll_handshake(cx)).await
+    }
+
+    pub fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        loop {
+            match self.flush_tls() {
+                Ok(()) => {}
+                Err(NetworkError::WouldBlock) => match self.inner.poll_write_ready(cx) {
+                    Poll::Ready(Ok(_)) => continue,
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                },
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+            if !self.conn.is_handshaking() {
+                return Poll::Ready(Ok(()));
+            }
+            if self.eof {
+                return Poll::Ready(Err(NetworkError::ConnectionReset));
+            }
+            match self.read_tls() {
+                Ok(()) => {}
+                Err(NetworkError::WouldBlock) => match self.inner.poll_read_ready(cx) {
+                    Poll::Ready(Ok(_)) => {}
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                },
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.conn.is_handshaking()
+    }
+
+    /// The protocol that was agreed on with ALPN
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.conn.alpn_protocol()
+    }
+
+    /// The name the client asked for (SNI), only known on the server side
+    pub fn server_name(&self) -> Option<&str> {
+        match &self.conn {
+            Connection::Server(conn) => conn.server_name(),
+            Connection::Client(_) => None,
+        }
+    }
+
+    pub fn inner(&self) -> &(dyn VirtualTcpSocket + Sync) {
+        self.inner.as_ref()
+    }
+
+    /// Reads one chunk of ciphertext from the socket and decrypts it
+    fn read_tls(&mut self) -> Result<()> {
+        let mut buf = [MaybeUninit::<u8>::uninit(); TLS_READ_CHUNK];
+        let read = self.inner.try_recv(&mut buf)?;
+        let mut data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+        if read == 0 {
+            self.eof = true;
+        }
+        loop {
+            // reading nothing tells the session about the end of the stream
+            self.conn
+                .read_tls(&mut data)
+                .map_err(io_err_into_net_error)?;
+            self.process_packets()?;
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn process_packets(&mut self) -> Result<rustls::IoState> {
+        let ret = self.conn.process_new_packets();
+        // also sends the alert of a failed session
+        self.queue_tls();
+        ret.map_err(|err| {
+            tracing::debug!(error = &err as &dyn std::error::Error, "TLS session failed");
+            self.flush_tls().ok();
+            tls_err_into_net_error(err)
+        })
+    }
+
+    fn queue_tls(&mut self) {
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut self.outgoing).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Sends the queued ciphertext
+    fn flush_tls(&mut self) -> Result<()> {
+        while !self.outgoing.is_empty() {
+            let sent = self.inner.try_send(&self.outgoing)?;
+            if sent == 0 {
+                return Err(NetworkError::WriteZero);
+            }
+            self.outgoing.drain(..sent);
+        }
+        Ok(())
+    }
+
+    /// Sends as much of the queued ciphertext as possible
+    fn flush_pending(&mut self) -> Result<()> {
+        match self.flush_tls() {
+            Err(NetworkError::WouldBlock) => Ok(()),
+            ret => ret,
+        }
+    }
+
+    /// Makes progress with the handshake without blocking
+    fn drive_handshake(&mut self) -> Result<()> {
+        while self.conn.is_handshaking() && !self.eof {
+            match self.read_tls() {
+                Ok(()) => {}
+                Err(NetworkError::WouldBlock) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        self.flush_pending()
+    }
+}
+
+impl VirtualIoSource for TlsTcpSocket {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        loop {
+            if let Err(err) = self.flush_pending() {
+                return Poll::Ready(Err(err));
+            }
+            let state = match self.process_packets() {
+                Ok(state) => state,
+                Err(err) => return Poll::Ready(Err(err)),
+            };
+            if state.plaintext_bytes_to_read() > 0 || state.peer_has_closed() || self.eof {
+                return Poll::Ready(Ok(state.plaintext_bytes_to_read()));
+            }
+            match self.read_tls() {
+                Ok(()) => {}
+                Err(NetworkError::WouldBlock) => match self.inner.poll_read_ready(cx) {
+                    Poll::Ready(Ok(_)) => {}
+                    ret => return ret,
+                },
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        loop {
+            match self.flush_tls() {
+                Ok(()) => return self.inner.poll_write_ready(cx),
+                Err(NetworkError::WouldBlock) => match self.inner.poll_write_ready(cx) {
+                    Poll::Ready(Ok(_)) => {}
+                    ret => return ret,
+                },
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+}
+
+impl VirtualSocket for TlsTcpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        self.inner.status()
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+}
+
+impl VirtualConnectedSocket for TlsTcpSocket {
+    fn set_linger(&mut self, linger: Option<Duration>) -> Result<()> {
+        self.inner.set_linger(linger)
+    }
+
+    fn linger(&self) -> Result<Option<Duration>> {
+        self.inner.linger()
+    }
+
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        self.drive_handshake()?;
+        if self.outgoing.len() >= MAX_OUTGOING {
+            return Err(NetworkError::WouldBlock);
+        }
+        // before the handshake completes the plaintext is buffered
+        let written = self
+            .conn
+            .writer()
+            .write(data)
+            .map_err(io_err_into_net_error)?;
+        if written == 0 && !data.is_empty() {
+            return Err(NetworkError::WouldBlock);
+        }
+        self.queue_tls();
+        self.flush_pending()?;
+        Ok(written)
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        self.drive_handshake()?;
+        self.flush_tls()?;
+        if self.conn.is_handshaking() {
+            return Err(NetworkError::WouldBlock);
+        }
+        self.inner.try_flush()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.conn.send_close_notify();
+        self.queue_tls();
+        self.flush_pending().ok();
+        self.inner.close()
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let buf: &mut [u8] = unsafe { std::mem::transmute(buf) };
+        loop {
+            self.flush_pending()?;
+            match self.conn.reader().read(buf) {
+                Ok(read) => return Ok(read),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                // a lot of servers do not bother with `close_notify`, the
+                // truncation is only an error while still handshaking
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return match self.conn.is_handshaking() {
+                        true => Err(NetworkError::ConnectionReset),
+                        false => Ok(0),
+                    };
+                }
+                Err(err) => return Err(io_err_into_net_error(err)),
+            }
+            self.read_tls()?;
+        }
+    }
+}
+
+impl VirtualTcpSocket for TlsTcpSocket {
+    fn set_recv_buf_size(&mut self, size: usize) -> Result<()> {
+        self.inner.set_recv_buf_size(size)
+    }
+
+    fn recv_buf_size(&self) -> Result<usize> {
+        self.inner.recv_buf_size()
+    }
+
+    fn set_send_buf_size(&mut self, size: usize) -> Result<()> {
+        self.inner.set_send_buf_size(size)
+    }
+
+    fn send_buf_size(&self) -> Result<usize> {
+        self.inner.send_buf_size()
+    }
+
+    fn set_nodelay(&mut self, reuse: bool) -> Result<()> {
+        self.inner.set_nodelay(reuse)
+    }
+
+    fn nodelay(&self) -> Result<bool> {
+        self.inner.nodelay()
+    }
+
+    fn set_keepalive(&mut self, keepalive: bool) -> Result<()> {
+        self.inner.set_keepalive(keepalive)
+    }
+
+    fn keepalive(&self) -> Result<bool> {
+        self.inner.keepalive()
+    }
+
+    fn set_dontroute(&mut self, keepalive: bool) -> Result<()> {
+        self.inner.set_dontroute(keepalive)
+    }
+
+    fn dontroute(&self) -> Result<bool> {
+        self.inner.dontroute()
+    }
+
+    fn addr_peer(&self) -> Result<SocketAddr> {
+        self.inner.addr_peer()
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        if how != Shutdown::Read {
+            self.conn.send_close_notify();
+            self.queue_tls();
+            self.flush_pending()?;
+        }
+        self.inner.shutdown(how)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+/// A TCP listener whose connections are upgraded to TLS
+#[derive(Debug)]
+pub struct TlsTcpListener {
+    inner: Box<dyn VirtualTcpListener + Sync>,
+    config: TlsServerConfig,
+}
+
+impl VirtualIoSource for TlsTcpListener {
+    fn remove_handler(&mut self) {
+        self.inner.remove_handler()
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_read_ready(cx)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl VirtualTcpListener for TlsTcpListener {
+    fn try_accept(&mut self) -> Result<(Box<dyn VirtualTcpSocket + Sync>, SocketAddr)> {
+        let (socket, addr) = self.inner.try_accept()?;
+        let socket = self.config.accept(socket).map_err(|err| {
+            tracing::debug!(
+                error = &err as &dyn std::error::Error,
+                "Unable to start a TLS session"
+            );
+            NetworkError::InvalidData
+        })?;
+        Ok((Box::new(socket), addr))
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.inner.set_handler(handler)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        self.inner.addr_local()
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    fn ttl(&self) -> Result<u8> {
+        self.inner.ttl()
+    }
+}
+
+#[cfg(all(test, feature = "host-net"))]
+mod tests {
+    use crate::host::LocalNetworking;
+    use crate::{VirtualConnectedSocketExt, VirtualNetworking, VirtualTcpListenerExt};
+
+    use super::*;
+
+    const CA: &[u8] = include_bytes!("../tests/certs/ca.pem");
+    const CERT: &[u8] = include_bytes!("../tests/certs/server.pem");
+    const KEY: &[u8] = include_bytes!("../tests/certs/server.key");
+
+    /// Accepts one TLS connection and sends back whatever it receives
+    async fn echo_server(
+        net: &LocalNetworking,
+        config: TlsServerConfig,
+    ) -> (SocketAddr, tokio::task::JoinHandle<Result<Option<String>>>) {
+        let listener = net
+            .listen_tcp("127.0.0.1:0".parse().unwrap(), false, false, false)
+            .await
+            .unwrap();
+        let addr = listener.addr_local().unwrap();
+        let mut listener = config.listener(listener);
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut buf = [MaybeUninit::<u8>::uninit(); 1024];
+            loop {
+                let read = socket.recv(&mut buf).await?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                let mut data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+                while !data.is_empty() {
+                    let sent = socket.send(data).await?;
+                    data = &data[sent..];
+                }
+                socket.flush().await?;
+            }
+        });
+        (addr, server)
+    }
+
+    async fn connect(net: &LocalNetworking, addr: SocketAddr) -> Box<dyn VirtualTcpSocket + Sync> {
+        net.connect_tcp("127.0.0.1:0".parse().unwrap(), addr)
+            .await
+            .unwrap()
+    }
+
+    async fn echo(socket: &mut TlsTcpSocket, message: &[u8]) -> Vec<u8> {
+        let mut sent = 0;
+        while sent < message.len() {
+            sent += socket.send(&message[sent..]).await.unwrap();
+        }
+        socket.flush().await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [MaybeUninit::<u8>::uninit(); 1024];
+        while received.len() < message.len() {
+            let read = socket.recv(&mut buf).await.unwrap();
+            assert_ne!(read, 0);
+            let data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+            received.extend_from_slice(data);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn client_and_server_exchange_data() {
+        let net = LocalNetworking::new();
+        let server_config = TlsServerConfig::from_pem(CERT, KEY)
+            .unwrap()
+            .with_alpn_protocols(vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        let (addr, server) = echo_server(&net, server_config).await;
+
+        let client_config = TlsClientConfig::from_pem(CA)
+            .unwrap()
+            .with_alpn_protocols(vec![b"http/1.1".to_vec()]);
+        let mut socket = client_config
+            .connect(connect(&net, addr).await, "localhost")
+            .unwrap();
+        socket.handshake().await.unwrap();
+        assert_eq!(socket.alpn_protocol(), Some(&b"http/1.1"[..]));
+        assert_eq!(socket.addr_peer().unwrap(), addr);
+
+        assert_eq!(echo(&mut socket, b"hello").await, b"hello");
+        // more than a single TLS record
+        let large = vec![7u8; 100_000];
+        assert_eq!(echo(&mut socket, &large).await, large);
+
+        socket.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn the_handshake_is_driven_by_the_first_write() {
+        let net = LocalNetworking::new();
+        let (addr, _server) =
+            echo_server(&net, TlsServerConfig::from_pem(CERT, KEY).unwrap()).await;
+
+        let client_config = TlsClientConfig::from_pem(CA).unwrap();
+        let mut socket = client_config
+            .connect(connect(&net, addr).await, "127.0.0.1")
+            .unwrap();
+        assert!(socket.is_handshaking());
+        assert_eq!(
+            echo(&mut socket, b"no explicit handshake").await,
+            b"no explicit handshake"
+        );
+        assert!(!socket.is_handshaking());
+    }
+
+    #[tokio::test]
+    async fn the_server_name_is_verified() {
+        let net = LocalNetworking::new();
+        let (addr, server) = echo_server(&net, TlsServerConfig::from_pem(CERT, KEY).unwrap()).await;
+
+        let client_config = TlsClientConfig::from_pem(CA).unwrap();
+        let mut socket = client_config
+            .connect(connect(&net, addr).await, "wrong.example")
+            .unwrap();
+        assert_eq!(
+            socket.handshake().await,
+            Err(NetworkError::PermissionDenied)
+        );
+        assert!(server.await.unwrap().is_err());
+
+        // a certificate that is not signed by a trusted root
+        let (addr, _server) =
+            echo_server(&net, TlsServerConfig::from_pem(CERT, KEY).unwrap()).await;
+        let mut socket = TlsClientConfig::webpki_roots()
+            .connect(connect(&net, addr).await, "localhost")
+            .unwrap();
+        assert_eq!(
+            socket.handshake().await,
+            Err(NetworkError::PermissionDenied)
+        );
+    }
+
+    #[tokio::test]
+    async fn the_address_can_be_the_server_name() {
+        let net = LocalNetworking::new();
+        let (addr, _server) =
+            echo_server(&net, TlsServerConfig::from_pem(CERT, KEY).unwrap()).await;
+
+        let mut socket = TlsClientConfig::from_pem(CA)
+            .unwrap()
+            .connect(connect(&net, addr).await, &addr.ip().to_string())
+            .unwrap();
+        socket.handshake().await.unwrap();
+        assert_eq!(echo(&mut socket, b"ip").await, b"ip");
+    }
+
+    #[test]
+    fn invalid_pem_is_rejected() {
+        assert!(matches!(
+            TlsServerConfig::from_pem(CERT, CA),
+            Err(TlsError::NoPrivateKey)
+        ));
+        assert!(matches!(
+            TlsClientConfig::from_pem(b"not a certificate"),
+            Err(TlsError::NoCertificates)
+        ));
+    }
+}
@@ -0,0 +1,2655 @@
+//! A userspace TCP/IP stack that implements [`VirtualNetworking`] on top of
+//! any link that is able to carry raw frames (i.e. a [`VirtualRawSocket`]).
+//!
+//! Every [`UserspaceNetworking`] instance owns its own IP addresses, routing
+//! table, neighbor cache and sockets which means it behaves like an isolated
+//! network namespace. The link can be a TAP/TUN device, the raw socket of
+//! another networking implementation (for instance a
+//! [`RemoteNetworkingClient`](crate::RemoteNetworkingClient)) or another
+//! userspace stack connected via a [`FramePipe`].
+//!
+//! Similar to the remote networking client the stack does not spawn any
+//! background tasks, instead it returns a [`UserspaceNetworkingDriver`] that
+//! must be polled (or spawned onto a runtime) for packets to flow.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use derivative::Derivative;
+use futures_util::future::poll_fn;
+use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Route, Routes, SocketHandle};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::{
+    Dhcpv4Event, Dhcpv4Socket, IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer,
+    TcpSocket, TcpSocketBuffer, TcpState, UdpPacketMetadata, UdpSocket, UdpSocketBuffer,
+};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, Icmpv4Message, Icmpv4Packet, IpAddress, IpCidr as SmolIpCidr, IpEndpoint,
+};
+use virtual_mio::InterestType;
+
+use crate::dns::{decode_response, encode_query, DNS_TYPE_A, DNS_TYPE_AAAA};
+use crate::{
+    InterestHandler, IpCidr, IpRoute, NetworkError, Result, SocketStatus, VirtualConnectedSocket,
+    VirtualConnectionlessSocket, VirtualConnectionlessSocketExt, VirtualIcmpSocket,
+    VirtualIoSource, VirtualNetworking, VirtualRawSocket, VirtualSocket, VirtualTcpListener,
+    VirtualTcpSocket, VirtualUdpSocket,
+};
+
+const DEFAULT_MTU: usize = 1500;
+const ETHERNET_HEADER_LEN: usize = 14;
+const TCP_BUFFER_SIZE: usize = 65536;
+const UDP_BUFFER_SIZE: usize = 65536;
+const PACKET_SLOTS: usize = 64;
+const LISTEN_SOCKETS: usize = 4;
+const MAX_PENDING_CONNECTIONS: usize = 128;
+const MAX_QUEUED_FRAMES: usize = 1024;
+const MAX_INGRESS_PER_POLL: usize = 256;
+const MAX_DRIVER_SPINS: usize = 32;
+const DEFAULT_TTL: u8 = 64;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(75);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(75);
+const DHCP_TIMEOUT: Duration = Duration::from_secs(30);
+const DNS_TIMEOUT: Duration = Duration::from_secs(3);
+const DNS_ATTEMPTS: usize = 2;
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// The kind of frames that travel over the link of a [`UserspaceNetworking`]
+/// stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMedium {
+    /// Ethernet frames (e.g. a TAP device) sent and received using the
+    /// given hardware address
+    Ethernet { mac: [u8; 6] },
+    /// Bare IP packets without any link layer header (e.g. a TUN device)
+    Ip,
+}
+
+impl LinkMedium {
+    /// Ethernet medium with a random locally administered MAC address
+    pub fn ethernet() -> Self {
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&random_u64().to_le_bytes()[..6]);
+        mac[0] = (mac[0] & 0xFE) | 0x02;
+        Self::Ethernet { mac }
+    }
+}
+
+/// Networking implementation that runs a full TCP/IP stack in userspace
+/// over a frame based link
+#[derive(Debug, Clone)]
+pub struct UserspaceNetworking {
+    stack: Arc<Mutex<Stack>>,
+}
+
+impl UserspaceNetworking {
+    /// Creates a new stack on top of the supplied link using the default
+    /// MTU of 1500 bytes. The returned driver must be polled for the
+    /// stack to make progress.
+    pub fn new(
+        link: Box<dyn VirtualRawSocket + Sync>,
+        medium: LinkMedium,
+    ) -> (Self, UserspaceNetworkingDriver) {
+        Self::new_with_mtu(link, medium, DEFAULT_MTU)
+    }
+
+    /// Creates a new stack on top of the supplied link with a specific
+    /// MTU (the maximum size of an IP packet on the link)
+    pub fn new_with_mtu(
+        link: Box<dyn VirtualRawSocket + Sync>,
+        medium: LinkMedium,
+        mtu: usize,
+    ) -> (Self, UserspaceNetworkingDriver) {
+        let stack = Arc::new(Mutex::new(Stack::new(medium, mtu)));
+        let driver = UserspaceNetworkingDriver {
+            stack: stack.clone(),
+            link,
+            outbound: VecDeque::new(),
+            buf: vec![0u8; mtu + ETHERNET_HEADER_LEN],
+            sleep: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        };
+        (Self { stack }, driver)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap()
+    }
+
+    /// Assigns a static IP address to the interface, this is the same as
+    /// [`VirtualNetworking::ip_add`] but usable outside of an async context
+    pub fn add_ip(&self, ip: IpAddr, prefix: u8) -> Result<()> {
+        let cidr = smol_cidr(IpCidr { ip, prefix })?;
+        let mut stack = self.lock();
+        stack.iface.update_ip_addrs(|addrs| {
+            let mut list: Vec<_> = addrs
+                .iter()
+                .filter(|a| a.address() != cidr.address())
+                .copied()
+                .collect();
+            list.push(cidr);
+            *addrs = list.into();
+        });
+        stack.wake_driver();
+        Ok(())
+    }
+
+    /// Returns the DNS servers that were handed out by DHCP
+    pub fn dns_servers(&self) -> Vec<IpAddr> {
+        self.lock().dns_servers.clone()
+    }
+
+    async fn dns_query(
+        &self,
+        socket: &mut UserspaceUdpSocket,
+        server: SocketAddr,
+        host: &str,
+        qtype: u16,
+    ) -> Result<Vec<IpAddr>> {
+        let id = random_u64() as u16;
+        let query = encode_query(id, host, qtype).ok_or(NetworkError::InvalidInput)?;
+        socket.send_to(&query, server).await?;
+
+        let mut buf = [MaybeUninit::<u8>::uninit(); 1500];
+        let deadline = tokio::time::Instant::now() + DNS_TIMEOUT;
+        loop {
+            let (read, from) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf))
+                .await
+                .map_err(|_| NetworkError::TimedOut)??;
+            if from != server {
+                continue;
+            }
+            let response: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+            if let Some(response) = decode_response(response, id) {
+                return response.into_result();
+            }
+        }
+    }
+}
+
+impl Drop for UserspaceNetworking {
+    fn drop(&mut self) {
+        // lets the driver notice when it is the last one holding the stack
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.wake_driver();
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualNetworking for UserspaceNetworking {
+    async fn dhcp_acquire(&self) -> Result<Vec<IpAddr>> {
+        {
+            let mut stack = self.lock();
+            if stack.dhcp.is_none() {
+                let handle = stack.iface.add_socket(Dhcpv4Socket::new());
+                stack.dhcp = Some(DhcpState {
+                    handle,
+                    address: None,
+                    wakers: Vec::new(),
+                });
+            }
+            stack.wake_driver();
+        }
+
+        let acquire = poll_fn(|cx| {
+            let mut stack = self.lock();
+            let dhcp = stack.dhcp.as_mut().unwrap();
+            match dhcp.address {
+                Some(cidr) => Poll::Ready(vec![IpAddr::V4(cidr.address().into())]),
+                None => {
+                    add_waker(&mut dhcp.wakers, cx.waker());
+                    Poll::Pending
+                }
+            }
+        });
+        tokio::time::timeout(DHCP_TIMEOUT, acquire)
+            .await
+            .map_err(|_| NetworkError::TimedOut)
+    }
+
+    async fn ip_add(&self, ip: IpAddr, prefix: u8) -> Result<()> {
+        self.add_ip(ip, prefix)
+    }
+
+    async fn ip_remove(&self, ip: IpAddr) -> Result<()> {
+        let ip = IpAddress::from(ip);
+        let mut stack = self.lock();
+        if !stack.iface.has_ip_addr(ip) {
+            return Err(NetworkError::AddressNotAvailable);
+        }
+        stack.iface.update_ip_addrs(|addrs| {
+            let list: Vec<_> = addrs
+                .iter()
+                .filter(|a| a.address() != ip)
+                .copied()
+                .collect();
+            *addrs = list.into();
+        });
+        Ok(())
+    }
+
+    async fn ip_clear(&self) -> Result<()> {
+        let mut stack = self.lock();
+        stack.iface.update_ip_addrs(|addrs| {
+            *addrs = Vec::new().into();
+        });
+        Ok(())
+    }
+
+    async fn ip_list(&self) -> Result<Vec<IpCidr>> {
+        let stack = self.lock();
+        Ok(stack
+            .iface
+            .ip_addrs()
+            .iter()
+            .map(|c| from_smol_cidr(*c))
+            .collect())
+    }
+
+    async fn mac(&self) -> Result<[u8; 6]> {
+        match self.lock().medium {
+            LinkMedium::Ethernet { mac } => Ok(mac),
+            LinkMedium::Ip => Err(NetworkError::Unsupported),
+        }
+    }
+
+    async fn gateway_set(&self, ip: IpAddr) -> Result<()> {
+        let mut stack = self.lock();
+        let routes = stack.iface.routes_mut();
+        match ip {
+            IpAddr::V4(ip) => routes.add_default_ipv4_route(ip.into()),
+            IpAddr::V6(ip) => routes.add_default_ipv6_route(ip.into()),
+        }
+        .map_err(|_| NetworkError::InsufficientMemory)?;
+        Ok(())
+    }
+
+    async fn route_add(
+        &self,
+        cidr: IpCidr,
+        via_router: IpAddr,
+        preferred_until: Option<Duration>,
+        expires_at: Option<Duration>,
+    ) -> Result<()> {
+        let cidr = smol_cidr(cidr)?;
+        let route = Route {
+            via_router: via_router.into(),
+            preferred_until: preferred_until.map(to_instant),
+            expires_at: expires_at.map(to_instant),
+        };
+        let mut ret = Ok(());
+        self.lock().iface.routes_mut().update(|map| {
+            if map.insert(cidr, route).is_err() {
+                ret = Err(NetworkError::InsufficientMemory);
+            }
+        });
+        ret
+    }
+
+    async fn route_remove(&self, cidr: IpAddr) -> Result<()> {
+        let ip = IpAddress::from(cidr);
+        let mut removed = false;
+        self.lock().iface.routes_mut().update(|map| {
+            let keys: Vec<_> = map
+                .iter()
+                .map(|(k, _)| *k)
+                .filter(|k| k.address() == ip)
+                .collect();
+            for key in keys {
+                removed |= map.remove(&key).is_some();
+            }
+        });
+        if removed {
+            Ok(())
+        } else {
+            Err(NetworkError::AddressNotAvailable)
+        }
+    }
+
+    async fn route_clear(&self) -> Result<()> {
+        self.lock().iface.routes_mut().update(|map| map.clear());
+        Ok(())
+    }
+
+    async fn route_list(&self) -> Result<Vec<IpRoute>> {
+        let mut routes = Vec::new();
+        self.lock().iface.routes_mut().update(|map| {
+            routes.extend(map.iter().map(|(cidr, route)| IpRoute {
+                cidr: from_smol_cidr(*cidr),
+                via_router: route.via_router.into(),
+                preferred_until: route.preferred_until.map(from_instant),
+                expires_at: route.expires_at.map(from_instant),
+            }));
+        });
+        Ok(routes)
+    }
+
+    async fn bind_raw(&self) -> Result<Box<dyn VirtualRawSocket + Sync>> {
+        let mut stack = self.lock();
+        let id = stack.next_id();
+        stack.taps.insert(id, TapState::default());
+        Ok(Box::new(UserspaceRawSocket {
+            stack: self.stack.clone(),
+            id,
+            promiscuous: false,
+        }))
+    }
+
+    async fn listen_tcp(
+        &self,
+        addr: SocketAddr,
+        _only_v6: bool,
+        reuse_port: bool,
+        reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualTcpListener + Sync>> {
+        let mut stack = self.lock();
+        stack.check_local_addr(addr.ip())?;
+
+        let port = match addr.port() {
+            0 => stack.ephemeral_port(),
+            port => port,
+        };
+        let local = IpEndpoint::new(smol_addr_or_unspecified(addr.ip()), port);
+        let conflict = stack.listeners.values().any(|l| {
+            l.local.port == port
+                && (l.local.addr == local.addr
+                    || l.local.addr.is_unspecified()
+                    || local.addr.is_unspecified())
+        });
+        if conflict && !(reuse_port || reuse_addr) {
+            return Err(NetworkError::AddressInUse);
+        }
+
+        let id = stack.next_id();
+        stack.listeners.insert(
+            id,
+            ListenerState {
+                local,
+                hop_limit: None,
+                listening: Vec::new(),
+                pending: Vec::new(),
+                accepted: VecDeque::new(),
+                handler: None,
+                wakers: Vec::new(),
+                readable: false,
+            },
+        );
+        stack.maintain_listeners();
+        stack.wake_driver();
+
+        Ok(Box::new(UserspaceTcpListener {
+            stack: self.stack.clone(),
+            id,
+            local: endpoint_to_addr(local, addr.ip()),
+        }))
+    }
+
+    async fn bind_udp(
+        &self,
+        addr: SocketAddr,
+        _reuse_port: bool,
+        _reuse_addr: bool,
+    ) -> Result<Box<dyn VirtualUdpSocket + Sync>> {
+        Ok(Box::new(self.bind_udp_socket(addr)?))
+    }
+
+    async fn bind_icmp(&self, addr: IpAddr) -> Result<Box<dyn VirtualIcmpSocket + Sync>> {
+        let mut stack = self.lock();
+        stack.check_local_addr(addr)?;
+
+        let ident = random_u64() as u16;
+        let mut socket = IcmpSocket::new(
+            IcmpSocketBuffer::new(
+                vec![IcmpPacketMetadata::EMPTY; PACKET_SLOTS],
+                vec![0; UDP_BUFFER_SIZE],
+            ),
+            IcmpSocketBuffer::new(
+                vec![IcmpPacketMetadata::EMPTY; PACKET_SLOTS],
+                vec![0; UDP_BUFFER_SIZE],
+            ),
+        );
+        socket
+            .bind(IcmpEndpoint::Ident(ident))
+            .map_err(|_| NetworkError::AddressInUse)?;
+        let handle = stack.iface.add_socket(socket);
+        stack
+            .sockets
+            .insert(handle, SocketState::new(SocketKind::Icmp));
+
+        Ok(Box::new(UserspaceIcmpSocket {
+            stack: self.stack.clone(),
+            handle,
+            addr,
+            ident,
+            guest_ident: None,
+        }))
+    }
+
+    async fn connect_tcp(
+        &self,
+        addr: SocketAddr,
+        peer: SocketAddr,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        let handle = {
+            let mut stack = self.lock();
+            stack.check_local_addr(addr.ip())?;
+
+            let port = match addr.port() {
+                0 => stack.ephemeral_port(),
+                port => port,
+            };
+            let local = IpEndpoint::new(smol_addr_or_unspecified(addr.ip()), port);
+            let handle = stack.iface.add_socket(new_tcp_socket());
+            let (socket, cx) = stack.iface.get_socket_and_context::<TcpSocket>(handle);
+            if let Err(err) = socket.connect(cx, IpEndpoint::from(peer), local) {
+                stack.iface.remove_socket(handle);
+                return Err(match err {
+                    smoltcp::Error::Unaddressable => NetworkError::AddressNotAvailable,
+                    _ => NetworkError::InvalidInput,
+                });
+            }
+            stack
+                .sockets
+                .insert(handle, SocketState::new(SocketKind::Tcp));
+            stack.wake_driver();
+            handle
+        };
+
+        // dropping the socket on failure closes it and releases the handle
+        let socket = UserspaceTcpSocket::new(self.stack.clone(), handle);
+        let established = poll_fn(|cx| {
+            let mut stack = self.lock();
+            match stack.iface.get_socket::<TcpSocket>(handle).state() {
+                TcpState::SynSent | TcpState::SynReceived => {
+                    add_waker(&mut stack.socket_mut(handle).wakers, cx.waker());
+                    Poll::Pending
+                }
+                TcpState::Closed | TcpState::TimeWait => {
+                    Poll::Ready(Err(NetworkError::ConnectionRefused))
+                }
+                _ => Poll::Ready(Ok(())),
+            }
+        });
+        tokio::time::timeout(CONNECT_TIMEOUT, established)
+            .await
+            .map_err(|_| NetworkError::TimedOut)??;
+        Ok(Box::new(socket))
+    }
+
+    async fn resolve(
+        &self,
+        host: &str,
+        _port: Option<u16>,
+        dns_server: Option<IpAddr>,
+    ) -> Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        if host.eq_ignore_ascii_case("localhost") {
+            return Ok(vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()]);
+        }
+
+        let (servers, has_ipv6) = {
+            let stack = self.lock();
+            let servers = match dns_server {
+                Some(server) => vec![server],
+                None => stack.dns_servers.clone(),
+            };
+            let has_ipv6 = stack
+                .iface
+                .ip_addrs()
+                .iter()
+                .any(|c| matches!(c.address(), IpAddress::Ipv6(_)));
+            (servers, has_ipv6)
+        };
+        if servers.is_empty() {
+            return Err(NetworkError::AddressNotAvailable);
+        }
+
+        let mut socket = self.bind_udp_socket(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))?;
+        let mut last_err = NetworkError::TimedOut;
+        for _ in 0..DNS_ATTEMPTS {
+            for server in servers.iter() {
+                let server = SocketAddr::new(*server, 53);
+                let mut ret = match self.dns_query(&mut socket, server, host, DNS_TYPE_A).await {
+                    Ok(ret) => ret,
+                    Err(err) => {
+                        last_err = err;
+                        continue;
+                    }
+                };
+                if has_ipv6 {
+                    if let Ok(v6) = self
+                        .dns_query(&mut socket, server, host, DNS_TYPE_AAAA)
+                        .await
+                    {
+                        ret.extend(v6);
+                    }
+                }
+                return Ok(ret);
+            }
+        }
+        Err(last_err)
+    }
+}
+
+impl UserspaceNetworking {
+    fn bind_udp_socket(&self, addr: SocketAddr) -> Result<UserspaceUdpSocket> {
+        let mut stack = self.lock();
+        stack.check_local_addr(addr.ip())?;
+
+        let port = match addr.port() {
+            0 => stack.ephemeral_port(),
+            port => port,
+        };
+        let mut socket = UdpSocket::new(
+            UdpSocketBuffer::new(
+                vec![UdpPacketMetadata::EMPTY; PACKET_SLOTS],
+                vec![0; UDP_BUFFER_SIZE],
+            ),
+            UdpSocketBuffer::new(
+                vec![UdpPacketMetadata::EMPTY; PACKET_SLOTS],
+                vec![0; UDP_BUFFER_SIZE],
+            ),
+        );
+        let local = IpEndpoint::new(smol_addr_or_unspecified(addr.ip()), port);
+        socket.bind(local).map_err(|_| NetworkError::AddressInUse)?;
+        let handle = stack.iface.add_socket(socket);
+        stack
+            .sockets
+            .insert(handle, SocketState::new(SocketKind::Udp));
+
+        Ok(UserspaceUdpSocket {
+            stack: self.stack.clone(),
+            handle,
+            local: endpoint_to_addr(local, addr.ip()),
+            broadcast: false,
+            multicast_loop_v4: true,
+            multicast_loop_v6: true,
+            multicast_ttl_v4: 1,
+            joined: Vec::new(),
+        })
+    }
+}
+
+/// Future that moves frames between the link and the userspace stack and
+/// drives all of its timers. It completes when the link is closed or when
+/// the networking implementation and all its sockets have been dropped.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct UserspaceNetworkingDriver {
+    stack: Arc<Mutex<Stack>>,
+    link: Box<dyn VirtualRawSocket + Sync>,
+    #[derivative(Debug = "ignore")]
+    outbound: VecDeque<Vec<u8>>,
+    #[derivative(Debug = "ignore")]
+    buf: Vec<u8>,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl UserspaceNetworkingDriver {
+    fn shutdown(&mut self) -> Poll<()> {
+        let mut stack = self.stack.lock().unwrap();
+        stack.link_closed = true;
+        for state in stack.sockets.values_mut() {
+            state.wake_all();
+        }
+        Poll::Ready(())
+    }
+}
+
+impl Future for UserspaceNetworkingDriver {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if Arc::strong_count(&this.stack) == 1 {
+            return Poll::Ready(());
+        }
+
+        for _ in 0..MAX_DRIVER_SPINS {
+            let handler: Box<dyn InterestHandler + Send + Sync> = cx.waker().into();
+            if this.link.set_handler(handler).is_err() {
+                return this.shutdown();
+            }
+
+            let mut stack = this.stack.lock().unwrap();
+            match stack.driver_waker.as_ref() {
+                Some(waker) if waker.will_wake(cx.waker()) => {}
+                _ => stack.driver_waker = Some(cx.waker().clone()),
+            }
+
+            // Pull in everything that is waiting on the link
+            let mut saturated = true;
+            for _ in 0..MAX_INGRESS_PER_POLL {
+                let buf: &mut [MaybeUninit<u8>] = unsafe { std::mem::transmute(&mut this.buf[..]) };
+                match this.link.try_recv(buf) {
+                    Ok(0) | Err(NetworkError::WouldBlock) => {
+                        saturated = false;
+                        break;
+                    }
+                    Ok(read) => stack.ingress(&this.buf[..read]),
+                    Err(err) => {
+                        tracing::debug!("userspace network link failed - {}", err);
+                        drop(stack);
+                        return this.shutdown();
+                    }
+                }
+            }
+
+            stack.poll();
+            this.outbound.extend(stack.link_out.drain(..));
+            this.outbound.extend(stack.iface.device_mut().tx.drain(..));
+            let delay = stack.iface.poll_delay(Instant::now()).map(Duration::from);
+            drop(stack);
+
+            // Push everything the stack produced back out to the link
+            while let Some(frame) = this.outbound.front() {
+                match this.link.try_send(frame) {
+                    Ok(_) => {
+                        this.outbound.pop_front();
+                    }
+                    Err(NetworkError::WouldBlock) => break,
+                    Err(err) => {
+                        tracing::debug!("userspace network link failed - {}", err);
+                        return this.shutdown();
+                    }
+                }
+            }
+            let _ = this.link.try_flush();
+
+            if saturated {
+                continue;
+            }
+            match delay {
+                Some(delay) if delay.is_zero() => continue,
+                Some(delay) => {
+                    this.sleep
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + delay);
+                    if this.sleep.as_mut().poll(cx).is_ready() {
+                        continue;
+                    }
+                }
+                None => {}
+            }
+            return Poll::Pending;
+        }
+
+        // Yield to other tasks before doing more work
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+struct FrameDevice {
+    medium: Medium,
+    mtu: usize,
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+impl<'a> Device<'a> for FrameDevice {
+    type RxToken = FrameRxToken;
+    type TxToken = FrameTxToken<'a>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.rx.pop_front()?;
+        Some((FrameRxToken(frame), FrameTxToken(&mut self.tx)))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        if self.tx.len() >= MAX_QUEUED_FRAMES {
+            return None;
+        }
+        Some(FrameTxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = self.medium;
+        caps.max_transmission_unit = match self.medium {
+            Medium::Ethernet => self.mtu + ETHERNET_HEADER_LEN,
+            _ => self.mtu,
+        };
+        caps
+    }
+}
+
+struct FrameRxToken(Vec<u8>);
+
+impl RxToken for FrameRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+struct FrameTxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl<'a> TxToken for FrameTxToken<'a> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut frame = vec![0u8; len];
+        let ret = f(&mut frame)?;
+        self.0.push_back(frame);
+        Ok(ret)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SocketKind {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Readiness {
+    readable: bool,
+    writable: bool,
+    closed: bool,
+}
+
+/// Book keeping for a socket that has been handed out to the guest
+struct SocketState {
+    kind: SocketKind,
+    ready: Readiness,
+    handler: Option<Box<dyn InterestHandler + Send + Sync>>,
+    wakers: Vec<Waker>,
+}
+
+impl SocketState {
+    fn new(kind: SocketKind) -> Self {
+        Self {
+            kind,
+            ready: Readiness::default(),
+            handler: None,
+            wakers: Vec::new(),
+        }
+    }
+
+    /// Raises interest events for anything that became ready since the
+    /// last time this socket was inspected (edge triggered)
+    fn notify(&mut self, now: Readiness) {
+        let mut wake = false;
+        for (was, is, interest) in [
+            (self.ready.readable, now.readable, InterestType::Readable),
+            (self.ready.writable, now.writable, InterestType::Writable),
+            (self.ready.closed, now.closed, InterestType::Closed),
+        ] {
+            if is && !was {
+                if let Some(handler) = self.handler.as_mut() {
+                    handler.push_interest(interest);
+                }
+                wake = true;
+            }
+        }
+        if wake {
+            self.wake_all();
+        }
+        self.ready = now;
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+struct ListenerState {
+    local: IpEndpoint,
+    hop_limit: Option<u8>,
+    /// Sockets waiting for a SYN to arrive
+    listening: Vec<SocketHandle>,
+    /// Sockets that are in the middle of the handshake
+    pending: Vec<SocketHandle>,
+    /// Sockets that have been established and are waiting to be accepted
+    accepted: VecDeque<SocketHandle>,
+    handler: Option<Box<dyn InterestHandler + Send + Sync>>,
+    wakers: Vec<Waker>,
+    readable: bool,
+}
+
+#[derive(Default)]
+struct TapState {
+    queue: VecDeque<Vec<u8>>,
+    promiscuous: bool,
+    handler: Option<Box<dyn InterestHandler + Send + Sync>>,
+    wakers: Vec<Waker>,
+}
+
+struct DhcpState {
+    handle: SocketHandle,
+    address: Option<smoltcp::wire::Ipv4Cidr>,
+    wakers: Vec<Waker>,
+}
+
+struct Stack {
+    medium: LinkMedium,
+    iface: Interface<'static, FrameDevice>,
+    sockets: HashMap<SocketHandle, SocketState>,
+    listeners: HashMap<u64, ListenerState>,
+    taps: HashMap<u64, TapState>,
+    /// Sockets that were dropped by the guest but are still shutting down
+    closing: Vec<SocketHandle>,
+    /// Frames written directly to the link by raw sockets
+    link_out: VecDeque<Vec<u8>>,
+    dhcp: Option<DhcpState>,
+    dns_servers: Vec<IpAddr>,
+    next_id: u64,
+    next_port: u16,
+    link_closed: bool,
+    driver_waker: Option<Waker>,
+}
+
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stack")
+            .field("medium", &self.medium)
+            .field("ip_addrs", &self.iface.ip_addrs())
+            .field("sockets", &self.sockets.len())
+            .field("listeners", &self.listeners.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Stack {
+    fn new(medium: LinkMedium, mtu: usize) -> Self {
+        let device = FrameDevice {
+            medium: match medium {
+                LinkMedium::Ethernet { .. } => Medium::Ethernet,
+                LinkMedium::Ip => Medium::Ip,
+            },
+            mtu,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+        };
+        let mut builder = InterfaceBuilder::new(device, vec![])
+            .ip_addrs(Vec::new())
+            .routes(Routes::new(BTreeMap::new()))
+            .ipv4_multicast_groups(BTreeMap::new())
+            .random_seed(random_u64());
+        if let LinkMedium::Ethernet { mac } = medium {
+            builder = builder
+                .hardware_addr(EthernetAddress(mac).into())
+                .neighbor_cache(NeighborCache::new(BTreeMap::new()));
+        }
+
+        let port_range = (u16::MAX - EPHEMERAL_PORT_START) as u64;
+        Self {
+            medium,
+            iface: builder.finalize(),
+            sockets: HashMap::new(),
+            listeners: HashMap::new(),
+            taps: HashMap::new(),
+            closing: Vec::new(),
+            link_out: VecDeque::new(),
+            dhcp: None,
+            dns_servers: Vec::new(),
+            next_id: 1,
+            next_port: EPHEMERAL_PORT_START + (random_u64() % port_range) as u16,
+            link_closed: false,
+            driver_waker: None,
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn wake_driver(&mut self) {
+        if let Some(waker) = self.driver_waker.as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+
+    fn socket_mut(&mut self, handle: SocketHandle) -> &mut SocketState {
+        self.sockets.get_mut(&handle).unwrap()
+    }
+
+    /// Makes sure the address is either unspecified or assigned to this
+    /// interface
+    fn check_local_addr(&self, ip: IpAddr) -> Result<()> {
+        if self.link_closed {
+            return Err(NetworkError::NoDevice);
+        }
+        if ip.is_unspecified() || self.iface.has_ip_addr(ip) {
+            Ok(())
+        } else {
+            Err(NetworkError::AddressNotAvailable)
+        }
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = match self.next_port {
+            u16::MAX => EPHEMERAL_PORT_START,
+            port => port + 1,
+        };
+        port
+    }
+
+    /// Accepts a frame from the link and hands a copy to any raw sockets
+    /// that are interested in it
+    fn ingress(&mut self, frame: &[u8]) {
+        let for_us = match self.medium {
+            LinkMedium::Ethernet { mac } => {
+                frame.len() >= 6 && (frame[..6] == mac || frame[0] & 0x01 == 0x01)
+            }
+            LinkMedium::Ip => true,
+        };
+        for tap in self.taps.values_mut() {
+            if !for_us && !tap.promiscuous {
+                continue;
+            }
+            if tap.queue.len() >= MAX_QUEUED_FRAMES {
+                tap.queue.pop_front();
+            }
+            tap.queue.push_back(frame.to_vec());
+            if let Some(handler) = tap.handler.as_mut() {
+                handler.push_interest(InterestType::Readable);
+            }
+            for waker in tap.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+
+        let device = self.iface.device_mut();
+        if for_us && device.rx.len() < MAX_QUEUED_FRAMES {
+            device.rx.push_back(frame.to_vec());
+        }
+    }
+
+    fn poll(&mut self) {
+        let now = Instant::now();
+        for _ in 0..MAX_INGRESS_PER_POLL {
+            match self.iface.poll(now) {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(err) => tracing::trace!("userspace network stack error - {}", err),
+            }
+            if self.iface.device().rx.is_empty() {
+                break;
+            }
+        }
+
+        self.poll_dhcp();
+        self.maintain_listeners();
+
+        let handles: Vec<_> = self.sockets.keys().copied().collect();
+        for handle in handles {
+            let now = self.readiness(handle);
+            self.socket_mut(handle).notify(now);
+        }
+
+        let iface = &mut self.iface;
+        self.closing.retain(|handle| {
+            if iface.get_socket::<TcpSocket>(*handle).state() == TcpState::Closed {
+                iface.remove_socket(*handle);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn readiness(&mut self, handle: SocketHandle) -> Readiness {
+        let kind = self.sockets[&handle].kind;
+        let link_closed = self.link_closed;
+        match kind {
+            SocketKind::Tcp => {
+                let socket = self.iface.get_socket::<TcpSocket>(handle);
+                let opening = matches!(
+                    socket.state(),
+                    TcpState::SynSent | TcpState::SynReceived | TcpState::Listen
+                );
+                Readiness {
+                    readable: socket.can_recv() || (!opening && !socket.may_recv()),
+                    writable: socket.can_send() || (!opening && !socket.may_send()),
+                    closed: !socket.is_open() || link_closed,
+                }
+            }
+            SocketKind::Udp => {
+                let socket = self.iface.get_socket::<UdpSocket>(handle);
+                Readiness {
+                    readable: socket.can_recv(),
+                    writable: socket.can_send(),
+                    closed: !socket.is_open() || link_closed,
+                }
+            }
+            SocketKind::Icmp => {
+                let socket = self.iface.get_socket::<IcmpSocket>(handle);
+                Readiness {
+                    readable: socket.can_recv(),
+                    writable: socket.can_send(),
+                    closed: !socket.is_open() || link_closed,
+                }
+            }
+        }
+    }
+
+    /// Lowers the readiness flags that are no longer true so that the next
+    /// time they become true an event is raised again
+    fn sync(&mut self, handle: SocketHandle) {
+        let now = self.readiness(handle);
+        let state = self.socket_mut(handle);
+        state.ready.readable &= now.readable;
+        state.ready.writable &= now.writable;
+        state.ready.closed &= now.closed;
+    }
+
+    fn set_handler(
+        &mut self,
+        handle: SocketHandle,
+        mut handler: Box<dyn InterestHandler + Send + Sync>,
+    ) {
+        let now = self.readiness(handle);
+        if now.readable {
+            handler.push_interest(InterestType::Readable);
+        }
+        if now.writable {
+            handler.push_interest(InterestType::Writable);
+        }
+        if now.closed {
+            handler.push_interest(InterestType::Closed);
+        }
+        let state = self.socket_mut(handle);
+        state.handler = Some(handler);
+        state.ready = now;
+    }
+
+    fn poll_dhcp(&mut self) {
+        let dhcp = match self.dhcp.as_mut() {
+            Some(dhcp) => dhcp,
+            None => return,
+        };
+        let event = self.iface.get_socket::<Dhcpv4Socket>(dhcp.handle).poll();
+        let old = match event {
+            Some(Dhcpv4Event::Configured(_)) | Some(Dhcpv4Event::Deconfigured) => {
+                dhcp.address.take()
+            }
+            None => return,
+        };
+        if let Some(old) = old {
+            self.iface.update_ip_addrs(|addrs| {
+                let list: Vec<_> = addrs
+                    .iter()
+                    .filter(|a| **a != SmolIpCidr::Ipv4(old))
+                    .copied()
+                    .collect();
+                *addrs = list.into();
+            });
+            self.iface.routes_mut().remove_default_ipv4_route();
+        }
+
+        if let Some(Dhcpv4Event::Configured(config)) = event {
+            tracing::debug!("dhcp lease acquired - {}", config.address);
+            self.iface.update_ip_addrs(|addrs| {
+                let mut list = addrs.to_vec();
+                list.push(SmolIpCidr::Ipv4(config.address));
+                *addrs = list.into();
+            });
+            if let Some(router) = config.router {
+                let _ = self.iface.routes_mut().add_default_ipv4_route(router);
+            }
+            self.dns_servers = config
+                .dns_servers
+                .iter()
+                .flatten()
+                .map(|ip| IpAddr::V4((*ip).into()))
+                .collect();
+            dhcp.address = Some(config.address);
+            for waker in dhcp.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Promotes established connections into the accept queues of the
+    /// listeners and keeps enough sockets in the LISTEN state
+    fn maintain_listeners(&mut self) {
+        let iface = &mut self.iface;
+        for listener in self.listeners.values_mut() {
+            let mut listening = Vec::new();
+            let mut pending = Vec::new();
+            for handle in listener
+                .listening
+                .drain(..)
+                .chain(listener.pending.drain(..))
+            {
+                match iface.get_socket::<TcpSocket>(handle).state() {
+                    TcpState::Listen => listening.push(handle),
+                    TcpState::SynReceived => pending.push(handle),
+                    TcpState::Closed | TcpState::TimeWait => {
+                        iface.remove_socket(handle);
+                    }
+                    _ => listener.accepted.push_back(handle),
+                }
+            }
+            while listening.len() < LISTEN_SOCKETS
+                && pending.len() + listener.accepted.len() < MAX_PENDING_CONNECTIONS
+            {
+                let mut socket = new_tcp_socket();
+                socket.set_hop_limit(listener.hop_limit);
+                if socket.listen(listener.local).is_err() {
+                    break;
+                }
+                listening.push(iface.add_socket(socket));
+            }
+            listener.listening = listening;
+            listener.pending = pending;
+
+            let readable = !listener.accepted.is_empty();
+            if readable && !listener.readable {
+                if let Some(handler) = listener.handler.as_mut() {
+                    handler.push_interest(InterestType::Readable);
+                }
+                for waker in listener.wakers.drain(..) {
+                    waker.wake();
+                }
+            }
+            listener.readable = readable;
+        }
+    }
+
+    /// Removes a UDP or ICMP socket after giving it one last chance to
+    /// dispatch any datagrams that are still queued
+    fn release_datagram(&mut self, handle: SocketHandle) {
+        self.sockets.remove(&handle);
+        if let Err(err) = self.iface.poll(Instant::now()) {
+            tracing::trace!("userspace network stack error - {}", err);
+        }
+        self.iface.remove_socket(handle);
+        self.wake_driver();
+    }
+
+    /// Closes a TCP socket and lets it linger until the connection has
+    /// been torn down
+    fn release_tcp(&mut self, handle: SocketHandle, abort: bool) {
+        self.sockets.remove(&handle);
+        let socket = self.iface.get_socket::<TcpSocket>(handle);
+        if abort {
+            socket.abort();
+        } else {
+            socket.close();
+        }
+        self.closing.push(handle);
+        self.wake_driver();
+    }
+}
+
+fn new_tcp_socket() -> TcpSocket<'static> {
+    let mut socket = TcpSocket::new(
+        TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    );
+    socket.set_nagle_enabled(false);
+    socket
+}
+
+/// A TCP listener running on the userspace stack
+#[derive(Debug)]
+pub struct UserspaceTcpListener {
+    stack: Arc<Mutex<Stack>>,
+    id: u64,
+    local: SocketAddr,
+}
+
+impl UserspaceTcpListener {
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap()
+    }
+}
+
+impl Drop for UserspaceTcpListener {
+    fn drop(&mut self) {
+        let mut stack = match self.stack.lock() {
+            Ok(stack) => stack,
+            Err(_) => return,
+        };
+        if let Some(listener) = stack.listeners.remove(&self.id) {
+            for handle in listener.listening {
+                stack.iface.remove_socket(handle);
+            }
+            for handle in listener.pending.into_iter().chain(listener.accepted) {
+                stack.iface.get_socket::<TcpSocket>(handle).abort();
+                stack.closing.push(handle);
+            }
+            stack.wake_driver();
+        }
+    }
+}
+
+impl VirtualTcpListener for UserspaceTcpListener {
+    fn try_accept(&mut self) -> Result<(Box<dyn VirtualTcpSocket + Sync>, SocketAddr)> {
+        let mut stack = self.lock();
+        let listener = stack.listeners.get_mut(&self.id).unwrap();
+        let handle = match listener.accepted.pop_front() {
+            Some(handle) => handle,
+            None => {
+                listener.readable = false;
+                return Err(NetworkError::WouldBlock);
+            }
+        };
+        stack
+            .sockets
+            .insert(handle, SocketState::new(SocketKind::Tcp));
+        let peer = stack
+            .iface
+            .get_socket::<TcpSocket>(handle)
+            .remote_endpoint();
+        stack.wake_driver();
+        drop(stack);
+
+        let socket = UserspaceTcpSocket::new(self.stack.clone(), handle);
+        Ok((Box::new(socket), endpoint_to_addr(peer, self.local.ip())))
+    }
+
+    fn set_handler(&mut self, mut handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        let mut stack = self.lock();
+        let listener = stack.listeners.get_mut(&self.id).unwrap();
+        if !listener.accepted.is_empty() {
+            handler.push_interest(InterestType::Readable);
+        }
+        listener.handler.replace(handler);
+        Ok(())
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn set_ttl(&mut self, ttl: u8) -> Result<()> {
+        let mut stack = self.lock();
+        let Stack {
+            iface, listeners, ..
+        } = &mut *stack;
+        let listener = listeners.get_mut(&self.id).unwrap();
+        listener.hop_limit = Some(ttl);
+        for handle in listener.listening.iter() {
+            iface
+                .get_socket::<TcpSocket>(*handle)
+                .set_hop_limit(Some(ttl));
+        }
+        Ok(())
+    }
+
+    fn ttl(&self) -> Result<u8> {
+        let stack = self.lock();
+        Ok(stack.listeners[&self.id].hop_limit.unwrap_or(DEFAULT_TTL))
+    }
+}
+
+impl VirtualIoSource for UserspaceTcpListener {
+    fn remove_handler(&mut self) {
+        let mut stack = self.lock();
+        if let Some(listener) = stack.listeners.get_mut(&self.id) {
+            listener.handler.take();
+        }
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut stack = self.lock();
+        let listener = stack.listeners.get_mut(&self.id).unwrap();
+        if !listener.accepted.is_empty() {
+            return Poll::Ready(Ok(listener.accepted.len()));
+        }
+        listener.readable = false;
+        add_waker(&mut listener.wakers, cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        Poll::Pending
+    }
+}
+
+/// A TCP connection running on the userspace stack
+#[derive(Debug)]
+pub struct UserspaceTcpSocket {
+    stack: Arc<Mutex<Stack>>,
+    handle: SocketHandle,
+    linger: Option<Duration>,
+    dontroute: bool,
+    read_shutdown: bool,
+}
+
+impl UserspaceTcpSocket {
+    fn new(stack: Arc<Mutex<Stack>>, handle: SocketHandle) -> Self {
+        Self {
+            stack,
+            handle,
+            linger: None,
+            dontroute: false,
+            read_shutdown: false,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap()
+    }
+
+    fn with_socket<T>(&self, f: impl FnOnce(&mut TcpSocket<'static>) -> T) -> T {
+        f(self.lock().iface.get_socket::<TcpSocket>(self.handle))
+    }
+}
+
+impl Drop for UserspaceTcpSocket {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            // a zero linger means the connection is reset rather than closed
+            let abort = self.linger == Some(Duration::ZERO);
+            stack.release_tcp(self.handle, abort);
+        }
+    }
+}
+
+impl VirtualTcpSocket for UserspaceTcpSocket {
+    fn set_recv_buf_size(&mut self, _size: usize) -> Result<()> {
+        // The buffers of the stack are fixed in size so the hint is ignored
+        Ok(())
+    }
+
+    fn recv_buf_size(&self) -> Result<usize> {
+        Ok(self.with_socket(|s| s.recv_capacity()))
+    }
+
+    fn set_send_buf_size(&mut self, _size: usize) -> Result<()> {
+        Ok(())
+    }
+
+    fn send_buf_size(&self) -> Result<usize> {
+        Ok(self.with_socket(|s| s.send_capacity()))
+    }
+
+    fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        self.with_socket(|s| s.set_nagle_enabled(!nodelay));
+        Ok(())
+    }
+
+    fn nodelay(&self) -> Result<bool> {
+        Ok(self.with_socket(|s| !s.nagle_enabled()))
+    }
+
+    fn set_keepalive(&mut self, keepalive: bool) -> Result<()> {
+        let interval = keepalive.then_some(KEEPALIVE_INTERVAL.into());
+        self.with_socket(|s| s.set_keep_alive(interval));
+        Ok(())
+    }
+
+    fn keepalive(&self) -> Result<bool> {
+        Ok(self.with_socket(|s| s.keep_alive().is_some()))
+    }
+
+    fn set_dontroute(&mut self, dontroute: bool) -> Result<()> {
+        self.dontroute = dontroute;
+        Ok(())
+    }
+
+    fn dontroute(&self) -> Result<bool> {
+        Ok(self.dontroute)
+    }
+
+    fn addr_peer(&self) -> Result<SocketAddr> {
+        let local = self.addr_local()?;
+        let peer = self.with_socket(|s| s.remote_endpoint());
+        if peer.is_specified() {
+            Ok(endpoint_to_addr(peer, local.ip()))
+        } else {
+            Err(NetworkError::NotConnected)
+        }
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.read_shutdown = true;
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            let mut stack = self.lock();
+            stack.iface.get_socket::<TcpSocket>(self.handle).close();
+            stack.wake_driver();
+        }
+        Ok(())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.with_socket(|s| !s.is_open())
+    }
+}
+
+impl VirtualConnectedSocket for UserspaceTcpSocket {
+    fn set_linger(&mut self, linger: Option<Duration>) -> Result<()> {
+        self.linger = linger;
+        Ok(())
+    }
+
+    fn linger(&self) -> Result<Option<Duration>> {
+        Ok(self.linger)
+    }
+
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        let mut stack = self.lock();
+        let socket = stack.iface.get_socket::<TcpSocket>(self.handle);
+        if !socket.may_send() {
+            return Err(match socket.state() {
+                TcpState::SynSent | TcpState::SynReceived => NetworkError::NotConnected,
+                _ => NetworkError::BrokenPipe,
+            });
+        }
+        match socket.send_slice(data) {
+            Ok(0) if !data.is_empty() => {
+                stack.sync(self.handle);
+                Err(NetworkError::WouldBlock)
+            }
+            Ok(sent) => {
+                stack.wake_driver();
+                Ok(sent)
+            }
+            Err(_) => Err(NetworkError::BrokenPipe),
+        }
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        self.lock().wake_driver();
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        if self.read_shutdown {
+            return Ok(0);
+        }
+        let buf: &mut [u8] = unsafe { std::mem::transmute(buf) };
+
+        let mut stack = self.lock();
+        let socket = stack.iface.get_socket::<TcpSocket>(self.handle);
+        match socket.recv_slice(buf) {
+            Ok(0) if !buf.is_empty() => {
+                stack.sync(self.handle);
+                Err(NetworkError::WouldBlock)
+            }
+            Ok(read) => {
+                // reading frees up window space that the peer needs to hear about
+                stack.wake_driver();
+                Ok(read)
+            }
+            Err(smoltcp::Error::Finished) => Ok(0),
+            Err(_) => match socket.state() {
+                TcpState::Closed => Err(NetworkError::ConnectionReset),
+                _ => Err(NetworkError::NotConnected),
+            },
+        }
+    }
+}
+
+impl VirtualSocket for UserspaceTcpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        let ttl = u8::try_from(ttl).map_err(|_| NetworkError::InvalidInput)?;
+        self.with_socket(|s| s.set_hop_limit(Some(ttl)));
+        Ok(())
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        Ok(self.with_socket(|s| s.hop_limit().unwrap_or(DEFAULT_TTL)) as u32)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        let local = self.with_socket(|s| s.local_endpoint());
+        Ok(endpoint_to_addr(local, Ipv4Addr::UNSPECIFIED.into()))
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        Ok(self.with_socket(|s| match s.state() {
+            TcpState::SynSent | TcpState::SynReceived => SocketStatus::Opening,
+            TcpState::Established
+            | TcpState::FinWait1
+            | TcpState::FinWait2
+            | TcpState::CloseWait => SocketStatus::Opened,
+            _ => SocketStatus::Closed,
+        }))
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.lock().set_handler(self.handle, handler);
+        Ok(())
+    }
+}
+
+impl VirtualIoSource for UserspaceTcpSocket {
+    fn remove_handler(&mut self) {
+        self.lock().socket_mut(self.handle).handler.take();
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut stack = self.lock();
+        let now = stack.readiness(self.handle);
+        if now.readable {
+            let queued = stack
+                .iface
+                .get_socket::<TcpSocket>(self.handle)
+                .recv_queue();
+            return Poll::Ready(Ok(queued));
+        }
+        stack.sync(self.handle);
+        add_waker(&mut stack.socket_mut(self.handle).wakers, cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut stack = self.lock();
+        let now = stack.readiness(self.handle);
+        if now.writable {
+            let socket = stack.iface.get_socket::<TcpSocket>(self.handle);
+            return Poll::Ready(Ok(socket.send_capacity() - socket.send_queue()));
+        }
+        stack.sync(self.handle);
+        add_waker(&mut stack.socket_mut(self.handle).wakers, cx.waker());
+        Poll::Pending
+    }
+}
+
+/// A UDP socket running on the userspace stack
+#[derive(Debug)]
+pub struct UserspaceUdpSocket {
+    stack: Arc<Mutex<Stack>>,
+    handle: SocketHandle,
+    local: SocketAddr,
+    broadcast: bool,
+    multicast_loop_v4: bool,
+    multicast_loop_v6: bool,
+    multicast_ttl_v4: u32,
+    joined: Vec<Ipv4Addr>,
+}
+
+impl UserspaceUdpSocket {
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap()
+    }
+}
+
+impl Drop for UserspaceUdpSocket {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            for group in self.joined.drain(..) {
+                let _ = stack
+                    .iface
+                    .leave_multicast_group(IpAddress::from(group), Instant::now());
+            }
+            stack.release_datagram(self.handle);
+        }
+    }
+}
+
+impl VirtualUdpSocket for UserspaceUdpSocket {
+    fn set_broadcast(&mut self, broadcast: bool) -> Result<()> {
+        self.broadcast = broadcast;
+        Ok(())
+    }
+
+    fn broadcast(&self) -> Result<bool> {
+        Ok(self.broadcast)
+    }
+
+    fn set_multicast_loop_v4(&mut self, val: bool) -> Result<()> {
+        self.multicast_loop_v4 = val;
+        Ok(())
+    }
+
+    fn multicast_loop_v4(&self) -> Result<bool> {
+        Ok(self.multicast_loop_v4)
+    }
+
+    fn set_multicast_loop_v6(&mut self, val: bool) -> Result<()> {
+        self.multicast_loop_v6 = val;
+        Ok(())
+    }
+
+    fn multicast_loop_v6(&self) -> Result<bool> {
+        Ok(self.multicast_loop_v6)
+    }
+
+    fn set_multicast_ttl_v4(&mut self, ttl: u32) -> Result<()> {
+        self.multicast_ttl_v4 = ttl;
+        Ok(())
+    }
+
+    fn multicast_ttl_v4(&self) -> Result<u32> {
+        Ok(self.multicast_ttl_v4)
+    }
+
+    fn join_multicast_v4(&mut self, multiaddr: Ipv4Addr, _iface: Ipv4Addr) -> Result<()> {
+        if !multiaddr.is_multicast() {
+            return Err(NetworkError::InvalidInput);
+        }
+        let mut stack = self.lock();
+        stack
+            .iface
+            .join_multicast_group(IpAddress::from(multiaddr), Instant::now())
+            .map_err(|_| NetworkError::InsufficientMemory)?;
+        stack.wake_driver();
+        drop(stack);
+
+        if !self.joined.contains(&multiaddr) {
+            self.joined.push(multiaddr);
+        }
+        Ok(())
+    }
+
+    fn leave_multicast_v4(&mut self, multiaddr: Ipv4Addr, _iface: Ipv4Addr) -> Result<()> {
+        if !self.joined.contains(&multiaddr) {
+            return Err(NetworkError::AddressNotAvailable);
+        }
+        self.joined.retain(|a| *a != multiaddr);
+        let mut stack = self.lock();
+        let _ = stack
+            .iface
+            .leave_multicast_group(IpAddress::from(multiaddr), Instant::now());
+        stack.wake_driver();
+        Ok(())
+    }
+
+    fn join_multicast_v6(&mut self, _multiaddr: Ipv6Addr, _iface: u32) -> Result<()> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn leave_multicast_v6(&mut self, _multiaddr: Ipv6Addr, _iface: u32) -> Result<()> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn addr_peer(&self) -> Result<Option<SocketAddr>> {
+        Ok(None)
+    }
+}
+
+impl VirtualConnectionlessSocket for UserspaceUdpSocket {
+    fn try_send_to(&mut self, data: &[u8], mut addr: SocketAddr) -> Result<usize> {
+        let mut stack = self.lock();
+        if let IpAddr::V4(ip) = addr.ip() {
+            // The stack only knows how to send to the limited broadcast
+            // address so directed broadcasts on our subnets are mapped to it
+            let directed = stack.iface.ip_addrs().iter().any(|cidr| match cidr {
+                SmolIpCidr::Ipv4(cidr) => cidr.broadcast() == Some(ip.into()),
+                _ => false,
+            });
+            if ip.is_broadcast() || directed {
+                if !self.broadcast {
+                    return Err(NetworkError::PermissionDenied);
+                }
+                if stack.medium == LinkMedium::Ip {
+                    return Err(NetworkError::Unsupported);
+                }
+                addr.set_ip(Ipv4Addr::BROADCAST.into());
+            }
+        }
+
+        let socket = stack.iface.get_socket::<UdpSocket>(self.handle);
+        match socket.send_slice(data, IpEndpoint::from(addr)) {
+            Ok(()) => {
+                stack.wake_driver();
+                Ok(data.len())
+            }
+            Err(smoltcp::Error::Exhausted) => {
+                stack.sync(self.handle);
+                Err(NetworkError::WouldBlock)
+            }
+            Err(smoltcp::Error::Unaddressable) => Err(NetworkError::AddressNotAvailable),
+            Err(smoltcp::Error::Truncated) => Err(NetworkError::InvalidInput),
+            Err(_) => Err(NetworkError::IOError),
+        }
+    }
+
+    fn try_recv_from(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<(usize, SocketAddr)> {
+        let buf: &mut [u8] = unsafe { std::mem::transmute(buf) };
+
+        let mut stack = self.lock();
+        let socket = stack.iface.get_socket::<UdpSocket>(self.handle);
+        match socket.recv_slice(buf) {
+            Ok((read, from)) => Ok((read, endpoint_to_addr(from, self.local.ip()))),
+            Err(smoltcp::Error::Exhausted) => {
+                stack.sync(self.handle);
+                Err(NetworkError::WouldBlock)
+            }
+            Err(_) => Err(NetworkError::IOError),
+        }
+    }
+}
+
+impl VirtualSocket for UserspaceUdpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        let ttl = u8::try_from(ttl).map_err(|_| NetworkError::InvalidInput)?;
+        let mut stack = self.lock();
+        stack
+            .iface
+            .get_socket::<UdpSocket>(self.handle)
+            .set_hop_limit(Some(ttl));
+        Ok(())
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        let mut stack = self.lock();
+        let ttl = stack.iface.get_socket::<UdpSocket>(self.handle).hop_limit();
+        Ok(ttl.unwrap_or(DEFAULT_TTL) as u32)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        Ok(SocketStatus::Opened)
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.lock().set_handler(self.handle, handler);
+        Ok(())
+    }
+}
+
+impl VirtualIoSource for UserspaceUdpSocket {
+    fn remove_handler(&mut self) {
+        self.lock().socket_mut(self.handle).handler.take();
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut stack = self.lock();
+        let socket = stack.iface.get_socket::<UdpSocket>(self.handle);
+        if let Ok((data, _)) = socket.peek() {
+            return Poll::Ready(Ok(data.len()));
+        }
+        stack.sync(self.handle);
+        add_waker(&mut stack.socket_mut(self.handle).wakers, cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut stack = self.lock();
+        let socket = stack.iface.get_socket::<UdpSocket>(self.handle);
+        if socket.can_send() {
+            return Poll::Ready(Ok(socket.payload_send_capacity()));
+        }
+        stack.sync(self.handle);
+        add_waker(&mut stack.socket_mut(self.handle).wakers, cx.waker());
+        Poll::Pending
+    }
+}
+
+/// An ICMP socket running on the userspace stack
+///
+/// The stack matches echo replies using its own identifier, hence the
+/// identifier of outgoing echo requests is rewritten and then restored on
+/// the replies.
+#[derive(Debug)]
+pub struct UserspaceIcmpSocket {
+    stack: Arc<Mutex<Stack>>,
+    handle: SocketHandle,
+    addr: IpAddr,
+    ident: u16,
+    guest_ident: Option<u16>,
+}
+
+impl UserspaceIcmpSocket {
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap()
+    }
+}
+
+impl Drop for UserspaceIcmpSocket {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.release_datagram(self.handle);
+        }
+    }
+}
+
+impl VirtualIcmpSocket for UserspaceIcmpSocket {}
+
+impl VirtualConnectionlessSocket for UserspaceIcmpSocket {
+    fn try_send_to(&mut self, data: &[u8], addr: SocketAddr) -> Result<usize> {
+        let mut packet = data.to_vec();
+        if let Ok(mut icmp) = Icmpv4Packet::new_checked(&mut packet[..]) {
+            if icmp.msg_type() == Icmpv4Message::EchoRequest {
+                self.guest_ident = Some(icmp.echo_ident());
+                icmp.set_echo_ident(self.ident);
+                icmp.fill_checksum();
+            }
+        }
+
+        let mut stack = self.lock();
+        let socket = stack.iface.get_socket::<IcmpSocket>(self.handle);
+        match socket.send_slice(&packet, addr.ip().into()) {
+            Ok(()) => {
+                stack.wake_driver();
+                Ok(data.len())
+            }
+            Err(smoltcp::Error::Exhausted) => {
+                stack.sync(self.handle);
+                Err(NetworkError::WouldBlock)
+            }
+            Err(smoltcp::Error::Unaddressable) => Err(NetworkError::AddressNotAvailable),
+            Err(_) => Err(NetworkError::InvalidInput),
+        }
+    }
+
+    fn try_recv_from(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<(usize, SocketAddr)> {
+        let buf: &mut [u8] = unsafe { std::mem::transmute(buf) };
+
+        let mut stack = self.lock();
+        let socket = stack.iface.get_socket::<IcmpSocket>(self.handle);
+        let (read, from) = match socket.recv_slice(buf) {
+            Ok(ret) => ret,
+            Err(smoltcp::Error::Exhausted) => {
+                stack.sync(self.handle);
+                return Err(NetworkError::WouldBlock);
+            }
+            Err(_) => return Err(NetworkError::IOError),
+        };
+        drop(stack);
+
+        if let (Some(ident), Ok(mut icmp)) = (
+            self.guest_ident,
+            Icmpv4Packet::new_checked(&mut buf[..read]),
+        ) {
+            if icmp.msg_type() == Icmpv4Message::EchoReply {
+                icmp.set_echo_ident(ident);
+                icmp.fill_checksum();
+            }
+        }
+        Ok((read, SocketAddr::new(from.into(), 0)))
+    }
+}
+
+impl VirtualSocket for UserspaceIcmpSocket {
+    fn set_ttl(&mut self, ttl: u32) -> Result<()> {
+        let ttl = u8::try_from(ttl).map_err(|_| NetworkError::InvalidInput)?;
+        let mut stack = self.lock();
+        stack
+            .iface
+            .get_socket::<IcmpSocket>(self.handle)
+            .set_hop_limit(Some(ttl));
+        Ok(())
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        let mut stack = self.lock();
+        let ttl = stack
+            .iface
+            .get_socket::<IcmpSocket>(self.handle)
+            .hop_limit();
+        Ok(ttl.unwrap_or(DEFAULT_TTL) as u32)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        Ok(SocketAddr::new(self.addr, 0))
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        Ok(SocketStatus::Opened)
+    }
+
+    fn set_handler(&mut self, handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        self.lock().set_handler(self.handle, handler);
+        Ok(())
+    }
+}
+
+impl VirtualIoSource for UserspaceIcmpSocket {
+    fn remove_handler(&mut self) {
+        self.lock().socket_mut(self.handle).handler.take();
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut stack = self.lock();
+        if stack.readiness(self.handle).readable {
+            return Poll::Ready(Ok(1));
+        }
+        stack.sync(self.handle);
+        add_waker(&mut stack.socket_mut(self.handle).wakers, cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut stack = self.lock();
+        if stack.readiness(self.handle).writable {
+            return Poll::Ready(Ok(1));
+        }
+        stack.sync(self.handle);
+        add_waker(&mut stack.socket_mut(self.handle).wakers, cx.waker());
+        Poll::Pending
+    }
+}
+
+/// A raw socket that taps directly into the link of the userspace stack.
+/// Frames that are sent are put on the link as-is while every frame that
+/// arrives for this interface (or any frame at all when in promiscuous
+/// mode) is copied to the socket.
+#[derive(Debug)]
+pub struct UserspaceRawSocket {
+    stack: Arc<Mutex<Stack>>,
+    id: u64,
+    promiscuous: bool,
+}
+
+impl UserspaceRawSocket {
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap()
+    }
+}
+
+impl Drop for UserspaceRawSocket {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.taps.remove(&self.id);
+        }
+    }
+}
+
+impl VirtualRawSocket for UserspaceRawSocket {
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        let mut stack = self.lock();
+        if stack.link_closed {
+            return Err(NetworkError::ConnectionReset);
+        }
+        if stack.link_out.len() >= MAX_QUEUED_FRAMES {
+            return Err(NetworkError::WouldBlock);
+        }
+        stack.link_out.push_back(data.to_vec());
+        stack.wake_driver();
+        Ok(data.len())
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let mut stack = self.lock();
+        let tap = stack.taps.get_mut(&self.id).unwrap();
+        match tap.queue.pop_front() {
+            Some(frame) => {
+                let len = frame.len().min(buf.len());
+                let buf: &mut [u8] = unsafe { std::mem::transmute(&mut buf[..len]) };
+                buf.copy_from_slice(&frame[..len]);
+                Ok(len)
+            }
+            None if stack.link_closed => Err(NetworkError::ConnectionReset),
+            None => Err(NetworkError::WouldBlock),
+        }
+    }
+
+    fn set_promiscuous(&mut self, promiscuous: bool) -> Result<()> {
+        self.promiscuous = promiscuous;
+        self.lock().taps.get_mut(&self.id).unwrap().promiscuous = promiscuous;
+        Ok(())
+    }
+
+    fn promiscuous(&self) -> Result<bool> {
+        Ok(self.promiscuous)
+    }
+}
+
+impl VirtualSocket for UserspaceRawSocket {
+    fn set_ttl(&mut self, _ttl: u32) -> Result<()> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        Ok(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        match self.lock().link_closed {
+            true => Ok(SocketStatus::Closed),
+            false => Ok(SocketStatus::Opened),
+        }
+    }
+
+    fn set_handler(&mut self, mut handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        let mut stack = self.lock();
+        let tap = stack.taps.get_mut(&self.id).unwrap();
+        if !tap.queue.is_empty() {
+            handler.push_interest(InterestType::Readable);
+        }
+        handler.push_interest(InterestType::Writable);
+        tap.handler.replace(handler);
+        Ok(())
+    }
+}
+
+impl VirtualIoSource for UserspaceRawSocket {
+    fn remove_handler(&mut self) {
+        self.lock().taps.get_mut(&self.id).unwrap().handler.take();
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let mut stack = self.lock();
+        let tap = stack.taps.get_mut(&self.id).unwrap();
+        if let Some(frame) = tap.queue.front() {
+            return Poll::Ready(Ok(frame.len()));
+        }
+        add_waker(&mut tap.wakers, cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        Poll::Ready(Ok(DEFAULT_MTU))
+    }
+}
+
+/// An in-memory link that carries frames between its two ends, it is
+/// mostly useful for connecting two [`UserspaceNetworking`] stacks
+/// together without involving the host
+#[derive(Debug)]
+pub struct FramePipe {
+    side: usize,
+    state: Arc<Mutex<FramePipeState>>,
+    promiscuous: bool,
+}
+
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+struct FramePipeState {
+    queues: [VecDeque<Vec<u8>>; 2],
+    #[derivative(Debug = "ignore")]
+    handlers: [Option<Box<dyn InterestHandler + Send + Sync>>; 2],
+    #[derivative(Debug = "ignore")]
+    wakers: [Vec<Waker>; 2],
+    closed: bool,
+}
+
+impl FramePipe {
+    /// Creates both ends of the pipe
+    pub fn pair() -> (FramePipe, FramePipe) {
+        let state = Arc::new(Mutex::new(FramePipeState::default()));
+        (
+            FramePipe {
+                side: 0,
+                state: state.clone(),
+                promiscuous: false,
+            },
+            FramePipe {
+                side: 1,
+                state,
+                promiscuous: false,
+            },
+        )
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FramePipeState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for FramePipe {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+            let other = 1 - self.side;
+            if let Some(handler) = state.handlers[other].as_mut() {
+                handler.push_interest(InterestType::Closed);
+            }
+            for waker in state.wakers[other].drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl VirtualRawSocket for FramePipe {
+    fn try_send(&mut self, data: &[u8]) -> Result<usize> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(NetworkError::ConnectionReset);
+        }
+        let other = 1 - self.side;
+        // like a real wire the frame is lost when the other side is not
+        // keeping up
+        if state.queues[other].len() < MAX_QUEUED_FRAMES {
+            state.queues[other].push_back(data.to_vec());
+            if let Some(handler) = state.handlers[other].as_mut() {
+                handler.push_interest(InterestType::Readable);
+            }
+            for waker in state.wakers[other].drain(..) {
+                waker.wake();
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn try_flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn try_recv(&mut self, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+        let mut state = self.lock();
+        match state.queues[self.side].pop_front() {
+            Some(frame) => {
+                let len = frame.len().min(buf.len());
+                let buf: &mut [u8] = unsafe { std::mem::transmute(&mut buf[..len]) };
+                buf.copy_from_slice(&frame[..len]);
+                Ok(len)
+            }
+            None if state.closed => Err(NetworkError::ConnectionReset),
+            None => Err(NetworkError::WouldBlock),
+        }
+    }
+
+    fn set_promiscuous(&mut self, promiscuous: bool) -> Result<()> {
+        self.promiscuous = promiscuous;
+        Ok(())
+    }
+
+    fn promiscuous(&self) -> Result<bool> {
+        Ok(self.promiscuous)
+    }
+}
+
+impl VirtualSocket for FramePipe {
+    fn set_ttl(&mut self, _ttl: u32) -> Result<()> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn ttl(&self) -> Result<u32> {
+        Err(NetworkError::Unsupported)
+    }
+
+    fn addr_local(&self) -> Result<SocketAddr> {
+        Ok(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+    }
+
+    fn status(&self) -> Result<SocketStatus> {
+        match self.lock().closed {
+            true => Ok(SocketStatus::Closed),
+            false => Ok(SocketStatus::Opened),
+        }
+    }
+
+    fn set_handler(&mut self, mut handler: Box<dyn InterestHandler + Send + Sync>) -> Result<()> {
+        let mut state = self.lock();
+        if !state.queues[self.side].is_empty() {
+            handler.push_interest(InterestType::Readable);
+        }
+        if state.closed {
+            handler.push_interest(InterestType::Closed);
+        }
+        handler.push_interest(InterestType::Writable);
+        state.handlers[self.side].replace(handler);
+        Ok(())
+    }
+}
+
+impl VirtualIoSource for FramePipe {
+    fn remove_handler(&mut self) {
+        let side = self.side;
+        self.lock().handlers[side].take();
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        let side = self.side;
+        let mut state = self.lock();
+        if let Some(frame) = state.queues[side].front() {
+            return Poll::Ready(Ok(frame.len()));
+        }
+        if state.closed {
+            return Poll::Ready(Err(NetworkError::ConnectionReset));
+        }
+        add_waker(&mut state.wakers[side], cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_write_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        Poll::Ready(Ok(DEFAULT_MTU))
+    }
+}
+
+fn add_waker(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+fn random_u64() -> u64 {
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+fn smol_cidr(cidr: IpCidr) -> Result<SmolIpCidr> {
+    let max = match cidr.ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    if cidr.prefix > max {
+        return Err(NetworkError::InvalidInput);
+    }
+    let ret = SmolIpCidr::new(cidr.ip.into(), cidr.prefix);
+    if !ret.address().is_unicast() {
+        return Err(NetworkError::InvalidInput);
+    }
+    Ok(ret)
+}
+
+fn from_smol_cidr(cidr: SmolIpCidr) -> IpCidr {
+    IpCidr {
+        ip: cidr.address().into(),
+        prefix: cidr.prefix_len(),
+    }
+}
+
+fn smol_addr_or_unspecified(ip: IpAddr) -> IpAddress {
+    match ip.is_unspecified() {
+        true => IpAddress::Unspecified,
+        false => ip.into(),
+    }
+}
+
+/// Converts an endpoint back into a socket address, the `fallback` is used
+/// when the endpoint is not bound to any specific address
+fn endpoint_to_addr(endpoint: IpEndpoint, fallback: IpAddr) -> SocketAddr {
+    let ip = match endpoint.addr {
+        IpAddress::Unspecified => fallback,
+        addr => addr.into(),
+    };
+    SocketAddr::new(ip, endpoint.port)
+}
+
+/// Routes are expressed as durations since the UNIX epoch which is the
+/// same clock that the stack uses
+fn to_instant(duration: Duration) -> Instant {
+    Instant::from_micros(duration.as_micros() as i64)
+}
+
+fn from_instant(instant: Instant) -> Duration {
+    Duration::from_micros(instant.total_micros().max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{VirtualConnectedSocketExt, VirtualTcpListenerExt};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    async fn stack(link: FramePipe, ip: Ipv4Addr) -> UserspaceNetworking {
+        let (net, driver) = UserspaceNetworking::new(Box::new(link), LinkMedium::ethernet());
+        tokio::task::spawn(driver);
+        net.ip_add(ip.into(), 24).await.unwrap();
+        net
+    }
+
+    async fn connected_stacks() -> (UserspaceNetworking, UserspaceNetworking) {
+        let (a, b) = FramePipe::pair();
+        let a = stack(a, Ipv4Addr::new(10, 0, 0, 1)).await;
+        let b = stack(b, Ipv4Addr::new(10, 0, 0, 2)).await;
+        (a, b)
+    }
+
+    async fn write_all(socket: &mut Box<dyn VirtualTcpSocket + Sync>, mut data: &[u8]) {
+        while !data.is_empty() {
+            let sent = socket.send(data).await.unwrap();
+            data = &data[sent..];
+        }
+    }
+
+    async fn read_to_end(socket: &mut Box<dyn VirtualTcpSocket + Sync>) -> Vec<u8> {
+        let mut ret = Vec::new();
+        let mut buf = [MaybeUninit::uninit(); 4096];
+        loop {
+            let read = socket.recv(&mut buf).await.unwrap();
+            if read == 0 {
+                return ret;
+            }
+            let data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+            ret.extend_from_slice(data);
+        }
+    }
+
+    fn any_addr(port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_echo_between_stacks() {
+        let (a, b) = connected_stacks().await;
+
+        let mut listener = b
+            .listen_tcp(any_addr(80), false, false, false)
+            .await
+            .unwrap();
+        let server = tokio::task::spawn(async move {
+            let (mut socket, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer.ip(), IpAddr::from(Ipv4Addr::new(10, 0, 0, 1)));
+            let data = read_to_end(&mut socket).await;
+            write_all(&mut socket, &data).await;
+            socket.shutdown(Shutdown::Write).unwrap();
+        });
+
+        let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 80);
+        let mut socket = tokio::time::timeout(TIMEOUT, a.connect_tcp(any_addr(0), peer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(socket.addr_peer().unwrap(), peer);
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        write_all(&mut socket, &data).await;
+        socket.shutdown(Shutdown::Write).unwrap();
+        let echoed = tokio::time::timeout(TIMEOUT, read_to_end(&mut socket))
+            .await
+            .unwrap();
+        assert_eq!(echoed, data);
+        server.await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_connect_to_closed_port_is_refused() {
+        let (a, _b) = connected_stacks().await;
+
+        let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 81);
+        let ret = tokio::time::timeout(TIMEOUT, a.connect_tcp(any_addr(0), peer))
+            .await
+            .unwrap();
+        assert_eq!(ret.unwrap_err(), NetworkError::ConnectionRefused);
+    }
+
+    async fn connect(
+        a: &UserspaceNetworking,
+        port: u16,
+    ) -> Result<Box<dyn VirtualTcpSocket + Sync>> {
+        let peer = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), port);
+        tokio::time::timeout(TIMEOUT, a.connect_tcp(any_addr(0), peer))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_close_is_seen_as_end_of_stream() {
+        let (a, b) = connected_stacks().await;
+
+        let mut listener = b
+            .listen_tcp(any_addr(80), false, false, false)
+            .await
+            .unwrap();
+        let server = tokio::task::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            write_all(&mut socket, b"goodbye").await;
+        });
+
+        let mut socket = connect(&a, 80).await.unwrap();
+        server.await.unwrap();
+
+        let data = tokio::time::timeout(TIMEOUT, read_to_end(&mut socket))
+            .await
+            .unwrap();
+        assert_eq!(data, b"goodbye");
+        // reading again keeps returning the end of the stream
+        let mut buf = [MaybeUninit::uninit(); 16];
+        assert_eq!(socket.recv(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_zero_linger_resets_the_connection() {
+        let (a, b) = connected_stacks().await;
+
+        let mut listener = b
+            .listen_tcp(any_addr(80), false, false, false)
+            .await
+            .unwrap();
+        let (connected_tx, connected_rx) = tokio::sync::oneshot::channel();
+        let server = tokio::task::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            connected_rx.await.unwrap();
+            socket.set_linger(Some(Duration::ZERO)).unwrap();
+        });
+
+        let mut socket = connect(&a, 80).await.unwrap();
+        connected_tx.send(()).unwrap();
+        server.await.unwrap();
+
+        let mut buf = [MaybeUninit::uninit(); 16];
+        let ret = tokio::time::timeout(TIMEOUT, socket.recv(&mut buf))
+            .await
+            .unwrap();
+        assert_eq!(ret.unwrap_err(), NetworkError::ConnectionReset);
+        assert!(socket.is_closed());
+        assert_eq!(
+            socket.try_send(b"hello").unwrap_err(),
+            NetworkError::BrokenPipe
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_connect_after_the_listener_is_dropped_is_refused() {
+        let (a, b) = connected_stacks().await;
+
+        let listener = b
+            .listen_tcp(any_addr(80), false, false, false)
+            .await
+            .unwrap();
+        connect(&a, 80).await.unwrap();
+        drop(listener);
+
+        assert_eq!(
+            connect(&a, 80).await.unwrap_err(),
+            NetworkError::ConnectionRefused
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_listener_backlog_is_bounded() {
+        let (a, b) = connected_stacks().await;
+
+        let mut listener = b
+            .listen_tcp(any_addr(80), false, false, false)
+            .await
+            .unwrap();
+
+        // Nobody is accepting, so connections pile up until the backlog is
+        // full and the stack starts refusing them
+        let mut clients = Vec::new();
+        let refused = loop {
+            match connect(&a, 80).await {
+                Ok(socket) => clients.push(socket),
+                Err(e) => break e,
+            }
+            assert!(clients.len() <= MAX_PENDING_CONNECTIONS + LISTEN_SOCKETS);
+        };
+        assert_eq!(refused, NetworkError::ConnectionRefused);
+        assert!(clients.len() >= MAX_PENDING_CONNECTIONS);
+
+        // Accepting the queued connections makes room for new ones
+        for _ in 0..clients.len() {
+            tokio::time::timeout(TIMEOUT, listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        connect(&a, 80).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn udp_between_stacks() {
+        let (a, b) = connected_stacks().await;
+
+        let mut server = b.bind_udp(any_addr(5000), false, false).await.unwrap();
+        let mut client = a.bind_udp(any_addr(0), false, false).await.unwrap();
+
+        let target = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 5000);
+        client.send_to(b"hello", target).await.unwrap();
+
+        let mut buf = [MaybeUninit::uninit(); 64];
+        let (read, from) = tokio::time::timeout(TIMEOUT, server.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+        assert_eq!(data, b"hello");
+        assert_eq!(from.ip(), IpAddr::from(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(from.port(), client.addr_local().unwrap().port());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn udp_to_an_unbound_port_is_unreachable() {
+        use smoltcp::wire::{
+            EthernetFrame, EthernetProtocol, Icmpv4DstUnreachable, IpProtocol, Ipv4Packet,
+        };
+
+        let (a, b) = connected_stacks().await;
+
+        let mut tap = a.bind_raw().await.unwrap();
+        let mut client = a.bind_udp(any_addr(0), false, false).await.unwrap();
+        let target = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 6000);
+        client.send_to(b"anyone?", target).await.unwrap();
+
+        // The peer answers with an ICMP port unreachable
+        let unreachable = tokio::time::timeout(TIMEOUT, async {
+            let mut buf = [MaybeUninit::uninit(); 2048];
+            loop {
+                let read = match tap.try_recv(&mut buf) {
+                    Ok(read) => read,
+                    Err(NetworkError::WouldBlock) => {
+                        tokio::time::sleep(Duration::from_millis(5)).await;
+                        continue;
+                    }
+                    Err(e) => panic!("{e}"),
+                };
+                let frame: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+                let frame = EthernetFrame::new_checked(frame).unwrap();
+                if frame.ethertype() != EthernetProtocol::Ipv4 {
+                    continue;
+                }
+                let ip = Ipv4Packet::new_checked(frame.payload()).unwrap();
+                if ip.protocol() != IpProtocol::Icmp {
+                    continue;
+                }
+                let icmp = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+                if icmp.msg_type() == Icmpv4Message::DstUnreachable {
+                    break (IpAddr::from(Ipv4Addr::from(ip.src_addr())), icmp.msg_code());
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            unreachable,
+            (target.ip(), u8::from(Icmpv4DstUnreachable::PortUnreachable))
+        );
+
+        // The socket is still usable once something is listening
+        let mut server = b.bind_udp(any_addr(6000), false, false).await.unwrap();
+        client.send_to(b"hello", target).await.unwrap();
+        let mut buf = [MaybeUninit::uninit(); 64];
+        let (read, _) = tokio::time::timeout(TIMEOUT, server.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let data: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+        assert_eq!(data, b"hello");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ping_between_stacks() {
+        let (a, _b) = connected_stacks().await;
+
+        let mut socket = a.bind_icmp(Ipv4Addr::UNSPECIFIED.into()).await.unwrap();
+        // echo request with identifier 0x1234 and sequence 1
+        let mut request = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g'];
+        Icmpv4Packet::new_unchecked(&mut request[..]).fill_checksum();
+        let target = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 0);
+        socket.send_to(&request, target).await.unwrap();
+
+        let mut buf = [MaybeUninit::uninit(); 64];
+        let (read, from) = tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+        assert_eq!(from.ip(), target.ip());
+        assert_eq!(reply[0], 0);
+        assert_eq!(&reply[4..], &request[4..]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resolve_queries_the_dns_server() {
+        let (a, b) = connected_stacks().await;
+
+        let mut server = b.bind_udp(any_addr(53), false, false).await.unwrap();
+        tokio::task::spawn(async move {
+            let mut buf = [MaybeUninit::uninit(); 512];
+            let (read, from) = server.recv_from(&mut buf).await.unwrap();
+            let query: &[u8] = unsafe { std::mem::transmute(&buf[..read]) };
+
+            let mut response = query.to_vec();
+            response[2..4].copy_from_slice(&[0x81, 0x80]);
+            response[6..8].copy_from_slice(&1u16.to_be_bytes());
+            response.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            response.extend_from_slice(&[192, 168, 1, 10]);
+            server.send_to(&response, from).await.unwrap();
+        });
+
+        let ips = tokio::time::timeout(
+            TIMEOUT,
+            a.resolve("example.com", None, Some(Ipv4Addr::new(10, 0, 0, 2).into())),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(ips, vec![IpAddr::from(Ipv4Addr::new(192, 168, 1, 10))]);
+    }
+
+    #[tokio::test]
+    async fn addresses_and_routes_are_managed() {
+        let (link, _other) = FramePipe::pair();
+        let net = stack(link, Ipv4Addr::new(10, 0, 0, 1)).await;
+
+        net.gateway_set(Ipv4Addr::new(10, 0, 0, 254).into())
+            .await
+            .unwrap();
+        let routes = net.route_list().await.unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(
+            routes[0].via_router,
+            IpAddr::from(Ipv4Addr::new(10, 0, 0, 254))
+        );
+
+        net.ip_remove(Ipv4Addr::new(10, 0, 0, 1).into())
+            .await
+            .unwrap();
+        assert!(net.ip_list().await.unwrap().is_empty());
+        let ret = net
+            .bind_udp(
+                SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 0),
+                false,
+                false,
+            )
+            .await;
+        assert_eq!(ret.unwrap_err(), NetworkError::AddressNotAvailable);
+    }
+}
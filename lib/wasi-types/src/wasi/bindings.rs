@@ -1028,6 +1028,8 @@ pub enum Sockoption {
     MulticastTtlV4,
     Type,
     Proto,
+    TlsClient,
+    TlsServer,
 }
 impl core::fmt::Debug for Sockoption {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -1059,6 +1061,8 @@ impl core::fmt::Debug for Sockoption {
             Sockoption::MulticastTtlV4 => f.debug_tuple("Sockoption::MulticastTtlV4").finish(),
             Sockoption::Type => f.debug_tuple("Sockoption::Type").finish(),
             Sockoption::Proto => f.debug_tuple("Sockoption::Proto").finish(),
+            Sockoption::TlsClient => f.debug_tuple("Sockoption::TlsClient").finish(),
+            Sockoption::TlsServer => f.debug_tuple("Sockoption::TlsServer").finish(),
         }
     }
 }
@@ -2972,6 +2976,8 @@ unsafe impl wasmer::FromToNativeWasmType for Sockoption {
             24 => Self::MulticastTtlV4,
             25 => Self::Type,
             26 => Self::Proto,
+            27 => Self::TlsClient,
+            28 => Self::TlsServer,
 
             q => {
                 tracing::debug!("could not serialize number {q} to enum Sockoption");
@@ -154,6 +154,8 @@ impl std::fmt::Display for Sockoption {
             Self::MulticastTtlV4 => "Sockoption::MulticastTtlV4",
             Self::Type => "Sockoption::Type",
             Self::Proto => "Sockoption::Proto",
+            Self::TlsClient => "Sockoption::TlsClient",
+            Self::TlsServer => "Sockoption::TlsServer",
         };
         write!(f, "{}", s)
     }
@@ -10,7 +10,10 @@ use webc::{compat::SharedBytes, Container};
 use crate::{
     runtime::{
         module_cache::ModuleHash,
-        resolver::{PackageId, PackageInfo, PackageSpecifier, ResolveError},
+        resolver::{
+            DistributionInfo, Lockfile, LockfileMode, PackageId, PackageInfo, PackageSpecifier,
+            Resolution, ResolveError, Source,
+        },
     },
     Runtime,
 };
@@ -81,6 +84,21 @@ impl BinaryPackage {
         container: &Container,
         rt: &(dyn Runtime + Send + Sync),
     ) -> Result<Self, anyhow::Error> {
+        let (pkg, _) = BinaryPackage::from_webc_with_lockfile(container, rt, None).await?;
+        Ok(pkg)
+    }
+
+    /// Load a [`webc::Container`] and all its dependencies into a
+    /// [`BinaryPackage`], sticking to the versions in a [`Lockfile`].
+    ///
+    /// The [`Lockfile`] for the dependencies that were used is returned
+    /// alongside the package.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn from_webc_with_lockfile(
+        container: &Container,
+        rt: &(dyn Runtime + Send + Sync),
+        lockfile: Option<LockfileMode<'_>>,
+    ) -> Result<(Self, Lockfile), anyhow::Error> {
         let source = rt.source();
         let root = PackageInfo::from_manifest(container.manifest())?;
         let root_id = PackageId {
@@ -88,14 +106,15 @@ impl BinaryPackage {
             version: root.version.clone(),
         };
 
-        let resolution = crate::runtime::resolver::resolve(&root_id, &root, &*source).await?;
+        let (resolution, lockfile) =
+            resolve_locked(&root_id, &root, &*source, None, lockfile).await?;
         let pkg = rt
             .package_loader()
             .load_package_tree(container, &resolution)
             .await
             .map_err(|e| anyhow::anyhow!(e))?;
 
-        Ok(pkg)
+        Ok((pkg, lockfile))
     }
 
     /// Load a [`BinaryPackage`] and all its dependencies from a registry.
@@ -104,28 +123,71 @@ impl BinaryPackage {
         specifier: &PackageSpecifier,
         runtime: &(dyn Runtime + Send + Sync),
     ) -> Result<Self, anyhow::Error> {
+        let (pkg, _) = BinaryPackage::from_registry_with_lockfile(specifier, runtime, None).await?;
+        Ok(pkg)
+    }
+
+    /// Load a [`BinaryPackage`] and all its dependencies from a registry,
+    /// sticking to the versions in a [`Lockfile`] (including for the package
+    /// itself).
+    ///
+    /// The [`Lockfile`] for the packages that were used is returned alongside
+    /// the package.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn from_registry_with_lockfile(
+        specifier: &PackageSpecifier,
+        runtime: &(dyn Runtime + Send + Sync),
+        lockfile: Option<LockfileMode<'_>>,
+    ) -> Result<(Self, Lockfile), anyhow::Error> {
+        let specifier = match (specifier, lockfile) {
+            (PackageSpecifier::Registry { full_name, version }, Some(mode))
+                if mode.lockfile().is_for(full_name)
+                    && version.matches(&mode.lockfile().root.version) =>
+            {
+                let locked = &mode.lockfile().root.version;
+                PackageSpecifier::Registry {
+                    full_name: full_name.clone(),
+                    version: semver::VersionReq {
+                        comparators: vec![semver::Comparator {
+                            op: semver::Op::Exact,
+                            major: locked.major,
+                            minor: Some(locked.minor),
+                            patch: Some(locked.patch),
+                            pre: locked.pre.clone(),
+                        }],
+                    },
+                }
+            }
+            _ => specifier.clone(),
+        };
+
         let source = runtime.source();
         let root_summary =
             source
-                .latest(specifier)
+                .latest(&specifier)
                 .await
                 .map_err(|error| ResolveError::Registry {
                     package: specifier.clone(),
                     error,
                 })?;
-        let root = runtime.package_loader().load(&root_summary).await?;
         let id = root_summary.package_id();
 
-        let resolution = crate::runtime::resolver::resolve(&id, &root_summary.pkg, &source)
-            .await
-            .context("Dependency resolution failed")?;
+        let (resolution, lockfile) = resolve_locked(
+            &id,
+            &root_summary.pkg,
+            &source,
+            Some(&root_summary.dist),
+            lockfile,
+        )
+        .await?;
+        let root = runtime.package_loader().load(&root_summary).await?;
         let pkg = runtime
             .package_loader()
             .load_package_tree(&root, &resolution)
             .await
             .map_err(|e| anyhow::anyhow!(e))?;
 
-        Ok(pkg)
+        Ok((pkg, lockfile))
     }
 
     pub fn get_command(&self, name: &str) -> Option<&BinaryPackageCommand> {
@@ -151,6 +213,48 @@ impl BinaryPackage {
     }
 }
 
+/// Resolve the dependencies of `root`, making sure the result agrees with
+/// the [`Lockfile`] when running in locked mode.
+async fn resolve_locked(
+    root_id: &PackageId,
+    root: &PackageInfo,
+    source: &dyn Source,
+    root_dist: Option<&DistributionInfo>,
+    lockfile: Option<LockfileMode<'_>>,
+) -> Result<(Resolution, Lockfile), anyhow::Error> {
+    let existing = lockfile
+        .map(LockfileMode::lockfile)
+        .filter(|lockfile| lockfile.is_for(&root.name));
+
+    if let (Some(LockfileMode::Locked(lockfile)), None) = (lockfile, existing) {
+        anyhow::bail!(
+            "The lockfile is for \"{}\", not \"{}\"",
+            lockfile.root.name,
+            root.name
+        );
+    }
+
+    let resolution = match existing {
+        Some(lockfile) => {
+            crate::runtime::resolver::resolve_with_lockfile(root_id, root, source, lockfile).await
+        }
+        None => crate::runtime::resolver::resolve(root_id, root, source).await,
+    }
+    .context("Dependency resolution failed")?;
+
+    let mut resolved = Lockfile::from_resolution(&resolution);
+    if let Some(dist) = root_dist {
+        resolved.root.url = Some(dist.webc.clone());
+        resolved.root.sha256 = Some(hex::encode(dist.webc_sha256.as_bytes()));
+    }
+
+    if let Some(LockfileMode::Locked(lockfile)) = lockfile {
+        lockfile.check(&resolved)?;
+    }
+
+    Ok((resolution, resolved))
+}
+
 #[cfg(test)]
 mod tests {
     use tempfile::TempDir;
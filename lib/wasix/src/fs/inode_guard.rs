@@ -568,6 +568,16 @@ impl VirtualFile for WasiStateFileGuard {
             Poll::Ready(Ok(0))
         }
     }
+
+    fn poll_sync(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut guard = self.lock_write();
+        if let Some(guard) = guard.as_mut() {
+            let file = Pin::new(guard.deref_mut());
+            file.poll_sync(cx)
+        } else {
+            Poll::Ready(Err(std::io::ErrorKind::Unsupported.into()))
+        }
+    }
 }
 
 impl AsyncSeek for WasiStateFileGuard {
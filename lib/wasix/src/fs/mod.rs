@@ -491,6 +491,10 @@ pub struct WasiFs {
     pub current_dir: Mutex<String>,
     #[cfg_attr(feature = "enable-serde", serde(skip, default))]
     pub root_fs: WasiFsRoot,
+    /// The root file system before any access policy was applied to it, if
+    /// it is different from [`WasiFs::root_fs`].
+    #[cfg_attr(feature = "enable-serde", serde(skip, default))]
+    pub(crate) unrestricted_root_fs: Option<WasiFsRoot>,
     pub root_inode: InodeGuard,
     pub has_unioned: Arc<Mutex<HashSet<String>>>,
 
@@ -527,6 +531,7 @@ impl WasiFs {
             current_dir: Mutex::new(self.current_dir.lock().unwrap().clone()),
             is_wasix: AtomicBool::new(self.is_wasix.load(Ordering::Acquire)),
             root_fs: self.root_fs.clone(),
+            unrestricted_root_fs: self.unrestricted_root_fs.clone(),
             root_inode: self.root_inode.clone(),
             has_unioned: Arc::new(Mutex::new(HashSet::new())),
             init_preopens: self.init_preopens.clone(),
@@ -573,11 +578,18 @@ impl WasiFs {
             return Ok(());
         }
 
-        self.root_fs.merge(&binary.webc_fs).await?;
+        self.unrestricted_root_fs().merge(&binary.webc_fs).await?;
 
         Ok(())
     }
 
+    /// The root file system the runtime should use when it makes changes on
+    /// the guest's behalf (e.g. injecting packages), bypassing any access
+    /// policy that only applies to the guest.
+    pub(crate) fn unrestricted_root_fs(&self) -> &WasiFsRoot {
+        self.unrestricted_root_fs.as_ref().unwrap_or(&self.root_fs)
+    }
+
     /// Created for the builder API. like `new` but with more information
     pub(crate) fn new_with_preopen(
         inodes: &WasiInodes,
@@ -630,6 +642,7 @@ impl WasiFs {
             current_dir: Mutex::new("/".to_string()),
             is_wasix: AtomicBool::new(false),
             root_fs: fs_backing,
+            unrestricted_root_fs: None,
             root_inode,
             has_unioned: Arc::new(Mutex::new(HashSet::new())),
             init_preopens: Default::default(),
@@ -1442,6 +1455,15 @@ impl WasiFs {
 
     #[allow(clippy::await_holding_lock)]
     pub async fn flush(&self, fd: WasiFd) -> Result<(), Errno> {
+        self.flush_or_sync(fd, false).await
+    }
+
+    /// Flush a file and wait for its data to reach durable storage.
+    pub async fn sync(&self, fd: WasiFd) -> Result<(), Errno> {
+        self.flush_or_sync(fd, true).await
+    }
+
+    async fn flush_or_sync(&self, fd: WasiFd, sync: bool) -> Result<(), Errno> {
         match fd {
             __WASI_STDIN_FILENO => (),
             __WASI_STDOUT_FILENO => {
@@ -1476,17 +1498,23 @@ impl WasiFs {
 
                 struct FlushPoller {
                     file: Arc<RwLock<Box<dyn VirtualFile + Send + Sync>>>,
+                    sync: bool,
                 }
                 impl Future for FlushPoller {
                     type Output = Result<(), Errno>;
                     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                        let sync = self.sync;
                         let mut file = self.file.write().unwrap();
-                        Pin::new(file.as_mut())
-                            .poll_flush(cx)
-                            .map_err(|_| Errno::Io)
+                        let file = Pin::new(file.as_mut());
+                        let result = if sync {
+                            file.poll_sync(cx)
+                        } else {
+                            file.poll_flush(cx)
+                        };
+                        result.map_err(|_| Errno::Io)
                     }
                 }
-                FlushPoller { file }.await?;
+                FlushPoller { file, sync }.await?;
             }
         }
         Ok(())
@@ -2007,7 +2035,9 @@ impl std::fmt::Debug for WasiFs {
 /// Returns the default filesystem backing
 pub fn default_fs_backing() -> Box<dyn virtual_fs::FileSystem + Send + Sync> {
     cfg_if::cfg_if! {
-        if #[cfg(feature = "host-fs")] {
+        if #[cfg(all(target_os = "linux", feature = "host-fs-io-uring"))] {
+            Box::<virtual_fs::host_fs::io_uring::FileSystem>::default()
+        } else if #[cfg(feature = "host-fs")] {
             Box::<virtual_fs::host_fs::FileSystem>::default()
         } else if #[cfg(not(feature = "host-fs"))] {
             Box::<virtual_fs::mem_fs::FileSystem>::default()
@@ -2088,6 +2118,7 @@ pub fn fs_error_from_wasi_err(err: Errno) -> FsError {
         Errno::Again => FsError::WouldBlock,
         Errno::Nospc => FsError::WriteZero,
         Errno::Notempty => FsError::DirectoryNotEmpty,
+        Errno::Dquot => FsError::QuotaExceeded,
         _ => FsError::UnknownError,
     }
 }
@@ -2118,6 +2149,7 @@ pub fn fs_error_into_wasi_err(fs_error: FsError) -> Errno {
         FsError::WriteZero => Errno::Nospc,
         FsError::DirectoryNotEmpty => Errno::Notempty,
         FsError::StorageFull => Errno::Overflow,
+        FsError::QuotaExceeded => Errno::Dquot,
         FsError::Lock | FsError::UnknownError => Errno::Io,
     }
 }
@@ -66,6 +66,12 @@ impl JournalEffector {
                     read_timeout: None,
                     accept_timeout: None,
                     connect_timeout: None,
+                    #[cfg(feature = "net-tls")]
+                    tls_client: None,
+                    #[cfg(feature = "net-tls")]
+                    tls_server_name: None,
+                    #[cfg(feature = "net-tls")]
+                    tls_server: None,
                     handler: None,
                 },
             }),
@@ -51,6 +51,16 @@ pub struct SocketProperties {
     pub read_timeout: Option<Duration>,
     pub accept_timeout: Option<Duration>,
     pub connect_timeout: Option<Duration>,
+    /// Upgrades the connection to TLS once it is connected
+    #[cfg(feature = "net-tls")]
+    pub tls_client: Option<virtual_net::TlsClientConfig>,
+    /// Name the certificate of the server is verified against, or else the
+    /// address of the peer
+    #[cfg(feature = "net-tls")]
+    pub tls_server_name: Option<String>,
+    /// Upgrades the accepted connections to TLS once it is listening
+    #[cfg(feature = "net-tls")]
+    pub tls_server: Option<virtual_net::TlsServerConfig>,
     #[derivative(Debug = "ignore")]
     pub handler: Option<Box<dyn InterestHandler + Send + Sync>>,
 }
@@ -114,6 +124,8 @@ pub enum WasiSocketOption {
     MulticastTtlV4,
     Type,
     Proto,
+    TlsClient,
+    TlsServer,
 }
 
 impl From<Sockoption> for WasiSocketOption {
@@ -147,6 +159,8 @@ impl From<Sockoption> for WasiSocketOption {
             Sockoption::MulticastTtlV4 => MulticastTtlV4,
             Sockoption::Type => Type,
             Sockoption::Proto => Proto,
+            Sockoption::TlsClient => TlsClient,
+            Sockoption::TlsServer => TlsServer,
         }
     }
 }
@@ -363,6 +377,8 @@ impl InodeSocket {
             .flatten()
             .unwrap_or(Duration::from_secs(30));
 
+        #[cfg(feature = "net-tls")]
+        let mut tls_server = None;
         let socket = {
             let inner = self.inner.protected.read().unwrap();
             match &inner.kind {
@@ -376,6 +392,10 @@ impl InodeSocket {
                         let only_v6 = props.only_v6;
                         let reuse_port = props.reuse_port;
                         let reuse_addr = props.reuse_addr;
+                        #[cfg(feature = "net-tls")]
+                        {
+                            tls_server = props.tls_server.clone();
+                        }
                         drop(inner);
 
                         net.listen_tcp(addr, only_v6, reuse_port, reuse_addr)
@@ -438,6 +458,11 @@ impl InodeSocket {
         tokio::select! {
             socket = socket => {
                 let socket = socket.map_err(net_error_into_wasi_err)?;
+                #[cfg(feature = "net-tls")]
+                let socket = match tls_server {
+                    Some(tls) => Box::new(tls.listener(socket)) as Box<dyn VirtualTcpListener + Sync>,
+                    None => socket,
+                };
                 Ok(Some(InodeSocket::new(InodeSocketKind::TcpListener {
                     socket,
                     accept_timeout: Some(timeout),
@@ -555,6 +580,13 @@ impl InodeSocket {
                             let no_delay = props.no_delay;
                             let keep_alive = props.keep_alive;
                             let dont_route = props.dont_route;
+                            #[cfg(feature = "net-tls")]
+                            let tls_client = props.tls_client.clone();
+                            #[cfg(feature = "net-tls")]
+                            let tls_server_name = props
+                                .tls_server_name
+                                .clone()
+                                .unwrap_or_else(|| peer.ip().to_string());
                             let addr = match addr {
                                 Some(a) => *a,
                                 None => {
@@ -576,6 +608,10 @@ impl InodeSocket {
                                 if let Some(dont_route) = dont_route {
                                     ret.set_dontroute(dont_route).ok();
                                 }
+                                #[cfg(feature = "net-tls")]
+                                if let Some(tls) = tls_client {
+                                    ret = Box::new(tls.connect(ret, &tls_server_name)?);
+                                }
                                 Ok(ret)
                             })
                         }
@@ -751,6 +787,63 @@ impl InodeSocket {
         Ok(())
     }
 
+    /// Upgrades the connection to TLS once the socket is connected, this
+    /// has to be set before connecting
+    #[cfg(feature = "net-tls")]
+    pub fn set_tls_client(
+        &mut self,
+        config: Option<virtual_net::TlsClientConfig>,
+    ) -> Result<(), Errno> {
+        let mut inner = self.inner.protected.write().unwrap();
+        match &mut inner.kind {
+            InodeSocketKind::PreSocket { props, .. } if props.ty == Socktype::Stream => {
+                props.tls_client = config;
+                Ok(())
+            }
+            InodeSocketKind::TcpStream { .. } | InodeSocketKind::TcpListener { .. } => {
+                Err(Errno::Isconn)
+            }
+            _ => Err(Errno::Notsup),
+        }
+    }
+
+    /// Upgrades the accepted connections to TLS once the socket is
+    /// listening, this has to be set before listening
+    #[cfg(feature = "net-tls")]
+    pub fn set_tls_server(
+        &mut self,
+        config: Option<virtual_net::TlsServerConfig>,
+    ) -> Result<(), Errno> {
+        let mut inner = self.inner.protected.write().unwrap();
+        match &mut inner.kind {
+            InodeSocketKind::PreSocket { props, .. } if props.ty == Socktype::Stream => {
+                props.tls_server = config;
+                Ok(())
+            }
+            InodeSocketKind::TcpStream { .. } | InodeSocketKind::TcpListener { .. } => {
+                Err(Errno::Isconn)
+            }
+            _ => Err(Errno::Notsup),
+        }
+    }
+
+    /// Sets the name the certificate of the server is verified against once
+    /// the connection is upgraded to TLS, this has to be set before connecting
+    #[cfg(feature = "net-tls")]
+    pub fn set_tls_server_name(&mut self, name: Option<String>) -> Result<(), Errno> {
+        let mut inner = self.inner.protected.write().unwrap();
+        match &mut inner.kind {
+            InodeSocketKind::PreSocket { props, .. } if props.ty == Socktype::Stream => {
+                props.tls_server_name = name;
+                Ok(())
+            }
+            InodeSocketKind::TcpStream { .. } | InodeSocketKind::TcpListener { .. } => {
+                Err(Errno::Isconn)
+            }
+            _ => Err(Errno::Notsup),
+        }
+    }
+
     pub fn get_opt_flag(&self, option: WasiSocketOption) -> Result<bool, Errno> {
         let mut inner = self.inner.protected.write().unwrap();
         Ok(match &mut inner.kind {
@@ -761,6 +854,10 @@ impl InodeSocket {
                 WasiSocketOption::ReuseAddr => props.reuse_addr,
                 WasiSocketOption::NoDelay => props.no_delay.unwrap_or_default(),
                 WasiSocketOption::KeepAlive => props.keep_alive.unwrap_or_default(),
+                #[cfg(feature = "net-tls")]
+                WasiSocketOption::TlsClient => props.tls_client.is_some(),
+                #[cfg(feature = "net-tls")]
+                WasiSocketOption::TlsServer => props.tls_server.is_some(),
                 _ => return Err(Errno::Inval),
             },
             InodeSocketKind::Raw(sock) => match option {
@@ -3,6 +3,7 @@ use std::sync::Arc;
 use derivative::Derivative;
 
 use super::*;
+use crate::runners::observer::Observer;
 use crate::runners::wcgi::{self, CreateEnvConfig, CreateEnvResult, RecycleEnvConfig};
 use virtual_fs::NullFile;
 use wasmer_wasix_types::types::{__WASI_STDERR_FILENO, __WASI_STDIN_FILENO, __WASI_STDOUT_FILENO};
@@ -20,10 +21,11 @@ impl DcgiCallbacks {
     where
         C: wcgi::Callbacks,
     {
-        Self {
-            inner: Arc::new(inner),
-            factory,
-        }
+        Self::wrap(factory, Arc::new(inner))
+    }
+
+    pub(crate) fn wrap(factory: DcgiInstanceFactory, inner: Arc<dyn wcgi::Callbacks>) -> Self {
+        Self { inner, factory }
     }
 }
 
@@ -41,6 +43,18 @@ impl wcgi::Callbacks for DcgiCallbacks {
         self.inner.on_stderr_error(error)
     }
 
+    fn on_load_shed(&self, reason: wcgi::LoadShedReason) {
+        self.inner.on_load_shed(reason)
+    }
+
+    fn on_request_timeout(&self) {
+        self.inner.on_request_timeout()
+    }
+
+    fn observer(&self) -> Option<Arc<dyn Observer>> {
+        self.inner.observer()
+    }
+
     async fn recycle_env(&self, conf: RecycleEnvConfig) {
         tracing::debug!("recycling DCGI instance");
 
@@ -80,6 +94,7 @@ impl wcgi::Callbacks for DcgiCallbacks {
             // We disable the cleanup to prevent the instance so that the
             // resources can be reused
             ret.env.disable_fs_cleanup = true;
+            self.factory.track(&ret.env);
         }
 
         ret
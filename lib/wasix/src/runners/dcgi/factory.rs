@@ -1,29 +1,72 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use derivative::Derivative;
+use http::HeaderMap;
 use virtual_fs::Pipe;
 use wasmer_wasix_types::types::{__WASI_STDERR_FILENO, __WASI_STDIN_FILENO, __WASI_STDOUT_FILENO};
 
 use crate::{
-    runners::wcgi::{CreateEnvConfig, CreateEnvResult, RecycleEnvConfig},
+    os::task::{process::WasiProcessId, thread::WasiThreadError},
+    runners::wcgi::{CreateEnvConfig, CreateEnvResult, RecycleEnvConfig, BODY_BUFFER_SIZE},
     state::conv_env_vars,
-    WasiStateCreationError,
+    VirtualTaskManager, WasiEnv, WasiStateCreationError,
 };
 
-use super::*;
+use super::{
+    shard::{Shard, ShardKey},
+    *,
+};
+
+tokio::task_local! {
+    /// The shard of the request currently being handled.
+    static CURRENT_SHARD: Shard;
+}
 
 #[derive(Debug, Default)]
 struct State {
-    /// Once the instance is running it will
-    instance: Option<DcgiInstance>,
+    /// Idle instances, waiting for the next request routed to their shard.
+    instances: HashMap<Shard, DcgiInstance>,
+    /// The shards that instances handling a request will be returned to.
+    busy: HashMap<WasiProcessId, Shard>,
+    /// Locks that make sure each shard handles one request at a time.
+    locks: HashMap<Shard, Arc<tokio::sync::Mutex<()>>>,
+    /// Where the next request without a shard key will start looking for an
+    /// idle shard.
+    next: usize,
+    /// Whether idle instances are being destroyed in the background.
+    sweeping: bool,
 }
 
 /// This factory will store and reuse instances between invocations thus
 /// allowing for the instances to be stateful.
-#[derive(Derivative, Clone, Default)]
+///
+/// Instances are kept in a pool with one instance per shard. Requests are
+/// routed to any idle shard unless a [`ShardKey`] is set, in which case all
+/// requests with the same key are handled by the same instance.
+#[derive(Derivative, Clone)]
 #[derivative(Debug)]
 pub struct DcgiInstanceFactory {
     state: Arc<Mutex<State>>,
+    size: usize,
+    shard_key: Option<ShardKey>,
+    idle_timeout: Option<Duration>,
+    max_memory: Option<u64>,
+}
+
+impl Default for DcgiInstanceFactory {
+    fn default() -> Self {
+        DcgiInstanceFactory {
+            state: Arc::default(),
+            size: 1,
+            shard_key: None,
+            idle_timeout: None,
+            max_memory: None,
+        }
+    }
 }
 
 impl DcgiInstanceFactory {
@@ -31,39 +74,245 @@ impl DcgiInstanceFactory {
         Default::default()
     }
 
+    /// Set the number of instances in the pool.
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = size.max(1);
+        self
+    }
+
+    /// Route requests to instances based on a header or cookie.
+    pub fn with_shard_key(mut self, key: ShardKey) -> Self {
+        self.shard_key = Some(key);
+        self
+    }
+
+    /// Destroy instances that haven't handled a request for this long.
+    ///
+    /// Idle instances are cleaned up whenever an instance is acquired or
+    /// released, and periodically in the background once the runner has
+    /// started.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Destroy instances once their memory grows beyond this many bytes,
+    /// rather than reusing them.
+    pub fn with_max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Pick the shard a request should be handled by, waiting until that
+    /// shard is free.
+    ///
+    /// The request should be handled inside [`DcgiInstanceFactory::scope()`]
+    /// while the returned guard is held.
+    pub(crate) async fn route(
+        &self,
+        headers: &HeaderMap,
+    ) -> (Shard, tokio::sync::OwnedMutexGuard<()>) {
+        let key = self.shard_key.as_ref().and_then(|key| key.extract(headers));
+
+        let shard = match key {
+            Some(key) => Shard::for_key(key, self.size),
+            None => {
+                let mut state = self.state.lock().unwrap();
+                let start = state.next;
+                state.next = state.next.wrapping_add(1);
+
+                // Prefer a shard that isn't busy, otherwise queue up behind
+                // the next one in line
+                for i in 0..self.size {
+                    let shard = Shard::nth(start.wrapping_add(i), self.size);
+                    if let Ok(guard) = state.lock_for(shard).try_lock_owned() {
+                        return (shard, guard);
+                    }
+                }
+
+                Shard::nth(start, self.size)
+            }
+        };
+
+        let lock = self.state.lock().unwrap().lock_for(shard);
+        (shard, lock.lock_owned().await)
+    }
+
+    /// Periodically destroy idle instances in the background, so they are
+    /// cleaned up even when no requests come in.
+    pub(crate) fn sweep_idle(
+        &self,
+        task_manager: Arc<dyn VirtualTaskManager>,
+    ) -> Result<(), WasiThreadError> {
+        let idle_timeout = match self.idle_timeout {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.sweeping {
+                return Ok(());
+            }
+            state.sweeping = true;
+        }
+
+        // Stops once the factory is dropped
+        let state = Arc::downgrade(&self.state);
+        let period = idle_timeout / 2;
+        let sleeper = Arc::clone(&task_manager);
+        let result = task_manager.task_shared(Box::new(move || {
+            Box::pin(async move {
+                loop {
+                    sleeper.sleep_now(period).await;
+                    let state = match state.upgrade() {
+                        Some(state) => state,
+                        None => break,
+                    };
+                    destroy_idle(&state, idle_timeout).await;
+                }
+            })
+        }));
+
+        if result.is_err() {
+            self.state.lock().unwrap().sweeping = false;
+        }
+        result
+    }
+
+    /// Run a future that handles a request routed to a particular shard.
+    pub(crate) async fn scope<F: std::future::Future>(&self, shard: Shard, fut: F) -> F::Output {
+        CURRENT_SHARD.scope(shard, fut).await
+    }
+
+    /// Keep track of an instance that was created for the current request so
+    /// it can be returned to the right shard.
+    pub(crate) fn track(&self, env: &WasiEnv) {
+        if let Ok(shard) = CURRENT_SHARD.try_with(|shard| *shard) {
+            let mut state = self.state.lock().unwrap();
+            state.busy.insert(env.pid(), shard);
+        }
+    }
+
     pub async fn release(&self, conf: RecycleEnvConfig) {
-        let mut state = self.state.lock().unwrap();
-        state.instance.replace(DcgiInstance {
-            env: conf.env,
-            //memory: conf.memory,
-            //store: conf.store,
-        });
+        self.destroy_idle().await;
+
+        let shard = self.state.lock().unwrap().busy.remove(&conf.env.pid());
+        let shard = match shard {
+            Some(shard) => shard,
+            None => return destroy(conf.env).await,
+        };
+
+        if let Err(reason) = self.health_check(&conf) {
+            tracing::debug!(?shard, reason, "Destroying the DCGI instance");
+            return destroy(conf.env).await;
+        }
+
+        let previous = {
+            let mut state = self.state.lock().unwrap();
+            state.instances.insert(
+                shard,
+                DcgiInstance {
+                    env: conf.env,
+                    last_used: Instant::now(),
+                    //memory: conf.memory,
+                    //store: conf.store,
+                },
+            )
+        };
+
+        if let Some(previous) = previous {
+            destroy(previous.env).await;
+        }
     }
 
     pub async fn acquire(&self, conf: &mut CreateEnvConfig) -> Option<CreateEnvResult> {
-        let mut state = self.state.lock().unwrap();
-        if let Some(inst) = state.instance.take() {
-            tracing::debug!("attempting to reinitialize DCGI instance");
-            match convert_instance(inst, conf) {
-                Ok(converted) => return Some(converted),
-                Err(err) => {
-                    tracing::warn!("failed to reinitialize DCGI instance - {}", err);
-                }
+        self.destroy_idle().await;
+
+        let shard = CURRENT_SHARD.try_with(|shard| *shard).ok()?;
+        let inst = self.state.lock().unwrap().instances.remove(&shard)?;
+
+        tracing::debug!(?shard, "attempting to reinitialize DCGI instance");
+        match convert_instance(inst, conf) {
+            Ok(converted) => {
+                self.track(&converted.env);
+                Some(converted)
             }
+            Err(err) => {
+                tracing::warn!("failed to reinitialize DCGI instance - {}", err);
+                None
+            }
+        }
+    }
+
+    /// Check whether an instance is fit to handle more requests.
+    fn health_check(&self, conf: &RecycleEnvConfig) -> Result<(), &'static str> {
+        match conf.env.process.try_join() {
+            Some(Ok(code)) if !code.is_success() => return Err("it exited with an error"),
+            Some(Err(_)) => return Err("it crashed"),
+            _ => {}
         }
 
-        None
+        if let Some(max_memory) = self.max_memory {
+            if conf.memory.view(&conf.store).data_size() > max_memory {
+                return Err("it is using too much memory");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Destroy any instances that have been idle for longer than the idle
+    /// timeout.
+    async fn destroy_idle(&self) {
+        if let Some(idle_timeout) = self.idle_timeout {
+            destroy_idle(&self.state, idle_timeout).await;
+        }
+    }
+}
+
+impl State {
+    fn lock_for(&mut self, shard: Shard) -> Arc<tokio::sync::Mutex<()>> {
+        Arc::clone(self.locks.entry(shard).or_default())
+    }
+}
+
+/// Destroy any instances that have been idle for longer than `idle_timeout`.
+async fn destroy_idle(state: &Mutex<State>, idle_timeout: Duration) {
+    let expired: Vec<_> = {
+        let mut state = state.lock().unwrap();
+        let shards: Vec<_> = state
+            .instances
+            .iter()
+            .filter(|(_, inst)| inst.last_used.elapsed() > idle_timeout)
+            .map(|(shard, _)| *shard)
+            .collect();
+        shards
+            .into_iter()
+            .filter_map(|shard| state.instances.remove(&shard))
+            .collect()
+    };
+
+    for inst in expired {
+        tracing::debug!("Destroying an idle DCGI instance");
+        destroy(inst.env).await;
     }
 }
 
+/// Clean up an instance that won't be reused.
+async fn destroy(mut env: WasiEnv) {
+    env.disable_fs_cleanup = false;
+    env.on_exit(None).await;
+}
+
 fn convert_instance(
     inst: DcgiInstance,
     conf: &mut CreateEnvConfig,
 ) -> anyhow::Result<CreateEnvResult> {
     let mut env = inst.env;
 
-    let (req_body_sender, req_body_receiver) = Pipe::channel();
-    let (res_body_sender, res_body_receiver) = Pipe::channel();
+    let (req_body_sender, req_body_receiver) = Pipe::bounded_channel(BODY_BUFFER_SIZE);
+    let (res_body_sender, res_body_receiver) = Pipe::bounded_channel(BODY_BUFFER_SIZE);
     let (stderr_sender, stderr_receiver) = Pipe::channel();
 
     env.reinit()?;
@@ -18,12 +18,11 @@ pub(crate) struct Handler {
 }
 
 impl Handler {
-    pub(crate) fn new(handler: wcgi::Handler) -> Self {
+    pub(crate) fn new(handler: wcgi::Handler, factory: DcgiInstanceFactory) -> Self {
         Handler {
             state: Arc::new(SharedState {
                 inner: handler.deref().clone(),
-                factory: DcgiInstanceFactory::new(),
-                master_lock: Default::default(),
+                factory,
             }),
             inner: handler,
         }
@@ -31,13 +30,24 @@ impl Handler {
 
     #[tracing::instrument(level = "debug", skip_all, err)]
     pub(crate) async fn handle(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
-        // we acquire a guard token so that only one request at a time can be processed
-        // which effectively means that DCGI is single-threaded. This is a limitation
-        // of the MVP which should be rectified in future releases.
-        let guard_token = self.state.master_lock.clone().lock_owned().await;
+        // Static files don't need an instance, so there's no point waiting
+        // for a shard
+        if let Some(static_files) = &self.inner.static_files {
+            if let Some(response) = static_files.serve(&req).await {
+                return Ok(response);
+            }
+        }
+
+        // we acquire a guard token so that each instance only processes one
+        // request at a time, which effectively means that every shard is
+        // single-threaded.
+        let (shard, guard_token) = self.factory.route(req.headers()).await;
+        tracing::debug!(?shard, "Routing the request");
 
         // Process the request as a normal WCGI request
-        self.inner.handle(req, guard_token).await
+        self.factory
+            .scope(shard, self.inner.handle(req, guard_token))
+            .await
     }
 }
 
@@ -54,7 +64,6 @@ impl Deref for Handler {
 pub(crate) struct SharedState {
     pub(crate) inner: Arc<wcgi::SharedState>,
     factory: DcgiInstanceFactory,
-    master_lock: Arc<tokio::sync::Mutex<()>>,
 }
 
 impl Service<Request<Body>> for Handler {
@@ -1,10 +1,13 @@
 //use wasmer::{Memory, Store};
 
+use std::time::Instant;
+
 use crate::WasiEnv;
 
 #[derive(Debug)]
 pub(crate) struct DcgiInstance {
     pub env: WasiEnv,
+    pub last_used: Instant,
     //pub memory: Memory,
     //pub store: Store,
 }
@@ -3,9 +3,11 @@ mod factory;
 mod handler;
 mod instance;
 mod runner;
+mod shard;
 
 pub use self::runner::{Config, DcgiRunner};
 pub use callbacks::DcgiCallbacks;
 pub use factory::DcgiInstanceFactory;
 pub use futures::future::AbortHandle;
 pub(crate) use instance::DcgiInstance;
+pub use shard::ShardKey;
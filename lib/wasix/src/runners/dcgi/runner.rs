@@ -23,17 +23,16 @@ use super::{DcgiCallbacks, DcgiInstanceFactory};
 #[derive(Debug)]
 pub struct DcgiRunner {
     config: Config,
-    inner: wcgi::WcgiRunner,
+    factory: DcgiInstanceFactory,
 }
 
 impl DcgiRunner {
     pub fn new(factory: DcgiInstanceFactory) -> Self {
-        let callbacks = DcgiCallbacks::new(factory, NoOpWcgiCallbacks);
         DcgiRunner {
             config: Config {
-                inner: wcgi::Config::new(callbacks.clone()),
+                inner: wcgi::Config::new(NoOpWcgiCallbacks),
             },
-            inner: WcgiRunner::new(callbacks),
+            factory,
         }
     }
 
@@ -41,17 +40,34 @@ impl DcgiRunner {
         &mut self.config
     }
 
+    /// Create the [`WcgiRunner`] that serves requests, with the configured
+    /// callbacks wrapped so instances are reused.
+    fn wcgi_runner(&self) -> WcgiRunner {
+        let mut config = self.config.inner.clone();
+        // Warm instances are created outside of a shard so they could never
+        // be reused, DCGI keeps its own pool of instances instead
+        if config.warm_instances > 0 {
+            tracing::warn!("Warm instances are not supported by the DCGI runner");
+            config.warm_instances = 0;
+        }
+        config.callbacks = Arc::new(DcgiCallbacks::wrap(
+            self.factory.clone(),
+            Arc::clone(&config.callbacks),
+        ));
+        WcgiRunner::with_config(config)
+    }
+
     #[tracing::instrument(skip_all)]
     fn prepare_handler(
-        &mut self,
+        &self,
+        inner: &mut WcgiRunner,
         command_name: &str,
         pkg: &BinaryPackage,
         runtime: Arc<dyn Runtime + Send + Sync>,
     ) -> Result<Handler, Error> {
         let inner: wcgi::Handler =
-            self.inner
-                .prepare_handler(command_name, pkg, true, CgiDialect::Rfc3875, runtime)?;
-        Ok(Handler::new(inner))
+            inner.prepare_handler(command_name, pkg, true, CgiDialect::Rfc3875, runtime)?;
+        Ok(Handler::new(inner, self.factory.clone()))
     }
 }
 
@@ -91,10 +107,13 @@ impl crate::runners::Runner for DcgiRunner {
             .collect::<Vec<_>>();
         let runtime = OverriddenRuntime::new(runtime).with_journals(journals);
         let runtime = Arc::new(runtime) as Arc<DynRuntime>;
+        self.factory
+            .sweep_idle(Arc::clone(runtime.task_manager()))?;
 
         //We now pass the runtime to the handlers
-        let handler = self.prepare_handler(command_name, pkg, Arc::clone(&runtime))?;
-        self.inner.run_command_with_handler(handler, runtime)
+        let mut inner = self.wcgi_runner();
+        let handler = self.prepare_handler(&mut inner, command_name, pkg, Arc::clone(&runtime))?;
+        inner.run_command_with_handler(handler, runtime)
     }
 }
 
@@ -167,6 +186,9 @@ impl Config {
 
     /// Set callbacks that will be triggered at various points in the runner's
     /// lifecycle.
+    ///
+    /// The runner takes care of reusing instances, so these callbacks only
+    /// see the instances it needs to create.
     pub fn callbacks(
         &mut self,
         callbacks: impl wcgi::Callbacks + Send + Sync + 'static,
@@ -230,4 +252,40 @@ mod tests {
         assert_send::<DcgiRunner>();
         assert_sync::<DcgiRunner>();
     }
+
+    #[test]
+    fn the_configured_callbacks_are_used() {
+        #[derive(Default)]
+        struct Recorder(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl wcgi::Callbacks for Recorder {
+            fn on_stderr(&self, stderr: &[u8]) {
+                self.0.lock().unwrap().extend_from_slice(stderr);
+            }
+        }
+
+        let stderr = Arc::default();
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let mut runner = DcgiRunner::new(DcgiInstanceFactory::new());
+        runner
+            .config()
+            .addr(addr)
+            .callbacks(Recorder(Arc::clone(&stderr)));
+
+        let mut inner = runner.wcgi_runner();
+        inner.config().callbacks.on_stderr(b"oops");
+
+        assert_eq!(inner.config().addr, addr);
+        assert_eq!(stderr.lock().unwrap().as_slice(), b"oops");
+    }
+
+    #[test]
+    fn warm_instances_are_disabled() {
+        let mut runner = DcgiRunner::new(DcgiInstanceFactory::new());
+        runner.config().inner().warm_instances(4);
+
+        let mut inner = runner.wcgi_runner();
+
+        assert_eq!(inner.config().warm_instances, 0);
+    }
 }
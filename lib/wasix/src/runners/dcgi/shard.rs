@@ -0,0 +1,101 @@
+use std::hash::{Hash, Hasher};
+
+use http::{header::COOKIE, HeaderMap, HeaderName};
+
+/// The slot in the instance pool that a request is routed to.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum Shard {
+    #[default]
+    Singleton,
+    ById(u64),
+}
+
+impl Shard {
+    /// Get the shard with a particular index in a pool of `size` instances.
+    pub(crate) fn nth(index: usize, size: usize) -> Self {
+        if size <= 1 {
+            Shard::Singleton
+        } else {
+            Shard::ById((index % size) as u64)
+        }
+    }
+
+    /// Pick the shard that all requests with this key are routed to.
+    pub(crate) fn for_key(key: &[u8], size: usize) -> Self {
+        // Note: DefaultHasher::new() always uses the same keys, so a key
+        // will be routed to the same shard for the lifetime of the server
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        Shard::nth((hasher.finish() % size.max(1) as u64) as usize, size)
+    }
+}
+
+/// Which part of a request decides the instance it is routed to.
+///
+/// Requests with the same key are always handled by the same instance,
+/// allowing stateful applications to keep per-client state in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShardKey {
+    /// Route requests by the value of a header.
+    Header(HeaderName),
+    /// Route requests by the value of a cookie.
+    Cookie(String),
+}
+
+impl ShardKey {
+    /// Get the key from a request's headers, if it is present.
+    pub(crate) fn extract<'a>(&self, headers: &'a HeaderMap) -> Option<&'a [u8]> {
+        match self {
+            ShardKey::Header(name) => headers.get(name).map(|value| value.as_bytes()),
+            ShardKey::Cookie(name) => headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn the_same_key_always_goes_to_the_same_shard() {
+        let first = Shard::for_key(b"client-42", 8);
+
+        for _ in 0..10 {
+            assert_eq!(Shard::for_key(b"client-42", 8), first);
+        }
+        assert!(matches!(first, Shard::ById(id) if id < 8));
+        assert_eq!(Shard::for_key(b"client-42", 1), Shard::Singleton);
+    }
+
+    #[test]
+    fn extract_the_key_from_a_header() {
+        let key = ShardKey::Header(HeaderName::from_static("x-session"));
+        let mut headers = HeaderMap::new();
+        assert_eq!(key.extract(&headers), None);
+
+        headers.insert("x-session", HeaderValue::from_static("abc"));
+
+        assert_eq!(key.extract(&headers), Some(b"abc".as_slice()));
+    }
+
+    #[test]
+    fn extract_the_key_from_a_cookie() {
+        let key = ShardKey::Cookie("session".to_string());
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("theme=dark"));
+        assert_eq!(key.extract(&headers), None);
+
+        headers.append(COOKIE, HeaderValue::from_static("lang=en; session=abc"));
+
+        assert_eq!(key.extract(&headers), Some(b"abc".as_slice()));
+    }
+}
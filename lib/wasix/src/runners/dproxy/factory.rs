@@ -7,10 +7,12 @@ use std::{
 
 use derivative::Derivative;
 use wasmer_journal::{DynJournal, RecombinedJournal};
+use wasmer_wasix_types::wasi::{Errno, ExitCode};
 
 use crate::{
     runners::Runner,
     runtime::{DynRuntime, OverriddenRuntime},
+    WasiRuntimeError,
 };
 
 use super::{
@@ -53,6 +55,8 @@ impl DProxyInstanceFactory {
     }
 
     pub async fn spin_up(&self, handler: &Handler, shard: Shard) -> anyhow::Result<DProxyInstance> {
+        let started = Instant::now();
+
         // Get the runtime with its already wired local networking
         let runtime = handler.runtime.clone();
 
@@ -99,16 +103,27 @@ impl DProxyInstanceFactory {
         let connector_inner = connector.clone();
         let runtime = Arc::new(runtime) as Arc<DynRuntime>;
         let mut runner = handler.config.inner.clone();
+        let observer = handler.config.observer.clone();
         runtime
             .task_manager()
             .clone()
             .task_dedicated(Box::new(move || {
                 #[cfg(feature = "sys")]
                 let _guard = handle.enter();
-                if let Err(err) = runner.run_command(&command_name, &pkg, runtime) {
-                    tracing::error!("Instance Exited: {}", err);
-                } else {
-                    tracing::info!("Instance Exited: Nominal");
+                let exit_code = match runner.run_command(&command_name, &pkg, runtime) {
+                    Ok(()) => {
+                        tracing::info!("Instance Exited: Nominal");
+                        ExitCode::Errno(Errno::Success)
+                    }
+                    Err(err) => {
+                        tracing::error!("Instance Exited: {}", err);
+                        err.downcast_ref::<WasiRuntimeError>()
+                            .and_then(|e| e.as_exit_code())
+                            .unwrap_or(Errno::Unknown.into())
+                    }
+                };
+                if let Some(observer) = &observer {
+                    observer.on_instance_exited(exit_code);
                 }
                 {
                     let mut state = this.state.lock().unwrap();
@@ -117,6 +132,10 @@ impl DProxyInstanceFactory {
                 connector_inner.shutdown();
             }))?;
 
+        if let Some(observer) = &handler.config.observer {
+            observer.on_instance_created(started.elapsed());
+        }
+
         // Return an instance
         Ok(DProxyInstance {
             last_used: Arc::new(Mutex::new(Instant::now())),
@@ -10,7 +10,10 @@ use webc::metadata::Command;
 
 use crate::{
     bin_factory::BinaryPackage,
-    runners::wasi::WasiRunner,
+    runners::{
+        observer::{Observer, ObserverLayer},
+        wasi::WasiRunner,
+    },
     runtime::{task_manager::VirtualTaskManagerExt, DynRuntime},
 };
 
@@ -77,6 +80,7 @@ impl crate::runners::Runner for DProxyRunner {
             )
             .layer(CatchPanicLayer::new())
             .layer(CorsLayer::permissive())
+            .layer(ObserverLayer::new(self.config.observer.clone()))
             .service(handler);
 
         let address = self.config.addr;
@@ -102,13 +106,16 @@ impl crate::runners::Runner for DProxyRunner {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(derivative::Derivative, Clone)]
+#[derivative(Debug)]
 pub struct Config {
     pub(crate) inner: WasiRunner,
     pub(crate) addr: SocketAddr,
     pub(crate) pkg: BinaryPackage,
     pub(crate) proxy_connect_init_timeout: Duration,
     pub(crate) proxy_connect_nominal_timeout: Duration,
+    #[derivative(Debug = "ignore")]
+    pub(crate) observer: Option<Arc<dyn Observer>>,
 }
 
 impl Config {
@@ -119,6 +126,7 @@ impl Config {
             addr: ([127, 0, 0, 1], 8000).into(),
             proxy_connect_init_timeout: Duration::from_secs(30),
             proxy_connect_nominal_timeout: Duration::from_secs(30),
+            observer: None,
         }
     }
 
@@ -126,6 +134,13 @@ impl Config {
         self.addr = addr;
         self
     }
+
+    /// Tell an [`Observer`] about every request and instance, for example to
+    /// write access logs or record metrics.
+    pub fn observer(&mut self, observer: impl Observer) -> &mut Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
 }
 
 #[cfg(test)]
@@ -6,6 +6,8 @@ pub mod dcgi;
 pub mod dproxy;
 #[cfg(feature = "webc_runner_rt_emscripten")]
 pub mod emscripten;
+#[cfg(any(feature = "webc_runner_rt_wcgi", feature = "webc_runner_rt_dproxy"))]
+pub mod observer;
 pub mod wasi;
 mod wasi_common;
 #[cfg(feature = "webc_runner_rt_wcgi")]
@@ -0,0 +1,685 @@
+//! Access logs and metrics for the runners that serve HTTP (WCGI, DCGI and
+//! DProxy).
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::Write,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        mpsc::{SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use futures::{Future, FutureExt, Stream};
+use http::{Method, Request, Response, StatusCode, Version};
+use hyper::Body;
+use tower::{Layer, Service};
+use wasmer_wasix_types::wasi::ExitCode;
+
+/// Receives events from an HTTP runner so they can be logged or turned into
+/// metrics.
+pub trait Observer: Send + Sync + 'static {
+    /// A request was handled and its response body was fully sent (or the
+    /// client went away).
+    fn on_request(&self, _entry: &AccessLogEntry) {}
+
+    /// A new instance was created to handle requests.
+    fn on_instance_created(&self, _elapsed: Duration) {}
+
+    /// An instance exited.
+    fn on_instance_exited(&self, _exit_code: ExitCode) {}
+
+    /// The number of requests waiting for an instance changed.
+    fn on_queue_length(&self, _queued: usize) {}
+}
+
+impl<O: Observer + ?Sized> Observer for Arc<O> {
+    fn on_request(&self, entry: &AccessLogEntry) {
+        (**self).on_request(entry)
+    }
+
+    fn on_instance_created(&self, elapsed: Duration) {
+        (**self).on_instance_created(elapsed)
+    }
+
+    fn on_instance_exited(&self, exit_code: ExitCode) {
+        (**self).on_instance_exited(exit_code)
+    }
+
+    fn on_queue_length(&self, queued: usize) {
+        (**self).on_queue_length(queued)
+    }
+}
+
+impl Observer for Vec<Arc<dyn Observer>> {
+    fn on_request(&self, entry: &AccessLogEntry) {
+        self.iter().for_each(|o| o.on_request(entry));
+    }
+
+    fn on_instance_created(&self, elapsed: Duration) {
+        self.iter().for_each(|o| o.on_instance_created(elapsed));
+    }
+
+    fn on_instance_exited(&self, exit_code: ExitCode) {
+        self.iter().for_each(|o| o.on_instance_exited(exit_code));
+    }
+
+    fn on_queue_length(&self, queued: usize) {
+        self.iter().for_each(|o| o.on_queue_length(queued));
+    }
+}
+
+/// Identifies the instance that handled a request.
+///
+/// Runners attach this to their responses as an extension so it can be
+/// included in the access log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceId(pub String);
+
+/// Everything that is known about a request once it has been handled.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct AccessLogEntry {
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: SystemTime,
+    #[serde(serialize_with = "serialize_display")]
+    pub method: Method,
+    pub path: String,
+    #[serde(serialize_with = "serialize_version")]
+    pub version: Version,
+    #[serde(serialize_with = "serialize_status")]
+    pub status: StatusCode,
+    /// How long it took from receiving the request until the response body
+    /// was finished.
+    #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+    pub latency: Duration,
+    /// The number of bytes in the response body.
+    pub bytes: u64,
+    pub instance: Option<String>,
+}
+
+fn serialize_timestamp<S: serde::Serializer>(t: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&chrono::DateTime::<chrono::Utc>::from(*t).to_rfc3339())
+}
+
+fn serialize_display<T: std::fmt::Display, S: serde::Serializer>(
+    value: &T,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_str(value)
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u16(status.as_u16())
+}
+
+fn serialize_version<S: serde::Serializer>(v: &Version, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format!("{v:?}"))
+}
+
+fn serialize_millis<S: serde::Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64() * 1000.0)
+}
+
+/// The format used when writing access logs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// The [Common Log Format][clf] used by most web servers, extended with
+    /// the latency and instance.
+    ///
+    /// [clf]: https://en.wikipedia.org/wiki/Common_Log_Format
+    Common,
+    /// One JSON object per line.
+    Json,
+}
+
+impl AccessLogFormat {
+    pub fn format(&self, entry: &AccessLogEntry) -> String {
+        match self {
+            AccessLogFormat::Common => {
+                let timestamp = chrono::DateTime::<chrono::Utc>::from(entry.timestamp);
+                format!(
+                    "- - - [{}] \"{} {} {:?}\" {} {} {}ms {}",
+                    timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+                    entry.method,
+                    entry.path,
+                    entry.version,
+                    entry.status.as_u16(),
+                    entry.bytes,
+                    entry.latency.as_millis(),
+                    entry.instance.as_deref().unwrap_or("-"),
+                )
+            }
+            AccessLogFormat::Json => {
+                serde_json::to_string(entry).expect("Serializing to JSON never fails")
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for AccessLogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "json" => Ok(AccessLogFormat::Json),
+            other => anyhow::bail!(
+                "Unknown access log format, \"{other}\" (expected \"common\" or \"json\")"
+            ),
+        }
+    }
+}
+
+/// An [`Observer`] which writes an access log line for every request.
+///
+/// Lines are written by a background thread so a slow writer never holds up
+/// requests, if it falls too far behind new lines are dropped.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    lines: SyncSender<String>,
+}
+
+impl AccessLog {
+    /// How many lines may be waiting for the writer before new ones are
+    /// dropped.
+    const BACKLOG: usize = 1024;
+
+    pub fn new(
+        format: AccessLogFormat,
+        mut writer: impl Write + Send + 'static,
+    ) -> Result<Self, std::io::Error> {
+        let (lines, receiver) = std::sync::mpsc::sync_channel::<String>(Self::BACKLOG);
+
+        // Stops once the access log is dropped and every line was written
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                while let Ok(line) = receiver.recv() {
+                    let _ = writeln!(writer, "{line}");
+                    while let Ok(line) = receiver.try_recv() {
+                        let _ = writeln!(writer, "{line}");
+                    }
+                    let _ = writer.flush();
+                }
+            })?;
+
+        Ok(AccessLog { format, lines })
+    }
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Observer for AccessLog {
+    fn on_request(&self, entry: &AccessLogEntry) {
+        let line = self.format.format(entry);
+        if let Err(TrySendError::Full(_)) = self.lines.try_send(line) {
+            tracing::debug!("Dropped an access log line because the writer is falling behind");
+        }
+    }
+}
+
+/// An [`Observer`] which records metrics that can be scraped by Prometheus.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    requests: BTreeMap<(&'static str, u16), u64>,
+    request_duration: Histogram,
+    response_bytes: u64,
+    instance_creation: Histogram,
+    instance_exits: BTreeMap<i32, u64>,
+    queue_length: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Render the metrics using Prometheus' text exposition format.
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP wasmer_http_requests_total The number of HTTP requests that were handled."
+        );
+        let _ = writeln!(out, "# TYPE wasmer_http_requests_total counter");
+        for ((method, status), count) in &state.requests {
+            let _ = writeln!(
+                out,
+                "wasmer_http_requests_total{{method=\"{method}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        state.request_duration.render(
+            &mut out,
+            "wasmer_http_request_duration_seconds",
+            "How long it took to handle HTTP requests.",
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP wasmer_http_response_bytes_total The number of bytes sent in response bodies."
+        );
+        let _ = writeln!(out, "# TYPE wasmer_http_response_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "wasmer_http_response_bytes_total {}",
+            state.response_bytes
+        );
+
+        state.instance_creation.render(
+            &mut out,
+            "wasmer_instance_creation_duration_seconds",
+            "How long it took to create new instances.",
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP wasmer_instance_exits_total The number of instances that exited, by exit code."
+        );
+        let _ = writeln!(out, "# TYPE wasmer_instance_exits_total counter");
+        for (code, count) in &state.instance_exits {
+            let _ = writeln!(
+                out,
+                "wasmer_instance_exits_total{{exit_code=\"{code}\"}} {count}"
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP wasmer_request_queue_length The number of requests waiting for an instance."
+        );
+        let _ = writeln!(out, "# TYPE wasmer_request_queue_length gauge");
+        let _ = writeln!(out, "wasmer_request_queue_length {}", state.queue_length);
+
+        out
+    }
+}
+
+impl Observer for Metrics {
+    fn on_request(&self, entry: &AccessLogEntry) {
+        let mut state = self.state.lock().unwrap();
+        *state
+            .requests
+            .entry((method_label(&entry.method), entry.status.as_u16()))
+            .or_default() += 1;
+        state.request_duration.observe(entry.latency);
+        state.response_bytes += entry.bytes;
+    }
+
+    fn on_instance_created(&self, elapsed: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .instance_creation
+            .observe(elapsed);
+    }
+
+    fn on_instance_exited(&self, exit_code: ExitCode) {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .instance_exits
+            .entry(exit_code.raw())
+            .or_default() += 1;
+    }
+
+    fn on_queue_length(&self, queued: usize) {
+        self.state.lock().unwrap().queue_length = queued;
+    }
+}
+
+/// The method a request is counted under, anything but the standard methods
+/// is lumped together so clients can't create an unbounded number of series.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => "OTHER",
+    }
+}
+
+/// A Prometheus histogram with buckets suitable for latencies.
+#[derive(Debug, Default)]
+struct Histogram {
+    /// The number of observations that were less than or equal to each
+    /// bucket's upper bound.
+    buckets: [u64; Histogram::BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    const BUCKETS: [f64; 11] = [
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+    ];
+
+    fn observe(&mut self, value: Duration) {
+        let value = value.as_secs_f64();
+
+        for (bound, count) in Histogram::BUCKETS.iter().zip(&mut self.buckets) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, count) in Histogram::BUCKETS.iter().zip(&self.buckets) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+/// Serve the metrics on `/metrics` so they can be scraped by Prometheus.
+pub async fn serve_metrics(address: SocketAddr, metrics: Arc<Metrics>) -> Result<(), hyper::Error> {
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let metrics = Arc::clone(&metrics);
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                move |req: Request<Body>| {
+                    let response = if req.uri().path() == "/metrics" {
+                        Response::builder()
+                            .header(
+                                http::header::CONTENT_TYPE,
+                                "text/plain; version=0.0.4; charset=utf-8",
+                            )
+                            .body(Body::from(metrics.render()))
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                    };
+                    futures::future::ready(response)
+                },
+            ))
+        }
+    });
+
+    tracing::info!(%address, "Serving metrics");
+    hyper::Server::bind(&address).serve(make_service).await
+}
+
+/// A [`Layer`] which reports every request to an [`Observer`].
+#[derive(Clone)]
+pub(crate) struct ObserverLayer {
+    observer: Option<Arc<dyn Observer>>,
+}
+
+impl ObserverLayer {
+    pub(crate) fn new(observer: Option<Arc<dyn Observer>>) -> Self {
+        ObserverLayer { observer }
+    }
+}
+
+impl<S> Layer<S> for ObserverLayer {
+    type Service = ObserverService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ObserverService {
+            inner,
+            observer: self.observer.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ObserverService<S> {
+    inner: S,
+    observer: Option<Arc<dyn Observer>>,
+}
+
+impl<S> Service<Request<Body>> for ObserverService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let observer = match &self.observer {
+            Some(o) => Arc::clone(o),
+            None => return self.inner.call(req).boxed(),
+        };
+
+        let started = Instant::now();
+        let entry = AccessLogEntry {
+            timestamp: SystemTime::now(),
+            method: req.method().clone(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map(|p| p.to_string())
+                .unwrap_or_else(|| "/".to_string()),
+            version: req.version(),
+            status: StatusCode::OK,
+            latency: Duration::ZERO,
+            bytes: 0,
+            instance: None,
+        };
+
+        self.inner
+            .call(req)
+            .map(move |result| {
+                result.map(|response| {
+                    let (parts, body) = response.into_parts();
+                    let entry = AccessLogEntry {
+                        status: parts.status,
+                        instance: parts.extensions.get::<InstanceId>().map(|id| id.0.clone()),
+                        ..entry
+                    };
+                    let body = ObservedBody {
+                        inner: body,
+                        started,
+                        entry: Some(entry),
+                        observer,
+                    };
+                    Response::from_parts(parts, Body::wrap_stream(body))
+                })
+            })
+            .boxed()
+    }
+}
+
+/// A response body which tells the [`Observer`] about the request once it
+/// has been sent.
+struct ObservedBody {
+    inner: Body,
+    started: Instant,
+    entry: Option<AccessLogEntry>,
+    observer: Arc<dyn Observer>,
+}
+
+impl Stream for ObservedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let len = chunk.len() as u64;
+                if let Some(entry) = self.entry.as_mut() {
+                    entry.bytes += len;
+                }
+            }
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => self.finish(),
+            Poll::Pending => {}
+        }
+
+        poll
+    }
+}
+
+impl ObservedBody {
+    fn finish(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.latency = self.started.elapsed();
+            self.observer.on_request(&entry);
+        }
+    }
+}
+
+impl Drop for ObservedBody {
+    fn drop(&mut self) {
+        // The client may have disconnected before the body was finished
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            method: Method::GET,
+            path: "/index.html?q=1".to_string(),
+            version: Version::HTTP_11,
+            status: StatusCode::NOT_FOUND,
+            latency: Duration::from_millis(12),
+            bytes: 42,
+            instance: Some("7".to_string()),
+        }
+    }
+
+    #[test]
+    fn common_log_format() {
+        let line = AccessLogFormat::Common.format(&entry());
+
+        assert_eq!(
+            line,
+            "- - - [14/Nov/2023:22:13:20 +0000] \"GET /index.html?q=1 HTTP/1.1\" 404 42 12ms 7"
+        );
+    }
+
+    #[test]
+    fn json_log_format() {
+        let line = AccessLogFormat::Json.format(&entry());
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "timestamp": "2023-11-14T22:13:20+00:00",
+                "method": "GET",
+                "path": "/index.html?q=1",
+                "version": "HTTP/1.1",
+                "status": 404,
+                "latency_ms": 12.0,
+                "bytes": 42,
+                "instance": "7",
+            })
+        );
+    }
+
+    #[test]
+    fn render_prometheus_metrics() {
+        let metrics = Metrics::new();
+
+        metrics.on_request(&entry());
+        metrics.on_request(&entry());
+        metrics.on_instance_created(Duration::from_millis(30));
+        metrics.on_instance_exited(ExitCode::Other(1));
+        metrics.on_queue_length(3);
+        let rendered = metrics.render();
+
+        assert!(rendered.contains("wasmer_http_requests_total{method=\"GET\",status=\"404\"} 2\n"));
+        assert!(rendered.contains("wasmer_http_request_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(rendered.contains("wasmer_http_request_duration_seconds_bucket{le=\"0.025\"} 2\n"));
+        assert!(rendered.contains("wasmer_http_request_duration_seconds_count 2\n"));
+        assert!(rendered.contains("wasmer_http_response_bytes_total 84\n"));
+        assert!(rendered.contains("wasmer_instance_creation_duration_seconds_count 1\n"));
+        assert!(rendered.contains("wasmer_instance_exits_total{exit_code=\"1\"} 1\n"));
+        assert!(rendered.contains("wasmer_request_queue_length 3\n"));
+    }
+
+    #[test]
+    fn non_standard_methods_share_a_label() {
+        let metrics = Metrics::new();
+
+        for method in ["PURGE", "FOO", "BAR"] {
+            metrics.on_request(&AccessLogEntry {
+                method: Method::from_bytes(method.as_bytes()).unwrap(),
+                ..entry()
+            });
+        }
+        let rendered = metrics.render();
+
+        assert!(
+            rendered.contains("wasmer_http_requests_total{method=\"OTHER\",status=\"404\"} 3\n")
+        );
+        assert!(!rendered.contains("PURGE"));
+    }
+
+    #[test]
+    fn access_log_lines_are_written_in_the_background() {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let written = Shared::default();
+        let log = AccessLog::new(AccessLogFormat::Common, written.clone()).unwrap();
+
+        log.on_request(&entry());
+        drop(log);
+
+        let expected = format!("{}\n", AccessLogFormat::Common.format(&entry()));
+        for _ in 0..100 {
+            if written.0.lock().unwrap().as_slice() == expected.as_bytes() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the access log line was never written");
+    }
+}
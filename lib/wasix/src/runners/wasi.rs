@@ -25,6 +25,7 @@ pub struct WasiRunner {
     stdin: Option<ArcBoxFile>,
     stdout: Option<ArcBoxFile>,
     stderr: Option<ArcBoxFile>,
+    root_fs: Option<TmpFileSystem>,
 }
 
 impl WasiRunner {
@@ -175,6 +176,45 @@ impl WasiRunner {
         self
     }
 
+    /// Use a specific networking implementation for the instance instead of
+    /// the one provided by the runtime (e.g. a host on a
+    /// [`virtual_net::VirtualSwitch`]).
+    pub fn with_networking(&mut self, networking: virtual_net::DynVirtualNetworking) -> &mut Self {
+        self.wasi.networking = Some(networking);
+        self
+    }
+
+    /// Store changes to the package's file system in `upper` so they persist
+    /// across runs (see [`virtual_fs::UpperDirFileSystem`]).
+    pub fn with_persistent_upper_layer(
+        &mut self,
+        upper: Arc<dyn FileSystem + Send + Sync>,
+    ) -> &mut Self {
+        self.wasi.persistent_upper = Some(upper);
+        self
+    }
+
+    /// Check every file system operation against a policy, denying the ones
+    /// it doesn't allow and optionally recording them in an audit log.
+    #[cfg(feature = "fs-policy")]
+    pub fn with_fs_policy(
+        &mut self,
+        policy: virtual_fs::FsPolicy,
+        audit_sink: Option<Arc<dyn virtual_fs::AuditSink>>,
+    ) -> &mut Self {
+        self.wasi.fs_policy = Some(super::wasi_common::FsPolicyOptions { policy, audit_sink });
+        self
+    }
+
+    /// Use a specific in-memory file system as the root of the sandbox.
+    ///
+    /// The file system is shared with the caller, so whatever the program
+    /// writes to it can be inspected after it exits.
+    pub fn with_root_fs(&mut self, root_fs: TmpFileSystem) -> &mut Self {
+        self.root_fs = Some(root_fs);
+        self
+    }
+
     pub fn with_stdin(&mut self, stdin: Box<dyn VirtualFile + Send + Sync>) -> &mut Self {
         self.stdin = Some(ArcBoxFile::new(stdin));
         self
@@ -226,6 +266,7 @@ impl WasiRunner {
         runtime: Arc<dyn Runtime + Send + Sync>,
         root_fs: Option<TmpFileSystem>,
     ) -> Result<WasiEnvBuilder, anyhow::Error> {
+        let runtime = self.wasi.override_runtime(runtime);
         let mut builder = WasiEnvBuilder::new(program_name).runtime(runtime);
 
         let container_fs = if let Some(pkg) = pkg {
@@ -236,6 +277,7 @@ impl WasiRunner {
             None
         };
 
+        let root_fs = root_fs.or_else(|| self.root_fs.clone());
         self.wasi
             .prepare_webc_env(&mut builder, container_fs, wasi, root_fs)?;
 
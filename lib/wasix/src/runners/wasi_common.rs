@@ -15,7 +15,8 @@ use crate::{
     bin_factory::BinaryPackage,
     capabilities::Capabilities,
     journal::{DynJournal, SnapshotTrigger},
-    WasiEnvBuilder,
+    runtime::OverriddenRuntime,
+    Runtime, WasiEnvBuilder,
 };
 
 #[derive(Debug, Clone)]
@@ -42,9 +43,37 @@ pub(crate) struct CommonWasiOptions {
     pub(crate) snapshot_interval: Option<std::time::Duration>,
     pub(crate) current_dir: Option<PathBuf>,
     pub(crate) additional_imports: Imports,
+    #[derivative(Debug = "ignore")]
+    pub(crate) persistent_upper: Option<Arc<dyn FileSystem + Send + Sync>>,
+    #[cfg(feature = "fs-policy")]
+    pub(crate) fs_policy: Option<FsPolicyOptions>,
+    pub(crate) networking: Option<virtual_net::DynVirtualNetworking>,
+}
+
+/// A policy to enforce on the guest's file system, and where to send the
+/// audit records.
+#[cfg(feature = "fs-policy")]
+#[derive(Debug, Clone)]
+pub(crate) struct FsPolicyOptions {
+    pub(crate) policy: virtual_fs::FsPolicy,
+    pub(crate) audit_sink: Option<Arc<dyn virtual_fs::AuditSink>>,
 }
 
 impl CommonWasiOptions {
+    /// Swap out the runtime's networking for the one given to the runner,
+    /// if any.
+    pub(crate) fn override_runtime(
+        &self,
+        runtime: Arc<dyn Runtime + Send + Sync>,
+    ) -> Arc<dyn Runtime + Send + Sync> {
+        match &self.networking {
+            Some(networking) => {
+                Arc::new(OverriddenRuntime::new(runtime).with_networking(Arc::clone(networking)))
+            }
+            None => runtime,
+        }
+    }
+
     pub(crate) fn prepare_webc_env(
         &self,
         builder: &mut WasiEnvBuilder,
@@ -53,7 +82,16 @@ impl CommonWasiOptions {
         root_fs: Option<TmpFileSystem>,
     ) -> Result<(), anyhow::Error> {
         let root_fs = root_fs.unwrap_or_else(|| RootFileSystemBuilder::default().build());
-        let fs = prepare_filesystem(root_fs, &self.mounts, container_fs)?;
+        let fs = prepare_filesystem(
+            root_fs,
+            &self.mounts,
+            container_fs,
+            self.persistent_upper.clone(),
+        )?;
+        #[cfg(feature = "fs-policy")]
+        if let Some(FsPolicyOptions { policy, audit_sink }) = &self.fs_policy {
+            builder.set_fs_policy(policy.clone(), audit_sink.clone());
+        }
 
         builder.add_preopen_dir("/")?;
 
@@ -170,6 +208,7 @@ fn prepare_filesystem(
     mut root_fs: TmpFileSystem,
     mounted_dirs: &[MountedDirectory],
     container_fs: Option<Arc<dyn FileSystem + Send + Sync>>,
+    persistent_upper: Option<Arc<dyn FileSystem + Send + Sync>>,
 ) -> Result<Box<dyn FileSystem + Send + Sync>, Error> {
     if !mounted_dirs.is_empty() {
         build_directory_mappings(&mut root_fs, mounted_dirs)?;
@@ -185,9 +224,29 @@ fn prepare_filesystem(
     // operations using an absolute path if it failed using a relative path.
 
     let fs = if let Some(container) = container_fs {
-        let container = RelativeOrAbsolutePathHack(container);
-        let fs = OverlayFileSystem::new(root_fs, [container]);
-        Box::new(fs) as Box<dyn FileSystem + Send + Sync>
+        match persistent_upper {
+            Some(upper) => {
+                // Changes to the package's files go to the upper layer so
+                // they can outlive this instance.
+                let lower = RelativeOrAbsolutePathHack(container);
+                let container: Arc<dyn FileSystem + Send + Sync> =
+                    Arc::new(OverlayFileSystem::new(upper, [lower]));
+                let fs = PersistentContainerRoot {
+                    sandbox: root_fs.clone(),
+                    merged: OverlayFileSystem::new(
+                        root_fs,
+                        [RelativeOrAbsolutePathHack(container.clone())],
+                    ),
+                    container: RelativeOrAbsolutePathHack(container),
+                };
+                Box::new(fs) as Box<dyn FileSystem + Send + Sync>
+            }
+            None => {
+                let container = RelativeOrAbsolutePathHack(container);
+                let fs = OverlayFileSystem::new(root_fs, [container]);
+                Box::new(fs) as Box<dyn FileSystem + Send + Sync>
+            }
+        }
     } else {
         let fs = RelativeOrAbsolutePathHack(root_fs);
         Box::new(fs) as Box<dyn FileSystem + Send + Sync>
@@ -196,6 +255,96 @@ fn prepare_filesystem(
     Ok(fs)
 }
 
+type ContainerFileSystem = RelativeOrAbsolutePathHack<Arc<dyn FileSystem + Send + Sync>>;
+
+/// The root file system used when a package's changes are persisted.
+///
+/// Anything under a top-level directory the sandbox provides (`/tmp`, `/dev`,
+/// user mounts, etc.) behaves exactly like it would without persistence.
+/// Every other path, including files created directly in `/` and new
+/// top-level directories, is sent to the container so writes land in its
+/// persistent upper layer.
+#[derive(Debug)]
+struct PersistentContainerRoot {
+    sandbox: TmpFileSystem,
+    merged: OverlayFileSystem<TmpFileSystem, [ContainerFileSystem; 1]>,
+    container: ContainerFileSystem,
+}
+
+impl PersistentContainerRoot {
+    /// Is this path outside the directories provided by the sandbox?
+    fn is_persisted(&self, path: &Path) -> bool {
+        let top_level = path.components().find_map(|c| match c {
+            std::path::Component::Normal(name) => Some(name),
+            _ => None,
+        });
+
+        match top_level {
+            Some(name) => self.sandbox.metadata(&Path::new("/").join(name)).is_err(),
+            None => false,
+        }
+    }
+
+    fn route(&self, path: &Path) -> &dyn FileSystem {
+        if self.is_persisted(path) {
+            &self.container
+        } else {
+            &self.merged
+        }
+    }
+}
+
+impl virtual_fs::FileSystem for PersistentContainerRoot {
+    fn read_dir(&self, path: &Path) -> virtual_fs::Result<virtual_fs::ReadDir> {
+        self.route(path).read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.route(path).create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.route(path).remove_dir(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, virtual_fs::Result<()>> {
+        if self.is_persisted(from) && self.is_persisted(to) {
+            self.container.rename(from, to)
+        } else {
+            self.merged.rename(from, to)
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> virtual_fs::Result<virtual_fs::Metadata> {
+        self.route(path).metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> virtual_fs::Result<virtual_fs::Metadata> {
+        self.route(path).symlink_metadata(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> virtual_fs::Result<()> {
+        self.route(path).remove_file(path)
+    }
+
+    fn new_open_options(&self) -> virtual_fs::OpenOptions {
+        virtual_fs::OpenOptions::new(self)
+    }
+}
+
+impl virtual_fs::FileOpener for PersistentContainerRoot {
+    fn open(
+        &self,
+        path: &Path,
+        conf: &virtual_fs::OpenOptionsConfig,
+    ) -> virtual_fs::Result<Box<dyn virtual_fs::VirtualFile + Send + Sync + 'static>> {
+        self.route(path)
+            .new_open_options()
+            .options(conf.clone())
+            .open(path)
+    }
+}
+
 /// HACK: We need this so users can mount host directories at relative paths.
 /// This assumes that the current directory when a runner starts will be "/", so
 /// instead of mounting to a relative path, we just mount to "/$path".
@@ -350,7 +499,7 @@ mod tests {
     use std::time::SystemTime;
 
     use tempfile::TempDir;
-    use virtual_fs::{DirEntry, FileType, Metadata, WebcVolumeFileSystem};
+    use virtual_fs::{AsyncWriteExt, DirEntry, FileType, Metadata, WebcVolumeFileSystem};
     use webc::Container;
 
     use super::*;
@@ -432,7 +581,7 @@ mod tests {
         let webc_fs = WebcVolumeFileSystem::mount_all(&container);
 
         let root_fs = RootFileSystemBuilder::default().build();
-        let fs = prepare_filesystem(root_fs, &mapping, Some(Arc::new(webc_fs))).unwrap();
+        let fs = prepare_filesystem(root_fs, &mapping, Some(Arc::new(webc_fs)), None).unwrap();
 
         assert!(fs.metadata("/home/file.txt".as_ref()).unwrap().is_file());
         assert!(fs.metadata("lib".as_ref()).unwrap().is_dir());
@@ -446,6 +595,63 @@ mod tests {
             .is_file());
     }
 
+    #[tokio::test]
+    async fn writes_to_the_container_go_to_the_persistent_upper_layer() {
+        let container = virtual_fs::mem_fs::FileSystem::default();
+        container.create_dir("/lib".as_ref()).unwrap();
+        container
+            .insert_ro_file("/lib/io.py".as_ref(), b"import os".as_slice().into())
+            .unwrap();
+        let upper = Arc::new(virtual_fs::mem_fs::FileSystem::default());
+
+        let root_fs = RootFileSystemBuilder::default().build();
+        let fs = prepare_filesystem(root_fs, &[], Some(Arc::new(container)), Some(upper.clone()))
+            .unwrap();
+
+        fs.new_open_options()
+            .write(true)
+            .create(true)
+            .open("/lib/new.py")
+            .unwrap()
+            .write_all(b"print('hi')")
+            .await
+            .unwrap();
+        fs.remove_file("/lib/io.py".as_ref()).unwrap();
+        fs.new_open_options()
+            .write(true)
+            .create(true)
+            .open("/tmp/scratch.txt")
+            .unwrap();
+        fs.new_open_options()
+            .write(true)
+            .create(true)
+            .open("/state.json")
+            .unwrap();
+        fs.create_dir("/data".as_ref()).unwrap();
+        fs.new_open_options()
+            .write(true)
+            .create(true)
+            .open("/data/db.sqlite")
+            .unwrap();
+
+        assert!(upper.metadata("/lib/new.py".as_ref()).unwrap().is_file());
+        assert!(upper.metadata("/state.json".as_ref()).unwrap().is_file());
+        assert!(upper
+            .metadata("/data/db.sqlite".as_ref())
+            .unwrap()
+            .is_file());
+        assert!(fs.metadata("/state.json".as_ref()).unwrap().is_file());
+        assert!(fs
+            .read_dir("/".as_ref())
+            .unwrap()
+            .flatten()
+            .any(|entry| entry.file_name() == "data"));
+        assert!(upper.metadata("/lib/.wh.io.py".as_ref()).is_ok());
+        assert!(fs.metadata("/lib/io.py".as_ref()).is_err());
+        // Directories owned by the sandbox aren't persisted
+        assert!(upper.metadata("/tmp".as_ref()).is_err());
+    }
+
     fn unix_timestamp_nanos(instant: SystemTime) -> Option<u64> {
         let duration = instant.duration_since(SystemTime::UNIX_EPOCH).ok()?;
         Some(duration.as_nanos() as u64)
@@ -3,7 +3,7 @@ use std::{collections::HashMap, sync::Arc};
 use virtual_fs::Pipe;
 use wasmer::{Memory, Module, Store};
 
-use crate::{runtime::module_cache::ModuleHash, WasiEnv};
+use crate::{runners::observer::Observer, runtime::module_cache::ModuleHash, WasiEnv};
 
 use super::{create_env::default_recycle_env, handler::SetupBuilder, *};
 
@@ -46,6 +46,20 @@ pub trait Callbacks: Send + Sync + 'static {
     /// Reading from stderr failed.
     fn on_stderr_error(&self, _error: std::io::Error) {}
 
+    /// A request was rejected with a `503 Service Unavailable` because the
+    /// maximum number of instances were already running.
+    fn on_load_shed(&self, _reason: LoadShedReason) {}
+
+    /// An instance was killed because it didn't finish handling a request
+    /// before the request timeout.
+    fn on_request_timeout(&self) {}
+
+    /// An [`Observer`] that should be told about every request and instance,
+    /// for example to write access logs or record metrics.
+    fn observer(&self) -> Option<Arc<dyn Observer>> {
+        None
+    }
+
     /// Recycle the WASI environment
     async fn recycle_env(&self, conf: RecycleEnvConfig) {
         default_recycle_env(conf).await
@@ -7,6 +7,10 @@ use crate::{
 
 use super::{callbacks::CreateEnvConfig, RecycleEnvConfig};
 
+/// How many bytes of the request and response bodies can be waiting in the
+/// pipes to and from an instance before the writer has to wait for the reader.
+pub(crate) const BODY_BUFFER_SIZE: usize = 64 * 1024;
+
 pub(crate) async fn default_recycle_env(mut conf: RecycleEnvConfig) {
     tracing::debug!("Destroying the WebAssembly instance");
 
@@ -17,8 +21,8 @@ pub(crate) async fn default_recycle_env(mut conf: RecycleEnvConfig) {
 pub(crate) async fn default_create_env(conf: CreateEnvConfig) -> anyhow::Result<CreateEnvResult> {
     tracing::debug!("Creating the WebAssembly instance");
 
-    let (req_body_sender, req_body_receiver) = Pipe::channel();
-    let (res_body_sender, res_body_receiver) = Pipe::channel();
+    let (req_body_sender, req_body_receiver) = Pipe::bounded_channel(BODY_BUFFER_SIZE);
+    let (res_body_sender, res_body_receiver) = Pipe::bounded_channel(BODY_BUFFER_SIZE);
     let (stderr_sender, stderr_receiver) = Pipe::channel();
 
     let mut builder = WasiEnvBuilder::new(&conf.program_name);
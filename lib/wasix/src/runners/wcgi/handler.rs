@@ -1,28 +1,49 @@
-use std::{collections::HashMap, ops::Deref, pin::Pin, sync::Arc, task::Poll};
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
 
 use anyhow::Error;
-use futures::{Future, FutureExt, StreamExt};
-use http::{Request, Response, StatusCode};
+use futures::{future::AbortHandle, Future, FutureExt, StreamExt};
+use http::{
+    header::{CONNECTION, UPGRADE},
+    Request, Response, StatusCode,
+};
 use hyper::{service::Service, Body};
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
 use tracing::Instrument;
 use virtual_mio::InlineWaker;
 use wasmer::Module;
-use wasmer_wasix_types::wasi::ExitCode;
+use wasmer_wasix_types::wasi::{Errno, ExitCode, Signal};
 use wcgi_host::CgiDialect;
 
 use crate::{
     bin_factory::run_exec,
-    os::task::OwnedTaskStatus,
-    runners::wcgi::{
-        callbacks::{CreateEnvConfig, RecycleEnvConfig},
-        Callbacks,
+    os::task::{process::WasiProcess, thread::WasiThreadError, OwnedTaskStatus},
+    runners::{
+        observer::{InstanceId, Observer},
+        wcgi::{
+            callbacks::{CreateEnvConfig, CreateEnvResult, RecycleEnvConfig},
+            limits::Limiter,
+            pool::WarmPool,
+            static_files::StaticFiles,
+            Callbacks,
+        },
     },
     runtime::{
         module_cache::ModuleHash,
         task_manager::{TaskWasm, TaskWasmRecycleProperties},
     },
-    Runtime, VirtualTaskManager, WasiEnvBuilder,
+    Runtime, VirtualTaskManager, WasiEnv, WasiEnvBuilder,
 };
 
 /// The shared object that manages the instantiaion of WASI executables and
@@ -32,13 +53,15 @@ pub(crate) struct Handler(Arc<SharedState>);
 
 impl Handler {
     pub(crate) fn new(state: Arc<SharedState>) -> Self {
-        Handler(state)
+        let handler = Handler(state);
+        handler.refill_warm_pool();
+        handler
     }
 
     #[tracing::instrument(level = "debug", skip_all, err)]
     pub(crate) async fn handle<T>(
         &self,
-        req: Request<Body>,
+        mut req: Request<Body>,
         token: T,
     ) -> Result<Response<Body>, Error>
     where
@@ -46,6 +69,29 @@ impl Handler {
     {
         tracing::debug!(headers=?req.headers());
 
+        if let Some(static_files) = &self.static_files {
+            if let Some(response) = static_files.serve(&req).await {
+                return Ok(response);
+            }
+        }
+
+        let on_upgrade = if is_upgrade_request(&req) {
+            Some(hyper::upgrade::on(&mut req))
+        } else {
+            None
+        };
+
+        let permit = match self.limiter.acquire().await {
+            Ok(permit) => permit,
+            Err(reason) => {
+                tracing::debug!(%reason, "Shedding load");
+                self.callbacks.on_load_shed(reason);
+                return Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::from(format!("Service unavailable: {reason}")))?);
+            }
+        };
+
         let (parts, body) = req.into_parts();
 
         // Note: We want to apply the CGI environment variables *after*
@@ -60,17 +106,15 @@ impl Handler {
         self.dialect
             .prepare_environment_variables(parts, &mut request_specific_env);
 
-        let create = self
-            .callbacks
-            .create_env(CreateEnvConfig {
-                env: request_specific_env,
-                program_name: self.program_name.clone(),
-                module: self.module.clone(),
-                module_hash: self.module_hash,
-                runtime: self.runtime.clone(),
-                setup_builder: self.setup_builder.clone(),
-            })
-            .await?;
+        let create = match self.warm_pool.take() {
+            Some(create) => {
+                tracing::debug!("Using an environment from the warm pool");
+                add_envs(&create.env, request_specific_env);
+                self.refill_warm_pool();
+                create
+            }
+            None => self.create_env(request_specific_env).await?,
+        };
 
         tracing::debug!(
             dialect=%self.dialect,
@@ -79,13 +123,23 @@ impl Handler {
 
         let task_manager = self.runtime.task_manager();
         let env = create.env;
+        let process = env.process.clone();
         let module = self.module.clone();
 
         // The recycle function will attempt to reuse the instance
         let callbacks = Arc::clone(&self.callbacks);
         let recycle = {
             let callbacks = callbacks.clone();
+            let observer = self.observer.clone();
             move |props: TaskWasmRecycleProperties| {
+                if let (Some(observer), Some(result)) = (&observer, props.env.process.try_join()) {
+                    let exit_code = match result {
+                        Ok(code) => code,
+                        Err(e) => e.as_exit_code().unwrap_or(Errno::Unknown.into()),
+                    };
+                    observer.on_instance_exited(exit_code);
+                }
+
                 InlineWaker::block_on(callbacks.recycle_env(RecycleEnvConfig {
                     env: props.env,
                     store: props.store,
@@ -94,8 +148,10 @@ impl Handler {
 
                 // We release the token after we recycle the environment
                 // so that race conditions (such as reusing instances) are
-                // avoided
+                // avoided. The permit is held until now so the instance
+                // counts towards the concurrency limit until it has exited.
                 drop(token);
+                drop(permit);
             }
         };
         let finished = env.process.finished.clone();
@@ -130,75 +186,241 @@ impl Handler {
                 err
             })?;
 
-        let mut res_body_receiver = tokio::io::BufReader::new(create.body_receiver);
+        let deadline = match self.enforce_request_timeout(process.clone()) {
+            Ok(deadline) => deadline,
+            Err(err) => {
+                // without the watchdog nothing would stop the instance
+                process.signal_process(Signal::Sigkill);
+                process.terminate(Errno::Timedout.into());
+                return Err(err.into());
+            }
+        };
 
-        let stderr_receiver = create.stderr_receiver;
-        let propagate_stderr = self.propagate_stderr;
-        let work_consume_stderr = {
-            let callbacks = callbacks.clone();
+        let stdin = create.body_sender;
+        let mut stdout = tokio::io::BufReader::new(create.body_receiver);
+        let stderr = {
+            let stderr_receiver = create.stderr_receiver;
+            let propagate_stderr = self.propagate_stderr && on_upgrade.is_none();
             async move { consume_stderr(stderr_receiver, callbacks, propagate_stderr).await }
                 .in_current_span()
         };
 
-        tracing::trace!(
-            dialect=%self.dialect,
-            "spawning request forwarder",
-        );
-
-        let req_body_sender = create.body_sender;
-        let ret = drive_request_to_completion(finished, body, req_body_sender).await;
+        if let Some(on_upgrade) = on_upgrade {
+            self.in_background(stderr.map(|_| ()));
+            return self
+                .upgrade(on_upgrade, body, stdin, stdout, process, deadline)
+                .await;
+        }
 
         // When set this will cause any stderr responses to
         // take precedence over nominal responses but it
         // will cause the stderr pipe to be read to the end
         // before transmitting the body
-        if propagate_stderr {
-            if let Some(stderr) = work_consume_stderr.await {
+        if self.propagate_stderr {
+            // The instance's output has to be drained while we wait for it
+            // to exit, otherwise it would block once the pipe is full
+            let mut output = Vec::new();
+            let (ret, read) = futures::join!(
+                drive_request_to_completion(finished, body, stdin),
+                stdout.read_to_end(&mut output),
+            );
+
+            if deadline.timed_out() {
+                return gateway_timeout();
+            }
+
+            if let Some(stderr) = stderr.await {
                 if !stderr.is_empty() {
                     return Ok(Response::builder()
                         .status(StatusCode::INTERNAL_SERVER_ERROR)
                         .body(Body::from(stderr))?);
                 }
             }
-        } else {
-            task_manager
-                .task_shared(Box::new(move || {
-                    Box::pin(async move {
-                        work_consume_stderr.await;
-                    })
-                }))
-                .ok();
+
+            if let Err(e) = ret {
+                return internal_server_error(e);
+            }
+            if let Err(e) = read {
+                return internal_server_error(e.into());
+            }
+
+            let mut output = std::io::Cursor::new(output);
+            let parts = self.dialect.extract_response_header(&mut output).await?;
+            return Ok(self.respond(parts, output, &process));
         }
 
-        match ret {
-            Ok(_) => {}
+        self.in_background(stderr.map(|_| ()));
+
+        // Stream the request body to the instance while its response is
+        // being streamed back to the client
+        self.in_background({
+            let finished = Arc::clone(&finished);
+            async move {
+                if let Err(e) = drive_request_to_completion(finished, body, stdin).await {
+                    tracing::debug!(error = &*e, "Unable to drive the request to completion");
+                }
+            }
+            .in_current_span()
+        });
+
+        self.stream_response(stdout, &process, &deadline).await
+    }
+
+    /// Wait for the instance to write its response headers, then stream the
+    /// rest of its output to the client as the response body.
+    async fn stream_response(
+        &self,
+        mut stdout: tokio::io::BufReader<virtual_fs::Pipe>,
+        process: &WasiProcess,
+        deadline: &Deadline,
+    ) -> Result<Response<Body>, Error> {
+        tracing::trace!(
+            dialect=%self.dialect,
+            "extracting response parts",
+        );
+
+        match self.dialect.extract_response_header(&mut stdout).await {
+            Ok(parts) => Ok(self.respond(parts, stdout, process)),
             Err(e) => {
-                let e = e.to_string();
-                tracing::error!(error = e, "Unable to drive the request to completion");
-                return Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::from(e.as_bytes().to_vec()))?);
+                // The instance didn't write a valid response. That's usually
+                // because it exited early, so give the user a more helpful
+                // error message if we can.
+                let ret = process.finished.await_termination_anyhow().await;
+
+                if deadline.timed_out() {
+                    gateway_timeout()
+                } else if let Err(e) = ret {
+                    internal_server_error(e)
+                } else {
+                    Err(e.into())
+                }
             }
         }
+    }
+
+    /// Handle a request that wants to switch protocols (e.g. to WebSockets).
+    ///
+    /// The instance is given the request as normal and decides whether to
+    /// accept the upgrade by responding with `101 Switching Protocols`. From
+    /// then on, the connection is a raw bidirectional stream: anything the
+    /// client sends is written to the instance's stdin and anything the
+    /// instance writes to stdout is sent to the client.
+    async fn upgrade(
+        &self,
+        on_upgrade: hyper::upgrade::OnUpgrade,
+        body: Body,
+        mut stdin: virtual_fs::Pipe,
+        mut stdout: tokio::io::BufReader<virtual_fs::Pipe>,
+        process: WasiProcess,
+        deadline: Deadline,
+    ) -> Result<Response<Body>, Error> {
+        let finished = process.finished.clone();
 
         tracing::trace!(
             dialect=%self.dialect,
-            "extracting response parts",
+            "waiting for the instance to accept the upgrade",
         );
 
-        let parts = self
-            .dialect
-            .extract_response_header(&mut res_body_receiver)
-            .await;
-        let parts = parts?;
+        let parts = match self.dialect.extract_response_header(&mut stdout).await {
+            Ok(parts) if parts.status == StatusCode::SWITCHING_PROTOCOLS => parts,
+            Ok(parts) => {
+                // The instance turned the upgrade down, so it's just a normal
+                // request and response
+                self.in_background(
+                    async move {
+                        if let Err(e) = drive_request_to_completion(finished, body, stdin).await {
+                            tracing::debug!(
+                                error = &*e,
+                                "Unable to drive the request to completion",
+                            );
+                        }
+                    }
+                    .in_current_span(),
+                );
+                return Ok(self.respond(parts, stdout, &process));
+            }
+            Err(_) => {
+                stdin.shutdown().await.ok();
+                return self.stream_response(stdout, &process, &deadline).await;
+            }
+        };
+
+        // Long-lived connections aren't subject to the request timeout
+        deadline.cancel();
+
+        self.in_background(
+            async move {
+                let upgraded = match on_upgrade.await {
+                    Ok(upgraded) => upgraded,
+                    Err(e) => {
+                        tracing::debug!(
+                            error = &e as &dyn std::error::Error,
+                            "Unable to upgrade the connection",
+                        );
+                        stdin.shutdown().await.ok();
+                        return;
+                    }
+                };
+                let (mut client_rx, mut client_tx) = tokio::io::split(upgraded);
+
+                let to_instance = Box::pin(async move {
+                    tokio::io::copy(&mut client_rx, &mut stdin).await.ok();
+                    stdin.shutdown().await.ok();
+                });
+                let to_client = Box::pin(async move {
+                    tokio::io::copy_buf(&mut stdout, &mut client_tx).await.ok();
+                    client_tx.shutdown().await.ok();
+                });
+
+                // Once the client hangs up, the instance sees EOF on stdin
+                // and should exit, closing its stdout. If the instance exits
+                // first, there is nobody left to send data to.
+                if let futures::future::Either::Left((_, to_client)) =
+                    futures::future::select(to_instance, to_client).await
+                {
+                    to_client.await;
+                }
+
+                if let Err(e) = finished.await_termination_anyhow().await {
+                    tracing::debug!(error = &*e, "The instance exited with an error");
+                }
+            }
+            .in_current_span(),
+        );
 
+        tracing::trace!(
+            dialect=%self.dialect,
+            "switching protocols",
+        );
+
+        let mut response = Response::from_parts(parts, Body::empty());
+        response
+            .extensions_mut()
+            .insert(InstanceId(process.pid().to_string()));
+        Ok(response)
+    }
+
+    /// Create a response which streams the instance's output to the client
+    /// as it is written.
+    ///
+    /// Note: At most [`BODY_BUFFER_SIZE`] bytes of output are buffered
+    /// between the instance and the client, so an instance writing to a slow
+    /// client has to wait for the client to catch up.
+    ///
+    /// [`BODY_BUFFER_SIZE`]: crate::runners::wcgi::BODY_BUFFER_SIZE
+    fn respond(
+        &self,
+        mut parts: http::response::Parts,
+        stdout: impl AsyncBufRead + Send + Unpin + 'static,
+        process: &WasiProcess,
+    ) -> Response<Body> {
         tracing::trace!(
             dialect=%self.dialect,
             status=%parts.status,
             "received response parts",
         );
 
-        let chunks = futures::stream::try_unfold(res_body_receiver, |mut r| async move {
+        let chunks = futures::stream::try_unfold(stdout, |mut r| async move {
             match r.fill_buf().await {
                 Ok(chunk) if chunk.is_empty() => Ok(None),
                 Ok(chunk) => {
@@ -210,14 +432,172 @@ impl Handler {
             }
         });
         let body = hyper::Body::wrap_stream(chunks);
+        parts
+            .extensions
+            .insert(InstanceId(process.pid().to_string()));
 
         tracing::trace!(
             dialect=%self.dialect,
             "returning response with body stream",
         );
 
-        let response = hyper::Response::from_parts(parts, body);
-        Ok(response)
+        hyper::Response::from_parts(parts, body)
+    }
+
+    /// Kill the instance if it is still running once the request timeout
+    /// elapses.
+    fn enforce_request_timeout(&self, process: WasiProcess) -> Result<Deadline, WasiThreadError> {
+        let deadline = Deadline::default();
+
+        let timeout = match self.request_timeout {
+            Some(t) => t,
+            None => return Ok(deadline),
+        };
+
+        let sleep = self.runtime.task_manager().sleep_now(timeout);
+        let callbacks = Arc::clone(&self.callbacks);
+        let timed_out = Arc::clone(&deadline.timed_out);
+        let (watchdog, abort) = futures::future::abortable(async move {
+            tokio::select! {
+                _ = process.finished.await_termination() => {}
+                _ = sleep => {
+                    tracing::warn!(
+                        timeout=?timeout,
+                        "Killing the instance because it didn't handle the request in time",
+                    );
+                    timed_out.store(true, Ordering::SeqCst);
+                    // Note: the guest only notices it has been killed the
+                    // next time it makes a syscall
+                    process.signal_process(Signal::Sigkill);
+                    process.terminate(Errno::Timedout.into());
+                    callbacks.on_request_timeout();
+                }
+            }
+        });
+        let watchdog = watchdog.map(|_| ()).in_current_span();
+        self.runtime
+            .task_manager()
+            .task_shared(Box::new(move || Box::pin(watchdog)))
+            .map_err(|err| {
+                tracing::warn!("failed to start the request timeout - {}", err);
+                err
+            })?;
+
+        Ok(Deadline {
+            abort: Some(abort),
+            ..deadline
+        })
+    }
+
+    fn in_background(&self, task: impl Future<Output = ()> + Send + 'static) {
+        if let Err(err) = self
+            .runtime
+            .task_manager()
+            .task_shared(Box::new(move || Box::pin(task)))
+        {
+            tracing::warn!("failed to start a background task - {}", err);
+        }
+    }
+}
+
+/// Tracks whether an instance was killed for taking too long.
+#[derive(Debug, Default)]
+struct Deadline {
+    timed_out: Arc<AtomicBool>,
+    abort: Option<AbortHandle>,
+}
+
+impl Deadline {
+    fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::SeqCst)
+    }
+
+    fn cancel(&self) {
+        if let Some(abort) = &self.abort {
+            abort.abort();
+        }
+    }
+}
+
+/// Does this request want to switch protocols (e.g. a WebSocket handshake)?
+fn is_upgrade_request(req: &Request<Body>) -> bool {
+    req.version() == http::Version::HTTP_11
+        && req.headers().contains_key(UPGRADE)
+        && req
+            .headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+fn gateway_timeout() -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(StatusCode::GATEWAY_TIMEOUT)
+        .body(Body::empty())?)
+}
+
+fn internal_server_error(e: Error) -> Result<Response<Body>, Error> {
+    let e = e.to_string();
+    tracing::error!(error = e, "Unable to drive the request to completion");
+    Ok(Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::from(e.as_bytes().to_vec()))?)
+}
+
+impl Handler {
+    /// Create a new environment, letting the observer know how long it took.
+    async fn create_env(&self, env: HashMap<String, String>) -> anyhow::Result<CreateEnvResult> {
+        let started = Instant::now();
+        let result = self.callbacks.create_env(self.create_env_config(env)).await;
+
+        if let (Some(observer), Ok(_)) = (&self.observer, &result) {
+            observer.on_instance_created(started.elapsed());
+        }
+
+        result
+    }
+
+    fn create_env_config(&self, env: HashMap<String, String>) -> CreateEnvConfig {
+        CreateEnvConfig {
+            env,
+            program_name: self.program_name.clone(),
+            module: self.module.clone(),
+            module_hash: self.module_hash,
+            runtime: self.runtime.clone(),
+            setup_builder: self.setup_builder.clone(),
+        }
+    }
+
+    /// Create environments in the background until the warm pool is full.
+    fn refill_warm_pool(&self) {
+        for _ in 0..self.warm_pool.reserve() {
+            let handler = self.clone();
+            let result = self.runtime.task_manager().task_shared(Box::new(move || {
+                Box::pin(async move {
+                    let result = handler.create_env(HashMap::new()).await;
+                    handler.warm_pool.put(result);
+                })
+            }));
+
+            if let Err(e) = result {
+                self.warm_pool.put(Err(e.into()));
+            }
+        }
+    }
+}
+
+/// Add request-specific environment variables to an environment that was
+/// created ahead of time, overriding any existing variables with the same
+/// name.
+fn add_envs(env: &WasiEnv, vars: HashMap<String, String>) {
+    let mut envs = env.state.envs.lock().unwrap();
+
+    for (name, value) in vars {
+        let prefix = format!("{name}=");
+        envs.retain(|existing| !existing.starts_with(prefix.as_bytes()));
+        envs.push(format!("{name}={value}").into_bytes());
     }
 }
 
@@ -317,11 +697,17 @@ pub(crate) struct SharedState {
     pub(crate) dialect: CgiDialect,
     pub(crate) program_name: String,
     pub(crate) propagate_stderr: bool,
+    pub(crate) limiter: Limiter,
+    pub(crate) warm_pool: WarmPool,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) static_files: Option<StaticFiles>,
     #[derivative(Debug = "ignore")]
     pub(crate) setup_builder: SetupBuilder,
     #[derivative(Debug = "ignore")]
     pub(crate) callbacks: Arc<dyn Callbacks>,
     #[derivative(Debug = "ignore")]
+    pub(crate) observer: Option<Arc<dyn Observer>>,
+    #[derivative(Debug = "ignore")]
     pub(crate) runtime: Arc<dyn Runtime + Send + Sync>,
 }
 
@@ -342,3 +728,176 @@ impl Service<Request<Body>> for Handler {
         fut.boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        runners::wcgi::NoOpWcgiCallbacks, runtime::task_manager::tokio::TokioTaskManager,
+        PluggableRuntime,
+    };
+
+    use super::*;
+
+    /// Accepts any upgrade, then echoes everything it reads back to the client.
+    const ECHO_WAT: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_read"
+                (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0)
+                "Status: 101 Switching Protocols\0d\0a"
+                "Upgrade: echo\0d\0a"
+                "Connection: Upgrade\0d\0a"
+                "\0d\0a")
+            (func (export "_start")
+                (i32.store (i32.const 1024) (i32.const 0))
+                (i32.store (i32.const 1028) (i32.const 71))
+                (drop (call $fd_write (i32.const 1) (i32.const 1024) (i32.const 1) (i32.const 1040)))
+                (block $done
+                    (loop $echo
+                        (i32.store (i32.const 1024) (i32.const 2048))
+                        (i32.store (i32.const 1028) (i32.const 1024))
+                        (br_if $done
+                            (call $fd_read (i32.const 0) (i32.const 1024) (i32.const 1) (i32.const 1040)))
+                        (br_if $done (i32.eqz (i32.load (i32.const 1040))))
+                        (i32.store (i32.const 1028) (i32.load (i32.const 1040)))
+                        (drop (call $fd_write (i32.const 1) (i32.const 1024) (i32.const 1) (i32.const 1044)))
+                        (br $echo))))
+        )
+    "#;
+
+    /// Responds with 4 times [`BODY_BUFFER_SIZE`] bytes of zeroes.
+    ///
+    /// [`BODY_BUFFER_SIZE`]: crate::runners::wcgi::BODY_BUFFER_SIZE
+    const LARGE_RESPONSE_WAT: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 2)
+            (data (i32.const 0) "Status: 200 OK\0d\0a\0d\0a")
+            (func (export "_start")
+                (local $i i32)
+                (i32.store (i32.const 1024) (i32.const 0))
+                (i32.store (i32.const 1028) (i32.const 18))
+                (drop (call $fd_write (i32.const 1) (i32.const 1024) (i32.const 1) (i32.const 1040)))
+                (i32.store (i32.const 1024) (i32.const 65536))
+                (i32.store (i32.const 1028) (i32.const 65536))
+                (loop $write
+                    (drop (call $fd_write (i32.const 1) (i32.const 1024) (i32.const 1) (i32.const 1040)))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $write (i32.lt_u (local.get $i) (i32.const 4)))))
+        )
+    "#;
+
+    fn handler(wat: &str, propagate_stderr: bool) -> Handler {
+        let task_manager = Arc::new(TokioTaskManager::new(tokio::runtime::Handle::current()));
+        let mut runtime = PluggableRuntime::new(task_manager);
+        runtime.set_engine(Some(wasmer::Engine::default()));
+        let runtime: Arc<dyn Runtime + Send + Sync> = Arc::new(runtime);
+        let module = Module::new(&runtime.engine(), wat).unwrap();
+        let setup_builder: SetupBuilder = Arc::new({
+            let runtime = Arc::clone(&runtime);
+            move |builder| {
+                builder.set_runtime(Arc::clone(&runtime));
+                Ok(())
+            }
+        });
+
+        Handler::new(Arc::new(SharedState {
+            module,
+            module_hash: ModuleHash::hash(wat),
+            dialect: CgiDialect::Rfc3875,
+            program_name: "test".to_string(),
+            propagate_stderr,
+            limiter: Limiter::new(None, None, None),
+            warm_pool: WarmPool::new(0),
+            request_timeout: None,
+            static_files: None,
+            setup_builder,
+            callbacks: Arc::new(NoOpWcgiCallbacks),
+            observer: None,
+            runtime,
+        }))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn upgraded_connections_are_passed_through_to_the_instance() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(
+            hyper::server::conn::Http::new()
+                .serve_connection(server, handler(ECHO_WAT, false))
+                .with_upgrades(),
+        );
+
+        client
+            .write_all(
+                b"GET /echo HTTP/1.1\r\n\
+                  Host: localhost\r\n\
+                  Connection: Upgrade\r\n\
+                  Upgrade: echo\r\n\
+                  \r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut client = tokio::io::BufReader::new(client);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            assert_ne!(client.read_line(&mut head).await.unwrap(), 0);
+        }
+        assert!(head.starts_with("HTTP/1.1 101"), "{head}");
+        assert!(head.to_lowercase().contains("upgrade: echo"), "{head}");
+
+        // The connection now goes straight to the instance and back
+        client.write_all(b"Hello, World!").await.unwrap();
+        let mut echoed = [0; 13];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"Hello, World!");
+
+        // Hanging up makes the instance exit, which closes the connection
+        client.get_mut().shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn detect_upgrade_requests() {
+        let websocket = Request::get("/")
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        let missing_upgrade = Request::get("/")
+            .header(CONNECTION, "upgrade")
+            .body(Body::empty())
+            .unwrap();
+        let http2 = Request::get("/")
+            .version(http::Version::HTTP_2)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+
+        assert!(is_upgrade_request(&websocket));
+        assert!(!is_upgrade_request(&missing_upgrade));
+        assert!(!is_upgrade_request(&http2));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_responses_are_drained_while_waiting_for_stderr() {
+        let handler = handler(LARGE_RESPONSE_WAT, true);
+
+        let response = handler
+            .handle(Request::get("/").body(Body::empty()).unwrap(), ())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), 4 * 65536);
+        assert!(body.iter().all(|&b| b == 0));
+    }
+}
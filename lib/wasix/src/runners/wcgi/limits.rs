@@ -0,0 +1,212 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::runners::observer::Observer;
+
+/// Why a request was turned away without being handled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LoadShedReason {
+    /// The maximum number of instances were running and the request queue
+    /// was already full.
+    QueueFull,
+    /// The request spent too long in the queue waiting for an instance to
+    /// finish.
+    QueueTimeout,
+}
+
+impl std::fmt::Display for LoadShedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadShedReason::QueueFull => write!(f, "the request queue is full"),
+            LoadShedReason::QueueTimeout => {
+                write!(f, "timed out waiting for an instance to become available")
+            }
+        }
+    }
+}
+
+/// Limits the number of instances that are running at any one time, queueing
+/// requests that arrive while every slot is taken.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub(crate) struct Limiter {
+    /// One permit per instance that may run concurrently, or `None` when
+    /// there is no limit.
+    instances: Option<Arc<Semaphore>>,
+    queued: AtomicUsize,
+    max_queue_length: Option<usize>,
+    queue_timeout: Option<Duration>,
+    #[derivative(Debug = "ignore")]
+    observer: Option<Arc<dyn Observer>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(
+        max_concurrency: Option<usize>,
+        max_queue_length: Option<usize>,
+        queue_timeout: Option<Duration>,
+    ) -> Self {
+        Limiter {
+            instances: max_concurrency.map(|n| Arc::new(Semaphore::new(n))),
+            queued: AtomicUsize::new(0),
+            max_queue_length,
+            queue_timeout,
+            observer: None,
+        }
+    }
+
+    /// Report changes to the queue length to an [`Observer`].
+    pub(crate) fn with_observer(mut self, observer: Option<Arc<dyn Observer>>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Wait for a slot to become available.
+    ///
+    /// The instance handling the request should hold onto the returned permit
+    /// until it exits.
+    pub(crate) async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, LoadShedReason> {
+        let instances = match &self.instances {
+            Some(s) => Arc::clone(s),
+            None => return Ok(None),
+        };
+
+        if let Ok(permit) = Arc::clone(&instances).try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+
+        let slot = QueueSlot::enter(&self.queued, self.observer.as_deref());
+        if let Some(max_queue_length) = self.max_queue_length {
+            if slot.position >= max_queue_length {
+                return Err(LoadShedReason::QueueFull);
+            }
+        }
+
+        let permit = match self.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, instances.acquire_owned())
+                .await
+                .map_err(|_| LoadShedReason::QueueTimeout)?,
+            None => instances.acquire_owned().await,
+        };
+
+        Ok(Some(permit.expect("The semaphore is never closed")))
+    }
+
+    /// The number of requests currently waiting for an instance.
+    #[cfg(test)]
+    pub(crate) fn queue_length(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+/// A request's place in the queue, which is given up when dropped.
+struct QueueSlot<'a> {
+    queued: &'a AtomicUsize,
+    observer: Option<&'a dyn Observer>,
+    /// The number of requests that were already queued.
+    position: usize,
+}
+
+impl<'a> QueueSlot<'a> {
+    fn enter(queued: &'a AtomicUsize, observer: Option<&'a dyn Observer>) -> Self {
+        let position = queued.fetch_add(1, Ordering::SeqCst);
+        if let Some(observer) = observer {
+            observer.on_queue_length(position + 1);
+        }
+
+        QueueSlot {
+            queued,
+            observer,
+            position,
+        }
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        let previous = self.queued.fetch_sub(1, Ordering::SeqCst);
+        if let Some(observer) = self.observer {
+            observer.on_queue_length(previous - 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn everything_is_admitted_without_a_limit() {
+        let limiter = Limiter::new(None, Some(0), None);
+
+        for _ in 0..10 {
+            assert!(limiter.acquire().await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_requests_when_the_queue_is_full() {
+        let limiter = Limiter::new(Some(1), Some(0), None);
+        let _running = limiter.acquire().await.unwrap();
+
+        let err = limiter.acquire().await.unwrap_err();
+
+        assert_eq!(err, LoadShedReason::QueueFull);
+        assert_eq!(limiter.queue_length(), 0);
+    }
+
+    #[tokio::test]
+    async fn give_up_on_requests_that_wait_too_long() {
+        let limiter = Limiter::new(Some(1), None, Some(Duration::from_millis(10)));
+        let _running = limiter.acquire().await.unwrap();
+
+        let err = limiter.acquire().await.unwrap_err();
+
+        assert_eq!(err, LoadShedReason::QueueTimeout);
+        assert_eq!(limiter.queue_length(), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_requests_run_once_an_instance_finishes() {
+        let limiter = Arc::new(Limiter::new(Some(1), Some(1), None));
+        let running = limiter.acquire().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let limiter = Arc::clone(&limiter);
+            async move { limiter.acquire().await.map(|permit| permit.is_some()) }
+        });
+        while limiter.queue_length() == 0 {
+            tokio::task::yield_now().await;
+        }
+        drop(running);
+
+        assert_eq!(waiting.await.unwrap(), Ok(true));
+    }
+
+    #[tokio::test]
+    async fn report_the_queue_length() {
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<usize>>);
+        impl Observer for Recorder {
+            fn on_queue_length(&self, queued: usize) {
+                self.0.lock().unwrap().push(queued);
+            }
+        }
+        let recorder = Arc::new(Recorder::default());
+        let limiter = Limiter::new(Some(1), Some(0), None)
+            .with_observer(Some(recorder.clone() as Arc<dyn Observer>));
+        let _running = limiter.acquire().await.unwrap();
+
+        limiter.acquire().await.unwrap_err();
+
+        assert_eq!(*recorder.0.lock().unwrap(), [1, 0]);
+    }
+}
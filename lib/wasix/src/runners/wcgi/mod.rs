@@ -1,11 +1,17 @@
 mod callbacks;
 mod create_env;
 mod handler;
+mod limits;
+mod pool;
 mod runner;
+mod static_files;
 
 pub use self::runner::{Config, WcgiRunner};
 pub use callbacks::NoOpWcgiCallbacks;
 pub use callbacks::{Callbacks, CreateEnvConfig, CreateEnvResult, RecycleEnvConfig};
 pub(crate) use create_env::default_create_env;
+#[cfg(feature = "webc_runner_rt_dcgi")]
+pub(crate) use create_env::BODY_BUFFER_SIZE;
 pub use futures::future::AbortHandle;
 pub(crate) use handler::{Handler, SharedState};
+pub use limits::LoadShedReason;
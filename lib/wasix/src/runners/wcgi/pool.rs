@@ -0,0 +1,61 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use crate::runners::wcgi::CreateEnvResult;
+
+/// Environments that were created ahead of time so requests don't need to
+/// wait for a new instance to be set up.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub(crate) struct WarmPool {
+    size: usize,
+    #[derivative(Debug = "ignore")]
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    ready: VecDeque<CreateEnvResult>,
+    /// The number of environments that are currently being created.
+    pending: usize,
+}
+
+impl WarmPool {
+    pub(crate) fn new(size: usize) -> Self {
+        WarmPool {
+            size,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Take a pre-created environment out of the pool, if there are any.
+    pub(crate) fn take(&self) -> Option<CreateEnvResult> {
+        self.state.lock().unwrap().ready.pop_front()
+    }
+
+    /// Work out how many environments need to be created to top up the pool.
+    ///
+    /// Every reserved environment must be handed back to
+    /// [`WarmPool::put()`], even if creating it failed.
+    pub(crate) fn reserve(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let missing = self.size.saturating_sub(state.ready.len() + state.pending);
+        state.pending += missing;
+        missing
+    }
+
+    /// Add an environment that was reserved with [`WarmPool::reserve()`].
+    pub(crate) fn put(&self, result: anyhow::Result<CreateEnvResult>) {
+        let mut state = self.state.lock().unwrap();
+        state.pending -= 1;
+
+        match result {
+            Ok(env) => state.ready.push_back(env),
+            Err(e) => {
+                tracing::warn!(
+                    error = &*e,
+                    "Unable to create an environment for the warm pool",
+                );
+            }
+        }
+    }
+}
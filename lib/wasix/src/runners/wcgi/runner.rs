@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::{Context, Error};
 use http::{Request, Response};
@@ -16,9 +16,15 @@ use crate::{
     bin_factory::BinaryPackage,
     capabilities::Capabilities,
     runners::{
+        observer::ObserverLayer,
         wasi_common::CommonWasiOptions,
-        wcgi::handler::{Handler, SharedState},
-        MappedDirectory,
+        wcgi::{
+            handler::{Handler, SharedState},
+            limits::Limiter,
+            pool::WarmPool,
+            static_files::StaticFiles,
+        },
+        MappedDirectory, MountedDirectory,
     },
     runtime::task_manager::VirtualTaskManagerExt,
     Runtime, WasiEnvBuilder,
@@ -41,6 +47,11 @@ impl WcgiRunner {
         }
     }
 
+    #[cfg(feature = "webc_runner_rt_dcgi")]
+    pub(crate) fn with_config(config: Config) -> Self {
+        Self { config }
+    }
+
     pub fn config(&mut self) -> &mut Config {
         &mut self.config
     }
@@ -71,23 +82,45 @@ impl WcgiRunner {
         };
 
         let container_fs = Arc::clone(&pkg.webc_fs);
+        let static_files =
+            if self.config.static_prefixes.is_empty() && self.config.static_extensions.is_empty() {
+                None
+            } else {
+                Some(StaticFiles::new(
+                    Arc::clone(&container_fs),
+                    self.config.static_root.clone(),
+                    self.config.static_prefixes.clone(),
+                    self.config.static_extensions.clone(),
+                ))
+            };
 
         let wasi_common = self.config.wasi.clone();
-        let rt = Arc::clone(&runtime);
+        let rt = self.config.wasi.override_runtime(Arc::clone(&runtime));
         let setup_builder = move |builder: &mut WasiEnvBuilder| {
             wasi_common.prepare_webc_env(builder, Some(Arc::clone(&container_fs)), &wasi, None)?;
             builder.set_runtime(Arc::clone(&rt));
             Ok(())
         };
 
+        let observer = self.config.callbacks.observer();
         let shared = SharedState {
             module,
             module_hash: pkg.hash(),
             dialect,
             propagate_stderr,
+            limiter: Limiter::new(
+                self.config.max_concurrency,
+                self.config.max_queue_length,
+                self.config.queue_timeout,
+            )
+            .with_observer(observer.clone()),
+            warm_pool: WarmPool::new(self.config.warm_instances),
+            request_timeout: self.config.request_timeout,
+            static_files,
             program_name: command_name.to_string(),
             setup_builder: Arc::new(setup_builder),
             callbacks: Arc::clone(&self.config.callbacks),
+            observer,
             runtime,
         };
 
@@ -128,12 +161,15 @@ impl WcgiRunner {
             )
             .layer(CatchPanicLayer::new())
             .layer(CorsLayer::permissive())
+            .layer(ObserverLayer::new(self.config.callbacks.observer()))
             .service(handler);
 
         let address = self.config.addr;
         tracing::info!(%address, "Starting the server");
 
         let callbacks = Arc::clone(&self.config.callbacks);
+        #[cfg(feature = "net-tls")]
+        let tls = self.config.tls.clone();
         runtime
             .task_manager()
             .spawn_and_block_on(async move {
@@ -142,13 +178,27 @@ impl WcgiRunner {
 
                 callbacks.started(abort_handle);
 
+                let shutdown = async {
+                    let _ = shutdown.await;
+                    tracing::info!("Shutting down gracefully");
+                };
+
+                #[cfg(feature = "net-tls")]
+                if let Some(tls) = tls {
+                    let (incoming, accept_loop) = https_incoming(address, tls).await?;
+                    let result = hyper::Server::builder(incoming)
+                        .serve(Shared::new(service))
+                        .with_graceful_shutdown(shutdown)
+                        .await;
+                    accept_loop.abort();
+                    return result.map_err(Error::from);
+                }
+
                 hyper::Server::bind(&address)
                     .serve(Shared::new(service))
-                    .with_graceful_shutdown(async {
-                        let _ = shutdown.await;
-                        tracing::info!("Shutting down gracefully");
-                    })
+                    .with_graceful_shutdown(shutdown)
                     .await
+                    .map_err(Error::from)
             })
             .context("Unable to start the server")??;
 
@@ -156,6 +206,72 @@ impl WcgiRunner {
     }
 }
 
+/// Accept HTTPS connections, terminating TLS on the host before they are
+/// handed to hyper.
+///
+/// Handshakes happen on their own tasks so a slow (or malicious) client can't
+/// stop other connections from being accepted. The returned task is the
+/// accept loop, which should be aborted once the server stops.
+#[cfg(feature = "net-tls")]
+async fn https_incoming(
+    address: SocketAddr,
+    tls: virtual_net::TlsServerConfig,
+) -> Result<
+    (
+        impl hyper::server::accept::Accept<
+            Conn = tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+            Error = std::io::Error,
+        >,
+        tokio::task::JoinHandle<()>,
+    ),
+    Error,
+> {
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Unable to bind to {address}"))?;
+    // Offer HTTP/2 to clients that support it, unless the caller already
+    // chose which protocols to negotiate
+    let tls = if tls.rustls_config().alpn_protocols.is_empty() {
+        tls.with_alpn_protocols(vec![b"h2".to_vec(), b"http/1.1".to_vec()])
+    } else {
+        tls
+    };
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::clone(tls.rustls_config()));
+    let (sender, receiver) = tokio::sync::mpsc::channel(64);
+
+    let accept_loop = tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(s) => s,
+                Err(e) => {
+                    tracing::warn!(error = &e as &dyn std::error::Error, "Accept failed");
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = sender.send(Ok::<_, std::io::Error>(stream)).await;
+                    }
+                    Err(e) => {
+                        tracing::debug!(
+                            %peer,
+                            error = &e as &dyn std::error::Error,
+                            "TLS handshake failed",
+                        );
+                    }
+                }
+            });
+        }
+    });
+
+    let incoming =
+        hyper::server::accept::from_stream(tokio_stream::wrappers::ReceiverStream::new(receiver));
+    Ok((incoming, accept_loop))
+}
+
 impl crate::runners::Runner for WcgiRunner {
     fn can_run_command(command: &Command) -> Result<bool, Error> {
         Ok(command
@@ -180,13 +296,23 @@ impl crate::runners::Runner for WcgiRunner {
     }
 }
 
-#[derive(derivative::Derivative)]
+#[derive(Clone, derivative::Derivative)]
 #[derivative(Debug)]
 pub struct Config {
     pub(crate) wasi: CommonWasiOptions,
     pub(crate) addr: SocketAddr,
     #[derivative(Debug = "ignore")]
     pub(crate) callbacks: Arc<dyn Callbacks>,
+    #[cfg(feature = "net-tls")]
+    pub(crate) tls: Option<virtual_net::TlsServerConfig>,
+    pub(crate) max_concurrency: Option<usize>,
+    pub(crate) max_queue_length: Option<usize>,
+    pub(crate) queue_timeout: Option<Duration>,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) warm_instances: usize,
+    pub(crate) static_root: PathBuf,
+    pub(crate) static_prefixes: Vec<String>,
+    pub(crate) static_extensions: Vec<String>,
 }
 
 impl Config {
@@ -251,6 +377,115 @@ impl Config {
         self
     }
 
+    pub fn mount_directory(&mut self, dir: MountedDirectory) -> &mut Self {
+        self.wasi.mounts.push(dir);
+        self
+    }
+
+    pub fn mount_directories(
+        &mut self,
+        mounts: impl IntoIterator<Item = MountedDirectory>,
+    ) -> &mut Self {
+        self.wasi.mounts.extend(mounts);
+        self
+    }
+
+    /// Check every file system operation against a policy, denying the ones
+    /// it doesn't allow and optionally recording them in an audit log.
+    #[cfg(feature = "fs-policy")]
+    pub fn fs_policy(
+        &mut self,
+        policy: virtual_fs::FsPolicy,
+        audit_sink: Option<Arc<dyn virtual_fs::AuditSink>>,
+    ) -> &mut Self {
+        self.wasi.fs_policy =
+            Some(crate::runners::wasi_common::FsPolicyOptions { policy, audit_sink });
+        self
+    }
+
+    /// Use a specific networking implementation for the instances instead of
+    /// the one provided by the runtime.
+    pub fn networking(&mut self, networking: virtual_net::DynVirtualNetworking) -> &mut Self {
+        self.wasi.networking = Some(networking);
+        self
+    }
+
+    /// Serve HTTPS instead of plain HTTP, terminating TLS with this
+    /// configuration.
+    #[cfg(feature = "net-tls")]
+    pub fn tls(&mut self, config: virtual_net::TlsServerConfig) -> &mut Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Limit the number of instances that may handle requests at the same
+    /// time. Requests that arrive while every instance is busy are queued.
+    pub fn max_concurrency(&mut self, instances: usize) -> &mut Self {
+        self.max_concurrency = Some(instances);
+        self
+    }
+
+    /// Limit the number of requests that may wait for an instance, responding
+    /// to any others with a `503 Service Unavailable`.
+    ///
+    /// This only has an effect when [`Config::max_concurrency()`] is set.
+    pub fn max_queue_length(&mut self, requests: usize) -> &mut Self {
+        self.max_queue_length = Some(requests);
+        self
+    }
+
+    /// How long a request may wait for an instance before it is rejected with
+    /// a `503 Service Unavailable`.
+    ///
+    /// This only has an effect when [`Config::max_concurrency()`] is set.
+    pub fn queue_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+
+    /// How long an instance may spend handling a request before it is killed
+    /// and the client gets a `504 Gateway Timeout`.
+    pub fn request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Keep a number of environments ready ahead of time so requests don't
+    /// need to wait for them to be created.
+    ///
+    /// This is ignored by the DCGI runner, which reuses its own instances.
+    pub fn warm_instances(&mut self, instances: usize) -> &mut Self {
+        self.warm_instances = instances;
+        self
+    }
+
+    /// The directory in the package's volumes that static files are served
+    /// from (defaults to `/`).
+    ///
+    /// A request for `/css/site.css` is served from `<root>/css/site.css`.
+    pub fn static_root(&mut self, root: impl Into<PathBuf>) -> &mut Self {
+        self.static_root = root.into();
+        self
+    }
+
+    /// Serve requests whose path starts with this prefix (e.g. `/assets/`)
+    /// straight from the package's files instead of starting an instance.
+    ///
+    /// Requests for files that don't exist are still passed to the guest.
+    pub fn static_prefix(&mut self, prefix: impl Into<String>) -> &mut Self {
+        self.static_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Serve requests for files with this extension (e.g. `css`) straight
+    /// from the package's files instead of starting an instance.
+    ///
+    /// Requests for files that don't exist are still passed to the guest.
+    pub fn static_extension(&mut self, extension: impl Into<String>) -> &mut Self {
+        self.static_extensions.push(extension.into());
+        self
+    }
+
     /// Set callbacks that will be triggered at various points in the runner's
     /// lifecycle.
     pub fn callbacks(&mut self, callbacks: impl Callbacks + Send + Sync + 'static) -> &mut Self {
@@ -322,6 +557,16 @@ impl Config {
             addr: ([127, 0, 0, 1], 8000).into(),
             wasi: CommonWasiOptions::default(),
             callbacks: Arc::new(callbacks),
+            #[cfg(feature = "net-tls")]
+            tls: None,
+            max_concurrency: None,
+            max_queue_length: None,
+            queue_timeout: None,
+            request_timeout: None,
+            warm_instances: 0,
+            static_root: PathBuf::from("/"),
+            static_prefixes: Vec::new(),
+            static_extensions: Vec::new(),
         }
     }
 }
@@ -0,0 +1,623 @@
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use http::{
+    header::{
+        ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+        CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, VARY,
+    },
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use httpdate::HttpDate;
+use hyper::Body;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, BufReader};
+use virtual_fs::FileSystem;
+
+/// Precompressed variants that may be stored next to a file, in order of
+/// preference.
+const ENCODINGS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Serves requests for static assets straight from a package's volumes, so
+/// only dynamic routes need an instance.
+///
+/// Requests are only served when their path starts with one of the
+/// configured prefixes or ends with one of the configured extensions, and
+/// the file exists. Anything else is passed through to the guest.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub(crate) struct StaticFiles {
+    #[derivative(Debug = "ignore")]
+    fs: Arc<dyn FileSystem + Send + Sync>,
+    root: PathBuf,
+    prefixes: Vec<String>,
+    extensions: Vec<String>,
+    /// The modification time reported for files that don't have one (e.g.
+    /// everything inside a webc), it is fixed so the `Last-Modified` and
+    /// `ETag` headers stay the same when the server restarts.
+    default_modified: SystemTime,
+}
+
+/// A file in the package that a request can be served from.
+struct StaticFile {
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+    encoding: Option<&'static str>,
+}
+
+impl StaticFile {
+    /// A validator derived from the file's metadata, so the file never needs
+    /// to be read just to work out whether the client's copy is still fresh.
+    fn etag(&self) -> String {
+        let modified = self
+            .modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        match self.encoding {
+            Some(encoding) => format!("\"{:x}-{modified:x}-{encoding}\"", self.len),
+            None => format!("\"{:x}-{modified:x}\"", self.len),
+        }
+    }
+}
+
+impl StaticFiles {
+    pub(crate) fn new(
+        fs: Arc<dyn FileSystem + Send + Sync>,
+        root: PathBuf,
+        prefixes: Vec<String>,
+        extensions: Vec<String>,
+    ) -> Self {
+        StaticFiles {
+            fs,
+            root,
+            prefixes,
+            extensions: extensions
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            default_modified: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Try to serve a request from the package's files, returning `None` if
+    /// it should be handled by the guest instead.
+    pub(crate) async fn serve(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return None;
+        }
+
+        let path = self.resolve(req.uri().path())?;
+        let file = self.select(&path, req.headers())?;
+        tracing::trace!(path=%path.display(), encoding=?file.encoding, "Serving a static file");
+
+        self.respond(req, &path, file).await
+    }
+
+    /// Work out which file a request path refers to, if it is one we should
+    /// serve.
+    fn resolve(&self, uri_path: &str) -> Option<PathBuf> {
+        let decoded = percent_encoding::percent_decode_str(uri_path)
+            .decode_utf8()
+            .ok()?;
+
+        let has_prefix = self.prefixes.iter().any(|p| has_prefix(&decoded, p));
+        let has_extension = Path::new(decoded.as_ref())
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| self.extensions.contains(&ext.to_ascii_lowercase()))
+            .unwrap_or(false);
+        if !has_prefix && !has_extension {
+            return None;
+        }
+
+        let mut path = self.root.clone();
+        for component in decoded.split('/') {
+            match component {
+                "" | "." => continue,
+                // Never let a request escape the root directory
+                ".." => return None,
+                other => path.push(other),
+            }
+        }
+
+        Some(path)
+    }
+
+    /// Pick the best variant of a file that the client accepts.
+    fn select(&self, path: &Path, headers: &HeaderMap) -> Option<StaticFile> {
+        if !self.fs.metadata(path).ok()?.is_file() {
+            return None;
+        }
+
+        for (encoding, suffix) in ENCODINGS {
+            if !accepts_encoding(headers, encoding) {
+                continue;
+            }
+
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(suffix);
+
+            if let Some(file) = self.stat(Path::new(&variant)) {
+                return Some(StaticFile {
+                    encoding: Some(encoding),
+                    ..file
+                });
+            }
+        }
+
+        self.stat(path)
+    }
+
+    fn stat(&self, path: &Path) -> Option<StaticFile> {
+        let metadata = self.fs.metadata(path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+
+        let modified = match metadata.modified {
+            0 => self.default_modified,
+            nanos => SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos),
+        };
+
+        Some(StaticFile {
+            path: path.to_path_buf(),
+            len: metadata.len,
+            modified,
+            encoding: None,
+        })
+    }
+
+    async fn respond(
+        &self,
+        req: &Request<Body>,
+        path: &Path,
+        file: StaticFile,
+    ) -> Option<Response<Body>> {
+        let headers = req.headers();
+        let etag = file.etag();
+        let modified = HttpDate::from(file.modified);
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+
+        let mut builder = Response::builder()
+            .header(CONTENT_TYPE, content_type.as_ref())
+            .header(ETAG, &etag)
+            .header(LAST_MODIFIED, modified.to_string())
+            .header(ACCEPT_RANGES, "bytes")
+            .header(VARY, "accept-encoding");
+        if let Some(encoding) = file.encoding {
+            builder = builder.header(CONTENT_ENCODING, encoding);
+        }
+
+        if is_not_modified(headers, &etag, modified) {
+            return builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .ok();
+        }
+
+        let len = file.len;
+        let range = match headers.get(RANGE) {
+            Some(range) if is_range_fresh(headers, &etag, modified) => parse_range(range, len),
+            _ => ByteRange::Full,
+        };
+
+        let response = match range {
+            ByteRange::Full => builder
+                .header(CONTENT_LENGTH, len)
+                .body(self.body(req, &file, 0..len).await?),
+            ByteRange::Partial(range) => builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{len}", range.start, range.end - 1),
+                )
+                .header(CONTENT_LENGTH, range.end - range.start)
+                .body(self.body(req, &file, range).await?),
+            ByteRange::Unsatisfiable => builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{len}"))
+                .body(Body::empty()),
+        };
+
+        response.ok()
+    }
+
+    /// Stream part of a file to the client without buffering it in memory.
+    async fn body(
+        &self,
+        req: &Request<Body>,
+        file: &StaticFile,
+        range: Range<u64>,
+    ) -> Option<Body> {
+        if req.method() == Method::HEAD {
+            return Some(Body::empty());
+        }
+
+        let mut f = self
+            .fs
+            .new_open_options()
+            .read(true)
+            .open(&file.path)
+            .ok()?;
+        if range.start > 0 {
+            f.seek(SeekFrom::Start(range.start)).await.ok()?;
+        }
+
+        let reader = BufReader::new(f.take(range.end - range.start));
+        let chunks = futures::stream::try_unfold(reader, |mut r| async move {
+            match r.fill_buf().await {
+                Ok(chunk) if chunk.is_empty() => Ok(None),
+                Ok(chunk) => {
+                    let chunk = chunk.to_vec();
+                    r.consume(chunk.len());
+                    Ok(Some((chunk, r)))
+                }
+                Err(e) => Err(e),
+            }
+        });
+
+        Some(Body::wrap_stream(chunks))
+    }
+}
+
+/// Does the client accept responses compressed with this encoding?
+/// Whether a path is inside the directory a prefix refers to, comparing
+/// whole path segments so `/static` doesn't match `/staticfoo`.
+fn has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    let mut wildcard = false;
+
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let quality: f32 = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse().ok())
+                .unwrap_or(1.0);
+
+            if name.eq_ignore_ascii_case(encoding) {
+                return quality > 0.0;
+            } else if name == "*" {
+                wildcard = quality > 0.0;
+            }
+        }
+    }
+
+    wildcard
+}
+
+/// Can the client use the copy it has cached?
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: HttpDate) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH) {
+        return if_none_match
+            .to_str()
+            .map(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+            })
+            .unwrap_or(false);
+    }
+
+    headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<HttpDate>().ok())
+        .map(|since| modified <= since)
+        .unwrap_or(false)
+}
+
+/// Does the `If-Range` header (if any) still refer to this file?
+fn is_range_fresh(headers: &HeaderMap, etag: &str, modified: HttpDate) -> bool {
+    let if_range = match headers.get(IF_RANGE).map(|v| v.to_str()) {
+        Some(Ok(value)) => value.trim(),
+        Some(Err(_)) => return false,
+        None => return true,
+    };
+
+    if if_range.starts_with('"') {
+        if_range == etag
+    } else {
+        if_range
+            .parse::<HttpDate>()
+            .map(|date| date == modified)
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header.
+///
+/// Only a single range is supported. Requests for multiple ranges and
+/// headers we don't understand get the full file, as allowed by
+/// [RFC 9110](https://www.rfc-editor.org/rfc/rfc9110#section-14.2).
+fn parse_range(header: &http::HeaderValue, len: u64) -> ByteRange {
+    let spec = match header
+        .to_str()
+        .ok()
+        .and_then(|h| h.trim().strip_prefix("bytes="))
+    {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Full,
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // "bytes=-500" is the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            len.saturating_sub(suffix)..len
+        }
+        (Ok(start), Err(_)) if end.is_empty() => start..len,
+        (Ok(start), Ok(end)) if start <= end => start..len.min(end + 1),
+        _ => return ByteRange::Full,
+    };
+
+    if range.start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use tokio::io::AsyncWriteExt;
+    use virtual_fs::mem_fs;
+
+    use super::*;
+
+    async fn static_files() -> StaticFiles {
+        let fs = mem_fs::FileSystem::default();
+        fs.create_dir(Path::new("/public")).unwrap();
+        fs.create_dir(Path::new("/public/static")).unwrap();
+        fs.create_dir(Path::new("/public/staticfoo")).unwrap();
+
+        for (path, contents) in [
+            ("/public/static/app.css", "body { color: red; }"),
+            ("/public/static/app.css.gz", "<gzipped>"),
+            ("/public/logo.svg", "<svg/>"),
+            ("/public/index.php", "<?php echo 'Hello';"),
+            ("/public/staticfoo/app.css", "body { color: blue; }"),
+        ] {
+            let mut f = fs
+                .new_open_options()
+                .write(true)
+                .create(true)
+                .open(path)
+                .unwrap();
+            f.write_all(contents.as_bytes()).await.unwrap();
+        }
+
+        StaticFiles::new(
+            Arc::new(fs),
+            PathBuf::from("/public"),
+            vec!["/static/".to_string()],
+            vec![".svg".to_string()],
+        )
+    }
+
+    async fn body(response: Response<Body>) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serve_files_matching_a_prefix_or_extension() {
+        let files = static_files().await;
+
+        let css = files
+            .serve(&Request::get("/static/app.css").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let svg = files
+            .serve(&Request::get("/logo.svg").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(css.status(), StatusCode::OK);
+        assert_eq!(css.headers()[CONTENT_TYPE], "text/css");
+        assert!(css.headers().contains_key(ETAG));
+        assert!(css.headers().contains_key(LAST_MODIFIED));
+        assert_eq!(body(css).await, "body { color: red; }");
+        assert_eq!(svg.headers()[CONTENT_TYPE], "image/svg+xml");
+    }
+
+    #[tokio::test]
+    async fn dynamic_routes_go_to_the_guest() {
+        let files = static_files().await;
+
+        for req in [
+            Request::get("/index.php").body(Body::empty()).unwrap(),
+            Request::get("/static/missing.css")
+                .body(Body::empty())
+                .unwrap(),
+            Request::get("/static/../index.php")
+                .body(Body::empty())
+                .unwrap(),
+            Request::get("/staticfoo/app.css")
+                .body(Body::empty())
+                .unwrap(),
+            Request::post("/static/app.css")
+                .body(Body::empty())
+                .unwrap(),
+        ] {
+            assert!(files.serve(&req).await.is_none(), "{}", req.uri());
+        }
+    }
+
+    #[tokio::test]
+    async fn revalidate_with_an_etag() {
+        let files = static_files().await;
+        let first = files
+            .serve(&Request::get("/logo.svg").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let etag = first.headers()[ETAG].clone();
+
+        let second = files
+            .serve(
+                &Request::get("/logo.svg")
+                    .header(IF_NONE_MATCH, etag)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(second.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(body(second).await, "");
+    }
+
+    #[tokio::test]
+    async fn serve_precompressed_variants() {
+        let files = static_files().await;
+
+        let response = files
+            .serve(
+                &Request::get("/static/app.css")
+                    .header(ACCEPT_ENCODING, "br;q=0, gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/css");
+        assert_eq!(body(response).await, "<gzipped>");
+    }
+
+    #[tokio::test]
+    async fn serve_part_of_a_file() {
+        let files = static_files().await;
+
+        let response = files
+            .serve(
+                &Request::get("/static/app.css")
+                    .header(RANGE, "bytes=0-3")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 0-3/20");
+        assert_eq!(body(response).await, "body");
+    }
+
+    #[tokio::test]
+    async fn serve_the_middle_of_a_file() {
+        let files = static_files().await;
+
+        let response = files
+            .serve(
+                &Request::get("/static/app.css")
+                    .header(RANGE, "bytes=7-11")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+        assert_eq!(body(response).await, "color");
+    }
+
+    #[tokio::test]
+    async fn the_etag_changes_with_the_file() {
+        let files = static_files().await;
+        let first = files
+            .serve(&Request::get("/logo.svg").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let etag = first.headers()[ETAG].clone();
+
+        let mut f = files
+            .fs
+            .new_open_options()
+            .write(true)
+            .truncate(true)
+            .open("/public/logo.svg")
+            .unwrap();
+        f.write_all(b"<svg></svg>").await.unwrap();
+
+        let second = files
+            .serve(
+                &Request::get("/logo.svg")
+                    .header(IF_NONE_MATCH, etag)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(body(second).await, "<svg></svg>");
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        assert!(has_prefix("/static", "/static"));
+        assert!(has_prefix("/static/app.css", "/static"));
+        assert!(has_prefix("/static/app.css", "/static/"));
+        assert!(has_prefix("/app.css", "/"));
+        assert!(!has_prefix("/staticfoo/app.css", "/static"));
+        assert!(!has_prefix("/staticfoo", "/static/"));
+        assert!(!has_prefix("/other/static/app.css", "/static"));
+    }
+
+    #[test]
+    fn parse_range_headers() {
+        let inputs = [
+            ("bytes=0-9", ByteRange::Partial(0..10)),
+            ("bytes=90-", ByteRange::Partial(90..100)),
+            ("bytes=-10", ByteRange::Partial(90..100)),
+            ("bytes=50-1000", ByteRange::Partial(50..100)),
+            ("bytes=100-", ByteRange::Unsatisfiable),
+            ("bytes=-0", ByteRange::Unsatisfiable),
+            ("bytes=0-1,5-6", ByteRange::Full),
+            ("bytes=5-1", ByteRange::Full),
+            ("lines=1-2", ByteRange::Full),
+        ];
+
+        for (header, expected) in inputs {
+            let header = HeaderValue::from_static(header);
+            assert_eq!(parse_range(&header, 100), expected, "{header:?}");
+        }
+    }
+}
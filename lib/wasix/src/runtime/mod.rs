@@ -100,6 +100,20 @@ where
         None
     }
 
+    /// The configuration used when a guest upgrades a connection to TLS
+    /// (see [`wasmer_wasix_types::wasi::Sockoption::TlsClient`])
+    #[cfg(feature = "net-tls")]
+    fn tls_client(&self) -> Option<&virtual_net::TlsClientConfig> {
+        None
+    }
+
+    /// The certificate used when a guest terminates TLS on a listener
+    /// (see [`wasmer_wasix_types::wasi::Sockoption::TlsServer`])
+    #[cfg(feature = "net-tls")]
+    fn tls_server(&self) -> Option<&virtual_net::TlsServerConfig> {
+        None
+    }
+
     /// Load a a Webassembly module, trying to use a pre-compiled version if possible.
     fn load_module<'a>(&'a self, wasm: &'a [u8]) -> BoxFuture<'a, anyhow::Result<Module>> {
         let engine = self.engine();
@@ -216,6 +230,10 @@ pub struct PluggableRuntime {
     pub module_cache: Arc<dyn ModuleCache + Send + Sync>,
     #[derivative(Debug = "ignore")]
     pub tty: Option<Arc<dyn TtyBridge + Send + Sync>>,
+    #[cfg(feature = "net-tls")]
+    pub tls_client: Option<virtual_net::TlsClientConfig>,
+    #[cfg(feature = "net-tls")]
+    pub tls_server: Option<virtual_net::TlsServerConfig>,
     #[cfg(feature = "journal")]
     #[derivative(Debug = "ignore")]
     pub journals: Vec<Arc<DynJournal>>,
@@ -253,6 +271,10 @@ impl PluggableRuntime {
             source: Arc::new(source),
             package_loader: Arc::new(loader),
             module_cache: Arc::new(module_cache::in_memory()),
+            #[cfg(feature = "net-tls")]
+            tls_client: None,
+            #[cfg(feature = "net-tls")]
+            tls_server: None,
             #[cfg(feature = "journal")]
             journals: Vec::new(),
         }
@@ -305,6 +327,18 @@ impl PluggableRuntime {
         self
     }
 
+    #[cfg(feature = "net-tls")]
+    pub fn set_tls_client(&mut self, config: virtual_net::TlsClientConfig) -> &mut Self {
+        self.tls_client = Some(config);
+        self
+    }
+
+    #[cfg(feature = "net-tls")]
+    pub fn set_tls_server(&mut self, config: virtual_net::TlsServerConfig) -> &mut Self {
+        self.tls_server = Some(config);
+        self
+    }
+
     #[cfg(feature = "journal")]
     pub fn add_journal(&mut self, journal: Arc<DynJournal>) -> &mut Self {
         self.journals.push(journal);
@@ -356,6 +390,16 @@ impl Runtime for PluggableRuntime {
         self.module_cache.clone()
     }
 
+    #[cfg(feature = "net-tls")]
+    fn tls_client(&self) -> Option<&virtual_net::TlsClientConfig> {
+        self.tls_client.as_ref()
+    }
+
+    #[cfg(feature = "net-tls")]
+    fn tls_server(&self) -> Option<&virtual_net::TlsServerConfig> {
+        self.tls_server.as_ref()
+    }
+
     #[cfg(feature = "journal")]
     fn journals(&self) -> &'_ Vec<Arc<DynJournal>> {
         &self.journals
@@ -528,6 +572,16 @@ impl Runtime for OverriddenRuntime {
         }
     }
 
+    #[cfg(feature = "net-tls")]
+    fn tls_client(&self) -> Option<&virtual_net::TlsClientConfig> {
+        self.inner.tls_client()
+    }
+
+    #[cfg(feature = "net-tls")]
+    fn tls_server(&self) -> Option<&virtual_net::TlsServerConfig> {
+        self.inner.tls_server()
+    }
+
     #[cfg(feature = "journal")]
     fn journals(&self) -> &'_ Vec<Arc<DynJournal>> {
         if let Some(journals) = self.journals.as_ref() {
@@ -1,5 +1,8 @@
+use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use tempfile::NamedTempFile;
 use tokio::io::AsyncWriteExt;
@@ -11,17 +14,66 @@ use crate::runtime::task_manager::VirtualTaskManagerExt;
 
 /// A cache that saves modules to a folder on the host filesystem using
 /// [`Module::serialize()`].
+///
+/// The cache can be given a maximum size and number of entries, in which
+/// case the least recently used modules are evicted whenever a new module is
+/// saved. Multiple processes can safely share the same cache directory, they
+/// coordinate with a lock file on Unix and Windows.
+///
+/// Hits and misses are counted in memory and only added to the statistics
+/// file when a module is saved or the last clone of the cache is dropped, so
+/// loading a module never waits on another process.
+///
+/// Cached modules are memory-mapped when they are loaded, so processes
+/// running the same module share a single copy of its serialized artifact
+/// through the OS's page cache. Cache files are never modified in place,
+/// which means a module stays valid even if its file gets evicted or
+/// replaced while it is mapped.
 #[derive(Debug, Clone)]
 pub struct FileSystemCache {
     cache_dir: PathBuf,
     task_manager: Arc<TokioTaskManager>,
+    max_size: Option<u64>,
+    max_entries: Option<usize>,
+    pending: Arc<PendingStats>,
 }
 
 impl FileSystemCache {
+    /// The file used to coordinate eviction and statistics between processes.
+    const LOCK_FILE: &'static str = ".lock";
+    /// The file hit/miss/eviction counts are saved to.
+    const STATS_FILE: &'static str = "stats.json";
+    /// How stale a module's modification time needs to be before a cache hit
+    /// updates it. Eviction only needs a rough idea of when a module was last
+    /// used, and this saves a metadata write on almost every load.
+    const ACCESS_TIME_RESOLUTION: Duration = Duration::from_secs(10 * 60);
+
     pub fn new(cache_dir: impl Into<PathBuf>, task_manager: Arc<TokioTaskManager>) -> Self {
+        let cache_dir = cache_dir.into();
         FileSystemCache {
-            cache_dir: cache_dir.into(),
+            pending: Arc::new(PendingStats::new(cache_dir.clone())),
+            cache_dir,
             task_manager,
+            max_size: None,
+            max_entries: None,
+        }
+    }
+
+    /// Evict the least recently used modules when the cache takes up more
+    /// than this many bytes.
+    pub fn with_max_size(self, max_size: u64) -> Self {
+        FileSystemCache {
+            max_size: Some(max_size),
+            ..self
+        }
+    }
+
+    /// Evict the least recently used modules when the cache contains more
+    /// than this many modules.
+    pub fn with_max_entries(self, max_entries: usize) -> Self {
+        FileSystemCache {
+            max_entries: Some(max_entries),
+            ..self
         }
     }
 
@@ -29,6 +81,23 @@ impl FileSystemCache {
         &self.cache_dir
     }
 
+    /// Look at what is currently in the cache and how it has been used.
+    ///
+    /// The statistics include every process that used this cache directory.
+    pub fn stats(&self) -> Result<FileSystemCacheStats, CacheError> {
+        let _lock = lock(&self.cache_dir).map_err(CacheError::other)?;
+
+        let mut stats = read_stats(&self.cache_dir);
+        stats.hits += self.pending.hits.load(Ordering::Relaxed);
+        stats.misses += self.pending.misses.load(Ordering::Relaxed);
+        for entry in entries(&self.cache_dir).map_err(CacheError::other)? {
+            stats.entries += 1;
+            stats.size += entry.size;
+        }
+
+        Ok(stats)
+    }
+
     fn path(&self, key: ModuleHash, deterministic_id: &str) -> PathBuf {
         let artifact_version = wasmer_types::MetadataHeader::CURRENT_VERSION;
         self.cache_dir
@@ -49,18 +118,21 @@ impl ModuleCache for FileSystemCache {
             .spawn({
                 let task_manager = self.task_manager.clone();
                 let engine = engine.clone();
+                let pending = self.pending.clone();
 
                 async move {
-                    let bytes = read_file(&path).await?;
-
                     task_manager
-                    .spawn_await({
-
-                        move || match deserialize(&bytes, &engine) {
+                    .spawn_await(move || match deserialize_from_disk(&path, &engine) {
                             Ok(m) => {
                                 tracing::debug!("Cache hit!");
+                                touch(&path);
+                                pending.hits.fetch_add(1, Ordering::Relaxed);
                                 Ok(m)
                             }
+                            Err(e @ CacheError::NotFound) | Err(e @ CacheError::FileRead { .. }) => {
+                                pending.misses.fetch_add(1, Ordering::Relaxed);
+                                Err(e)
+                            }
                             Err(e) => {
                                 tracing::debug!(
                                     %key,
@@ -78,10 +150,11 @@ impl ModuleCache for FileSystemCache {
                                     );
                                 }
 
+                                pending.misses.fetch_add(1, Ordering::Relaxed);
                                 Err(e)
                             }
                         }
-                    })
+                    )
                     .await
                     .unwrap()
                 }
@@ -104,6 +177,7 @@ impl ModuleCache for FileSystemCache {
             .spawn({
                 let task_manager = self.task_manager.clone();
                 let module = module.clone();
+                let cache = self.clone();
 
                 async move {
                     let parent = path
@@ -142,6 +216,18 @@ impl ModuleCache for FileSystemCache {
                     temp.persist(&path).map_err(CacheError::other)?;
                     tracing::debug!(path=%path.display(), "Saved to disk");
 
+                    task_manager
+                        .spawn_await(move || {
+                            if let Err(e) = cache.maintain(&path) {
+                                tracing::warn!(
+                                    error = &*e,
+                                    "Unable to evict old modules from the cache",
+                                );
+                            }
+                        })
+                        .await
+                        .unwrap();
+
                     Ok(())
                 }
             })
@@ -150,18 +236,345 @@ impl ModuleCache for FileSystemCache {
     }
 }
 
-async fn read_file(path: &Path) -> Result<Vec<u8>, CacheError> {
-    match tokio::fs::read(path).await {
-        Ok(bytes) => Ok(bytes),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(CacheError::NotFound),
-        Err(error) => Err(CacheError::FileRead {
-            path: path.to_path_buf(),
-            error,
-        }),
+impl FileSystemCache {
+    /// Evict old modules and write out the pending statistics after a module
+    /// was saved.
+    ///
+    /// If another process is already doing the same, this is skipped and the
+    /// work is left for the next save.
+    fn maintain(&self, just_saved: &Path) -> Result<(), anyhow::Error> {
+        let _lock = match try_lock(&self.cache_dir)? {
+            Some(lock) => lock,
+            None => {
+                tracing::debug!("The cache is locked by another process");
+                return Ok(());
+            }
+        };
+
+        let evictions = if self.max_size.is_some() || self.max_entries.is_some() {
+            self.evict(just_saved)?
+        } else {
+            0
+        };
+
+        self.pending.flush(|stats| stats.evictions += evictions)
+    }
+
+    /// Delete the least recently used modules until the cache is within its
+    /// limits, never evicting the module that was just saved.
+    ///
+    /// The caller must hold the [`lock()`].
+    fn evict(&self, just_saved: &Path) -> Result<u64, anyhow::Error> {
+        let mut entries = entries(&self.cache_dir)?;
+        // Oldest first
+        entries.sort_by_key(|entry| entry.last_accessed);
+
+        let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut count = entries.len();
+        let mut evicted = 0;
+
+        for entry in &entries {
+            let too_big = self.max_size.map_or(false, |max| size > max);
+            let too_many = self.max_entries.map_or(false, |max| count > max);
+            if !too_big && !too_many {
+                break;
+            }
+            if entry.path == just_saved {
+                continue;
+            }
+
+            match std::fs::remove_file(&entry.path) {
+                Ok(_) => {
+                    tracing::debug!(path=%entry.path.display(), "Evicted from the cache");
+                    evicted += 1;
+                }
+                // Someone else got to it first
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    // Some platforms won't let us delete a file that another
+                    // process has memory-mapped
+                    tracing::debug!(
+                        path=%entry.path.display(),
+                        error=&e as &dyn std::error::Error,
+                        "Unable to evict a module, it may still be in use",
+                    );
+                    continue;
+                }
+            }
+
+            size -= entry.size;
+            count -= 1;
+        }
+
+        Ok(evicted)
+    }
+}
+
+/// Hits and misses that haven't been added to the statistics file yet.
+#[derive(Debug)]
+struct PendingStats {
+    cache_dir: PathBuf,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PendingStats {
+    fn new(cache_dir: PathBuf) -> Self {
+        PendingStats {
+            cache_dir,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Add the pending counts to the statistics file, assuming the caller
+    /// holds the [`lock()`].
+    fn flush(&self, update: impl FnOnce(&mut FileSystemCacheStats)) -> Result<(), anyhow::Error> {
+        let hits = self.hits.swap(0, Ordering::Relaxed);
+        let misses = self.misses.swap(0, Ordering::Relaxed);
+
+        let result = modify_stats(&self.cache_dir, |stats| {
+            stats.hits += hits;
+            stats.misses += misses;
+            update(stats);
+        });
+
+        if result.is_err() {
+            // Try again next time
+            self.hits.fetch_add(hits, Ordering::Relaxed);
+            self.misses.fetch_add(misses, Ordering::Relaxed);
+        }
+
+        result
     }
 }
 
-fn deserialize(bytes: &[u8], engine: &Engine) -> Result<Module, CacheError> {
+impl Drop for PendingStats {
+    fn drop(&mut self) {
+        if *self.hits.get_mut() == 0 && *self.misses.get_mut() == 0 {
+            return;
+        }
+
+        // This is the last chance to save the counts, so it's worth waiting
+        // for another process to finish with the lock
+        let result = lock(&self.cache_dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|_lock| self.flush(|_| {}));
+        if let Err(e) = result {
+            tracing::debug!(
+                cache_dir=%self.cache_dir.display(),
+                error=&*e,
+                "Unable to update the cache statistics",
+            );
+        }
+    }
+}
+
+/// Usage statistics for a [`FileSystemCache`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileSystemCacheStats {
+    /// The number of modules currently in the cache.
+    #[serde(skip)]
+    pub entries: u64,
+    /// The total size of every module in the cache, in bytes.
+    #[serde(skip)]
+    pub size: u64,
+    /// How often a module was found in the cache.
+    pub hits: u64,
+    /// How often a module wasn't found in the cache.
+    pub misses: u64,
+    /// How many modules were deleted to keep the cache within its limits.
+    pub evictions: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    last_accessed: SystemTime,
+}
+
+/// Find every module in the cache.
+fn entries(cache_dir: &Path) -> Result<Vec<CacheEntry>, std::io::Error> {
+    let mut entries = Vec::new();
+
+    let dirs = match std::fs::read_dir(cache_dir) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(e),
+    };
+
+    // Modules are saved as "$cache_dir/$engine-$version/$key.bin"
+    for dir in dirs {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+
+        for file in std::fs::read_dir(dir.path())? {
+            let file = file?;
+            let path = file.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("bin") {
+                continue;
+            }
+
+            let metadata = match file.metadata() {
+                Ok(m) => m,
+                // It was evicted while we were looking
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            entries.push(CacheEntry {
+                path,
+                size: metadata.len(),
+                last_accessed: metadata.modified()?,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Take an exclusive lock on the cache directory.
+///
+/// This is an advisory lock which is released when the returned [`File`] is
+/// dropped. Readers and writers don't need to take it because modules are
+/// always written to a temporary file and atomically renamed into place.
+fn lock(cache_dir: &Path) -> Result<File, std::io::Error> {
+    let file = open_lock_file(cache_dir)?;
+    lock_file(&file, true)?;
+    Ok(file)
+}
+
+/// Like [`lock()`], except `None` is returned instead of waiting when another
+/// process holds the lock.
+fn try_lock(cache_dir: &Path) -> Result<Option<File>, std::io::Error> {
+    let file = open_lock_file(cache_dir)?;
+    match lock_file(&file, false)? {
+        true => Ok(Some(file)),
+        false => Ok(None),
+    }
+}
+
+/// Take an exclusive lock on the whole file, returning `false` if `wait` is
+/// not set and another process holds it.
+#[cfg(unix)]
+fn lock_file(file: &File, wait: bool) -> Result<bool, std::io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = match wait {
+        true => libc::LOCK_EX,
+        false => libc::LOCK_EX | libc::LOCK_NB,
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        let error = std::io::Error::last_os_error();
+        if error.kind() == std::io::ErrorKind::WouldBlock {
+            return Ok(false);
+        }
+        return Err(error);
+    }
+    Ok(true)
+}
+
+#[cfg(windows)]
+fn lock_file(file: &File, wait: bool) -> Result<bool, std::io::Error> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+
+    let flags = match wait {
+        true => LOCKFILE_EXCLUSIVE_LOCK,
+        false => LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY,
+    };
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    let locked = unsafe {
+        LockFileEx(
+            file.as_raw_handle() as _,
+            flags,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    };
+    if locked == 0 {
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+            return Ok(false);
+        }
+        return Err(error);
+    }
+    Ok(true)
+}
+
+/// There is no way to lock files on other platforms, so processes sharing a
+/// cache directory may evict modules and save statistics at the same time.
+#[cfg(not(any(unix, windows)))]
+fn lock_file(_file: &File, _wait: bool) -> Result<bool, std::io::Error> {
+    Ok(true)
+}
+
+fn open_lock_file(cache_dir: &Path) -> Result<File, std::io::Error> {
+    std::fs::create_dir_all(cache_dir)?;
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(cache_dir.join(FileSystemCache::LOCK_FILE))
+}
+
+fn read_stats(cache_dir: &Path) -> FileSystemCacheStats {
+    std::fs::read(cache_dir.join(FileSystemCache::STATS_FILE))
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Update the statistics file, assuming the caller holds the [`lock()`].
+fn modify_stats(
+    cache_dir: &Path,
+    update: impl FnOnce(&mut FileSystemCacheStats),
+) -> Result<(), anyhow::Error> {
+    let mut stats = read_stats(cache_dir);
+    update(&mut stats);
+
+    let mut temp = NamedTempFile::new_in(cache_dir)?;
+    serde_json::to_writer(&mut temp, &stats)?;
+    temp.persist(cache_dir.join(FileSystemCache::STATS_FILE))?;
+
+    Ok(())
+}
+
+/// Mark a module as recently used.
+///
+/// The modification time doubles as the last access time, because access
+/// times often aren't updated by the OS.
+fn touch(path: &Path) {
+    let recently_touched = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map_or(false, |age| age < FileSystemCache::ACCESS_TIME_RESOLUTION);
+    if recently_touched {
+        return;
+    }
+
+    if let Err(e) = filetime::set_file_mtime(path, filetime::FileTime::now()) {
+        tracing::debug!(
+            path=%path.display(),
+            error=&e as &dyn std::error::Error,
+            "Unable to update the cache file's access time",
+        );
+    }
+}
+
+/// Load a module from disk.
+///
+/// The file is memory-mapped and deserialized in place, so processes loading
+/// the same module share the artifact's pages through the OS's page cache
+/// instead of each reading their own copy into memory.
+fn deserialize_from_disk(path: &Path, engine: &Engine) -> Result<Module, CacheError> {
     // We used to compress our compiled modules using LZW encoding in the past.
     // This was removed because it has a negative impact on startup times for
     // "wasmer run", so all new compiled modules should be saved directly to
@@ -177,12 +590,23 @@ fn deserialize(bytes: &[u8], engine: &Engine) -> Result<Module, CacheError> {
     // - ModuleCache::save(): 2.4s, 72MB binary
     // - ModuleCache::load(): 822ms
 
-    match unsafe { Module::deserialize(engine, bytes) } {
+    match unsafe { Module::deserialize_from_file(engine, path) } {
         // The happy case
         Ok(m) => Ok(m),
+        Err(wasmer::DeserializeError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(CacheError::NotFound)
+        }
+        Err(wasmer::DeserializeError::Io(error)) => Err(CacheError::FileRead {
+            path: path.to_path_buf(),
+            error,
+        }),
         Err(wasmer::DeserializeError::Incompatible(_)) => {
+            let bytes = std::fs::read(path).map_err(|error| CacheError::FileRead {
+                path: path.to_path_buf(),
+                error,
+            })?;
             let bytes = weezl::decode::Decoder::new(weezl::BitOrder::Msb, 8)
-                .decode(bytes)
+                .decode(&bytes)
                 .map_err(CacheError::other)?;
 
             let m = unsafe { Module::deserialize(engine, bytes)? };
@@ -276,6 +700,170 @@ mod tests {
         assert_eq!(exports, ["add"]);
     }
 
+    #[tokio::test]
+    async fn loaded_modules_outlive_their_cache_file() {
+        let temp = TempDir::new().unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, ADD_WAT).unwrap();
+        let key = ModuleHash::from_bytes([0; 8]);
+        let cache = FileSystemCache::new(temp.path(), create_tokio_task_manager());
+        cache.save(key, &engine, &module).await.unwrap();
+
+        let module = cache.load(key, &engine).await.unwrap();
+        std::fs::remove_file(cache.path(key, engine.deterministic_id())).unwrap();
+
+        let mut store = wasmer::Store::new(engine);
+        let instance = wasmer::Instance::new(&mut store, &module, &wasmer::imports! {}).unwrap();
+        let add = instance
+            .exports
+            .get_typed_function::<(i64, i64), i64>(&store, "add")
+            .unwrap();
+        assert_eq!(add.call(&mut store, 1, 2).unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn evict_the_least_recently_used_modules() {
+        let temp = TempDir::new().unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, ADD_WAT).unwrap();
+        let cache =
+            FileSystemCache::new(temp.path(), create_tokio_task_manager()).with_max_entries(2);
+        let keys = [1, 2, 3].map(|i| ModuleHash::from_bytes([i; 8]));
+        let paths = keys.map(|key| cache.path(key, engine.deterministic_id()));
+        cache.save(keys[0], &engine, &module).await.unwrap();
+        cache.save(keys[1], &engine, &module).await.unwrap();
+        // Pretend the second module was used less recently than the first
+        let now = filetime::FileTime::now().unix_seconds();
+        filetime::set_file_mtime(&paths[0], filetime::FileTime::from_unix_time(now - 100, 0))
+            .unwrap();
+        filetime::set_file_mtime(&paths[1], filetime::FileTime::from_unix_time(now - 200, 0))
+            .unwrap();
+
+        cache.save(keys[2], &engine, &module).await.unwrap();
+
+        assert!(paths[0].exists());
+        assert!(!paths[1].exists());
+        assert!(paths[2].exists());
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn never_evict_the_module_that_was_just_saved() {
+        let temp = TempDir::new().unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, ADD_WAT).unwrap();
+        let cache = FileSystemCache::new(temp.path(), create_tokio_task_manager()).with_max_size(1);
+        let first = ModuleHash::from_bytes([1; 8]);
+        let second = ModuleHash::from_bytes([2; 8]);
+
+        cache.save(first, &engine, &module).await.unwrap();
+        cache.save(second, &engine, &module).await.unwrap();
+
+        assert!(!cache.path(first, engine.deterministic_id()).exists());
+        assert!(cache.path(second, engine.deterministic_id()).exists());
+    }
+
+    #[tokio::test]
+    async fn keep_track_of_hits_and_misses() {
+        let temp = TempDir::new().unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, ADD_WAT).unwrap();
+        let cache = FileSystemCache::new(temp.path(), create_tokio_task_manager());
+        let key = ModuleHash::from_bytes([0; 8]);
+
+        cache.load(key, &engine).await.unwrap_err();
+        cache.save(key, &engine, &module).await.unwrap();
+        cache.load(key, &engine).await.unwrap();
+        cache.load(key, &engine).await.unwrap();
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions, 0);
+        assert_eq!(stats.entries, 1);
+        assert_eq!(
+            stats.size,
+            std::fs::metadata(cache.path(key, engine.deterministic_id()))
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[tokio::test]
+    async fn hits_and_misses_are_saved_with_the_next_module() {
+        let temp = TempDir::new().unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, ADD_WAT).unwrap();
+        let cache = FileSystemCache::new(temp.path(), create_tokio_task_manager());
+        let first = ModuleHash::from_bytes([1; 8]);
+        let second = ModuleHash::from_bytes([2; 8]);
+
+        cache.load(first, &engine).await.unwrap_err();
+        cache.load(second, &engine).await.unwrap_err();
+
+        // Loading never touches the statistics file
+        assert_eq!(read_stats(temp.path()), FileSystemCacheStats::default());
+
+        cache.save(first, &engine, &module).await.unwrap();
+        cache.load(first, &engine).await.unwrap();
+
+        assert_eq!(read_stats(temp.path()).misses, 2);
+        assert_eq!(read_stats(temp.path()).hits, 0);
+
+        // Whatever is left gets saved when the cache goes away
+        drop(cache);
+        assert_eq!(read_stats(temp.path()).hits, 1);
+    }
+
+    #[tokio::test]
+    async fn a_locked_cache_defers_the_statistics() {
+        let temp = TempDir::new().unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, ADD_WAT).unwrap();
+        let cache = FileSystemCache::new(temp.path(), create_tokio_task_manager());
+        let key = ModuleHash::from_bytes([0; 8]);
+        cache.load(key, &engine).await.unwrap_err();
+
+        // Pretend another process is evicting
+        let lock = lock(temp.path()).unwrap();
+        cache.save(key, &engine, &module).await.unwrap();
+        drop(lock);
+
+        assert_eq!(read_stats(temp.path()).misses, 0);
+        assert_eq!(cache.stats().unwrap().misses, 1);
+
+        cache.save(key, &engine, &module).await.unwrap();
+
+        assert_eq!(read_stats(temp.path()).misses, 1);
+        assert_eq!(cache.stats().unwrap().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn only_stale_access_times_are_updated() {
+        let temp = TempDir::new().unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, ADD_WAT).unwrap();
+        let cache = FileSystemCache::new(temp.path(), create_tokio_task_manager());
+        let key = ModuleHash::from_bytes([0; 8]);
+        let path = cache.path(key, engine.deterministic_id());
+        cache.save(key, &engine, &module).await.unwrap();
+        let now = filetime::FileTime::now().unix_seconds();
+
+        let recently = filetime::FileTime::from_unix_time(now - 60, 0);
+        filetime::set_file_mtime(&path, recently).unwrap();
+        cache.load(key, &engine).await.unwrap();
+        let mtime = filetime::FileTime::from_last_modification_time(&path.metadata().unwrap());
+        assert_eq!(mtime, recently);
+
+        let long_ago = filetime::FileTime::from_unix_time(now - 60 * 60, 0);
+        filetime::set_file_mtime(&path, long_ago).unwrap();
+        cache.load(key, &engine).await.unwrap();
+        let mtime = filetime::FileTime::from_last_modification_time(&path.metadata().unwrap());
+        assert!(mtime.unix_seconds() >= now);
+    }
+
     /// For backwards compatibility, make sure we can still work with LZW
     /// compressed modules.
     #[tokio::test]
@@ -46,7 +46,7 @@ pub use self::{
 };
 
 #[cfg(feature = "sys-thread")]
-pub use self::filesystem::FileSystemCache;
+pub use self::filesystem::{FileSystemCache, FileSystemCacheStats};
 
 /// Get a [`ModuleCache`] which should be good enough for most in-memory use
 /// cases.
@@ -34,6 +34,8 @@ pub struct BuiltinPackageLoader {
     cache: Option<FileSystemCache>,
     /// A mapping from hostnames to tokens
     tokens: HashMap<String, String>,
+    validate_hashes: bool,
+    offline: bool,
 }
 
 impl BuiltinPackageLoader {
@@ -43,6 +45,8 @@ impl BuiltinPackageLoader {
             client: Arc::new(crate::http::default_http_client().unwrap()),
             cache: None,
             tokens: HashMap::new(),
+            validate_hashes: false,
+            offline: false,
         }
     }
 
@@ -87,6 +91,21 @@ impl BuiltinPackageLoader {
         self
     }
 
+    /// Check that downloaded packages match the SHA-256 hash their
+    /// [`PackageSummary`] says they should have.
+    pub fn with_hash_validation(self, validate_hashes: bool) -> Self {
+        BuiltinPackageLoader {
+            validate_hashes,
+            ..self
+        }
+    }
+
+    /// Only load packages from the cache or local files, instead of
+    /// downloading them.
+    pub fn with_offline(self, offline: bool) -> Self {
+        BuiltinPackageLoader { offline, ..self }
+    }
+
     /// Insert a container into the in-memory hash.
     pub fn insert_cached(&self, hash: WebcHash, container: &Container) {
         self.in_memory.save(container, hash);
@@ -215,12 +234,30 @@ impl PackageLoader for BuiltinPackageLoader {
             return Ok(container);
         }
 
+        if self.offline && summary.dist.webc.scheme() != "file" {
+            anyhow::bail!(
+                "{} {} isn't in the local cache, and it can't be downloaded in offline mode",
+                summary.pkg.name,
+                summary.pkg.version,
+            );
+        }
+
         // looks like we had a cache miss and need to download it manually
         let bytes = self
             .download(&summary.dist)
             .await
             .with_context(|| format!("Unable to download \"{}\"", summary.dist.webc))?;
 
+        if self.validate_hashes {
+            let hash = WebcHash::sha256(&bytes);
+            anyhow::ensure!(
+                hash == summary.dist.webc_sha256,
+                "The hash of \"{}\" was {hash}, but {} was expected",
+                summary.dist.webc,
+                summary.dist.webc_sha256,
+            );
+        }
+
         // We want to cache the container we downloaded, but we want to do it
         // in a smart way to keep memory usage down.
 
@@ -471,6 +508,74 @@ mod tests {
         assert!(in_memory.contains_key(&summary.dist.webc_sha256));
     }
 
+    #[tokio::test]
+    async fn never_download_in_offline_mode() {
+        // Sending a request would panic because there are no responses
+        let client = Arc::new(DummyClient::with_responses([]));
+        let loader = BuiltinPackageLoader::new()
+            .with_shared_http_client(client)
+            .with_offline(true);
+        let summary = PackageSummary {
+            pkg: PackageInfo {
+                name: "python/python".to_string(),
+                version: "0.1.0".parse().unwrap(),
+                dependencies: Vec::new(),
+                commands: Vec::new(),
+                entrypoint: None,
+                filesystem: Vec::new(),
+            },
+            dist: DistributionInfo {
+                webc: "https://wasmer.io/python/python".parse().unwrap(),
+                webc_sha256: [0xaa; 32].into(),
+            },
+        };
+
+        let err = loader.load(&summary).await.unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "python/python 0.1.0 isn't in the local cache, and it can't be downloaded in offline mode"
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_downloads_with_the_wrong_hash() {
+        let client = Arc::new(DummyClient::with_responses([HttpResponse {
+            body: Some(b"tampered".to_vec()),
+            redirected: false,
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+        }]));
+        let loader = BuiltinPackageLoader::new()
+            .with_shared_http_client(client)
+            .with_hash_validation(true);
+        let summary = PackageSummary {
+            pkg: PackageInfo {
+                name: "python/python".to_string(),
+                version: "0.1.0".parse().unwrap(),
+                dependencies: Vec::new(),
+                commands: Vec::new(),
+                entrypoint: None,
+                filesystem: Vec::new(),
+            },
+            dist: DistributionInfo {
+                webc: "https://wasmer.io/python/python".parse().unwrap(),
+                webc_sha256: WebcHash::sha256(b"original"),
+            },
+        };
+
+        let err = loader.load(&summary).await.unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "The hash of \"https://wasmer.io/python/python\" was {}, but {} was expected",
+                WebcHash::sha256(b"tampered"),
+                WebcHash::sha256(b"original"),
+            )
+        );
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     #[tokio::test(flavor = "multi_thread")]
     async fn cache_misses_will_trigger_a_download() {
@@ -0,0 +1,95 @@
+use anyhow::{Context, Error};
+use webc::compat::Container;
+
+use crate::{
+    bin_factory::BinaryPackage,
+    runtime::{
+        package_loader::PackageLoader,
+        resolver::{MirrorSource, PackageSummary, Resolution},
+    },
+};
+
+/// A [`PackageLoader`] that loads packages out of a [`MirrorSource`],
+/// deferring to another [`PackageLoader`] for anything that wasn't mirrored.
+///
+/// Packages are looked up by hash, so this also works for packages that
+/// were resolved using a different [`Source`][crate::runtime::resolver::Source].
+#[derive(Debug)]
+pub struct MirrorPackageLoader<L> {
+    mirror: MirrorSource,
+    fallback: L,
+}
+
+impl<L> MirrorPackageLoader<L> {
+    pub fn new(mirror: MirrorSource, fallback: L) -> Self {
+        MirrorPackageLoader { mirror, fallback }
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: PackageLoader> PackageLoader for MirrorPackageLoader<L> {
+    #[tracing::instrument(
+        level="debug",
+        skip_all,
+        fields(
+            pkg.name=summary.pkg.name.as_str(),
+            pkg.version=%summary.pkg.version,
+        ),
+    )]
+    async fn load(&self, summary: &PackageSummary) -> Result<Container, Error> {
+        let path = match self.mirror.path_for(&summary.dist.webc_sha256) {
+            Some(path) => path,
+            None => return self.fallback.load(summary).await,
+        };
+
+        tracing::debug!(path=%path.display(), "Loading from the mirror");
+
+        crate::spawn_blocking({
+            let path = path.clone();
+            move || Container::from_disk(path)
+        })
+        .await?
+        .with_context(|| format!("Unable to load \"{}\"", path.display()))
+    }
+
+    async fn load_package_tree(
+        &self,
+        root: &Container,
+        resolution: &Resolution,
+    ) -> Result<BinaryPackage, Error> {
+        super::load_package_tree(root, self, resolution).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::runtime::{
+        package_loader::UnsupportedPackageLoader,
+        resolver::{PackageInfo, WebcHash},
+    };
+
+    const HELLO: &[u8] = include_bytes!(
+        "../../../../../tests/integration/cli/tests/webc/hello-0.1.0-665d2ddc-80e6-4845-85d3-4587b1693bb7.webc"
+    );
+
+    #[tokio::test]
+    async fn only_fall_back_for_packages_that_are_not_mirrored() {
+        let temp = TempDir::new().unwrap();
+        let mut mirror = MirrorSource::open_or_create(temp.path()).unwrap();
+        let summary = mirror.add_webc(HELLO).unwrap();
+        let loader = MirrorPackageLoader::new(mirror, UnsupportedPackageLoader);
+
+        let container = loader.load(&summary).await.unwrap();
+        assert_eq!(
+            PackageInfo::from_manifest(container.manifest()).unwrap(),
+            summary.pkg
+        );
+
+        let mut missing = summary.clone();
+        missing.dist.webc_sha256 = WebcHash::sha256(b"something else");
+        assert!(loader.load(&missing).await.is_err());
+    }
+}
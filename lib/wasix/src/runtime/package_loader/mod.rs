@@ -1,9 +1,11 @@
 mod builtin_loader;
 mod load_package_tree;
+mod mirror_loader;
 mod types;
 mod unsupported;
 
 pub use self::{
     builtin_loader::BuiltinPackageLoader, load_package_tree::load_package_tree,
-    types::PackageLoader, unsupported::UnsupportedPackageLoader,
+    mirror_loader::MirrorPackageLoader, types::PackageLoader,
+    unsupported::UnsupportedPackageLoader,
 };
@@ -0,0 +1,344 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use semver::Version;
+use url::Url;
+
+use crate::runtime::resolver::{PackageId, Resolution};
+
+/// The exact packages a [`Resolution`] used, stored as a `wasmer.lock` file
+/// so later runs resolve to the same code.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Lockfile {
+    /// The version of the lockfile format.
+    pub version: u32,
+    /// The package whose dependencies are locked.
+    pub root: LockedPackage,
+    /// Every other package in the dependency graph, sorted by name.
+    #[serde(default, rename = "package", skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<LockedPackage>,
+}
+
+/// A package recorded in a [`Lockfile`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: Version,
+    /// Where the `*.webc` file was downloaded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<Url>,
+    /// The hex-encoded SHA-256 hash of the `*.webc` file.
+    ///
+    /// This is only missing for a root package that was loaded from disk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The packages this package depends on, keyed by the alias it uses for
+    /// them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, String>,
+}
+
+impl LockedPackage {
+    pub fn id(&self) -> PackageId {
+        PackageId {
+            package_name: self.name.clone(),
+            version: self.version.clone(),
+        }
+    }
+}
+
+impl Lockfile {
+    /// The name lockfiles are saved under.
+    pub const FILE_NAME: &'static str = "wasmer.lock";
+    const FORMAT_VERSION: u32 = 1;
+
+    pub fn from_resolution(resolution: &Resolution) -> Self {
+        let graph = &resolution.graph;
+        let mut root = None;
+        let mut packages = Vec::new();
+
+        for (id, dependencies) in graph.iter_dependencies() {
+            let node = &graph[id];
+            let locked = LockedPackage {
+                name: id.package_name.clone(),
+                version: id.version.clone(),
+                url: node.dist.as_ref().map(|dist| dist.webc.clone()),
+                sha256: node
+                    .dist
+                    .as_ref()
+                    .map(|dist| hex::encode(dist.webc_sha256.as_bytes())),
+                dependencies: dependencies
+                    .into_iter()
+                    .map(|(alias, dep)| (alias.to_string(), dep.to_string()))
+                    .collect(),
+            };
+
+            if graph.packages()[id] == graph.root() {
+                root = Some(locked);
+            } else {
+                packages.push(locked);
+            }
+        }
+
+        packages.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+
+        Lockfile {
+            version: Lockfile::FORMAT_VERSION,
+            root: root.expect("The dependency graph always contains the root"),
+            packages,
+        }
+    }
+
+    /// Read a lockfile from disk.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, LockfileError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|error| LockfileError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        contents.parse()
+    }
+
+    /// Write this lockfile to disk.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), LockfileError> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_string()).map_err(|error| LockfileError::Io {
+            path: path.to_path_buf(),
+            error,
+        })
+    }
+
+    /// Was this lockfile generated for the package with this name?
+    pub fn is_for(&self, package_name: &str) -> bool {
+        self.root.name == package_name
+    }
+
+    /// Iterate over every package in the lockfile, including the root.
+    pub fn packages(&self) -> impl Iterator<Item = &LockedPackage> + '_ {
+        std::iter::once(&self.root).chain(&self.packages)
+    }
+
+    /// Describe how `other` differs from this lockfile, one change per line.
+    pub fn changes(&self, other: &Lockfile) -> Vec<String> {
+        let ours: BTreeMap<&str, &LockedPackage> =
+            self.packages().map(|p| (p.name.as_str(), p)).collect();
+        let theirs: BTreeMap<&str, &LockedPackage> =
+            other.packages().map(|p| (p.name.as_str(), p)).collect();
+        let names: BTreeSet<&str> = ours.keys().chain(theirs.keys()).copied().collect();
+
+        let mut changes = Vec::new();
+
+        for name in names {
+            match (ours.get(name), theirs.get(name)) {
+                (Some(old), None) => changes.push(format!("Removed {name} {}", old.version)),
+                (None, Some(new)) => changes.push(format!("Added {name} {}", new.version)),
+                (Some(old), Some(new)) if old.version != new.version => {
+                    changes.push(format!(
+                        "Updated {name} from {} to {}",
+                        old.version, new.version
+                    ));
+                }
+                (Some(old), Some(new)) => {
+                    if old.sha256.is_some() && new.sha256.is_some() && old.sha256 != new.sha256 {
+                        changes.push(format!(
+                            "The hash of {name} {} changed from {} to {}",
+                            old.version,
+                            old.sha256.as_deref().unwrap_or_default(),
+                            new.sha256.as_deref().unwrap_or_default(),
+                        ));
+                    }
+                    if old.dependencies != new.dependencies {
+                        changes.push(format!(
+                            "The dependencies of {name} {} changed",
+                            old.version
+                        ));
+                    }
+                }
+                (None, None) => unreachable!(),
+            }
+        }
+
+        changes
+    }
+
+    /// Make sure `other` doesn't deviate from this lockfile.
+    pub fn check(&self, other: &Lockfile) -> Result<(), LockfileError> {
+        let changes = self.changes(other);
+
+        if changes.is_empty() {
+            Ok(())
+        } else {
+            Err(LockfileError::Outdated { changes })
+        }
+    }
+}
+
+impl FromStr for Lockfile {
+    type Err = LockfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lockfile: Lockfile = toml::from_str(s)?;
+
+        if lockfile.version != Lockfile::FORMAT_VERSION {
+            return Err(LockfileError::UnsupportedVersion(lockfile.version));
+        }
+
+        Ok(lockfile)
+    }
+}
+
+impl Display for Lockfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "# This file is generated by Wasmer. It is not meant to be edited by hand."
+        )?;
+        writeln!(f)?;
+        let serialized = toml::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&serialized)
+    }
+}
+
+/// How an existing [`Lockfile`] should be used when resolving dependencies.
+#[derive(Debug, Clone, Copy)]
+pub enum LockfileMode<'a> {
+    /// Stick to the locked versions where possible, but resolve them again if
+    /// the dependencies changed.
+    Prefer(&'a Lockfile),
+    /// Fail instead of deviating from the lockfile.
+    Locked(&'a Lockfile),
+}
+
+impl<'a> LockfileMode<'a> {
+    pub fn lockfile(self) -> &'a Lockfile {
+        match self {
+            LockfileMode::Prefer(lockfile) | LockfileMode::Locked(lockfile) => lockfile,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockfileError {
+    #[error("Unable to access \"{}\"", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("Unable to parse the lockfile")]
+    Parse(#[from] toml::de::Error),
+    #[error("Version {_0} of the lockfile format isn't supported")]
+    UnsupportedVersion(u32),
+    #[error(
+        "The lockfile needs to be updated, but that isn't allowed in locked mode:\n{}",
+        changes.iter().map(|c| format!("  - {c}")).collect::<Vec<_>>().join("\n"),
+    )]
+    Outdated { changes: Vec<String> },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCKFILE: &str = r#"# This file is generated by Wasmer. It is not meant to be edited by hand.
+
+version = 1
+
+[root]
+name = "root"
+version = "1.0.0"
+
+[root.dependencies]
+dep = "dep@1.2.0"
+
+[[package]]
+name = "dep"
+version = "1.2.0"
+url = "https://example.com/dep-1.2.0.webc"
+sha256 = "0000000000000000000000000000000000000000000000000000000000000000"
+"#;
+
+    fn lockfile() -> Lockfile {
+        Lockfile {
+            version: 1,
+            root: LockedPackage {
+                name: "root".to_string(),
+                version: "1.0.0".parse().unwrap(),
+                url: None,
+                sha256: None,
+                dependencies: [("dep".to_string(), "dep@1.2.0".to_string())].into(),
+            },
+            packages: vec![LockedPackage {
+                name: "dep".to_string(),
+                version: "1.2.0".parse().unwrap(),
+                url: Some("https://example.com/dep-1.2.0.webc".parse().unwrap()),
+                sha256: Some("0".repeat(64)),
+                dependencies: BTreeMap::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(lockfile().to_string(), LOCKFILE);
+        assert_eq!(LOCKFILE.parse::<Lockfile>().unwrap(), lockfile());
+    }
+
+    #[test]
+    fn reject_unknown_versions() {
+        let err = LOCKFILE
+            .replace("version = 1", "version = 2")
+            .parse::<Lockfile>()
+            .unwrap_err();
+
+        assert!(matches!(err, LockfileError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn describe_changes() {
+        let old = lockfile();
+        let mut new = lockfile();
+        new.packages[0].version = "1.3.0".parse().unwrap();
+        new.root.dependencies = [("dep".to_string(), "dep@1.3.0".to_string())].into();
+        new.packages.push(LockedPackage {
+            name: "extra".to_string(),
+            version: "0.1.0".parse().unwrap(),
+            url: None,
+            sha256: Some("1".repeat(64)),
+            dependencies: BTreeMap::new(),
+        });
+
+        assert_eq!(
+            old.changes(&new),
+            [
+                "Updated dep from 1.2.0 to 1.3.0",
+                "Added extra 0.1.0",
+                "The dependencies of root 1.0.0 changed",
+            ]
+        );
+        assert!(old.check(&old).is_ok());
+    }
+
+    #[test]
+    fn hash_changes_are_detected() {
+        let old = lockfile();
+        let mut new = lockfile();
+        new.packages[0].sha256 = Some("f".repeat(64));
+
+        let err = old.check(&new).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "The lockfile needs to be updated, but that isn't allowed in locked mode:\n  - The hash of dep 1.2.0 changed from {} to {}",
+                "0".repeat(64),
+                "f".repeat(64),
+            )
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Error};
+use semver::Version;
+use webc::{compat::Container, metadata::Manifest};
+
+use crate::runtime::resolver::{
+    DistributionInfo, PackageInfo, PackageSpecifier, PackageSummary, QueryError, Source, WebcHash,
+};
+
+/// A [`Source`] backed by a local mirror of the registry, as created by
+/// `wasmer package mirror`.
+///
+/// A mirror is a directory containing `*.webc` files and an `index.json`
+/// file describing them, so packages can be resolved and loaded without
+/// network access.
+#[derive(Debug, Clone)]
+pub struct MirrorSource {
+    dir: PathBuf,
+    index: MirrorIndex,
+    packages: BTreeMap<String, Vec<PackageSummary>>,
+}
+
+impl MirrorSource {
+    /// The name of the file listing every package in the mirror.
+    pub const INDEX_FILE: &'static str = "index.json";
+
+    /// Open an existing mirror.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let index_path = dir.join(MirrorSource::INDEX_FILE);
+
+        let json = match std::fs::read(&index_path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                anyhow::bail!(
+                    "\"{}\" isn't a package mirror because it has no \"{}\" file",
+                    dir.display(),
+                    MirrorSource::INDEX_FILE,
+                );
+            }
+            Err(e) => {
+                return Err(
+                    Error::new(e).context(format!("Unable to read \"{}\"", index_path.display()))
+                );
+            }
+        };
+
+        let index: MirrorIndex = serde_json::from_slice(&json)
+            .with_context(|| format!("Unable to parse \"{}\"", index_path.display()))?;
+        anyhow::ensure!(
+            index.version == MirrorIndex::FORMAT_VERSION,
+            "Version {} of the mirror format isn't supported",
+            index.version,
+        );
+
+        MirrorSource::with_index(dir, index)
+    }
+
+    /// Open a mirror, creating an empty one if `dir` doesn't contain a mirror
+    /// yet.
+    pub fn open_or_create(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+
+        if dir.join(MirrorSource::INDEX_FILE).exists() {
+            return MirrorSource::open(dir);
+        }
+
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Unable to create \"{}\"", dir.display()))?;
+
+        MirrorSource::with_index(dir, MirrorIndex::default())
+    }
+
+    fn with_index(dir: &Path, index: MirrorIndex) -> Result<Self, Error> {
+        // Summaries point at the mirrored files using file:// URLs, which
+        // need to be absolute
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve \"{}\"", dir.display()))?;
+
+        let mut mirror = MirrorSource {
+            dir,
+            index: MirrorIndex::default(),
+            packages: BTreeMap::new(),
+        };
+
+        for entry in index.packages {
+            mirror.insert(entry)?;
+        }
+
+        Ok(mirror)
+    }
+
+    /// The directory this mirror lives in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Every package in the mirror.
+    pub fn summaries(&self) -> impl Iterator<Item = &PackageSummary> + '_ {
+        self.packages.values().flatten()
+    }
+
+    /// Find the mirrored `*.webc` file with a particular hash.
+    pub fn path_for(&self, hash: &WebcHash) -> Option<PathBuf> {
+        self.summaries()
+            .find(|summary| summary.dist.webc_sha256 == *hash)
+            .and_then(|summary| {
+                crate::runtime::resolver::utils::file_path_from_url(&summary.dist.webc).ok()
+            })
+    }
+
+    /// Copy a `*.webc` file into the mirror.
+    ///
+    /// The index isn't updated on disk until [`MirrorSource::save()`] is
+    /// called.
+    pub fn add_webc(&mut self, webc: impl Into<bytes::Bytes>) -> Result<PackageSummary, Error> {
+        let webc = webc.into();
+        let container = Container::from_bytes(webc.clone())?;
+        let manifest = container.manifest().clone();
+        let pkg = PackageInfo::from_manifest(&manifest)?;
+
+        let relative_path = format!("{}/{}.webc", pkg.name, pkg.version);
+        let path = self.dir.join(&relative_path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Unable to create \"{}\"", parent.display()))?;
+        }
+        std::fs::write(&path, &webc)
+            .with_context(|| format!("Unable to write to \"{}\"", path.display()))?;
+
+        self.insert(MirrorEntry {
+            name: pkg.name,
+            version: pkg.version,
+            webc: relative_path,
+            sha256: hex::encode(WebcHash::sha256(&webc).as_bytes()),
+            manifest,
+        })
+    }
+
+    /// Save the mirror's index to disk.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = self.dir.join(MirrorSource::INDEX_FILE);
+
+        // Write to a temporary file first so a half-written index is never
+        // visible
+        let mut temp = tempfile::NamedTempFile::new_in(&self.dir)
+            .context("Unable to create a temporary file")?;
+        serde_json::to_writer_pretty(&mut temp, &self.index)
+            .context("Unable to serialize the index")?;
+        temp.persist(&path)
+            .with_context(|| format!("Unable to save \"{}\"", path.display()))?;
+
+        Ok(())
+    }
+
+    fn insert(&mut self, entry: MirrorEntry) -> Result<PackageSummary, Error> {
+        let path = self.dir.join(&entry.webc);
+        let summary = PackageSummary {
+            pkg: PackageInfo::from_manifest(&entry.manifest)?,
+            dist: DistributionInfo {
+                webc: crate::runtime::resolver::utils::url_from_file_path(&path).ok_or_else(
+                    || anyhow::anyhow!("Unable to turn \"{}\" into a file:// URL", path.display()),
+                )?,
+                webc_sha256: WebcHash::parse_hex(&entry.sha256).with_context(|| {
+                    format!("Invalid hash for {} {}", entry.name, entry.version)
+                })?,
+            },
+        };
+
+        self.index
+            .packages
+            .retain(|e| e.name != entry.name || e.version != entry.version);
+        self.index.packages.push(entry);
+        self.index
+            .packages
+            .sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+
+        let summaries = self.packages.entry(summary.pkg.name.clone()).or_default();
+        summaries.retain(|s| s.pkg.version != summary.pkg.version);
+        summaries.push(summary.clone());
+        summaries.sort_by(|left, right| left.pkg.version.cmp(&right.pkg.version));
+
+        Ok(summary)
+    }
+}
+
+#[async_trait::async_trait]
+impl Source for MirrorSource {
+    #[tracing::instrument(level = "debug", skip_all, fields(%package))]
+    async fn query(&self, package: &PackageSpecifier) -> Result<Vec<PackageSummary>, QueryError> {
+        match package {
+            PackageSpecifier::Registry { full_name, version } => {
+                let summaries = self.packages.get(full_name).ok_or(QueryError::NotFound)?;
+                let matches: Vec<_> = summaries
+                    .iter()
+                    .filter(|summary| version.matches(&summary.pkg.version))
+                    .cloned()
+                    .collect();
+
+                if matches.is_empty() {
+                    return Err(QueryError::NoMatches {
+                        archived_versions: Vec::new(),
+                    });
+                }
+
+                Ok(matches)
+            }
+            PackageSpecifier::Url(_) | PackageSpecifier::Path(_) => Err(QueryError::Unsupported),
+        }
+    }
+}
+
+/// The contents of a mirror's `index.json` file.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct MirrorIndex {
+    version: u32,
+    packages: Vec<MirrorEntry>,
+}
+
+impl MirrorIndex {
+    const FORMAT_VERSION: u32 = 1;
+}
+
+impl Default for MirrorIndex {
+    fn default() -> Self {
+        MirrorIndex {
+            version: MirrorIndex::FORMAT_VERSION,
+            packages: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct MirrorEntry {
+    name: String,
+    version: Version,
+    /// The path to the `*.webc` file, relative to the mirror's directory.
+    webc: String,
+    /// The hex-encoded SHA-256 hash of the `*.webc` file.
+    sha256: String,
+    manifest: Manifest,
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const HELLO: &[u8] = include_bytes!(
+        "../../../../../tests/integration/cli/tests/webc/hello-0.1.0-665d2ddc-80e6-4845-85d3-4587b1693bb7.webc"
+    );
+
+    #[tokio::test]
+    async fn serve_packages_from_a_saved_mirror() {
+        let temp = TempDir::new().unwrap();
+        let mut mirror = MirrorSource::open_or_create(temp.path()).unwrap();
+        let added = mirror.add_webc(HELLO).unwrap();
+        mirror.save().unwrap();
+
+        let mirror = MirrorSource::open(temp.path()).unwrap();
+        let specifier = PackageSpecifier::Registry {
+            full_name: added.pkg.name.clone(),
+            version: "*".parse().unwrap(),
+        };
+        let summaries = mirror.query(&specifier).await.unwrap();
+
+        assert_eq!(summaries, [added.clone()]);
+        assert_eq!(added.dist.webc_sha256, WebcHash::sha256(HELLO));
+        let path = mirror.path_for(&added.dist.webc_sha256).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), HELLO);
+    }
+
+    #[test]
+    fn directories_without_an_index_are_not_mirrors() {
+        let temp = TempDir::new().unwrap();
+
+        let err = MirrorSource::open(temp.path()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "\"{}\" isn't a package mirror because it has no \"index.json\" file",
+                temp.path().display()
+            )
+        );
+    }
+}
@@ -1,9 +1,12 @@
 mod filesystem_source;
 mod in_memory_source;
 mod inputs;
+mod lockfile;
+mod mirror_source;
 mod multi_source;
 mod outputs;
 mod resolve;
+mod solver;
 mod source;
 pub(crate) mod utils;
 mod wapm_source;
@@ -16,12 +19,14 @@ pub use self::{
         Command, Dependency, DistributionInfo, FileSystemMapping, PackageInfo, PackageSpecifier,
         PackageSummary, WebcHash,
     },
+    lockfile::{LockedPackage, Lockfile, LockfileError, LockfileMode},
+    mirror_source::MirrorSource,
     multi_source::{MultiSource, MultiSourceStrategy},
     outputs::{
         DependencyGraph, Edge, ItemLocation, Node, PackageId, Resolution,
         ResolvedFileSystemMapping, ResolvedPackage,
     },
-    resolve::{resolve, ResolveError},
+    resolve::{resolve, resolve_with_lockfile, ResolveError},
     source::{QueryError, Source},
     wapm_source::WapmSource,
     web_source::WebSource,
@@ -1,7 +1,4 @@
-use std::{
-    collections::{BTreeMap, HashSet, VecDeque},
-    path::PathBuf,
-};
+use std::{collections::BTreeMap, path::PathBuf};
 
 use petgraph::{
     graph::{DiGraph, NodeIndex},
@@ -11,8 +8,9 @@ use semver::Version;
 
 use crate::runtime::resolver::{
     outputs::{Edge, Node},
-    DependencyGraph, ItemLocation, PackageId, PackageInfo, PackageSpecifier, PackageSummary,
-    QueryError, Resolution, ResolvedPackage, Source,
+    solver::{self, Solution},
+    DependencyGraph, ItemLocation, Lockfile, PackageId, PackageInfo, PackageSpecifier,
+    PackageSummary, QueryError, Resolution, ResolvedPackage, Source,
 };
 
 use super::ResolvedFileSystemMapping;
@@ -25,13 +23,40 @@ pub async fn resolve(
     root: &PackageInfo,
     source: &dyn Source,
 ) -> Result<Resolution, ResolveError> {
-    let graph = resolve_dependency_graph(root_id, root, source).await?;
+    resolve_preferring(root_id, root, source, BTreeMap::new()).await
+}
+
+/// Like [`resolve()`], except the versions recorded in a [`Lockfile`] are
+/// picked wherever they still satisfy the dependency constraints.
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn resolve_with_lockfile(
+    root_id: &PackageId,
+    root: &PackageInfo,
+    source: &dyn Source,
+    lockfile: &Lockfile,
+) -> Result<Resolution, ResolveError> {
+    let preferred = lockfile
+        .packages()
+        .map(|pkg| (pkg.name.clone(), pkg.version.clone()))
+        .collect();
+
+    resolve_preferring(root_id, root, source, preferred).await
+}
+
+async fn resolve_preferring(
+    root_id: &PackageId,
+    root: &PackageInfo,
+    source: &dyn Source,
+    preferred: BTreeMap<String, Version>,
+) -> Result<Resolution, ResolveError> {
+    let graph = resolve_dependency_graph(root_id, root, source, preferred).await?;
     let package = resolve_package(&graph)?;
 
     Ok(Resolution { graph, package })
 }
 
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum ResolveError {
     #[error("{}", registry_error_message(.package))]
     Registry {
@@ -41,6 +66,9 @@ pub enum ResolveError {
     },
     #[error("Dependency cycle detected: {}", print_cycle(_0))]
     Cycle(Vec<PackageId>),
+    #[deprecated(
+        note = "The resolver now picks a single version of each package or reports a `Conflict`"
+    )]
     #[error(
         "Multiple versions of {package_name} were found {}",
         versions.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", "),
@@ -49,6 +77,13 @@ pub enum ResolveError {
         package_name: String,
         versions: Vec<Version>,
     },
+    /// There is no set of package versions that satisfies every dependency
+    /// constraint.
+    #[error("Unable to find a set of package versions that satisfies every constraint\n{report}")]
+    Conflict {
+        /// A human-readable explanation of the conflict.
+        report: String,
+    },
 }
 
 fn registry_error_message(specifier: &PackageSpecifier) -> String {
@@ -94,28 +129,12 @@ async fn resolve_dependency_graph(
     root_id: &PackageId,
     root: &PackageInfo,
     source: &dyn Source,
+    preferred: BTreeMap<String, Version>,
 ) -> Result<DependencyGraph, ResolveError> {
-    let DiscoveredPackages {
-        root,
-        graph,
-        indices,
-        packages,
-    } = discover_dependencies(root_id, root, source).await?;
-
-    check_for_duplicate_versions(indices.iter().copied().map(|ix| &graph[ix].id))?;
-    log_dependencies(&graph, root);
+    let Solution { packages, names } = solver::solve(root, source, preferred).await?;
 
-    let graph = DependencyGraph::new(root, graph, packages);
-
-    Ok(graph)
-}
-
-async fn discover_dependencies(
-    root_id: &PackageId,
-    root: &PackageInfo,
-    source: &dyn Source,
-) -> Result<DiscoveredPackages, ResolveError> {
     let mut nodes: BTreeMap<PackageId, NodeIndex> = BTreeMap::new();
+    let mut indices_by_name: BTreeMap<String, NodeIndex> = BTreeMap::new();
     let mut graph: DiGraph<Node, Edge> = DiGraph::new();
 
     let root_index = graph.add_node(Node {
@@ -124,68 +143,39 @@ async fn discover_dependencies(
         dist: None,
     });
     nodes.insert(root_id.clone(), root_index);
+    indices_by_name.insert(root.name.clone(), root_index);
+
+    for (name, summary) in packages {
+        let id = summary.package_id();
+        let PackageSummary { pkg, dist } = summary;
+        let index = graph.add_node(Node {
+            id: id.clone(),
+            pkg,
+            dist: Some(dist),
+        });
+        nodes.insert(id, index);
+        indices_by_name.insert(name, index);
+    }
 
-    let mut to_visit = VecDeque::new();
-    to_visit.push_back(root_index);
-
-    while let Some(index) = to_visit.pop_front() {
-        let mut to_add = Vec::new();
-
-        for dep in &graph[index].pkg.dependencies {
-            // Get the latest version that satisfies our requirement. If we were
-            // doing this more rigorously, we would be narrowing the version
-            // down using existing requirements and trying to reuse the same
-            // dependency when possible.
-            let dep_summary =
-                source
-                    .latest(&dep.pkg)
-                    .await
-                    .map_err(|error| ResolveError::Registry {
-                        package: dep.pkg.clone(),
-                        error,
-                    })?;
-            let dep_id = dep_summary.package_id();
-
-            let PackageSummary { pkg, dist } = dep_summary;
-
+    for index in graph.node_indices().collect::<Vec<_>>() {
+        for dep in graph[index].pkg.dependencies.clone() {
+            // The solver picked exactly one version for every package it
+            // came across
+            let dep_index = indices_by_name[&names[&dep.pkg]];
             let alias = dep.alias().to_string();
-            let node = Node {
-                id: dep_id,
-                pkg,
-                dist: Some(dist),
-            };
-            // Note: We can't add the node to the graph directly because we're
-            // still iterating over it.
-            to_add.push((alias, node));
-        }
-
-        for (alias, node) in to_add {
-            let dep_id = node.id.clone();
-
-            let dep_index = match nodes.get(&dep_id) {
-                Some(&ix) => ix,
-                None => {
-                    // Create a new node and schedule its dependencies to be
-                    // retrieved
-                    let ix = graph.add_node(node);
-                    nodes.insert(dep_id, ix);
-                    to_visit.push_back(ix);
-                    ix
-                }
-            };
-
             graph.add_edge(index, dep_index, Edge { alias });
         }
     }
 
-    let sorted_indices = petgraph::algo::toposort(&graph, None).map_err(|_| cycle_error(&graph))?;
+    if petgraph::algo::toposort(&graph, None).is_err() {
+        return Err(cycle_error(&graph));
+    }
 
-    Ok(DiscoveredPackages {
-        root: root_index,
-        graph,
-        indices: sorted_indices,
-        packages: nodes,
-    })
+    log_dependencies(&graph, root_index);
+
+    let graph = DependencyGraph::new(root_index, graph, nodes);
+
+    Ok(graph)
 }
 
 fn cycle_error(graph: &petgraph::Graph<Node, Edge>) -> ResolveError {
@@ -215,15 +205,6 @@ fn cycle_error(graph: &petgraph::Graph<Node, Edge>) -> ResolveError {
     ResolveError::Cycle(package_ids)
 }
 
-#[derive(Debug)]
-struct DiscoveredPackages {
-    root: NodeIndex,
-    graph: DiGraph<Node, Edge>,
-    /// All node indices, in topologically sorted order.
-    indices: Vec<NodeIndex>,
-    packages: BTreeMap<PackageId, NodeIndex>,
-}
-
 #[tracing::instrument(level = "debug", name = "dependencies", skip_all)]
 fn log_dependencies(graph: &DiGraph<Node, Edge>, root: NodeIndex) {
     tracing::debug!(
@@ -247,42 +228,7 @@ fn log_dependencies(graph: &DiGraph<Node, Edge>, root: NodeIndex) {
     }
 }
 
-/// As a workaround for the lack of "proper" dependency merging, we'll make sure
-/// only one copy of each package is in the dependency tree. If the same package
-/// is included in the tree multiple times, they all need to use the exact same
-/// version otherwise it's an error.
-fn check_for_duplicate_versions<'a, I>(package_ids: I) -> Result<(), ResolveError>
-where
-    I: Iterator<Item = &'a PackageId>,
-{
-    let mut package_versions: BTreeMap<&str, HashSet<&Version>> = BTreeMap::new();
-
-    for PackageId {
-        package_name,
-        version,
-    } in package_ids
-    {
-        package_versions
-            .entry(package_name)
-            .or_default()
-            .insert(version);
-    }
-
-    for (package_name, versions) in package_versions {
-        if versions.len() > 1 {
-            let mut versions: Vec<_> = versions.into_iter().cloned().collect();
-            versions.sort();
-            return Err(ResolveError::DuplicateVersions {
-                package_name: package_name.to_string(),
-                versions,
-            });
-        }
-    }
-
-    Ok(())
-}
-
-/// Given some [`DiscoveredPackages`], figure out how the resulting "package"
+/// Given a [`DependencyGraph`], figure out how the resulting "package"
 /// would look when loaded at runtime.
 fn resolve_package(dependency_graph: &DependencyGraph) -> Result<ResolvedPackage, ResolveError> {
     // FIXME: This code is all super naive and will break the moment there
@@ -819,47 +765,6 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn version_merging_isnt_implemented_yet() {
-        let mut builder = RegistryBuilder::new();
-        builder
-            .register("root", "1.0.0")
-            .with_dependency("first", "=1.0.0")
-            .with_dependency("second", "=1.0.0");
-        builder
-            .register("first", "1.0.0")
-            .with_dependency("common", "^1.0.0");
-        builder
-            .register("second", "1.0.0")
-            .with_dependency("common", ">1.1,<1.3");
-        builder.register("common", "1.0.0");
-        builder.register("common", "1.1.0");
-        builder.register("common", "1.2.0");
-        builder.register("common", "1.5.0");
-        let registry = builder.finish();
-        let root = builder.get("root", "1.0.0");
-
-        let result = resolve(&root.package_id(), &root.pkg, &registry).await;
-
-        match result {
-            Err(ResolveError::DuplicateVersions {
-                package_name,
-                versions,
-            }) => {
-                assert_eq!(package_name, "common");
-                assert_eq!(
-                    versions,
-                    [
-                        Version::parse("1.2.0").unwrap(),
-                        Version::parse("1.5.0").unwrap(),
-                    ]
-                );
-            }
-            _ => unreachable!("Expected a duplicate versions error, found {:?}", result),
-        }
-    }
-
-    #[tokio::test]
-    #[ignore = "Version merging isn't implemented"]
     async fn merge_compatible_versions() {
         let mut builder = RegistryBuilder::new();
         builder
@@ -907,6 +812,158 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn stick_to_locked_versions() {
+        let mut builder = RegistryBuilder::new();
+        builder
+            .register("root", "1.0.0")
+            .with_dependency("dep", "^1.0.0");
+        builder.register("dep", "1.0.0");
+        builder.register("dep", "1.0.1");
+        let root = builder.get("root", "1.0.0").clone();
+        let resolution = resolve(&root.package_id(), &root.pkg, &builder.finish())
+            .await
+            .unwrap();
+        let lockfile = Lockfile::from_resolution(&resolution);
+        // a newer version gets published after the lockfile was written
+        builder.register("dep", "1.0.2");
+        let registry = builder.finish();
+
+        let resolution = resolve_with_lockfile(&root.package_id(), &root.pkg, &registry, &lockfile)
+            .await
+            .unwrap();
+
+        let mut dependency_graph = builder.start_dependency_graph();
+        dependency_graph
+            .insert("root", "1.0.0")
+            .with_dependency("dep", "1.0.1");
+        dependency_graph.insert("dep", "1.0.1");
+        assert_eq!(deps(&resolution), dependency_graph.finish());
+        assert_eq!(Lockfile::from_resolution(&resolution), lockfile);
+    }
+
+    #[tokio::test]
+    async fn backtrack_when_the_latest_version_conflicts() {
+        let mut builder = RegistryBuilder::new();
+        builder
+            .register("root", "1.0.0")
+            .with_dependency("first", "^1.0.0")
+            .with_dependency("second", "^1.0.0");
+        builder.register("first", "1.0.0");
+        builder
+            .register("first", "1.1.0")
+            .with_dependency("third", "=1.0.0");
+        builder.register("second", "1.0.0");
+        builder.register("second", "1.1.0");
+        builder.register("second", "1.2.0");
+        builder.register("second", "2.0.0");
+        builder
+            .register("third", "1.0.0")
+            .with_dependency("second", "^2.0.0");
+        let registry = builder.finish();
+        let root = builder.get("root", "1.0.0");
+
+        let resolution = resolve(&root.package_id(), &root.pkg, &registry)
+            .await
+            .unwrap();
+
+        let mut dependency_graph = builder.start_dependency_graph();
+        dependency_graph
+            .insert("root", "1.0.0")
+            .with_dependency("first", "1.0.0")
+            .with_dependency("second", "1.2.0");
+        dependency_graph.insert("first", "1.0.0");
+        dependency_graph.insert("second", "1.2.0");
+        assert_eq!(deps(&resolution), dependency_graph.finish());
+    }
+
+    #[tokio::test]
+    async fn skip_versions_with_missing_dependencies() {
+        let mut builder = RegistryBuilder::new();
+        builder
+            .register("root", "1.0.0")
+            .with_dependency("dep", "^1.0.0");
+        builder.register("dep", "1.0.0");
+        builder
+            .register("dep", "1.1.0")
+            .with_dependency("missing", "^1.0.0");
+        let registry = builder.finish();
+        let root = builder.get("root", "1.0.0");
+
+        let resolution = resolve(&root.package_id(), &root.pkg, &registry)
+            .await
+            .unwrap();
+
+        let mut dependency_graph = builder.start_dependency_graph();
+        dependency_graph
+            .insert("root", "1.0.0")
+            .with_dependency("dep", "1.0.0");
+        dependency_graph.insert("dep", "1.0.0");
+        assert_eq!(deps(&resolution), dependency_graph.finish());
+    }
+
+    #[tokio::test]
+    async fn report_incompatible_versions() {
+        let mut builder = RegistryBuilder::new();
+        builder
+            .register("root", "1.0.0")
+            .with_dependency("first", "=1.0.0")
+            .with_dependency("second", "=1.0.0");
+        builder
+            .register("first", "1.0.0")
+            .with_dependency("common", "^1.0.0");
+        builder
+            .register("second", "1.0.0")
+            .with_dependency("common", "^2.0.0");
+        builder.register("common", "1.0.0");
+        builder.register("common", "1.5.0");
+        builder.register("common", "2.0.0");
+        let registry = builder.finish();
+        let root = builder.get("root", "1.0.0");
+
+        let err = resolve(&root.package_id(), &root.pkg, &registry)
+            .await
+            .unwrap_err();
+
+        let ResolveError::Conflict { report } = err else {
+            unreachable!("Expected a conflict, found {err:?}");
+        };
+        assert_eq!(
+            report,
+            "Because first 1.0.0 depends on common ^1.0.0 and second 1.0.0 depends on common ^2.0.0, first 1.0.0 is incompatible with second 1.0.0.\n\
+             And because root 1.0.0 depends on first =1.0.0 and root 1.0.0 depends on second =1.0.0, version solving failed."
+        );
+    }
+
+    #[tokio::test]
+    async fn report_missing_dependencies() {
+        let mut builder = RegistryBuilder::new();
+        builder
+            .register("root", "1.0.0")
+            .with_dependency("dep", "^1.0.0");
+        builder
+            .register("dep", "1.0.0")
+            .with_dependency("missing", "^1.0.0");
+        builder
+            .register("dep", "1.1.0")
+            .with_dependency("missing", "^1.0.0");
+        let registry = builder.finish();
+        let root = builder.get("root", "1.0.0");
+
+        let err = resolve(&root.package_id(), &root.pkg, &registry)
+            .await
+            .unwrap_err();
+
+        let ResolveError::Conflict { report } = err else {
+            unreachable!("Expected a conflict, found {err:?}");
+        };
+        assert_eq!(
+            report,
+            "Because dep 1.0.0 depends on missing ^1.0.0 which doesn't exist and dep 1.1.0 depends on missing ^1.0.0 which doesn't exist, dep * is forbidden.\n\
+             And because root 1.0.0 depends on dep ^1.0.0, version solving failed."
+        );
+    }
+
     #[tokio::test]
     async fn commands_from_dependencies_end_up_in_the_package() {
         let mut builder = RegistryBuilder::new();
@@ -0,0 +1,943 @@
+//! A version solver based on [PubGrub][pubgrub].
+//!
+//! Every dependency is turned into an *incompatibility* (a set of terms that
+//! can't all be true at the same time). The solver alternates between unit
+//! propagation, where it derives what must be true given the decisions made
+//! so far, and picking a version for the next package. When it runs into a
+//! conflict, it works out the root cause, records it as a new
+//! incompatibility and backtracks to the point where that cause was
+//! introduced. If the root cause ends up implicating the root package, the
+//! chain of incompatibilities that led there is turned into an explanation
+//! for the user.
+//!
+//! Versions are only ever drawn from the candidates a [`Source`] returns, so
+//! version sets are represented as the explicit set of versions they contain.
+//!
+//! [pubgrub]: https://github.com/dart-lang/pub/blob/master/doc/solver.md
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use semver::Version;
+
+use crate::runtime::resolver::{
+    PackageInfo, PackageSpecifier, PackageSummary, QueryError, ResolveError, Source,
+};
+
+/// The packages picked by [`solve()`].
+#[derive(Debug)]
+pub(crate) struct Solution {
+    /// The selected version of every package, except the root.
+    pub packages: BTreeMap<String, PackageSummary>,
+    /// The name of the package each dependency specifier refers to.
+    pub names: HashMap<PackageSpecifier, String>,
+}
+
+/// Find a version for every package in the dependency tree of `root` such
+/// that each dependency constraint is satisfied, preferring the `preferred`
+/// version of a package and then newer versions.
+pub(crate) async fn solve(
+    root: &PackageInfo,
+    source: &dyn Source,
+    preferred: BTreeMap<String, Version>,
+) -> Result<Solution, ResolveError> {
+    let mut solver = Solver::new(root, source);
+    solver.previously_selected.extend(preferred);
+    let root_name = root.name.clone();
+
+    solver.add_incompatibility(Incompatibility {
+        terms: vec![Term::negative(&root_name, [root.version.clone()].into())],
+        cause: Cause::Root,
+    });
+
+    let mut next = root_name;
+    loop {
+        solver.propagate(next)?;
+
+        match solver.choose_package_version().await? {
+            Some(package) => next = package,
+            None => break,
+        }
+    }
+
+    Ok(solver.into_solution())
+}
+
+type VersionSet = BTreeSet<Version>;
+
+/// A statement about a package's version.
+///
+/// A positive term is satisfied when the package is selected with one of the
+/// versions, while a negative term is satisfied when the package is selected
+/// with any other version or isn't selected at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Term {
+    package: String,
+    positive: bool,
+    versions: VersionSet,
+}
+
+impl Term {
+    fn positive(package: &str, versions: VersionSet) -> Self {
+        Term {
+            package: package.to_string(),
+            positive: true,
+            versions,
+        }
+    }
+
+    fn negative(package: &str, versions: VersionSet) -> Self {
+        Term {
+            package: package.to_string(),
+            positive: false,
+            versions,
+        }
+    }
+
+    /// The term that is satisfied by anything.
+    fn any(package: &str) -> Self {
+        Term::negative(package, VersionSet::new())
+    }
+
+    fn is_any(&self) -> bool {
+        !self.positive && self.versions.is_empty()
+    }
+
+    /// Is this a term that can never be satisfied?
+    fn is_empty(&self) -> bool {
+        self.positive && self.versions.is_empty()
+    }
+
+    fn negate(&self) -> Term {
+        Term {
+            package: self.package.clone(),
+            positive: !self.positive,
+            versions: self.versions.clone(),
+        }
+    }
+
+    fn intersect(&self, other: &Term) -> Term {
+        debug_assert_eq!(self.package, other.package);
+
+        let (positive, versions) = match (self.positive, other.positive) {
+            (true, true) => (true, &self.versions & &other.versions),
+            (true, false) => (true, &self.versions - &other.versions),
+            (false, true) => (true, &other.versions - &self.versions),
+            (false, false) => (false, &self.versions | &other.versions),
+        };
+
+        Term {
+            package: self.package.clone(),
+            positive,
+            versions,
+        }
+    }
+
+    fn union(&self, other: &Term) -> Term {
+        self.negate().intersect(&other.negate()).negate()
+    }
+
+    /// Does everything allowed by this term also satisfy `other`?
+    fn satisfies(&self, other: &Term) -> bool {
+        self.intersect(&other.negate()).is_empty()
+    }
+
+    fn is_disjoint(&self, other: &Term) -> bool {
+        self.intersect(other).is_empty()
+    }
+}
+
+#[derive(Debug)]
+struct Incompatibility {
+    /// Terms which can't all be true at once, at most one per package.
+    terms: Vec<Term>,
+    cause: Cause,
+}
+
+impl Incompatibility {
+    fn term(&self, package: &str) -> Option<&Term> {
+        self.terms.iter().find(|term| term.package == package)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Cause {
+    /// The root package must be selected.
+    Root,
+    /// A package version depends on something.
+    Dependency {
+        package: String,
+        version: Version,
+        dependency: String,
+    },
+    /// A package version depends on something that couldn't be found.
+    Unavailable {
+        package: String,
+        version: Version,
+        dependency: String,
+        reason: String,
+    },
+    /// Derived from two other incompatibilities during conflict resolution.
+    Derived(usize, usize),
+}
+
+#[derive(Debug)]
+struct Assignment {
+    term: Term,
+    decision_level: usize,
+    /// The incompatibility this assignment was derived from, or `None` if it
+    /// is a decision.
+    cause: Option<usize>,
+}
+
+enum Relation {
+    /// Every term is satisfied.
+    Satisfied,
+    /// At least one term is contradicted.
+    Contradicted,
+    /// All terms but the one at this index are satisfied.
+    AlmostSatisfied(usize),
+    Inconclusive,
+}
+
+/// The outcome of querying the [`Source`] for a dependency.
+#[derive(Debug, Clone)]
+enum Candidates {
+    Found {
+        name: String,
+        versions: VersionSet,
+    },
+    /// Nothing could satisfy the dependency, for this reason (e.g. "doesn't
+    /// exist").
+    Unavailable(String),
+}
+
+struct Solver<'a> {
+    root: &'a PackageInfo,
+    source: &'a dyn Source,
+    incompatibilities: Vec<Incompatibility>,
+    /// The incompatibilities mentioning each package.
+    by_package: HashMap<String, Vec<usize>>,
+    assignments: Vec<Assignment>,
+    decision_level: usize,
+    decisions: BTreeMap<String, Version>,
+    /// The last version decided for each package (or the one we were asked
+    /// to prefer), so we can stick with it after backtracking over unrelated
+    /// decisions.
+    previously_selected: HashMap<String, Version>,
+    candidates: HashMap<PackageSpecifier, Candidates>,
+    summaries: HashMap<String, BTreeMap<Version, PackageSummary>>,
+}
+
+impl<'a> Solver<'a> {
+    fn new(root: &'a PackageInfo, source: &'a dyn Source) -> Self {
+        Solver {
+            root,
+            source,
+            incompatibilities: Vec::new(),
+            by_package: HashMap::new(),
+            assignments: Vec::new(),
+            decision_level: 0,
+            decisions: BTreeMap::new(),
+            previously_selected: HashMap::new(),
+            candidates: HashMap::new(),
+            summaries: HashMap::new(),
+        }
+    }
+
+    fn add_incompatibility(&mut self, incompatibility: Incompatibility) -> usize {
+        let id = self.incompatibilities.len();
+        for term in &incompatibility.terms {
+            self.by_package
+                .entry(term.package.clone())
+                .or_default()
+                .push(id);
+        }
+        self.incompatibilities.push(incompatibility);
+        id
+    }
+
+    /// Everything we know about a package from the partial solution.
+    fn accumulated(&self, package: &str) -> Term {
+        self.assignments
+            .iter()
+            .filter(|a| a.term.package == package)
+            .fold(Term::any(package), |acc, a| acc.intersect(&a.term))
+    }
+
+    fn relation(&self, incompatibility: &Incompatibility) -> Relation {
+        let mut unsatisfied = None;
+
+        for (i, term) in incompatibility.terms.iter().enumerate() {
+            let accumulated = self.accumulated(&term.package);
+            if accumulated.satisfies(term) {
+                continue;
+            }
+            if accumulated.is_disjoint(term) {
+                return Relation::Contradicted;
+            }
+            if unsatisfied.is_some() {
+                return Relation::Inconclusive;
+            }
+            unsatisfied = Some(i);
+        }
+
+        match unsatisfied {
+            Some(i) => Relation::AlmostSatisfied(i),
+            None => Relation::Satisfied,
+        }
+    }
+
+    fn derive(&mut self, term: Term, cause: usize) {
+        self.assignments.push(Assignment {
+            term,
+            decision_level: self.decision_level,
+            cause: Some(cause),
+        });
+    }
+
+    fn decide(&mut self, package: &str, version: Version) {
+        self.decision_level += 1;
+        self.assignments.push(Assignment {
+            term: Term::positive(package, [version.clone()].into()),
+            decision_level: self.decision_level,
+            cause: None,
+        });
+        self.previously_selected
+            .insert(package.to_string(), version.clone());
+        self.decisions.insert(package.to_string(), version);
+    }
+
+    fn backtrack(&mut self, decision_level: usize) {
+        self.assignments
+            .retain(|a| a.decision_level <= decision_level);
+        self.decisions.retain(|package, _| {
+            self.assignments
+                .iter()
+                .any(|a| a.cause.is_none() && a.term.package == *package)
+        });
+        self.decision_level = decision_level;
+    }
+
+    /// Derive everything that follows from the partial solution after
+    /// something changed for `package`.
+    fn propagate(&mut self, package: String) -> Result<(), ResolveError> {
+        let mut changed = vec![package];
+
+        while let Some(package) = changed.pop() {
+            let ids = self.by_package.get(&package).cloned().unwrap_or_default();
+            let mut conflict = None;
+
+            // Newer incompatibilities tend to be more specific, so check them
+            // first.
+            for &id in ids.iter().rev() {
+                match self.relation(&self.incompatibilities[id]) {
+                    Relation::Satisfied => {
+                        conflict = Some(id);
+                        break;
+                    }
+                    Relation::AlmostSatisfied(i) => {
+                        let term = self.incompatibilities[id].terms[i].negate();
+                        changed.push(term.package.clone());
+                        self.derive(term, id);
+                    }
+                    Relation::Contradicted | Relation::Inconclusive => {}
+                }
+            }
+
+            if let Some(id) = conflict {
+                let (root_cause, package) = self.resolve_conflict(id)?;
+                let term = self.incompatibilities[root_cause]
+                    .term(&package)
+                    .expect("The root cause mentions the satisfier's package")
+                    .negate();
+                changed.clear();
+                changed.push(package);
+                self.derive(term, root_cause);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the root cause of a conflict and backtrack to the point where
+    /// it no longer applies, returning the root cause and the package that
+    /// unit propagation should derive something about.
+    fn resolve_conflict(&mut self, mut id: usize) -> Result<(usize, String), ResolveError> {
+        tracing::trace!(incompatibility = %self.describe(id), "Resolving a conflict");
+
+        loop {
+            if self.is_terminal(&self.incompatibilities[id]) {
+                return Err(ResolveError::Conflict {
+                    report: self.report(id),
+                });
+            }
+
+            let (term_index, satisfier, previous_level) = self.find_satisfier(id);
+            let incompatibility = &self.incompatibilities[id];
+            let package = incompatibility.terms[term_index].package.clone();
+            let assignment = &self.assignments[satisfier];
+
+            match assignment.cause {
+                Some(cause) if previous_level >= assignment.decision_level => {
+                    // The satisfier was derived at the same level as the rest
+                    // of the conflict, so combine it with the incompatibility
+                    // it came from and try again.
+                    let prior_cause = self.prior_cause(id, cause, &package);
+                    tracing::trace!(incompatibility = %self.describe(prior_cause), "Derived");
+                    id = prior_cause;
+                }
+                _ => {
+                    self.backtrack(previous_level);
+                    return Ok((id, package));
+                }
+            }
+        }
+    }
+
+    /// Is this an incompatibility that rules out the root package?
+    fn is_terminal(&self, incompatibility: &Incompatibility) -> bool {
+        match incompatibility.terms.as_slice() {
+            [] => true,
+            [term] => {
+                term.positive
+                    && term.package == self.root.name
+                    && term.versions.contains(&self.root.version)
+            }
+            _ => false,
+        }
+    }
+
+    /// Find the earliest assignment that makes this incompatibility satisfied,
+    /// returning the index of the term it satisfies, the index of the
+    /// assignment and the decision level we need to go back to.
+    fn find_satisfier(&self, id: usize) -> (usize, usize, usize) {
+        let incompatibility = &self.incompatibilities[id];
+
+        let earliest: Vec<usize> = incompatibility
+            .terms
+            .iter()
+            .map(|term| {
+                self.earliest_satisfier(term, Term::any(&term.package), self.assignments.len())
+                    .expect("The incompatibility is satisfied")
+            })
+            .collect();
+
+        let (term_index, &satisfier) = earliest
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, &index)| index)
+            .expect("Terminal incompatibilities are handled separately");
+
+        let term = &incompatibility.terms[term_index];
+        let mut previous = earliest
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != term_index)
+            .map(|(_, &index)| index)
+            .max();
+
+        // The satisfier may only satisfy its term in combination with an
+        // earlier assignment for the same package.
+        let satisfier_term = self.assignments[satisfier].term.clone();
+        if !satisfier_term.satisfies(term) {
+            let index = self
+                .earliest_satisfier(term, satisfier_term, satisfier)
+                .expect("The incompatibility is satisfied");
+            previous = previous.max(Some(index));
+        }
+
+        let previous_level = previous
+            .map(|index| self.assignments[index].decision_level)
+            .unwrap_or_default()
+            .max(1);
+
+        (term_index, satisfier, previous_level)
+    }
+
+    /// Find the first of the first `len` assignments where everything
+    /// assigned to `term`'s package so far, combined with `start`, satisfies
+    /// `term`.
+    fn earliest_satisfier(&self, term: &Term, start: Term, len: usize) -> Option<usize> {
+        let mut accumulated = start;
+
+        for (index, assignment) in self.assignments[..len].iter().enumerate() {
+            if assignment.term.package != term.package {
+                continue;
+            }
+            accumulated = accumulated.intersect(&assignment.term);
+            if accumulated.satisfies(term) {
+                return Some(index);
+            }
+        }
+
+        None
+    }
+
+    /// Resolve an incompatibility with the incompatibility that caused its
+    /// satisfier, eliminating `package`.
+    fn prior_cause(&mut self, id: usize, cause: usize, package: &str) -> usize {
+        let mut terms: Vec<Term> = Vec::new();
+        // start from the empty term, which is the identity for unions
+        let mut resolved = Term::positive(package, VersionSet::new());
+
+        for term in self.incompatibilities[id]
+            .terms
+            .iter()
+            .chain(&self.incompatibilities[cause].terms)
+        {
+            if term.package == package {
+                resolved = resolved.union(term);
+            } else if let Some(existing) = terms.iter_mut().find(|t| t.package == term.package) {
+                *existing = existing.intersect(term);
+            } else {
+                terms.push(term.clone());
+            }
+        }
+
+        if !resolved.is_any() {
+            terms.push(resolved);
+        }
+
+        self.add_incompatibility(Incompatibility {
+            terms,
+            cause: Cause::Derived(id, cause),
+        })
+    }
+
+    /// Pick a version for the next undecided package, returning `None` once
+    /// every package has one.
+    async fn choose_package_version(&mut self) -> Result<Option<String>, ResolveError> {
+        let mut undecided: Vec<Term> = Vec::new();
+        for assignment in &self.assignments {
+            let package = &assignment.term.package;
+            if !self.decisions.contains_key(package)
+                && !undecided.iter().any(|t| t.package == *package)
+            {
+                let term = self.accumulated(package);
+                if term.positive {
+                    undecided.push(term);
+                }
+            }
+        }
+
+        // Packages with fewer options are more likely to conflict, so deal
+        // with them first.
+        let Some(term) = undecided
+            .into_iter()
+            .min_by(|a, b| (a.versions.len(), &a.package).cmp(&(b.versions.len(), &b.package)))
+        else {
+            return Ok(None);
+        };
+
+        let package = term.package;
+        let version = match self.previously_selected.get(&package) {
+            Some(previous) if term.versions.contains(previous) => previous.clone(),
+            _ => term
+                .versions
+                .last()
+                .cloned()
+                .expect("Unit propagation never leaves an empty term"),
+        };
+
+        let dependencies = if package == self.root.name && version == self.root.version {
+            self.root.dependencies.clone()
+        } else {
+            self.summaries[&package][&version].pkg.dependencies.clone()
+        };
+
+        for dep in &dependencies {
+            let dependency = describe_specifier(&dep.pkg);
+            let incompatibility = match self.candidates(&dep.pkg).await? {
+                Candidates::Found { name, versions } => {
+                    let depender = Term::positive(&package, [version.clone()].into());
+                    let dependee = Term::negative(&name, versions);
+                    let terms = if name == package {
+                        // A package depending on itself is either a no-op or
+                        // rules out this version
+                        let term = depender.intersect(&dependee);
+                        if term.is_empty() {
+                            continue;
+                        }
+                        vec![term]
+                    } else {
+                        vec![depender, dependee]
+                    };
+                    Incompatibility {
+                        terms,
+                        cause: Cause::Dependency {
+                            package: package.clone(),
+                            version: version.clone(),
+                            dependency,
+                        },
+                    }
+                }
+                Candidates::Unavailable(reason) => Incompatibility {
+                    terms: vec![Term::positive(&package, [version.clone()].into())],
+                    cause: Cause::Unavailable {
+                        package: package.clone(),
+                        version: version.clone(),
+                        dependency,
+                        reason,
+                    },
+                },
+            };
+            self.add_incompatibility(incompatibility);
+        }
+
+        tracing::trace!(package = package.as_str(), %version, "Selected a version");
+        self.decide(&package, version);
+
+        Ok(Some(package))
+    }
+
+    /// Ask the [`Source`] which versions could satisfy a dependency.
+    async fn candidates(&mut self, pkg: &PackageSpecifier) -> Result<Candidates, ResolveError> {
+        if let Some(candidates) = self.candidates.get(pkg) {
+            return Ok(candidates.clone());
+        }
+
+        let candidates = match self.source.query(pkg).await {
+            Ok(summaries) if !summaries.is_empty() => {
+                let name = summaries[0].pkg.name.clone();
+                let known = self.summaries.entry(name.clone()).or_default();
+                let mut versions = VersionSet::new();
+
+                for summary in summaries {
+                    if summary.pkg.name == name {
+                        versions.insert(summary.pkg.version.clone());
+                        known.insert(summary.pkg.version.clone(), summary);
+                    }
+                }
+
+                Candidates::Found { name, versions }
+            }
+            Ok(_) => Candidates::Unavailable("has no matching versions".to_string()),
+            Err(QueryError::NotFound) => Candidates::Unavailable("doesn't exist".to_string()),
+            Err(QueryError::NoMatches { archived_versions }) if archived_versions.is_empty() => {
+                Candidates::Unavailable("has no matching versions".to_string())
+            }
+            Err(QueryError::NoMatches { .. }) => {
+                Candidates::Unavailable("only matches archived versions".to_string())
+            }
+            Err(error) => {
+                return Err(ResolveError::Registry {
+                    package: pkg.clone(),
+                    error,
+                })
+            }
+        };
+
+        self.candidates.insert(pkg.clone(), candidates.clone());
+        Ok(candidates)
+    }
+
+    fn into_solution(mut self) -> Solution {
+        let packages = self
+            .decisions
+            .into_iter()
+            .filter(|(name, _)| *name != self.root.name)
+            .map(|(name, version)| {
+                let summary = self
+                    .summaries
+                    .get_mut(&name)
+                    .and_then(|versions| versions.remove(&version))
+                    .expect("Only versions from the source are selected");
+                (name, summary)
+            })
+            .collect();
+
+        let names = self
+            .candidates
+            .into_iter()
+            .filter_map(|(pkg, candidates)| match candidates {
+                Candidates::Found { name, .. } => Some((pkg, name)),
+                Candidates::Unavailable(_) => None,
+            })
+            .collect();
+
+        Solution { packages, names }
+    }
+
+    /// Explain why the terminal incompatibility came to be.
+    fn report(&self, id: usize) -> String {
+        let mut reporter = Reporter {
+            solver: self,
+            ref_counts: HashMap::new(),
+            line_numbers: HashMap::new(),
+            lines: Vec::new(),
+        };
+        reporter.count_references(id, &mut HashSet::new());
+
+        match self.incompatibilities[id].cause {
+            Cause::Derived(..) => reporter.build(id),
+            _ => reporter
+                .lines
+                .push(format!("{}.", capitalize(&self.describe(id)))),
+        }
+
+        reporter.lines.join("\n")
+    }
+
+    fn describe(&self, id: usize) -> String {
+        let incompatibility = &self.incompatibilities[id];
+
+        match &incompatibility.cause {
+            Cause::Root => format!("{} {} is being resolved", self.root.name, self.root.version),
+            Cause::Dependency {
+                package,
+                version,
+                dependency,
+            } => format!("{package} {version} depends on {dependency}"),
+            Cause::Unavailable {
+                package,
+                version,
+                dependency,
+                reason,
+            } => format!("{package} {version} depends on {dependency} which {reason}"),
+            Cause::Derived(..) => self.describe_terms(incompatibility),
+        }
+    }
+
+    fn describe_terms(&self, incompatibility: &Incompatibility) -> String {
+        if self.is_terminal(incompatibility) {
+            return "version solving failed".to_string();
+        }
+
+        let (positive, negative): (Vec<&Term>, Vec<&Term>) =
+            incompatibility.terms.iter().partition(|t| t.positive);
+        let positive: Vec<String> = positive
+            .into_iter()
+            .map(|t| self.describe_term(t))
+            .collect();
+        let negative: Vec<String> = negative
+            .into_iter()
+            .map(|t| self.describe_term(t))
+            .collect();
+
+        match (positive.as_slice(), negative.as_slice()) {
+            ([term], []) => format!("{term} is forbidden"),
+            ([], [term]) => format!("{term} is required"),
+            ([first, second], []) => format!("{first} is incompatible with {second}"),
+            ([], _) => format!("one of {} is required", negative.join(" or ")),
+            (_, []) => format!("{} are incompatible", positive.join(", ")),
+            (_, _) => format!(
+                "{} depends on {}",
+                positive.join(" and "),
+                negative.join(" or ")
+            ),
+        }
+    }
+
+    /// Describe the versions a term is about (ignoring whether it is
+    /// positive or negative), collapsing runs of consecutive versions into
+    /// ranges.
+    fn describe_term(&self, term: &Term) -> String {
+        let mut known: VersionSet = self
+            .summaries
+            .get(&term.package)
+            .map(|versions| versions.keys().cloned().collect())
+            .unwrap_or_default();
+        if term.package == self.root.name {
+            known.insert(self.root.version.clone());
+        }
+
+        let name = &term.package;
+
+        match term.versions.len() {
+            0 => return format!("no version of {name}"),
+            1 => return format!("{name} {}", term.versions.first().unwrap()),
+            _ if term.versions == known => return format!("{name} *"),
+            _ => {}
+        }
+
+        let mut runs: Vec<Vec<&Version>> = Vec::new();
+        let mut in_run = false;
+        for version in &known {
+            if term.versions.contains(version) {
+                match runs.last_mut() {
+                    Some(run) if in_run => run.push(version),
+                    _ => runs.push(vec![version]),
+                }
+                in_run = true;
+            } else {
+                in_run = false;
+            }
+        }
+
+        let ranges: Vec<String> = runs
+            .iter()
+            .map(|run| match run.as_slice() {
+                [version] => version.to_string(),
+                [first, .., last] => format!(">={first}, <={last}"),
+                [] => unreachable!(),
+            })
+            .collect();
+
+        format!("{name} {}", ranges.join(" || "))
+    }
+}
+
+fn describe_specifier(pkg: &PackageSpecifier) -> String {
+    match pkg {
+        PackageSpecifier::Registry { full_name, version } => format!("{full_name} {version}"),
+        PackageSpecifier::Url(url) => url.to_string(),
+        PackageSpecifier::Path(path) => path.display().to_string(),
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Turns the derivation tree behind a failed resolution into prose, giving
+/// numbers to lines which are referred to more than once.
+struct Reporter<'s, 'a> {
+    solver: &'s Solver<'a>,
+    ref_counts: HashMap<usize, usize>,
+    line_numbers: HashMap<usize, usize>,
+    lines: Vec<String>,
+}
+
+impl Reporter<'_, '_> {
+    fn causes(&self, id: usize) -> Option<(usize, usize)> {
+        match self.solver.incompatibilities[id].cause {
+            Cause::Derived(first, second) => Some((first, second)),
+            _ => None,
+        }
+    }
+
+    fn is_derived(&self, id: usize) -> bool {
+        self.causes(id).is_some()
+    }
+
+    fn describe(&self, id: usize) -> String {
+        self.solver.describe(id)
+    }
+
+    fn count_references(&mut self, id: usize, visited: &mut HashSet<usize>) {
+        if let Some((first, second)) = self.causes(id) {
+            for cause in [first, second] {
+                *self.ref_counts.entry(cause).or_default() += 1;
+                if visited.insert(cause) {
+                    self.count_references(cause, visited);
+                }
+            }
+        }
+    }
+
+    fn build(&mut self, id: usize) {
+        self.build_lines(id);
+
+        if self.ref_counts.get(&id).copied().unwrap_or_default() > 1
+            && !self.line_numbers.contains_key(&id)
+        {
+            self.number_last_line(id);
+        }
+    }
+
+    fn number_last_line(&mut self, id: usize) {
+        let number = self.line_numbers.len() + 1;
+        self.line_numbers.insert(id, number);
+        if let Some(line) = self.lines.last_mut() {
+            line.push_str(&format!(" ({number})"));
+        }
+    }
+
+    fn build_lines(&mut self, id: usize) {
+        let (first, second) = self
+            .causes(id)
+            .expect("Only derived incompatibilities are built");
+        let current = self.describe(id);
+
+        match (self.is_derived(first), self.is_derived(second)) {
+            (false, false) => {
+                let line = format!(
+                    "Because {} and {}, {current}.",
+                    self.describe(first),
+                    self.describe(second)
+                );
+                self.lines.push(line);
+            }
+            (true, false) => self.one_derived(first, second, &current),
+            (false, true) => self.one_derived(second, first, &current),
+            (true, true) => {
+                let line = match (
+                    self.line_numbers.get(&first).copied(),
+                    self.line_numbers.get(&second).copied(),
+                ) {
+                    (Some(n1), Some(n2)) => format!(
+                        "Because {} ({n1}) and {} ({n2}), {current}.",
+                        self.describe(first),
+                        self.describe(second)
+                    ),
+                    (Some(n), None) => {
+                        self.build(second);
+                        format!("And because {} ({n}), {current}.", self.describe(first))
+                    }
+                    (None, Some(n)) => {
+                        self.build(first);
+                        format!("And because {} ({n}), {current}.", self.describe(second))
+                    }
+                    (None, None) => {
+                        self.build(first);
+                        if !self.line_numbers.contains_key(&first) {
+                            self.number_last_line(first);
+                        }
+                        self.lines.push(String::new());
+                        self.build(second);
+                        format!(
+                            "And because {} ({}), {current}.",
+                            self.describe(first),
+                            self.line_numbers[&first]
+                        )
+                    }
+                };
+                self.lines.push(line);
+            }
+        }
+    }
+
+    fn one_derived(&mut self, derived: usize, external: usize, current: &str) {
+        if let Some(n) = self.line_numbers.get(&derived).copied() {
+            let line = format!(
+                "Because {} and {} ({n}), {current}.",
+                self.describe(external),
+                self.describe(derived)
+            );
+            self.lines.push(line);
+            return;
+        }
+
+        let (first, second) = self.causes(derived).expect("Checked by the caller");
+        let single_use = self.ref_counts.get(&derived).copied().unwrap_or_default() <= 1;
+
+        // When the derived incompatibility itself came from one derived and
+        // one external cause, we can explain both external causes at once.
+        let prior = match (self.is_derived(first), self.is_derived(second)) {
+            (true, false) if single_use => Some((first, second)),
+            (false, true) if single_use => Some((second, first)),
+            _ => None,
+        };
+
+        match prior {
+            Some((prior_derived, prior_external)) => {
+                self.build(prior_derived);
+                let line = format!(
+                    "And because {} and {}, {current}.",
+                    self.describe(prior_external),
+                    self.describe(external)
+                );
+                self.lines.push(line);
+            }
+            None => {
+                self.build(derived);
+                let line = format!("And because {}, {current}.", self.describe(external));
+                self.lines.push(line);
+            }
+        }
+    }
+}
@@ -26,6 +26,7 @@ pub struct WapmSource {
     client: Arc<dyn HttpClient + Send + Sync>,
     cache: Option<FileSystemCache>,
     token: Option<String>,
+    offline: bool,
 }
 
 impl WapmSource {
@@ -38,6 +39,7 @@ impl WapmSource {
             client,
             cache: None,
             token: None,
+            offline: false,
         }
     }
 
@@ -56,6 +58,12 @@ impl WapmSource {
         }
     }
 
+    /// Answer queries using only the local cache, regardless of how old the
+    /// cached responses are, instead of contacting the registry.
+    pub fn with_offline(self, offline: bool) -> Self {
+        WapmSource { offline, ..self }
+    }
+
     pub fn registry_endpoint(&self) -> &Url {
         &self.registry_endpoint
     }
@@ -154,7 +162,10 @@ impl Source for WapmSource {
         };
 
         if let Some(cache) = &self.cache {
-            match cache.lookup_cached_query(package_name) {
+            match cache.lookup_cached_query(package_name, self.offline) {
+                Ok(Some(cached)) if self.offline => {
+                    return matching_package_summaries(cached, version_constraint);
+                }
                 Ok(Some(cached)) => {
                     if let Ok(cached) = matching_package_summaries(cached, version_constraint) {
                         tracing::debug!("Cache hit!");
@@ -172,6 +183,12 @@ impl Source for WapmSource {
             }
         }
 
+        if self.offline {
+            return Err(QueryError::Other(anyhow::anyhow!(
+                "\"{package_name}\" isn't in the local cache, and the registry can't be queried in offline mode"
+            )));
+        }
+
         let response = self.query_graphql(package_name).await?;
 
         if let Some(cache) = &self.cache {
@@ -286,7 +303,11 @@ impl FileSystemCache {
         self.cache_dir.join(package_name)
     }
 
-    fn lookup_cached_query(&self, package_name: &str) -> Result<Option<WapmWebQuery>, Error> {
+    fn lookup_cached_query(
+        &self,
+        package_name: &str,
+        allow_stale: bool,
+    ) -> Result<Option<WapmWebQuery>, Error> {
         let filename = self.path(package_name);
 
         let _span =
@@ -317,7 +338,7 @@ impl FileSystemCache {
             }
         };
 
-        if !entry.is_still_valid(self.timeout) {
+        if !allow_stale && !entry.is_still_valid(self.timeout) {
             tracing::debug!(timestamp = entry.unix_timestamp, "Cached entry is stale");
             let _ = std::fs::remove_file(&filename);
             return Ok(None);
@@ -808,4 +829,64 @@ mod tests {
         assert_eq!(summaries.len(), 1);
         assert_eq!(summaries[0].pkg.version.to_string(), "4.0.0");
     }
+
+    #[tokio::test]
+    async fn offline_queries_only_use_the_cache() {
+        let cached_value = serde_json::from_value(serde_json::json! {
+            {
+                "data": {
+                    "getPackage": {
+                        "packageName": "python",
+                        "namespace": "wasmer",
+                        "versions": [
+                            {
+                                "version": "3.12.0",
+                                "piritaManifest": "{\"package\": {\"wapm\": {\"name\": \"wasmer/python\", \"version\": \"3.12.0\", \"description\": \"Python\"}}}",
+                                "distribution": {
+                                    "piritaDownloadUrl": "https://wasmer.io/wasmer/python@3.12.0",
+                                    "piritaSha256Hash": "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
+                                }
+                            },
+                        ]
+                    },
+                    "info": {
+                        "defaultFrontend": "https://wasmer.io/",
+                    },
+                }
+            }
+        }).unwrap();
+        let client = Arc::new(DummyClient::new(Vec::new()));
+        let registry_endpoint = WapmSource::WASMER_PROD_ENDPOINT.parse().unwrap();
+        let temp = tempfile::tempdir().unwrap();
+        // Everything in the cache is immediately stale
+        let source = WapmSource::new(registry_endpoint, client.clone())
+            .with_local_cache(temp.path(), Duration::ZERO)
+            .with_offline(true);
+        source
+            .cache
+            .as_ref()
+            .unwrap()
+            .update("wasmer/python", &cached_value)
+            .unwrap();
+
+        let python = PackageSpecifier::Registry {
+            full_name: "wasmer/python".to_string(),
+            version: "*".parse().unwrap(),
+        };
+        let summaries = source.query(&python).await.unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].pkg.version.to_string(), "3.12.0");
+
+        let numpy = PackageSpecifier::Registry {
+            full_name: "wasmer/numpy".to_string(),
+            version: "*".parse().unwrap(),
+        };
+        let err = source.query(&numpy).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "\"wasmer/numpy\" isn't in the local cache, and the registry can't be queried in offline mode"
+        );
+
+        assert!(client.take_requests().is_empty());
+    }
 }
@@ -34,12 +34,16 @@ use crate::{
 /// package is still valid. This checking is done using the [ETag][ETag] header,
 /// if available.
 ///
+/// In offline mode ([`WebSource::with_offline()`]), only packages that are
+/// already in the cache are used, no matter how old they are.
+///
 /// [ETag]: https://en.wikipedia.org/wiki/HTTP_ETag
 #[derive(Debug, Clone)]
 pub struct WebSource {
     cache_dir: PathBuf,
     client: Arc<dyn HttpClient + Send + Sync>,
     retry_period: Duration,
+    offline: bool,
 }
 
 impl WebSource {
@@ -50,6 +54,7 @@ impl WebSource {
             cache_dir: cache_dir.into(),
             client,
             retry_period: WebSource::DEFAULT_RETRY_PERIOD,
+            offline: false,
         }
     }
 
@@ -62,6 +67,12 @@ impl WebSource {
         }
     }
 
+    /// Only use packages from the local cache, instead of downloading or
+    /// re-validating them.
+    pub fn with_offline(self, offline: bool) -> Self {
+        WebSource { offline, ..self }
+    }
+
     /// Download a package and cache it locally.
     #[tracing::instrument(level = "debug", skip_all, fields(%url))]
     async fn get_locally_cached_file(&self, url: &Url) -> Result<PathBuf, Error> {
@@ -235,10 +246,21 @@ impl Source for WebSource {
             _ => return Err(QueryError::Unsupported),
         };
 
-        let local_path = self
-            .get_locally_cached_file(url)
-            .await
-            .context("Unable to get the locally cached file")?;
+        let local_path = if self.offline {
+            let cache_key = sha256(url.as_str().as_bytes());
+            match CacheInfo::for_url(&cache_key, &self.cache_dir) {
+                CacheInfo::Hit { path, .. } => path,
+                CacheInfo::Miss => {
+                    return Err(QueryError::Other(anyhow::anyhow!(
+                        "\"{url}\" isn't in the local cache, and it can't be downloaded in offline mode"
+                    )));
+                }
+            }
+        } else {
+            self.get_locally_cached_file(url)
+                .await
+                .context("Unable to get the locally cached file")?
+        };
 
         let webc_sha256 = crate::block_in_place(|| WebcHash::for_file(&local_path))
             .with_context(|| format!("Unable to hash \"{}\"", local_path.display()))?;
@@ -584,4 +606,40 @@ mod tests {
     async fn download_again_if_etag_is_different() {
         download_again_if_etag_is_different_internal().await
     }
+
+    async fn offline_mode_only_uses_the_cache_internal() {
+        let temp = TempDir::new().unwrap();
+        let client = Arc::new(DummyClient::with_responses([]));
+        // Everything in the cache is immediately stale
+        let source = WebSource::new(temp.path(), client.clone())
+            .with_retry_period(Duration::ZERO)
+            .with_offline(true);
+        let spec = PackageSpecifier::Url(DUMMY_URL.parse().unwrap());
+
+        let err = source.query(&spec).await.unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!("\"{DUMMY_URL}\" isn't in the local cache, and it can't be downloaded in offline mode")
+        );
+
+        std::fs::write(temp.path().join(DUMMY_URL_HASH), PYTHON).unwrap();
+
+        let summaries = source.query(&spec).await.unwrap();
+
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].pkg.name, "python");
+        // No requests were sent, not even to check the ETag
+        assert_eq!(client.requests.lock().unwrap().len(), 0);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn offline_mode_only_uses_the_cache() {
+        offline_mode_only_uses_the_cache_internal().await
+    }
+    #[cfg(target_arch = "wasm32")]
+    #[tokio::test()]
+    async fn offline_mode_only_uses_the_cache() {
+        offline_mode_only_uses_the_cache_internal().await
+    }
 }
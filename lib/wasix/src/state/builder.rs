@@ -8,7 +8,9 @@ use std::{
 
 use rand::Rng;
 use thiserror::Error;
-use virtual_fs::{ArcFile, FileSystem, FsError, TmpFileSystem, VirtualFile};
+use virtual_fs::{
+    ArcFile, FileSystem, FsError, QuotaFileSystem, QuotaLimits, TmpFileSystem, VirtualFile,
+};
 use wasmer::{AsStoreMut, Extern, Imports, Instance, Module, Store};
 
 #[cfg(feature = "journal")]
@@ -62,7 +64,16 @@ pub struct WasiEnvBuilder {
     pub(super) stderr: Option<Box<dyn VirtualFile + Send + Sync + 'static>>,
     pub(super) stdin: Option<Box<dyn VirtualFile + Send + Sync + 'static>>,
     pub(super) fs: Option<WasiFsRoot>,
+    /// File systems that get mounted into the sandbox with a disk quota.
+    pub(super) quota_mounts: Vec<(PathBuf, Arc<dyn FileSystem + Send + Sync>)>,
+    /// The access policy to enforce on the guest's file system.
+    #[cfg(feature = "fs-policy")]
+    #[allow(clippy::type_complexity)]
+    pub(super) fs_policy: Option<(virtual_fs::FsPolicy, Option<Arc<dyn virtual_fs::AuditSink>>)>,
     pub(super) runtime: Option<Arc<dyn crate::Runtime + Send + Sync + 'static>>,
+    /// Impairments applied to the networking of the runtime.
+    #[cfg(feature = "net-shaping")]
+    pub(super) network_profile: Option<virtual_net::NetworkProfile>,
     pub(super) current_dir: Option<PathBuf>,
 
     /// List of webc dependencies to be injected.
@@ -128,6 +139,8 @@ pub enum WasiStateCreationError {
     WasiIncludePackageError(String),
     #[error("control plane error")]
     ControlPlane(#[from] ControlPlaneError),
+    #[error("networking setup error: `{0}`")]
+    NetworkingSetupError(String),
 }
 
 fn validate_mapped_dir_alias(alias: &str) -> Result<(), WasiStateCreationError> {
@@ -517,6 +530,54 @@ impl WasiEnvBuilder {
         Ok(self)
     }
 
+    /// Mount a file system into the sandbox at `guest` and preopen it,
+    /// limiting how much data the WASI module can store in it.
+    ///
+    /// Writes that would go over the quota fail with `EDQUOT`. This is
+    /// mainly useful for exposing host directories (e.g. a
+    /// [`virtual_fs::ScopedDirectoryFileSystem`]) without letting the
+    /// module fill up the host's disk.
+    ///
+    /// This requires the default sandbox file system or one set with
+    /// [`WasiEnvBuilder::sandbox_fs()`].
+    pub fn mount_with_quota(
+        mut self,
+        guest: impl Into<PathBuf>,
+        fs: Arc<dyn FileSystem + Send + Sync>,
+        limits: QuotaLimits,
+    ) -> Result<Self, WasiStateCreationError> {
+        self.add_mount_with_quota(guest, fs, limits)?;
+        Ok(self)
+    }
+
+    /// Mount a file system into the sandbox at `guest` and preopen it,
+    /// limiting how much data the WASI module can store in it.
+    ///
+    /// See [`WasiEnvBuilder::mount_with_quota()`] for more.
+    pub fn add_mount_with_quota(
+        &mut self,
+        guest: impl Into<PathBuf>,
+        fs: Arc<dyn FileSystem + Send + Sync>,
+        limits: QuotaLimits,
+    ) -> Result<(), WasiStateCreationError> {
+        let guest = guest.into();
+        if !guest.is_absolute() {
+            return Err(WasiStateCreationError::PreopenedDirectoryError(format!(
+                "quota mounts must be absolute paths, found \"{}\"",
+                guest.display()
+            )));
+        }
+
+        self.quota_mounts
+            .push((guest.clone(), Arc::new(QuotaFileSystem::new(fs, limits))));
+
+        let mut pdb = PreopenDirBuilder::new();
+        pdb.directory(&guest).read(true).write(true).create(true);
+        self.preopens.push(pdb.build()?);
+
+        Ok(())
+    }
+
     /// Specifies one or more journal files that Wasmer will use to restore
     /// the state of the WASM process.
     ///
@@ -601,6 +662,31 @@ impl WasiEnvBuilder {
         self
     }
 
+    /// Restrict and audit what the guest can do with its file system.
+    ///
+    /// Every open, create, remove and rename made by the guest is checked
+    /// against the [`virtual_fs::FsPolicy`] and recorded in the audit sink.
+    /// Changes the runtime makes itself, like injecting the commands and
+    /// files of dependencies, aren't subject to the policy.
+    #[cfg(feature = "fs-policy")]
+    pub fn fs_policy(
+        mut self,
+        policy: virtual_fs::FsPolicy,
+        audit_sink: Option<Arc<dyn virtual_fs::AuditSink>>,
+    ) -> Self {
+        self.set_fs_policy(policy, audit_sink);
+        self
+    }
+
+    #[cfg(feature = "fs-policy")]
+    pub fn set_fs_policy(
+        &mut self,
+        policy: virtual_fs::FsPolicy,
+        audit_sink: Option<Arc<dyn virtual_fs::AuditSink>>,
+    ) {
+        self.fs_policy = Some((policy, audit_sink));
+    }
+
     /// Configure the WASI filesystem before running.
     // TODO: improve ergonomics on this function
     pub fn setup_fs(mut self, setup_fs_fn: SetupFsFn) -> Self {
@@ -620,6 +706,23 @@ impl WasiEnvBuilder {
         self.runtime = Some(runtime);
     }
 
+    /// Makes the network misbehave (latency, packet loss, connection
+    /// resets, ...) according to a [`virtual_net::NetworkProfile`] so it
+    /// is possible to test how the WASI module copes with a bad network.
+    ///
+    /// The profile is applied on top of the networking implementation of
+    /// the runtime.
+    #[cfg(feature = "net-shaping")]
+    pub fn network_profile(mut self, profile: virtual_net::NetworkProfile) -> Self {
+        self.set_network_profile(profile);
+        self
+    }
+
+    #[cfg(feature = "net-shaping")]
+    pub fn set_network_profile(&mut self, profile: virtual_net::NetworkProfile) {
+        self.network_profile = Some(profile);
+    }
+
     pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
         self.set_capabilities(capabilities);
         self
@@ -766,6 +869,23 @@ impl WasiEnvBuilder {
             .take()
             .unwrap_or_else(|| WasiFsRoot::Sandbox(Arc::new(TmpFileSystem::new())));
 
+        if !self.quota_mounts.is_empty() {
+            let WasiFsRoot::Sandbox(root_fs) = &fs_backing else {
+                return Err(WasiStateCreationError::WasiFsSetupError(
+                    "quota mounts require a sandboxed file system".to_string(),
+                ));
+            };
+
+            for (guest, fs) in &self.quota_mounts {
+                mount_into_sandbox(root_fs, guest, fs).map_err(|err| {
+                    WasiStateCreationError::WasiFsSetupError(format!(
+                        "Could not mount '{}': {err}",
+                        guest.display()
+                    ))
+                })?;
+            }
+        }
+
         if let Some(dir) = &self.current_dir {
             match fs_backing.read_dir(dir) {
                 Ok(_) => {
@@ -788,6 +908,23 @@ impl WasiEnvBuilder {
             }
         }
 
+        // The guest sees the file system through the policy, while the
+        // runtime keeps using the original one
+        #[allow(unused_mut)]
+        let mut unrestricted_fs = None;
+        #[cfg(feature = "fs-policy")]
+        let fs_backing = match self.fs_policy.take() {
+            Some((policy, audit_sink)) => {
+                let mut fs = virtual_fs::PolicyFileSystem::new(fs_backing.clone(), policy);
+                if let Some(sink) = audit_sink {
+                    fs = fs.with_audit_sink(sink);
+                }
+                unrestricted_fs = Some(fs_backing);
+                WasiFsRoot::Backing(Arc::new(Box::new(fs)))
+            }
+            None => fs_backing,
+        };
+
         // self.preopens are checked in [`PreopenDirBuilder::build`]
         let inodes = crate::state::WasiInodes::new();
         let wasi_fs = {
@@ -795,6 +932,7 @@ impl WasiEnvBuilder {
             let mut wasi_fs =
                 WasiFs::new_with_preopen(&inodes, &self.preopens, &self.vfs_preopens, fs_backing)
                     .map_err(WasiStateCreationError::WasiFsCreationError)?;
+            wasi_fs.unrestricted_root_fs = unrestricted_fs;
 
             // set up the file system, overriding base files and calling the setup function
             wasi_fs
@@ -858,6 +996,23 @@ impl WasiEnvBuilder {
             }
         });
 
+        #[cfg(feature = "net-shaping")]
+        let runtime = match self.network_profile {
+            Some(profile) => {
+                let (networking, driver) =
+                    virtual_net::ShapedNetworking::new(runtime.networking().clone(), profile);
+                runtime
+                    .task_manager()
+                    .task_shared(Box::new(move || Box::pin(driver)))
+                    .map_err(|e| WasiStateCreationError::NetworkingSetupError(e.to_string()))?;
+                Arc::new(
+                    crate::runtime::OverriddenRuntime::new(runtime)
+                        .with_networking(Arc::new(networking)),
+                )
+            }
+            None => runtime,
+        };
+
         let uses = self.uses;
         let map_commands = self.map_commands;
 
@@ -1038,6 +1193,24 @@ impl WasiEnvBuilder {
     }
 }
 
+fn mount_into_sandbox(
+    root_fs: &TmpFileSystem,
+    guest: &Path,
+    fs: &Arc<dyn FileSystem + Send + Sync>,
+) -> Result<(), FsError> {
+    if let Some(parent) = guest.parent() {
+        for dir in parent.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            match root_fs.create_dir(dir) {
+                Ok(()) | Err(FsError::AlreadyExists) => {}
+                Err(_) if root_fs.metadata(dir).map(|m| m.is_dir()).unwrap_or(false) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    root_fs.mount(guest.to_path_buf(), fs, "/".into())
+}
+
 pub(crate) fn conv_env_vars(envs: Vec<(String, Vec<u8>)>) -> Vec<Vec<u8>> {
     envs.into_iter()
         .map(|(key, value)| {
@@ -1230,4 +1403,36 @@ mod test {
             WasiStateCreationError::ArgumentContainsNulByte(_)
         ));
     }
+
+    #[test]
+    fn quota_mounts_need_a_sandbox() {
+        #[cfg(not(target_arch = "wasm32"))]
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        #[cfg(not(target_arch = "wasm32"))]
+        let _guard = runtime.enter();
+
+        let limits = QuotaLimits {
+            max_bytes: Some(1024),
+            max_inodes: None,
+        };
+        let data: Arc<dyn FileSystem + Send + Sync> =
+            Arc::new(virtual_fs::mem_fs::FileSystem::default());
+
+        let init = WasiEnvBuilder::new("test_prog")
+            .mount_with_quota("/mnt/data", Arc::clone(&data), limits)
+            .unwrap()
+            .build_init();
+        assert!(init.is_ok(), "{:?}", init.err());
+
+        let err = WasiEnvBuilder::new("test_prog")
+            .fs(Box::<virtual_fs::mem_fs::FileSystem>::default())
+            .mount_with_quota("/mnt/data", data, limits)
+            .unwrap()
+            .build_init()
+            .expect_err("should fail");
+        assert!(matches!(err, WasiStateCreationError::WasiFsSetupError(_)));
+    }
 }
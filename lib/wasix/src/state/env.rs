@@ -1044,7 +1044,7 @@ impl WasiEnv {
     /// [pkg-fs]: crate::bin_factory::BinaryPackage::webc_fs
     pub fn use_package(&self, pkg: &BinaryPackage) -> Result<(), WasiStateCreationError> {
         tracing::trace!(packagae=%pkg.package_name, "merging package dependency into wasi environment");
-        let root_fs = &self.state.fs.root_fs;
+        let root_fs = self.state.fs.unrestricted_root_fs();
 
         // We first need to copy any files in the package over to the
         // main file system
@@ -1179,7 +1179,7 @@ impl WasiEnv {
             })?;
             let file: std::borrow::Cow<'static, [u8]> = file.into();
 
-            if let WasiFsRoot::Sandbox(root_fs) = &self.state.fs.root_fs {
+            if let WasiFsRoot::Sandbox(root_fs) = self.state.fs.unrestricted_root_fs() {
                 let _ = root_fs.create_dir(Path::new("/bin"));
 
                 let path = format!("/bin/{}", command);
@@ -19,6 +19,6 @@ pub fn fd_datasync(mut ctx: FunctionEnvMut<'_, WasiEnv>, fd: WasiFd) -> Result<E
 
     #[allow(clippy::await_holding_lock)]
     Ok(wasi_try_ok!(__asyncify(&mut ctx, None, async move {
-        state.fs.flush(fd).await.map(|_| Errno::Success)
+        state.fs.sync(fd).await.map(|_| Errno::Success)
     })?))
 }
@@ -38,7 +38,9 @@ pub fn fd_sync(mut ctx: FunctionEnvMut<'_, WasiEnv>, fd: WasiFd) -> Result<Errno
                             // TODO: remove allow once inodes are refactored (see comments on [`WasiState`])
                             #[allow(clippy::await_holding_lock)]
                             let mut handle = handle.write().unwrap();
-                            handle.flush().await.map_err(map_io_err)?;
+                            futures::future::poll_fn(|cx| Pin::new(handle.as_mut()).poll_sync(cx))
+                                .await
+                                .map_err(map_io_err)?;
                             Ok(handle.size())
                         })?)
                     };
@@ -52,6 +52,7 @@ mod sock_send_to;
 mod sock_set_opt_flag;
 mod sock_set_opt_size;
 mod sock_set_opt_time;
+mod sock_set_tls_server_name;
 mod sock_shutdown;
 mod sock_status;
 mod stack_checkpoint;
@@ -120,6 +121,7 @@ pub use sock_send_to::*;
 pub use sock_set_opt_flag::*;
 pub use sock_set_opt_size::*;
 pub use sock_set_opt_time::*;
+pub use sock_set_tls_server_name::*;
 pub use sock_shutdown::*;
 pub use sock_status::*;
 pub use stack_checkpoint::*;
@@ -88,6 +88,12 @@ pub(crate) fn sock_open_internal(
                     read_timeout: None,
                     accept_timeout: None,
                     connect_timeout: None,
+                    #[cfg(feature = "net-tls")]
+                    tls_client: None,
+                    #[cfg(feature = "net-tls")]
+                    tls_server_name: None,
+                    #[cfg(feature = "net-tls")]
+                    tls_server: None,
                     handler: None,
                 },
                 addr: None,
@@ -43,6 +43,29 @@ pub(crate) fn sock_set_opt_flag_internal(
     flag: bool,
 ) -> Result<Result<(), Errno>, WasiError> {
     let option: crate::net::socket::WasiSocketOption = opt.into();
+
+    #[cfg(feature = "net-tls")]
+    if let Sockoption::TlsClient | Sockoption::TlsServer = opt {
+        // the certificates come from the runtime, not from the guest
+        let runtime = ctx.data().runtime.clone();
+        let (client, server) = match flag {
+            true => (runtime.tls_client().cloned(), runtime.tls_server().cloned()),
+            false => (None, None),
+        };
+        wasi_try_ok_ok!(__sock_actor_mut(
+            ctx,
+            sock,
+            Rights::empty(),
+            |mut socket, _| match opt {
+                Sockoption::TlsClient if flag && client.is_none() => Err(Errno::Notsup),
+                Sockoption::TlsClient => socket.set_tls_client(client),
+                _ if flag && server.is_none() => Err(Errno::Notsup),
+                _ => socket.set_tls_server(server),
+            }
+        ));
+        return Ok(Ok(()));
+    }
+
     wasi_try_ok_ok!(__sock_actor_mut(
         ctx,
         sock,
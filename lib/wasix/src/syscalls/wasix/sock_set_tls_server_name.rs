@@ -0,0 +1,44 @@
+use super::*;
+use crate::syscalls::*;
+
+/// ### `sock_set_tls_server_name()`
+/// Sets the name the certificate of the server is verified against when the
+/// socket is upgraded to TLS with the `tls_client` socket option, it is also
+/// sent to the server with SNI. Without a name the certificate has to be
+/// valid for the address the socket is connected to.
+///
+/// Note: This has to be set before connecting
+///
+/// ## Parameters
+///
+/// * `fd` - Socket descriptor
+/// * `name` - Name of the server (e.g. `example.com`)
+#[instrument(level = "debug", skip_all, fields(%sock, name = field::Empty), ret)]
+pub fn sock_set_tls_server_name<M: MemorySize>(
+    ctx: FunctionEnvMut<'_, WasiEnv>,
+    sock: WasiFd,
+    name: WasmPtr<u8, M>,
+    name_len: M::Offset,
+) -> Result<Errno, WasiError> {
+    let env = ctx.data();
+    let name = {
+        let memory = unsafe { env.memory_view(&ctx) };
+        unsafe { get_input_str_ok!(&memory, name, name_len) }
+    };
+    Span::current().record("name", name.as_str());
+
+    #[cfg(feature = "net-tls")]
+    {
+        let mut ctx = ctx;
+        let name = name.trim_end_matches('.').to_string();
+        wasi_try_ok!(__sock_actor_mut(
+            &mut ctx,
+            sock,
+            Rights::empty(),
+            |mut socket, _| socket.set_tls_server_name(Some(name))
+        ));
+        Ok(Errno::Success)
+    }
+    #[cfg(not(feature = "net-tls"))]
+    Ok(Errno::Notsup)
+}
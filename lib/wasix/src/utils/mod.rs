@@ -35,6 +35,16 @@ pub fn is_wasix_module(module: &Module) -> bool {
 }
 
 pub fn map_io_err(err: std::io::Error) -> Errno {
+    // Some file system errors (e.g. exceeding a quota) don't have an
+    // equivalent `std::io::ErrorKind`, so they get smuggled through as the
+    // error's payload
+    if let Some(fs_error) = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<virtual_fs::FsError>())
+    {
+        return crate::fs::fs_error_into_wasi_err(*fs_error);
+    }
+
     From::<std::io::Error>::from(err)
 }
 
@@ -94,6 +94,81 @@ mod wasi {
         };
         assert_eq!(exit_code.raw(), 42);
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[cfg(feature = "fs-policy")]
+    async fn fs_policy_only_applies_to_the_guest() {
+        use virtual_fs::{FsDecision, FsPolicy};
+        use wasmer_wasix::types::wasi::Errno;
+
+        // Tries to create "/tmp/file.txt" and exits with the errno
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "tmp/file.txt")
+                (func (export "_start")
+                    (call $proc_exit
+                        (call $path_open
+                            (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 12)
+                            (i32.const 1) (i64.const -1) (i64.const -1) (i32.const 0)
+                            (i32.const 64)))))
+        "#;
+        let temp = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            temp.path().join("main.wasm"),
+            wasmer::wat2wasm(wat.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let manifest = temp.path().join("wasmer.toml");
+        std::fs::write(
+            &manifest,
+            r#"
+                [package]
+                name = "test/fs-policy"
+                version = "0.0.0"
+                description = "Creates a file"
+
+                [[module]]
+                name = "main"
+                source = "main.wasm"
+                abi = "wasi"
+
+                [[command]]
+                name = "main"
+                module = "main"
+            "#,
+        )
+        .unwrap();
+        let container: Container = webc::wasmer_package::Package::from_manifest(manifest)
+            .unwrap()
+            .into();
+        let (rt, tasks) = runtime();
+        let pkg = BinaryPackage::from_webc(&container, &rt).await.unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let _guard = tasks.runtime_handle().enter();
+            WasiRunner::new()
+                .with_fs_policy(FsPolicy::new(FsDecision::Deny), None)
+                .with_stdin(Box::<virtual_fs::NullFile>::default())
+                .with_stdout(Box::<virtual_fs::NullFile>::default())
+                .with_stderr(Box::<virtual_fs::NullFile>::default())
+                .run_command("main", &pkg, Arc::new(rt))
+        });
+
+        // Injecting the package's commands isn't affected by the policy, so
+        // the program gets to run and is the one being denied
+        let err = handle.join().unwrap().unwrap_err();
+        let runtime_error = err.chain().find_map(|e| e.downcast_ref::<WasiError>());
+        let exit_code = match runtime_error {
+            Some(WasiError::Exit(code)) => *code,
+            Some(other) => panic!("Something else went wrong: {:?}", other),
+            None => panic!("Not a WasiError: {:?}", err),
+        };
+        assert_eq!(exit_code.raw(), Errno::Perm as i32);
+    }
 }
 
 #[cfg(feature = "webc_runner_rt_wcgi")]
@@ -687,6 +687,63 @@ fn issue_3794_unable_to_mount_relative_paths() {
     assert.success().stdout(contains("Hello, World!"));
 }
 
+#[test]
+#[cfg_attr(
+    all(target_env = "musl", target_os = "linux"),
+    ignore = "wasmer run-unstable segfaults on musl"
+)]
+fn run_a_package_with_an_fs_policy() {
+    let temp = TempDir::new().unwrap();
+    let policy = temp.path().join("policy.toml");
+    std::fs::write(
+        &policy,
+        r#"
+            default = "deny"
+
+            [[rules]]
+            path = "/**"
+            access = ["read"]
+            decision = "allow"
+        "#,
+    )
+    .unwrap();
+    let audit_log = temp.path().join("audit.jsonl");
+
+    // The package's commands still get injected into /bin...
+    let assert = Command::new(get_wasmer_path())
+        .arg("run")
+        .arg(fixtures::coreutils())
+        .arg("--fs-policy")
+        .arg(&policy)
+        .arg("--command-name=ls")
+        .arg("--")
+        .arg("/bin")
+        .env("RUST_LOG", &*RUST_LOG)
+        .assert();
+
+    assert.success().stdout(contains("touch"));
+
+    // ... but the guest can't write anywhere
+    let assert = Command::new(get_wasmer_path())
+        .arg("run")
+        .arg(fixtures::coreutils())
+        .arg("--fs-policy")
+        .arg(&policy)
+        .arg("--fs-audit-log")
+        .arg(&audit_log)
+        .arg("--command-name=touch")
+        .arg("--")
+        .arg("/tmp/file.txt")
+        .env("RUST_LOG", &*RUST_LOG)
+        .assert();
+
+    assert.failure();
+    let audit_log = std::fs::read_to_string(&audit_log).unwrap();
+    assert!(audit_log
+        .lines()
+        .any(|line| line.contains("/tmp/file.txt") && line.contains(r#""decision":"deny""#)));
+}
+
 #[test]
 #[cfg_attr(
     all(target_env = "musl", target_os = "linux"),